@@ -47,7 +47,7 @@ impl TryFrom<&BTypes> for Url {
     type Error = BencodeErr;
     fn try_from(value: &BTypes) -> Result<Self, Self::Error> {
         if let BTypes::BSTRING(s) = value {
-            let p = std::str::from_utf8(s).unwrap();
+            let p = std::str::from_utf8(s).map_err(|_| BencodeErr::InvalidUrl)?;
             Ok(Url::new(p)?)
         } else {
             Err(BencodeErr::Berr)
@@ -80,14 +80,14 @@ impl TryFrom<&BTypes> for usize {
 impl TryFrom<&BTypes> for Vec<String> {
     type Error = BencodeErr;
 
+    /// A list of strings. Anything else in the list, like a nested list, is an error
+    /// rather than being left out
     fn try_from(value: &BTypes) -> Result<Self, Self::Error> {
-        let store: Vec<String>;
         if let BTypes::LIST(l) = value {
-            store = l.iter().filter_map(|v| v.try_into().ok()).collect();
+            l.iter().map(|v| v.try_into()).collect()
         } else {
-            return Err(BencodeErr::Berr);
+            Err(BencodeErr::Berr)
         }
-        Ok(store)
     }
 }
 
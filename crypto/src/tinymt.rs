@@ -1,3 +1,5 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 // params
 const MAT1_PARAM: u32 = 0x8f7011ee_u32;
 const MAT2_PARAM: u32 = 0xfc78ff1f_u32;
@@ -13,7 +15,7 @@ const SH1: u32 = 10;
 const SH8: u32 = 8;
 const MASK: u32 = 0x7fffffff;
 
-#[derive(Default)]
+#[derive(Default, Debug)]
 pub struct TinyMT {
     status: [u32; 4],
     mat1: u32,
@@ -59,6 +61,22 @@ impl TinyMT {
         tinymt.next_state();
         tinymt
     }
+
+    /// Seeded from the clock, for when any seed will do
+    pub fn from_clock() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos() ^ d.as_secs() as u32);
+        Self::rand(seed)
+    }
+
+    /// Put `items` in a random order, Fisher-Yates
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            self.rng();
+            items.swap(i, self.get_u32() as usize % (i + 1));
+        }
+    }
     pub fn rng(&mut self) {
         self.next_state();
     }
@@ -83,7 +101,7 @@ impl TinyMT {
 
     pub fn get_u32(&self) -> u32 {
         let mut t0 = self.status[3];
-        let t1 = self.status[0] + (self.status[2] >> SH8);
+        let t1 = self.status[0].wrapping_add(self.status[2] >> SH8);
 
         t0 ^= t1;
 
@@ -112,6 +130,27 @@ mod test {
         assert!(rand.get_u32() != 1255019984);
     }
 
+    #[test]
+    fn shuffle() {
+        let mut items: Vec<u32> = (0..100).collect();
+        TinyMT::rand(1).shuffle(&mut items);
+        assert_ne!(items, (0..100).collect::<Vec<_>>());
+        items.sort();
+        assert_eq!(items, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn rand_no_overflow() {
+        // status[0] + (status[2] >> 8) regularly overflows a u32 and must wrap
+        for seed in 0..1000 {
+            let mut rand = TinyMT::rand(seed);
+            for _ in 0..16 {
+                rand.rng();
+                _ = rand.get_u32();
+            }
+        }
+    }
+
     #[test]
     fn rand_prime() {
         // 7823 is prime
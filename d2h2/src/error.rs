@@ -97,7 +97,7 @@ impl Display for D2H2ClientError {
                 write!(f, "Error: Breaking Error: Moving out of Arc")
             }
             D2H2ClientError::UrlFormError(e) => write!(f, "Invalid Url: {e}"),
-            D2H2ClientError::NetworkError(e) => write!(f, "Network Error: {e:?}"),
+            D2H2ClientError::NetworkError(e) => write!(f, "Network Error: {e}"),
             D2H2ClientError::Serde(e) => write!(f, "Serde Error: {e}"),
             D2H2ClientError::DHT(e) => write!(f, "DHT Error: {e}"),
             D2H2ClientError::LookupTimeout => write!(f, "Error: DHT lookup timed out"),
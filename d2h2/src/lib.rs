@@ -1,12 +1,14 @@
 // REFERENCE: https://www.bittorrent.org/beps/bep_0005.html
 
 use std::collections::HashSet;
+use std::net::SocketAddr;
 use std::time;
 use std::time::Duration;
 
 use error::D2H2ClientError;
 use kademlia::{DHT, Node, RouteTable};
 use serde::{MessageType, QueryType, ResponseType, KRPC};
+use uttd::proxy::{Proxy, Socks5Udp};
 use uttd::url::{Scheme, Url};
 use uttd::AsyncStream;
 
@@ -41,6 +43,8 @@ pub struct D2H2Client {
     // number of concurrent requests to send for path
     concurrent: u8,
     table: RouteTable,
+    // set when a proxy is configured: every query goes through its UDP relay
+    relay: Option<Socks5Udp>,
 }
 
 impl D2H2Client {
@@ -58,9 +62,17 @@ impl D2H2Client {
             paths: vec![base],
             concurrent: 5,
             table: dht.table,
+            relay: None,
         })
     }
 
+    /// Send every query through `proxy`, so no DHT traffic leaves without it.
+    /// Only SOCKS5 proxies relay UDP: with an HTTP proxy the DHT can't be used at all
+    pub async fn with_proxy(mut self, proxy: &Proxy) -> Result<Self, D2H2ClientError> {
+        self.relay = Some(proxy.associate_udp().await?);
+        Ok(self)
+    }
+
     /// Send a KRPC query to a DHT node and return the parsed response
     async fn send_query(&self, url: &Url, krpc: KRPC) -> Result<KRPC, D2H2ClientError> {
         let target = Url {
@@ -68,6 +80,14 @@ impl D2H2Client {
             host: url.host.clone(),
             location: "/".to_owned(),
         };
+        if let Some(relay) = &self.relay {
+            return tokio::time::timeout(
+                Duration::from_secs(QUERY_TIMEOUT_SECS),
+                Self::relay_query(relay, &url.host, krpc),
+            )
+            .await
+            .map_err(|_| D2H2ClientError::LookupTimeout)?;
+        }
         let mut stream = AsyncStream::new(target).await?;
         let data = serde::serialize(krpc);
         let mut res = vec![0u8; DHT_PACKET_SIZE];
@@ -82,6 +102,26 @@ impl D2H2Client {
         Ok(response)
     }
 
+    /// Send a query to `host` through the proxy's relay and wait for its response.
+    /// The relay is shared by every query, late answers to earlier ones are skipped
+    async fn relay_query(
+        relay: &Socks5Udp,
+        host: &str,
+        krpc: KRPC,
+    ) -> Result<KRPC, D2H2ClientError> {
+        let transaction_id = krpc.transaction_id.clone();
+        relay.send_to(&serde::serialize(krpc), host).await?;
+        loop {
+            let mut res = vec![0u8; DHT_PACKET_SIZE];
+            let (read, _) = relay.recv_from(&mut res).await?;
+            res.truncate(read);
+            match KRPC::deserialize_bytes(res) {
+                Ok(response) if response.transaction_id == transaction_id => return Ok(response),
+                _ => continue,
+            }
+        }
+    }
+
     /// Send a ping query to a DHT node
     pub async fn ping(&self, url: &Url) -> Result<KRPC, D2H2ClientError> {
         let krpc = KRPC::new("pg".into(), QueryType::Ping, &self.node_id);
@@ -94,8 +134,8 @@ impl D2H2Client {
     pub async fn find_peers(
         &mut self,
         info_hash: &[u8; 20],
-    ) -> Result<Box<[Url]>, D2H2ClientError> {
-        let mut peers: Vec<Url> = Vec::new();
+    ) -> Result<Box<[SocketAddr]>, D2H2ClientError> {
+        let mut peers: Vec<SocketAddr> = Vec::new();
         let mut queried: HashSet<String> = HashSet::new();
         // transaction id counter
         let mut tid: u16 = 0;
@@ -186,14 +226,55 @@ impl D2H2Client {
 
 #[cfg(test)]
 mod test {
+    use tokio::{net::UdpSocket, sync::mpsc};
+    use uttd::proxy::test::{http_proxy, socks5_proxy};
+    use uttd::url::{Scheme, Url};
+
+    use crate::error::D2H2ClientError;
     use crate::serde::{MessageType, ResponseType};
     use crate::D2H2Client;
-    use uttd::url::Url;
 
     fn bootstrap_url() -> Url {
         Url::from_ip("router.bittorrent.com", 6881).unwrap()
     }
 
+    #[tokio::test]
+    async fn queries_go_through_the_proxy() {
+        // a DHT node answering a ping, after a late answer to some other query
+        let node = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::from_socket_addr(node.local_addr().unwrap(), Scheme::UDP);
+        let (tx, mut senders) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            loop {
+                let (_, from) = node.recv_from(&mut buf).await.unwrap();
+                for tid in ["xx", "pg"] {
+                    let response = format!("d1:rd2:id20:aaaaaaaaaaaaaaaaaaaae1:t2:{tid}1:y1:re");
+                    node.send_to(response.as_bytes(), from).await.unwrap();
+                }
+                tx.send(from).unwrap();
+            }
+        });
+
+        let proxy = socks5_proxy(None).await;
+        let client = D2H2Client::new(None)
+            .unwrap()
+            .with_proxy(&proxy)
+            .await
+            .unwrap();
+        let response = client.ping(&url).await.unwrap();
+        assert_eq!(response.transaction_id, "pg");
+        client.ping(&url).await.unwrap();
+        // both came from the proxy's relay, not from a socket of their own
+        let first = senders.recv().await.unwrap();
+        assert_eq!(senders.recv().await.unwrap(), first);
+
+        // HTTP proxies don't carry UDP: no DHT rather than one around the proxy
+        let http = http_proxy(None).await;
+        let refused = D2H2Client::new(None).unwrap().with_proxy(&http).await;
+        assert!(matches!(refused, Err(D2H2ClientError::NetworkError(_))));
+    }
+
     // IMPORTANT: These tests interact with real DHT bootstrap nodes on the internet.
     // They may fail if the network is unreachable or the bootstrap nodes are down.
 
@@ -255,7 +336,7 @@ mod test {
                 // each node should have a valid 20-byte id and a reachable url
                 for node in nodes.iter() {
                     assert_eq!(node.id.len(), 20);
-                    assert!(node.node.port() > 0);
+                    assert!(node.node.port().unwrap() > 0);
                 }
             } else {
                 panic!("Expected Node response type, got: {:?}", r.response)
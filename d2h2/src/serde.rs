@@ -1,12 +1,15 @@
 #![allow(dead_code, unused_variables)]
 
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, net::SocketAddr};
 
 use crate::error::{self, DHTError, SerdeError};
 use ::bencode::benencode;
 use ::bencode::utils::vec_to_string;
 use bencode::{bencode, BTypes};
-use uttd::url::{Scheme, Url};
+use uttd::{
+    url::{Scheme, Url},
+    urutil::compact_addr,
+};
 
 #[derive(Debug)]
 pub struct KRPC {
@@ -68,7 +71,7 @@ pub enum ResponseType {
     // we don't need to add to them or remove from them. We can just drop them after
     // we've consumed them.
     Node(Box<[ResponseNode]>),
-    Values(Box<[Url]>),
+    Values(Box<[SocketAddr]>),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -276,32 +279,28 @@ impl KRPC {
                     response: None,
                 };
 
-                if let Some(BTypes::BSTRING(bs)) = d.get("nodes") {
-                    let ips = bs
-                        .chunks(26)
-                        .map(|x| {
-                            // todo: use this
-                            let node_id: [u8; 20] = x[0..20].try_into().unwrap();
-                            let ip: [u8; 4] = x[20..24].try_into().unwrap();
-                            let port = u16::from_be_bytes(x[24..26].try_into().unwrap());
-                            ResponseNode {
-                                id: node_id,
-                                node: Url::from_ip_bytes(&ip, port, Scheme::UDP),
-                            }
-                        })
-                        .collect();
-                    resp.response = Some(ResponseType::Node(ips));
+                // IPv4 nodes are 26 bytes each, IPv6 nodes (BEP 32) 38
+                let mut nodes = Vec::new();
+                for (key, size) in [("nodes", 26), ("nodes6", 38)] {
+                    if let Some(BTypes::BSTRING(bs)) = d.get(key) {
+                        nodes.extend(bs.chunks_exact(size).filter_map(|x| {
+                            let (node_id, addr) = x.split_at(20);
+                            Some(ResponseNode {
+                                id: node_id.try_into().ok()?,
+                                node: Url::from_socket_addr(compact_addr(addr)?, Scheme::UDP),
+                            })
+                        }));
+                    }
+                }
+                if !nodes.is_empty() {
+                    resp.response = Some(ResponseType::Node(nodes.into_boxed_slice()));
                 } else if let Some(BTypes::LIST(l)) = d.get("values") {
-                    // create urls from the compact url info contained in bs
-                    // this containes the actual peers for the find_peers query
+                    // the actual peers for the find_peers query, in compact form:
+                    // 6 bytes for IPv4 peers, 18 for IPv6 ones
                     let ips = l
-                        .into_iter()
+                        .iter()
                         .filter_map(|b| match b {
-                            BTypes::BSTRING(bs) if bs.len() >= 6 => {
-                                let ip: [u8; 4] = bs[0..4].try_into().unwrap();
-                                let port = u16::from_be_bytes(bs[4..6].try_into().unwrap());
-                                Some(Url::from_ip_bytes(&ip, port, Scheme::UDP))
-                            }
+                            BTypes::BSTRING(bs) => compact_addr(bs),
                             _ => None,
                         })
                         .collect();
@@ -399,9 +398,7 @@ mod test {
         if let MessageType::Response(r) = res.message_type {
             assert_eq!(r.id, "abcdefghij0123456789".to_string());
             if let Some(super::ResponseType::Values(nodes)) = r.response {
-                assert_eq!(nodes[0].host, "127.0.0.1:6881");
-                assert_eq!(nodes[0].scheme, Scheme::UDP);
-                assert_eq!(nodes[0].port(), 6881);
+                assert_eq!(nodes[..], ["127.0.0.1:6881".parse().unwrap()]);
             } else {
                 panic!("Message Response Type is not 'find_node': {:?}", r)
             }
@@ -420,7 +417,7 @@ mod test {
                 assert_eq!(&nodes[0].id, b"00000000000000000001");
                 assert_eq!(nodes[0].node.host, "127.0.0.1:6881");
                 assert_eq!(nodes[0].node.scheme, Scheme::UDP);
-                assert_eq!(nodes[0].node.port(), 6881);
+                assert_eq!(nodes[0].node.port().unwrap(), 6881);
             } else {
                 panic!("Message Response Type is not 'find_node': {:?}", r)
             }
@@ -429,6 +426,41 @@ mod test {
         }
     }
 
+    #[test]
+    fn deserialize_ipv6() {
+        let mut bytes = b"d1:rd2:id20:abcdefghij01234567896:nodes638:00000000000000000002".to_vec();
+        bytes.extend_from_slice(&[0; 15]);
+        bytes.extend_from_slice(b"\x01\x1a\xe1e1:t2:aa1:y1:re");
+        let res = KRPC::deserialize_bytes(bytes).unwrap();
+        let MessageType::Response(r) = res.message_type else {
+            panic!("Message Type is not response")
+        };
+        let Some(super::ResponseType::Node(nodes)) = r.response else {
+            panic!("Message Response Type is not 'find_node': {:?}", r)
+        };
+        assert_eq!(&nodes[0].id, b"00000000000000000002");
+        assert_eq!(nodes[0].node.host, "[::1]:6881");
+
+        // 18 byte values are IPv6 peers
+        let mut bytes = b"d1:rd2:id20:abcdefghij01234567896:valuesl18:".to_vec();
+        bytes.extend_from_slice(&[0; 15]);
+        bytes.extend_from_slice(b"\x01\x1a\xe16:\x7f\x00\x00\x01\x1a\xe1ee1:t2:aa1:y1:re");
+        let res = KRPC::deserialize_bytes(bytes).unwrap();
+        let MessageType::Response(r) = res.message_type else {
+            panic!("Message Type is not response")
+        };
+        let Some(super::ResponseType::Values(peers)) = r.response else {
+            panic!("Message Response Type is not 'get_peers': {:?}", r)
+        };
+        assert_eq!(
+            peers[..],
+            [
+                "[::1]:6881".parse().unwrap(),
+                "127.0.0.1:6881".parse().unwrap()
+            ]
+        );
+    }
+
     #[test]
     fn serialize_ping() {
         let krpc = KRPC::new("aa".into(), super::QueryType::Ping, &[0; 20]);
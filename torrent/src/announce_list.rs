@@ -0,0 +1,219 @@
+// REFERENCE: https://www.bittorrent.org/beps/bep_0012.html
+
+use bencode::utils::BencodeErr;
+use crypto::tinymt::TinyMT;
+use uttd::{http::HttpClient, url::Url};
+
+use crate::{
+    error::TrackerError,
+    peers::Peers,
+    torrent::Torrent,
+    tracker::{TrackerParams, UdpTrackers},
+};
+
+/// The trackers of a torrent, in tiers (BEP 12).
+/// Trackers are tried one after the other, tier by tier, until one answers.
+/// The one that answered moves to the front of its tier so it's asked first next time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceList {
+    tiers: Vec<Vec<Url>>,
+}
+
+impl AnnounceList {
+    /// Trackers of `torrent`: its announce list if it has one, else its `announce` url.
+    /// Each tier is shuffled, as the BEP asks
+    pub fn new(torrent: &Torrent) -> Self {
+        let tiers = match torrent.announce_list.is_empty() {
+            true => vec![vec![torrent.announce.clone()]],
+            false => torrent.announce_list.clone(),
+        };
+        Self::shuffled(tiers, TinyMT::from_clock())
+    }
+
+    /// Shuffle the trackers within each of `tiers`, using `seed`
+    pub fn from_tiers(tiers: Vec<Vec<Url>>, seed: u32) -> Self {
+        Self::shuffled(tiers, TinyMT::rand(seed))
+    }
+
+    fn shuffled(mut tiers: Vec<Vec<Url>>, mut rng: TinyMT) -> Self {
+        for tier in &mut tiers {
+            rng.shuffle(tier);
+        }
+        tiers.retain(|tier| !tier.is_empty());
+        Self { tiers }
+    }
+
+    pub fn tiers(&self) -> &[Vec<Url>] {
+        &self.tiers
+    }
+
+    /// Every tracker, in the order they are tried
+    pub fn trackers(&self) -> impl Iterator<Item = &Url> {
+        self.tiers.iter().flatten()
+    }
+
+    /// Move `url` to the front of its tier
+    pub fn promote(&mut self, url: &Url) {
+        for tier in &mut self.tiers {
+            if let Some(i) = tier.iter().position(|t| t == url) {
+                tier[..=i].rotate_right(1);
+                return;
+            }
+        }
+    }
+
+    /// Announce to the trackers in order until one answers, using a fresh `HttpClient`
+    /// and `UdpTrackers`. `params.url` is left set to the tracker that answered
+    pub async fn announce(
+        &mut self,
+        params: &mut TrackerParams<'_>,
+    ) -> Result<Peers, TrackerError> {
+        let client = params.http_client();
+        self.announce_with(&client, &UdpTrackers::default(), params)
+            .await
+    }
+
+    /// Same as `announce`, with HTTP trackers reached through `client`
+    /// and UDP trackers through `udp`
+    pub async fn announce_with(
+        &mut self,
+        client: &HttpClient,
+        udp: &UdpTrackers,
+        params: &mut TrackerParams<'_>,
+    ) -> Result<Peers, TrackerError> {
+        let mut last = TrackerError::Bencode(BencodeErr::InvalidUrl);
+        let trackers: Vec<Url> = self.trackers().cloned().collect();
+        for url in trackers {
+            params.url = url;
+            match params.announce_with(client, udp).await {
+                Ok(peers) => {
+                    self.promote(&params.url);
+                    return Ok(peers);
+                }
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+    use uttd::url::Url;
+
+    use super::AnnounceList;
+    use crate::{
+        torrent::Torrent,
+        tracker::{test::serve_once, TrackerParams},
+    };
+
+    fn urls(tier: &[&str]) -> Vec<Url> {
+        tier.iter().map(|u| Url::new(u).unwrap()).collect()
+    }
+
+    #[test]
+    fn tiers_are_shuffled_within() {
+        let tiers = vec![
+            urls(&[
+                "http://a/ann",
+                "http://b/ann",
+                "http://c/ann",
+                "http://d/ann",
+            ]),
+            vec![],
+            urls(&["udp://e:1", "udp://f:1"]),
+        ];
+        let orders: Vec<_> = (0..16)
+            .map(|seed| AnnounceList::from_tiers(tiers.clone(), seed))
+            .collect();
+        for list in &orders {
+            assert_eq!(list.tiers().len(), 2);
+            let mut first = list.tiers()[0].clone();
+            first.sort_by(|a, b| a.host.cmp(&b.host));
+            assert_eq!(first, tiers[0]);
+            assert_eq!(list.tiers()[1].len(), 2);
+        }
+        // some seeds reorder the tier
+        assert!(orders.iter().any(|l| l.tiers()[0] != tiers[0]));
+    }
+
+    #[test]
+    fn promote_to_front_of_tier() {
+        let mut list = AnnounceList {
+            tiers: vec![
+                urls(&["http://a/ann", "http://b/ann"]),
+                urls(&["http://c/ann", "http://d/ann", "http://e/ann"]),
+            ],
+        };
+        list.promote(&Url::new("http://e/ann").unwrap());
+        assert_eq!(
+            list.tiers(),
+            [
+                urls(&["http://a/ann", "http://b/ann"]),
+                urls(&["http://e/ann", "http://c/ann", "http://d/ann"]),
+            ]
+        );
+        // unknown trackers are ignored
+        list.promote(&Url::new("http://z/ann").unwrap());
+        assert_eq!(list.trackers().count(), 5);
+    }
+
+    #[tokio::test]
+    async fn falls_back_across_tiers() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = format!("http://{}/announce", closed.local_addr().unwrap());
+        drop(closed);
+        let (refusing, _) = serve_once(b"d14:failure reason6:deniede".to_vec()).await;
+        let refusing = format!("http://{refusing}/announce");
+        let mut body = b"d8:intervali900e5:peers6:".to_vec();
+        body.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        body.push(b'e');
+        let (working, _) = serve_once(body).await;
+        let working = format!("http://{working}/announce");
+
+        let mut list = AnnounceList {
+            tiers: vec![
+                urls(&[&dead, &refusing]),
+                urls(&["http://other.invalid/announce", &working]),
+            ],
+        };
+        let torrent = Torrent::default();
+        let mut params = TrackerParams::new(&torrent);
+        let peers = list.announce(&mut params).await.unwrap();
+        assert_eq!(peers.interval, 900);
+        assert_eq!(peers.peer, ["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(params.url, Url::new(&working).unwrap());
+        assert_eq!(
+            list.tiers(),
+            [
+                urls(&[&dead, &refusing]),
+                urls(&[&working, "http://other.invalid/announce"]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn all_trackers_failing() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = format!("http://{}/announce", closed.local_addr().unwrap());
+        drop(closed);
+        let mut list = AnnounceList {
+            tiers: vec![urls(&[&dead])],
+        };
+        let torrent = Torrent::default();
+        let mut params = TrackerParams::new(&torrent);
+        assert!(list.announce(&mut params).await.is_err());
+        assert_eq!(list.tiers(), [urls(&[&dead])]);
+    }
+
+    #[test]
+    fn single_announce_url() {
+        let torrent = Torrent {
+            announce: Url::new("udp://tracker.example.com:80").unwrap(),
+            ..Default::default()
+        };
+        let list = AnnounceList::new(&torrent);
+        assert_eq!(list.tiers(), [urls(&["udp://tracker.example.com:80"])]);
+    }
+}
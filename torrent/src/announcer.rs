@@ -0,0 +1,503 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use tokio::{
+    sync::{mpsc, watch},
+    time::{sleep_until, Duration, Instant},
+};
+use uttd::{http::HttpClient, proxy::Proxy};
+
+use crate::{
+    announce_list::AnnounceList,
+    error::TrackerError,
+    peers::Peers,
+    resume::TrackerState,
+    torrent::Torrent,
+    tracker::{Event, TrackerParams, UdpTrackers},
+};
+
+// used when the tracker doesn't give an interval
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// used when the tracker doesn't give a min interval
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+// first wait after a failed announce, doubled on each failure up to the interval
+const RETRY_INTERVAL: Duration = Duration::from_secs(15);
+
+/// What's been transferred of a torrent, as reported to its trackers.
+/// Shared between the download, which updates it, and the `Announcer`
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        Self {
+            left: AtomicU64::new(left),
+            ..Default::default()
+        }
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_left(&self, bytes: u64) {
+        self.left.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+}
+
+/// Keeps the trackers of a torrent informed for as long as the torrent runs:
+/// `started` first, then a regular announce every `interval`, `completed`
+/// once the download finishes and `stopped` when it's shut down
+pub struct Announcer {
+    torrent: Torrent,
+    trackers: AnnounceList,
+    client: HttpClient,
+    udp: UdpTrackers,
+    stats: Arc<TransferStats>,
+    peer_id: [u8; 20],
+    port: u16,
+    key: u32,
+    numwant: Option<u32>,
+    proxy: Option<Proxy>,
+    tracker_id: Option<Vec<u8>>,
+    interval: Duration,
+    min_interval: Duration,
+    last: Option<Instant>,
+    started: bool,
+    retry_interval: Duration,
+    // what `tracker_state` returns, for those watching while `run` owns the announcer
+    state: watch::Sender<TrackerState>,
+}
+
+impl Announcer {
+    pub fn new(torrent: Torrent, stats: Arc<TransferStats>) -> Self {
+        let trackers = AnnounceList::new(&torrent);
+        let params = TrackerParams::new(&torrent);
+        let (peer_id, port, key) = (params.peer_id, params.port, params.key);
+        Self {
+            torrent,
+            trackers,
+            client: HttpClient::new(),
+            udp: UdpTrackers::default(),
+            stats,
+            peer_id,
+            port,
+            key,
+            numwant: None,
+            proxy: None,
+            tracker_id: None,
+            interval: DEFAULT_INTERVAL,
+            min_interval: DEFAULT_MIN_INTERVAL,
+            last: None,
+            started: false,
+            retry_interval: RETRY_INTERVAL,
+            state: watch::Sender::new(TrackerState::default()),
+        }
+    }
+
+    /// Announce to `trackers` instead of those of the torrent
+    pub fn with_trackers(mut self, trackers: AnnounceList) -> Self {
+        self.trackers = trackers;
+        self
+    }
+
+    pub fn with_peer_id(mut self, peer_id: [u8; 20]) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    /// Port peers can reach us on
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Ask the trackers for `numwant` peers per announce
+    pub fn with_numwant(mut self, numwant: u32) -> Self {
+        self.numwant = Some(numwant);
+        self
+    }
+
+    /// Reach the trackers, and the peers they return, through `proxy`
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.client = HttpClient::new().with_proxy(proxy.clone());
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Wait `interval` after the first failed announce, instead of `RETRY_INTERVAL`
+    pub fn with_retry_interval(mut self, interval: Duration) -> Self {
+        self.retry_interval = interval;
+        self
+    }
+
+    /// Pick up where a previous run left off with the tracker
+    pub fn with_tracker_state(mut self, state: TrackerState) -> Self {
+        self.tracker_id = state.tracker_id;
+        if let Some(interval) = state.interval.filter(|i| !i.is_zero()) {
+            self.interval = interval;
+            self.min_interval = self.min_interval.min(interval);
+        }
+        self.state.send_replace(self.tracker_state());
+        self
+    }
+
+    /// Time between regular announces, as last asked by the tracker
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// What to remember of the tracker for the next run
+    pub fn tracker_state(&self) -> TrackerState {
+        TrackerState {
+            tracker_id: self.tracker_id.clone(),
+            interval: Some(self.interval),
+        }
+    }
+
+    /// Follow `tracker_state` as it changes with every announce, e.g. to save it
+    pub fn watch_tracker_state(&self) -> watch::Receiver<TrackerState> {
+        self.state.subscribe()
+    }
+
+    /// Earliest time an announce without event can be made
+    pub fn earliest_announce(&self) -> Instant {
+        self.last
+            .map_or_else(Instant::now, |last| last + self.min_interval)
+    }
+
+    /// Announce `event` once, with the current transfer stats.
+    /// Remembers the tracker id and intervals the tracker answers with
+    pub async fn announce(&mut self, event: Event) -> Result<Peers, TrackerError> {
+        let mut params = TrackerParams::new(&self.torrent);
+        params.peer_id = self.peer_id;
+        params.port = self.port;
+        params.key = self.key;
+        params.numwant = self.numwant;
+        params.proxy = self.proxy.clone();
+        params.trackerid = self.tracker_id.clone();
+        params.event = event;
+        params.uploaded = self.stats.uploaded();
+        params.downloaded = self.stats.downloaded();
+        params.left = self.stats.left();
+        let peers = self
+            .trackers
+            .announce_with(&self.client, &self.udp, &mut params)
+            .await?;
+
+        if peers.tracker_id.is_some() {
+            self.tracker_id = peers.tracker_id.clone();
+        }
+        self.interval = match peers.interval {
+            i if i > 0 => Duration::from_secs(i as u64),
+            _ => DEFAULT_INTERVAL,
+        };
+        self.min_interval = match peers.min_interval {
+            Some(i) if i >= 0 => Duration::from_secs(i as u64),
+            _ => DEFAULT_MIN_INTERVAL,
+        }
+        .min(self.interval);
+        self.last = Some(Instant::now());
+        self.state.send_replace(self.tracker_state());
+        match event {
+            Event::Started => self.started = true,
+            Event::Stopped => self.started = false,
+            _ => {}
+        }
+        Ok(peers)
+    }
+
+    /// Announce until told to stop, sending the outcome of every announce on `results`.
+    /// On `events`, `Event::Completed` announces the download finished, once
+    /// `started` went through, and never for a torrent that was complete from the start.
+    /// `Event::Empty` asks for more peers as soon as the tracker's min interval allows,
+    /// and `Event::Stopped`, or dropping the sender, announces `stopped` and returns.
+    /// Failed announces are retried, with the same event, after a growing delay
+    pub async fn run(
+        mut self,
+        mut events: mpsc::Receiver<Event>,
+        results: mpsc::Sender<Result<Peers, TrackerError>>,
+    ) {
+        let mut pending = Some(Event::Started);
+        let mut next = Instant::now();
+        let mut retry = self.retry_interval;
+        // a torrent complete from the start never sends completed
+        let complete_at_start = self.stats.left() == 0;
+        // finished while `started` wasn't through yet, sent right after it
+        let mut completed = false;
+        loop {
+            let event = tokio::select! {
+                _ = sleep_until(next) => pending.take().unwrap_or(Event::Empty),
+                received = events.recv() => {
+                    match received {
+                        Some(Event::Completed) if complete_at_start => {}
+                        Some(Event::Completed) if self.started => {
+                            pending = Some(Event::Completed);
+                            next = Instant::now();
+                        }
+                        Some(Event::Completed) => completed = true,
+                        Some(Event::Empty) => next = next.min(self.earliest_announce()),
+                        Some(Event::Stopped) | None => break,
+                        _ => {}
+                    }
+                    continue;
+                }
+            };
+
+            let res = self.announce(event).await;
+            match res {
+                Ok(_) => {
+                    retry = self.retry_interval;
+                    next = Instant::now() + self.interval;
+                    if event == Event::Started && completed {
+                        completed = false;
+                        pending = Some(Event::Completed);
+                        next = Instant::now();
+                    }
+                }
+                Err(_) => {
+                    if event != Event::Empty {
+                        pending = Some(event);
+                    }
+                    next = Instant::now() + retry;
+                    retry = (retry * 2).min(self.interval);
+                }
+            }
+            let _ = results.send(res).await;
+        }
+
+        // trackers that never heard we started don't need to hear we stopped
+        if self.started {
+            let res = self.announce(Event::Stopped).await;
+            let _ = results.send(res).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use tokio::{
+        sync::mpsc,
+        time::{timeout, Duration, Instant},
+    };
+    use uttd::url::Url;
+
+    use super::{Announcer, TransferStats};
+    use crate::{
+        announce_list::AnnounceList,
+        error::TrackerError,
+        torrent::Torrent,
+        tracker::{test::serve, Event},
+    };
+
+    // HTTP tracker answering every announce with the next of `bodies`,
+    // the last one over and over. Yields the request lines
+    async fn tracker(bodies: Vec<&'static [u8]>) -> (Url, mpsc::UnboundedReceiver<String>) {
+        let (addr, requests) = serve(bodies).await;
+        (
+            Url::new(&format!("http://{addr}/announce")).unwrap(),
+            requests,
+        )
+    }
+
+    fn announcer(url: Url, stats: Arc<TransferStats>) -> Announcer {
+        Announcer::new(Torrent::default(), stats)
+            .with_trackers(AnnounceList::from_tiers(vec![vec![url]], 0))
+            .with_numwant(30)
+    }
+
+    async fn next(requests: &mut mpsc::UnboundedReceiver<String>) -> String {
+        timeout(Duration::from_secs(5), requests.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn lifecycle() {
+        let (url, mut requests) = tracker(vec![
+            b"d8:intervali1e10:tracker id3:xyz5:peers0:e",
+            b"d8:intervali1e5:peers0:e",
+        ])
+        .await;
+        let stats = Arc::new(TransferStats::new(100));
+        let (events, events_rx) = mpsc::channel(4);
+        let (results_tx, mut results) = mpsc::channel(16);
+        let task = tokio::spawn(announcer(url, stats.clone()).run(events_rx, results_tx));
+
+        let started = next(&mut requests).await;
+        assert!(started.contains("event=started"));
+        assert!(started.contains("left=100"));
+        assert!(started.contains("numwant=30"));
+        assert!(!started.contains("trackerid"));
+
+        stats.add_downloaded(60);
+        stats.add_uploaded(5);
+        stats.set_left(40);
+        let begin = Instant::now();
+        let regular = next(&mut requests).await;
+        assert!(begin.elapsed() >= Duration::from_millis(500));
+        assert!(!regular.contains("event"));
+        assert!(regular.contains("trackerid=xyz"));
+        assert!(regular.contains("downloaded=60"));
+        assert!(regular.contains("uploaded=5"));
+        assert!(regular.contains("left=40"));
+
+        stats.set_left(0);
+        events.send(Event::Completed).await.unwrap();
+        let completed = next(&mut requests).await;
+        assert!(completed.contains("event=completed"));
+        assert!(completed.contains("left=0"));
+
+        drop(events);
+        let stopped = next(&mut requests).await;
+        assert!(stopped.contains("event=stopped"));
+        task.await.unwrap();
+
+        let mut announces = 0;
+        while let Some(res) = results.recv().await {
+            res.unwrap();
+            announces += 1;
+        }
+        assert_eq!(announces, 4);
+    }
+
+    #[tokio::test]
+    async fn tracker_state_carries_over() {
+        let (url, mut requests) =
+            tracker(vec![b"d8:intervali900e10:tracker id3:xyz5:peers0:e"]).await;
+        let stats = Arc::new(TransferStats::new(100));
+        let mut first = announcer(url.clone(), stats.clone());
+        let watched = first.watch_tracker_state();
+        first.announce(Event::Started).await.unwrap();
+        next(&mut requests).await;
+        let state = first.tracker_state();
+        assert_eq!(*watched.borrow(), state);
+        assert_eq!(state.tracker_id.as_deref(), Some(&b"xyz"[..]));
+        assert_eq!(state.interval, Some(Duration::from_secs(900)));
+
+        // after a restart
+        let mut second = announcer(url, stats).with_tracker_state(state.clone());
+        assert_eq!(second.interval(), Duration::from_secs(900));
+        second.announce(Event::Started).await.unwrap();
+        assert!(next(&mut requests).await.contains("trackerid=xyz"));
+        assert_eq!(second.tracker_state(), state);
+    }
+
+    #[tokio::test]
+    async fn failures_and_warnings_are_reported() {
+        let (url, _requests) = tracker(vec![
+            b"d14:failure reason6:deniede",
+            b"d8:intervali900e15:warning message4:slow5:peers0:e",
+        ])
+        .await;
+        let mut announcer = announcer(url, Arc::new(TransferStats::new(1)));
+
+        let err = announcer.announce(Event::Started).await.unwrap_err();
+        assert!(matches!(err, TrackerError::Failure(reason) if reason == "denied"));
+        assert!(!announcer.started);
+
+        let peers = announcer.announce(Event::Started).await.unwrap();
+        assert_eq!(peers.warning.as_deref(), Some("slow"));
+        assert!(announcer.started);
+        assert_eq!(announcer.interval(), Duration::from_secs(900));
+        // no min interval given, the default applies
+        assert!(announcer.earliest_announce() > Instant::now() + Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn failed_start_is_retried() {
+        let (url, mut requests) = tracker(vec![
+            b"d14:failure reason4:busye",
+            b"d8:intervali900e5:peers0:e",
+        ])
+        .await;
+        let (events, events_rx) = mpsc::channel(4);
+        let (results_tx, mut results) = mpsc::channel(16);
+        let announcer = announcer(url, Arc::new(TransferStats::new(1)));
+        let task = tokio::spawn(announcer.run(events_rx, results_tx));
+
+        assert!(next(&mut requests).await.contains("event=started"));
+        assert!(results.recv().await.unwrap().is_err());
+        // not waiting out the retry delay: stopping a torrent that never started is silent
+        drop(events);
+        task.await.unwrap();
+        assert!(results.recv().await.is_none());
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn completed_waits_for_started() {
+        let (url, mut requests) = tracker(vec![
+            b"d14:failure reason4:busye",
+            b"d8:intervali900e5:peers0:e",
+        ])
+        .await;
+        let stats = Arc::new(TransferStats::new(100));
+        let (events, events_rx) = mpsc::channel(4);
+        let (results_tx, mut results) = mpsc::channel(16);
+        let announcer =
+            announcer(url, stats.clone()).with_retry_interval(Duration::from_millis(200));
+        let task = tokio::spawn(announcer.run(events_rx, results_tx));
+
+        assert!(next(&mut requests).await.contains("event=started"));
+        assert!(results.recv().await.unwrap().is_err());
+        // done before the tracker heard we started
+        stats.set_left(0);
+        events.send(Event::Completed).await.unwrap();
+        assert!(next(&mut requests).await.contains("event=started"));
+        let completed = next(&mut requests).await;
+        assert!(completed.contains("event=completed"));
+        assert!(completed.contains("left=0"));
+
+        drop(events);
+        assert!(next(&mut requests).await.contains("event=stopped"));
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn more_peers_wait_for_min_interval() {
+        let (url, mut requests) =
+            tracker(vec![b"d8:intervali900e12:min intervali1e5:peers0:e"]).await;
+        let (events, events_rx) = mpsc::channel(4);
+        let (results_tx, _results) = mpsc::channel(16);
+        let announcer = announcer(url, Arc::new(TransferStats::new(1)));
+        let task = tokio::spawn(announcer.run(events_rx, results_tx));
+
+        assert!(next(&mut requests).await.contains("event=started"));
+        let begin = Instant::now();
+        events.send(Event::Empty).await.unwrap();
+        let regular = next(&mut requests).await;
+        assert!(!regular.contains("event"));
+        assert!(begin.elapsed() >= Duration::from_millis(500));
+        assert!(begin.elapsed() < Duration::from_secs(5));
+
+        events.send(Event::Stopped).await.unwrap();
+        assert!(next(&mut requests).await.contains("event=stopped"));
+        task.await.unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+// REFERENCE: https://www.bittorrent.org/beps/bep_0003.html#bitfield
+
+use std::ops::{BitAnd, BitOr};
+
+use crate::error::BitfieldError;
+
+/// One bit per piece of a torrent, set for the pieces someone has.
+/// Stored as on the wire: piece 0 is the highest bit of the first byte,
+/// and the spare bits of the last byte stay clear.
+///
+/// Indexing past the last piece panics, like indexing a slice does
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// `len` pieces, none of them set
+    pub fn new(len: usize) -> Self {
+        Self {
+            bits: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// `len` pieces, all of them set
+    pub fn full(len: usize) -> Self {
+        let mut field = Self {
+            bits: vec![0xff; len.div_ceil(8)],
+            len,
+        };
+        field.clear_spare_bits();
+        field
+    }
+
+    /// Read the bitfield of a torrent with `len` pieces from the wire.
+    /// Peers sending the wrong number of bytes, or setting bits past
+    /// the last piece, are to be dropped
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, BitfieldError> {
+        let expected = len.div_ceil(8);
+        if bytes.len() != expected {
+            return Err(BitfieldError::WrongLength {
+                expected,
+                got: bytes.len(),
+            });
+        }
+        let field = Self {
+            bits: bytes.to_vec(),
+            len,
+        };
+        if field.spare_mask() & bytes.last().copied().unwrap_or(0) != 0 {
+            return Err(BitfieldError::SpareBitsSet);
+        }
+        Ok(field)
+    }
+
+    /// The bitfield as sent on the wire
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /// Number of pieces
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        let (byte, mask) = self.position(index);
+        self.bits[byte] & mask != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        let (byte, mask) = self.position(index);
+        self.bits[byte] |= mask;
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        let (byte, mask) = self.position(index);
+        self.bits[byte] &= !mask;
+    }
+
+    /// Number of pieces set
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    /// Every piece is set
+    pub fn is_full(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// No piece is set
+    pub fn is_clear(&self) -> bool {
+        self.bits.iter().all(|b| *b == 0)
+    }
+
+    /// Indexes of the pieces set, in order
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|i| self.get(*i))
+    }
+
+    /// Indexes of the pieces not set, in order
+    pub fn zeros(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|i| !self.get(*i))
+    }
+
+    /// Pieces set in `self` but not in `other`,
+    /// e.g. what a peer has that we don't
+    pub fn and_not(&self, other: &Bitfield) -> Bitfield {
+        self.zip(other, |a, b| a & !b)
+    }
+
+    fn zip(&self, other: &Bitfield, op: impl Fn(u8, u8) -> u8) -> Bitfield {
+        assert_eq!(self.len, other.len, "bitfields of different torrents");
+        Bitfield {
+            bits: self
+                .bits
+                .iter()
+                .zip(&other.bits)
+                .map(|(a, b)| op(*a, *b))
+                .collect(),
+            len: self.len,
+        }
+    }
+
+    fn position(&self, index: usize) -> (usize, u8) {
+        assert!(
+            index < self.len,
+            "piece {index} out of a bitfield of {}",
+            self.len
+        );
+        (index / 8, 0x80 >> (index % 8))
+    }
+
+    // the bits of the last byte that are past the last piece
+    fn spare_mask(&self) -> u8 {
+        match self.len % 8 {
+            0 => 0,
+            used => 0xff >> used,
+        }
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let mask = self.spare_mask();
+        if let Some(last) = self.bits.last_mut() {
+            *last &= !mask;
+        }
+    }
+}
+
+impl BitAnd for &Bitfield {
+    type Output = Bitfield;
+
+    /// Pieces set in both
+    fn bitand(self, other: &Bitfield) -> Bitfield {
+        self.zip(other, |a, b| a & b)
+    }
+}
+
+impl BitOr for &Bitfield {
+    type Output = Bitfield;
+
+    /// Pieces set in either
+    fn bitor(self, other: &Bitfield) -> Bitfield {
+        self.zip(other, |a, b| a | b)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Bitfield;
+    use crate::error::BitfieldError;
+
+    #[test]
+    fn set_clear_get() {
+        let mut bits = Bitfield::new(10);
+        assert_eq!(bits.len(), 10);
+        assert!(bits.is_clear());
+        bits.set(4);
+        bits.set(4);
+        assert!(bits.get(4));
+        assert!(!bits.get(5));
+        assert_eq!(bits.count(), 1);
+        bits.clear(4);
+        bits.clear(4);
+        assert!(!bits.get(4));
+        assert!(bits.is_clear());
+    }
+
+    #[test]
+    fn wire_order_is_msb_first() {
+        let mut bits = Bitfield::new(10);
+        bits.set(0);
+        bits.set(9);
+        assert_eq!(bits.as_bytes(), [0b1000_0000, 0b0100_0000]);
+        let read = Bitfield::from_bytes(&[0b0010_0000, 0b1000_0000], 10).unwrap();
+        assert_eq!(read.ones().collect::<Vec<_>>(), [2, 8]);
+    }
+
+    #[test]
+    fn from_bytes_is_strict() {
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff], 10),
+            Err(BitfieldError::WrongLength {
+                expected: 2,
+                got: 1
+            })
+        );
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff, 0xff, 0], 10),
+            Err(BitfieldError::WrongLength {
+                expected: 2,
+                got: 3
+            })
+        );
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff, 0b1110_0000], 10),
+            Err(BitfieldError::SpareBitsSet)
+        );
+        let full = Bitfield::from_bytes(&[0xff, 0b1100_0000], 10).unwrap();
+        assert!(full.is_full());
+        assert_eq!(full, Bitfield::full(10));
+        // a whole number of bytes has no spare bits
+        assert!(Bitfield::from_bytes(&[0xff; 2], 16).unwrap().is_full());
+        assert!(Bitfield::from_bytes(&[], 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn iterate() {
+        let mut bits = Bitfield::new(12);
+        for i in [1, 7, 8, 11] {
+            bits.set(i);
+        }
+        assert_eq!(bits.ones().collect::<Vec<_>>(), [1, 7, 8, 11]);
+        assert_eq!(bits.zeros().collect::<Vec<_>>(), [0, 2, 3, 4, 5, 6, 9, 10]);
+        assert_eq!(Bitfield::full(3).zeros().count(), 0);
+    }
+
+    #[test]
+    fn set_operations() {
+        let theirs = Bitfield::from_bytes(&[0b1111_0000], 6).unwrap();
+        let ours = Bitfield::from_bytes(&[0b1010_1100], 6).unwrap();
+        assert_eq!((&theirs & &ours).ones().collect::<Vec<_>>(), [0, 2]);
+        assert_eq!(
+            (&theirs | &ours).ones().collect::<Vec<_>>(),
+            [0, 1, 2, 3, 4, 5]
+        );
+        assert_eq!(theirs.and_not(&ours).ones().collect::<Vec<_>>(), [1, 3]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_range() {
+        Bitfield::new(8).set(8);
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+
+use crate::{
+    error::StorageError,
+    storage::{FileLayout, FileStamp, Storage},
+};
+
+pub const DEFAULT_CAPACITY: usize = 16 << 20;
+// pieces read before they are asked for, once reads look sequential
+pub const READ_AHEAD: usize = 2;
+
+/// What the cache saved the storage from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// reads answered from memory
+    pub hits: u64,
+    /// reads that went to the storage
+    pub misses: u64,
+    /// pieces read before anyone asked for them
+    pub read_ahead: u64,
+    /// blocks written to the cache
+    pub blocks_written: u64,
+    /// writes made to the storage, after coalescing
+    pub storage_writes: u64,
+    /// pieces dropped, or written out early, to make room
+    pub evictions: u64,
+}
+
+/// A bounded cache in front of any `Storage`.
+///
+/// Blocks written are held back per piece until the piece is verified,
+/// then written out in as few writes as they allow. Pieces read are kept
+/// for the next uploads, least recently used dropped first, and the pieces
+/// after them are read ahead when reads are sequential
+#[derive(Debug)]
+pub struct BlockCache<S> {
+    storage: S,
+    capacity: usize,
+    read_ahead: usize,
+    // bytes held, written or read
+    size: usize,
+    // blocks not written out yet, by piece then offset in the piece
+    dirty: HashMap<u32, BTreeMap<u32, Vec<u8>>>,
+    // oldest first
+    dirty_order: VecDeque<u32>,
+    // whole pieces, as in storage
+    clean: HashMap<u32, Vec<u8>>,
+    // least recently used first
+    clean_order: VecDeque<u32>,
+    // where the last read ended, in the torrent
+    last_read_end: Option<u64>,
+    stats: CacheStats,
+}
+
+impl<S: Storage> BlockCache<S> {
+    pub fn new(storage: S) -> Self {
+        Self {
+            storage,
+            capacity: DEFAULT_CAPACITY,
+            read_ahead: READ_AHEAD,
+            size: 0,
+            dirty: HashMap::new(),
+            dirty_order: VecDeque::new(),
+            clean: HashMap::new(),
+            clean_order: VecDeque::new(),
+            last_read_end: None,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Hold at most `bytes` in memory
+    pub fn with_capacity(mut self, bytes: usize) -> Self {
+        self.capacity = bytes;
+        self
+    }
+
+    /// Read `pieces` ahead of sequential reads
+    pub fn with_read_ahead(mut self, pieces: usize) -> Self {
+        self.read_ahead = pieces;
+        self
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Bytes held in memory
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// The storage back, everything held back written to it
+    pub fn into_inner(mut self) -> Result<S, StorageError> {
+        self.flush()?;
+        Ok(self.storage)
+    }
+
+    /// Piece `index` failed its hash check: forget what was written of it
+    pub fn discard_piece(&mut self, index: u32) {
+        if let Some(blocks) = self.dirty.remove(&index) {
+            self.size -= blocks.values().map(Vec::len).sum::<usize>();
+            self.dirty_order.retain(|i| *i != index);
+        }
+    }
+
+    // write the blocks held of piece `index` out, merging adjacent ones
+    fn write_out(&mut self, index: u32, keep: bool) -> Result<(), StorageError> {
+        let Some(blocks) = self.dirty.remove(&index) else {
+            return Ok(());
+        };
+        self.dirty_order.retain(|i| *i != index);
+        self.size -= blocks.values().map(Vec::len).sum::<usize>();
+
+        let mut runs: Vec<(u32, Vec<u8>)> = Vec::new();
+        for (begin, block) in blocks {
+            match runs.last_mut() {
+                Some((start, run)) if *start as usize + run.len() == begin as usize => {
+                    run.extend_from_slice(&block)
+                }
+                _ => runs.push((begin, block)),
+            }
+        }
+        for (begin, run) in &runs {
+            self.storage.write_block(index, *begin, run)?;
+            self.stats.storage_writes += 1;
+        }
+        // all of it in one run: hot for uploads, no need to read it back
+        if let [(0, piece)] = &runs[..] {
+            if keep && piece.len() == self.layout().piece_len(index) as usize {
+                let piece = runs.pop().unwrap().1;
+                self.insert_clean(index, piece);
+            }
+        }
+        Ok(())
+    }
+
+    // drop clean pieces, then write out dirty ones, until `needed` more bytes fit
+    fn make_room(&mut self, needed: usize) -> Result<(), StorageError> {
+        while self.size + needed > self.capacity {
+            if let Some(index) = self.clean_order.pop_front() {
+                self.size -= self.clean.remove(&index).map_or(0, |p| p.len());
+            } else if let Some(index) = self.dirty_order.front().copied() {
+                self.write_out(index, false)?;
+            } else {
+                break;
+            }
+            self.stats.evictions += 1;
+        }
+        Ok(())
+    }
+
+    fn insert_clean(&mut self, index: u32, piece: Vec<u8>) {
+        // bigger than the whole cache: not worth it
+        if piece.len() > self.capacity || self.make_room(piece.len()).is_err() {
+            return;
+        }
+        self.size += piece.len();
+        self.clean.insert(index, piece);
+        self.clean_order.push_back(index);
+    }
+
+    fn forget_clean(&mut self, index: u32) {
+        if let Some(piece) = self.clean.remove(&index) {
+            self.size -= piece.len();
+            self.clean_order.retain(|i| *i != index);
+        }
+    }
+
+    fn touch(&mut self, index: u32) {
+        if let Some(i) = self.clean_order.iter().position(|i| *i == index) {
+            self.clean_order.remove(i);
+            self.clean_order.push_back(index);
+        }
+    }
+
+    // the pieces `len` bytes at `offset` fall on, as (index, begin, len)
+    fn split(&self, offset: u64, len: u64) -> Vec<(u32, u32, u32)> {
+        let piece_length = self.layout().piece_length().max(1);
+        let mut parts = Vec::new();
+        let (mut offset, end) = (offset, offset + len);
+        while offset < end {
+            let (index, begin) = (offset / piece_length, offset % piece_length);
+            let part = (piece_length - begin).min(end - offset);
+            parts.push((index as u32, begin as u32, part as u32));
+            offset += part;
+        }
+        parts
+    }
+}
+
+impl<S: Storage> Storage for BlockCache<S> {
+    fn layout(&self) -> &FileLayout {
+        self.storage.layout()
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), StorageError> {
+        self.layout().slices(offset, data.len() as u64)?;
+        let mut written = 0;
+        for (index, begin, len) in self.split(offset, data.len() as u64) {
+            self.write_block(index, begin, &data[written..written + len as usize])?;
+            written += len as usize;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        self.layout().slices(offset, len)?;
+        let mut data = Vec::with_capacity(len as usize);
+        for (index, begin, len) in self.split(offset, len) {
+            data.extend(self.read_block(index, begin, len)?);
+        }
+        Ok(data)
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        while let Some(index) = self.dirty_order.front().copied() {
+            self.write_out(index, true)?;
+        }
+        self.storage.flush()
+    }
+
+    fn has_piece(&mut self, index: u32) -> bool {
+        self.write_out(index, true).is_ok() && self.storage.has_piece(index)
+    }
+
+    fn move_to(&mut self, root: &Path) -> Result<(), StorageError> {
+        self.flush()?;
+        self.storage.move_to(root)
+    }
+
+    fn delete(&mut self) -> Result<(), StorageError> {
+        self.dirty.clear();
+        self.dirty_order.clear();
+        self.clean.clear();
+        self.clean_order.clear();
+        self.size = 0;
+        self.storage.delete()
+    }
+
+    fn write_block(&mut self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+        let offset = self.layout().offset(index, begin);
+        self.layout().slices(offset, data.len() as u64)?;
+        self.stats.blocks_written += 1;
+        self.forget_clean(index);
+        self.make_room(data.len())?;
+
+        let blocks = self.dirty.entry(index).or_default();
+        if blocks.is_empty() {
+            self.dirty_order.push_back(index);
+        }
+        self.size += data.len();
+        if let Some(old) = blocks.insert(begin, data.to_vec()) {
+            self.size -= old.len();
+        }
+        Ok(())
+    }
+
+    fn read_block(&mut self, index: u32, begin: u32, len: u32) -> Result<Vec<u8>, StorageError> {
+        let offset = self.layout().offset(index, begin);
+        let end = begin as usize + len as usize;
+        if end > self.layout().piece_len(index) as usize {
+            return Err(StorageError::OutOfRange {
+                offset,
+                len: len as u64,
+            });
+        }
+        // what's held back is read from the storage, like the rest
+        self.write_out(index, true)?;
+        let sequential = self.last_read_end == Some(offset);
+        self.last_read_end = Some(offset + len as u64);
+
+        if let Some(piece) = self.clean.get(&index) {
+            let block = piece[begin as usize..end].to_vec();
+            self.stats.hits += 1;
+            self.touch(index);
+            return Ok(block);
+        }
+        self.stats.misses += 1;
+        // a piece only partly written can't be read whole, but its blocks can
+        let Ok(piece) = self.storage.read_piece(index) else {
+            return self.storage.read_block(index, begin, len);
+        };
+        let block = piece[begin as usize..end].to_vec();
+        self.insert_clean(index, piece);
+
+        if sequential {
+            let last = self.layout().piece_count();
+            for next in (index + 1..last).take(self.read_ahead) {
+                if self.clean.contains_key(&next) || !self.storage.has_piece(next) {
+                    continue;
+                }
+                // reading ahead is a bet, losing it mustn't fail the read asked for
+                if let Ok(piece) = self.storage.read_piece(next) {
+                    self.insert_clean(next, piece);
+                    self.stats.read_ahead += 1;
+                }
+            }
+        }
+        Ok(block)
+    }
+
+    fn piece_verified(&mut self, index: u32) -> Result<(), StorageError> {
+        self.write_out(index, true)?;
+        self.storage.piece_verified(index)
+    }
+
+    fn piece_failed(&mut self, index: u32) {
+        self.discard_piece(index);
+        self.storage.piece_failed(index);
+    }
+
+    /// The file as in storage, which isn't what it will be until flushed
+    fn stamp(&self, file: usize) -> Option<FileStamp> {
+        self.storage.stamp(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BlockCache, CacheStats};
+    use crate::{
+        mem_storage::MemoryStorage,
+        storage::{
+            test::{exercise, multi_info},
+            FileLayout, Storage,
+        },
+    };
+
+    // 8 pieces of 8 bytes, in two files
+    fn storage() -> MemoryStorage {
+        let info = multi_info(&[(&["a"], 20), (&["b"], 44)], 8);
+        MemoryStorage::new(FileLayout::new(&info).unwrap())
+    }
+
+    #[test]
+    fn behaves_like_storage() {
+        let info = multi_info(&[(&["a"], 7), (&["empty"], 0), (&["b"], 13)], 8);
+        let storage = MemoryStorage::new(FileLayout::new(&info).unwrap());
+        exercise(&mut BlockCache::new(storage).with_capacity(12));
+    }
+
+    #[test]
+    fn writes_wait_for_verification() {
+        let mut cache = BlockCache::new(storage());
+        cache.write_block(1, 4, &[2; 4]).unwrap();
+        cache.write_block(1, 0, &[1; 4]).unwrap();
+        cache.write_block(2, 0, &[3; 4]).unwrap();
+        assert!(cache.storage().file(0).is_empty());
+        assert_eq!(cache.size(), 12);
+
+        // the two blocks of piece 1 go out in one write, and stay for uploads
+        cache.piece_verified(1).unwrap();
+        assert_eq!(&cache.storage().file(0)[8..], [1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(cache.read_block(1, 2, 4).unwrap(), [1, 1, 2, 2]);
+
+        // piece 2 failed its check
+        cache.piece_failed(2);
+        assert_eq!(cache.size(), 8);
+        cache.flush().unwrap();
+        assert_eq!(cache.storage().file(0).len(), 16);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                blocks_written: 3,
+                storage_writes: 1,
+                ..CacheStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn bounded() {
+        let mut cache = BlockCache::new(storage()).with_capacity(16);
+        for index in 0..4 {
+            cache.write_block(index, 0, &[index as u8; 8]).unwrap();
+            assert!(cache.size() <= 16);
+        }
+        // the oldest pieces were written out to make room
+        assert_eq!(cache.stats().evictions, 2);
+        assert_eq!(cache.storage().file(0).len(), 16);
+        assert_eq!(
+            cache.read(0, 32).unwrap(),
+            [[0; 8], [1; 8], [2; 8], [3; 8]].concat()
+        );
+        assert!(cache.size() <= 16);
+    }
+
+    #[test]
+    fn read_ahead() {
+        let content: Vec<u8> = (0..64).collect();
+        let mut storage = storage();
+        storage.write(0, &content).unwrap();
+        let mut cache = BlockCache::new(storage).with_read_ahead(2);
+
+        // a first read is a miss, and nothing more
+        assert_eq!(cache.read_block(5, 0, 8).unwrap(), &content[40..48]);
+        // the next one follows it: the rest is read ahead
+        assert_eq!(cache.read_block(6, 0, 8).unwrap(), &content[48..56]);
+        assert_eq!(cache.read(56, 4).unwrap(), &content[56..60]);
+        assert_eq!(cache.read(60, 4).unwrap(), &content[60..]);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 2,
+                read_ahead: 1,
+                ..CacheStats::default()
+            }
+        );
+
+        // somewhere else, then on from there
+        cache.read_block(1, 0, 4).unwrap();
+        cache.read_block(1, 4, 4).unwrap();
+        cache.read(16, 4).unwrap();
+        assert_eq!(cache.stats().read_ahead, 3);
+        assert_eq!(cache.read(24, 16).unwrap(), &content[24..40]);
+        assert_eq!(cache.stats().hits, 5);
+        assert_eq!(cache.stats().misses, 4);
+    }
+
+    #[test]
+    fn partial_pieces() {
+        // half of piece 1 made it to storage before a restart
+        let mut storage = storage();
+        storage.write(0, &[7; 12]).unwrap();
+        let mut cache = BlockCache::new(storage);
+        assert_eq!(cache.read_block(1, 0, 4).unwrap(), [7; 4]);
+        assert!(cache.read_block(1, 4, 4).is_err());
+        assert_eq!(cache.stats().misses, 2);
+        assert_eq!(cache.read_block(0, 0, 8).unwrap(), [7; 8]);
+    }
+}
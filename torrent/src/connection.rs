@@ -0,0 +1,719 @@
+// REFERENCE: https://www.bittorrent.org/beps/bep_0003.html#peer-messages
+
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+use uttd::transport::Transport;
+
+use crate::bitfield::Bitfield;
+use crate::error::PeerError;
+use crate::message::{Framed, Message};
+
+// peers drop connections quiet for two minutes, so speak up well before that
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+// clients refuse requests for more than 128 KiB, we do the same
+pub const MAX_REQUEST_LEN: u32 = 1 << 17;
+// requests of the peer not answered yet; as deep as we queue our own
+pub const MAX_PEER_REQUESTS: usize = 250;
+
+/// A block of a piece, as asked for by a request message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// What the controller asks a connection to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerCommand {
+    Interested,
+    NotInterested,
+    Choke,
+    Unchoke,
+    /// ask the peer for a block; answered by `PeerEvent::Block` or `PeerEvent::Dropped`
+    Request(BlockRequest),
+    Cancel(BlockRequest),
+    /// tell the peer we got a piece
+    Have(u32),
+    /// tell the peer what we have, only right after the handshake
+    Bitfield(Bitfield),
+    /// answer a request of the peer, sent only if it wasn't cancelled meanwhile
+    Block {
+        index: u32,
+        begin: u32,
+        data: Vec<u8>,
+    },
+    Close,
+}
+
+/// What a connection tells the controller, tagged with the connection's id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// the peer choked us, the requests it won't answer anymore are handed back
+    Choked(Vec<BlockRequest>),
+    Unchoked,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Bitfield),
+    /// a block we asked for
+    Block {
+        index: u32,
+        begin: u32,
+        data: Vec<u8>,
+    },
+    /// the peer asks for a block; never reported while we choke it
+    Request(BlockRequest),
+    /// the peer no longer wants a block it asked for
+    Cancel(BlockRequest),
+    /// the peer's DHT port
+    Port(u16),
+    /// requests that couldn't be sent because the peer chokes us
+    Dropped(Vec<BlockRequest>),
+    /// the connection is gone, with the requests that were still outstanding
+    Closed(Vec<BlockRequest>),
+}
+
+/// One peer, after the handshake: owns its stream and the state of both sides
+#[derive(Debug)]
+pub struct PeerConnection<T> {
+    id: usize,
+    framed: Framed<T>,
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+    bitfield: Bitfield,
+    // our requests the peer hasn't answered yet
+    requested: HashSet<BlockRequest>,
+    // the peer's requests we haven't answered yet
+    peer_requests: HashSet<BlockRequest>,
+    // anything but a keep-alive was received, so a bitfield is too late
+    started: bool,
+    keep_alive: Duration,
+    idle_timeout: Duration,
+    last_sent: Instant,
+    last_received: Instant,
+}
+
+impl<T: Transport> PeerConnection<T> {
+    /// `id` tags the events of this connection, `piece_count` is the torrent's
+    pub fn new(id: usize, stream: T, piece_count: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            id,
+            framed: Framed::new(stream),
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            bitfield: Bitfield::new(piece_count),
+            requested: HashSet::new(),
+            peer_requests: HashSet::new(),
+            started: false,
+            keep_alive: KEEP_ALIVE_INTERVAL,
+            idle_timeout: IDLE_TIMEOUT,
+            last_sent: now,
+            last_received: now,
+        }
+    }
+
+    /// Send keep-alives after `keep_alive` of silence on our side,
+    /// give up after `idle_timeout` of silence on the peer's
+    pub fn with_timeouts(mut self, keep_alive: Duration, idle_timeout: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    /// The pieces the peer has
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// Our requests the peer hasn't answered yet
+    pub fn outstanding(&self) -> impl Iterator<Item = &BlockRequest> {
+        self.requested.iter()
+    }
+
+    /// Run the connection on its own task.
+    /// Commands are never refused, so the controller can't block on a slow peer
+    pub fn spawn(self, events: mpsc::Sender<(usize, PeerEvent)>) -> PeerHandle
+    where
+        T: 'static,
+    {
+        let id = self.id;
+        let (commands, rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(self.run(rx, events));
+        PeerHandle { id, commands, task }
+    }
+
+    /// Talk to the peer until it leaves, breaks the protocol or goes quiet,
+    /// or until `commands` says `Close` or is dropped.
+    /// Whatever the reason, `PeerEvent::Closed` is the last event sent
+    pub async fn run(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<PeerCommand>,
+        events: mpsc::Sender<(usize, PeerEvent)>,
+    ) -> Result<(), PeerError> {
+        let res = self.drive(&mut commands, &events).await;
+        let outstanding = self.requested.drain().collect();
+        let _ = events.send((self.id, PeerEvent::Closed(outstanding))).await;
+        res
+    }
+
+    async fn drive(
+        &mut self,
+        commands: &mut mpsc::UnboundedReceiver<PeerCommand>,
+        events: &mpsc::Sender<(usize, PeerEvent)>,
+    ) -> Result<(), PeerError> {
+        // events the controller hasn't taken yet; commands are still handled
+        // meanwhile, while the peer waits until the controller catches up
+        let mut pending = VecDeque::new();
+        loop {
+            let idle_at = self.last_received + self.idle_timeout;
+            let keep_alive_at = self.last_sent + self.keep_alive;
+            let event = tokio::select! {
+                permit = events.reserve(), if !pending.is_empty() => match permit {
+                    Ok(permit) => {
+                        permit.send((self.id, pending.pop_front().unwrap()));
+                        None
+                    }
+                    // nobody listens anymore
+                    Err(_) => return Ok(()),
+                },
+                message = self.framed.recv(), if pending.is_empty() => match message? {
+                    Some(message) => {
+                        self.last_received = Instant::now();
+                        self.on_message(message)?
+                    }
+                    None => return Ok(()),
+                },
+                command = commands.recv() => match command {
+                    Some(PeerCommand::Close) | None => return Ok(()),
+                    Some(command) => self.on_command(command).await?,
+                },
+                _ = sleep_until(idle_at), if pending.is_empty() => return Err(PeerError::Timeout),
+                _ = sleep_until(keep_alive_at) => {
+                    self.send(Message::KeepAlive).await?;
+                    None
+                }
+            };
+            pending.extend(event);
+        }
+    }
+
+    fn on_message(&mut self, message: Message) -> Result<Option<PeerEvent>, PeerError> {
+        let first = !self.started;
+        if message != Message::KeepAlive {
+            self.started = true;
+        }
+        let event = match message {
+            Message::KeepAlive | Message::Unrecognized(..) => None,
+            Message::Choke => {
+                self.peer_choking = true;
+                Some(PeerEvent::Choked(self.requested.drain().collect()))
+            }
+            Message::Unchoke => {
+                self.peer_choking = false;
+                Some(PeerEvent::Unchoked)
+            }
+            Message::Interested => {
+                self.peer_interested = true;
+                Some(PeerEvent::Interested)
+            }
+            Message::NotInterested => {
+                self.peer_interested = false;
+                Some(PeerEvent::NotInterested)
+            }
+            Message::Have(index) => {
+                self.check_index(index)?;
+                self.bitfield.set(index as usize);
+                Some(PeerEvent::Have(index))
+            }
+            Message::BitField(bits) => {
+                if !first {
+                    return Err(PeerError::Protocol("bitfield after other messages"));
+                }
+                self.bitfield = Bitfield::from_bytes(&bits, self.bitfield.len())
+                    .map_err(|_| PeerError::Protocol("invalid bitfield"))?;
+                Some(PeerEvent::Bitfield(self.bitfield.clone()))
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                self.check_index(index)?;
+                if length > MAX_REQUEST_LEN {
+                    return Err(PeerError::Protocol("request too large"));
+                }
+                let request = BlockRequest {
+                    index,
+                    begin,
+                    length,
+                };
+                // a peer asking for more than it reads would have us queue blocks for it endlessly
+                if self.peer_requests.len() >= MAX_PEER_REQUESTS
+                    && !self.peer_requests.contains(&request)
+                {
+                    return Err(PeerError::Protocol("too many requests"));
+                }
+                match !self.am_choking && self.peer_requests.insert(request) {
+                    true => Some(PeerEvent::Request(request)),
+                    false => None,
+                }
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let request = BlockRequest {
+                    index,
+                    begin,
+                    length,
+                };
+                match self.peer_requests.remove(&request) {
+                    true => Some(PeerEvent::Cancel(request)),
+                    false => None,
+                }
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let request = BlockRequest {
+                    index,
+                    begin,
+                    length: block.len() as u32,
+                };
+                // blocks we didn't ask for, or cancelled, are thrown away
+                match self.requested.remove(&request) {
+                    true => Some(PeerEvent::Block {
+                        index,
+                        begin,
+                        data: block,
+                    }),
+                    false => None,
+                }
+            }
+            Message::Port(port) => Some(PeerEvent::Port(port)),
+        };
+        Ok(event)
+    }
+
+    async fn on_command(&mut self, command: PeerCommand) -> Result<Option<PeerEvent>, PeerError> {
+        match command {
+            PeerCommand::Interested if !self.am_interested => {
+                self.am_interested = true;
+                self.send(Message::Interested).await?;
+            }
+            PeerCommand::NotInterested if self.am_interested => {
+                self.am_interested = false;
+                self.send(Message::NotInterested).await?;
+            }
+            PeerCommand::Choke if !self.am_choking => {
+                self.am_choking = true;
+                // choking discards the peer's requests
+                self.peer_requests.clear();
+                self.send(Message::Choke).await?;
+            }
+            PeerCommand::Unchoke if self.am_choking => {
+                self.am_choking = false;
+                self.send(Message::Unchoke).await?;
+            }
+            PeerCommand::Request(request) => {
+                if self.peer_choking {
+                    return Ok(Some(PeerEvent::Dropped(vec![request])));
+                }
+                if self.requested.insert(request) {
+                    self.send(Message::Request {
+                        index: request.index,
+                        begin: request.begin,
+                        length: request.length,
+                    })
+                    .await?;
+                }
+            }
+            PeerCommand::Cancel(request) if self.requested.remove(&request) => {
+                self.send(Message::Cancel {
+                    index: request.index,
+                    begin: request.begin,
+                    length: request.length,
+                })
+                .await?;
+            }
+            PeerCommand::Have(index) => self.send(Message::Have(index)).await?,
+            PeerCommand::Bitfield(bits) => {
+                self.send(Message::BitField(bits.as_bytes().to_vec()))
+                    .await?
+            }
+            PeerCommand::Block { index, begin, data } => {
+                let request = BlockRequest {
+                    index,
+                    begin,
+                    length: data.len() as u32,
+                };
+                if self.peer_requests.remove(&request) {
+                    self.send(Message::Piece {
+                        index,
+                        begin,
+                        block: data,
+                    })
+                    .await?;
+                }
+            }
+            // already so, or nothing to cancel
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    async fn send(&mut self, message: Message) -> Result<(), PeerError> {
+        self.framed.send(&message).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn check_index(&self, index: u32) -> Result<(), PeerError> {
+        match (index as usize) < self.bitfield.len() {
+            true => Ok(()),
+            false => Err(PeerError::Protocol("piece index out of range")),
+        }
+    }
+}
+
+/// A connection running on its own task
+#[derive(Debug)]
+pub struct PeerHandle {
+    pub id: usize,
+    commands: mpsc::UnboundedSender<PeerCommand>,
+    task: JoinHandle<Result<(), PeerError>>,
+}
+
+impl PeerHandle {
+    /// `false` if the connection is already gone. Never waits
+    pub fn send(&self, command: PeerCommand) -> bool {
+        self.commands.send(command).is_ok()
+    }
+
+    /// Wait for the connection to end, and how it ended
+    pub async fn join(self) -> Result<(), PeerError> {
+        drop(self.commands);
+        self.task
+            .await
+            .unwrap_or(Err(PeerError::Protocol("connection task panicked")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::DuplexStream;
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+    use uttd::transport::pipe;
+
+    use super::{
+        BlockRequest, PeerCommand, PeerConnection, PeerEvent, PeerHandle, MAX_PEER_REQUESTS,
+    };
+    use crate::bitfield::Bitfield;
+    use crate::error::PeerError;
+    use crate::message::{Framed, Message};
+
+    const PIECES: usize = 10;
+
+    fn connect() -> (
+        PeerHandle,
+        mpsc::Receiver<(usize, PeerEvent)>,
+        Framed<DuplexStream>,
+    ) {
+        let (ours, theirs) = pipe();
+        let (tx, rx) = mpsc::channel(16);
+        let handle = PeerConnection::new(7, ours, PIECES)
+            .with_timeouts(Duration::from_millis(50), Duration::from_millis(300))
+            .spawn(tx);
+        (handle, rx, Framed::new(theirs))
+    }
+
+    async fn event(rx: &mut mpsc::Receiver<(usize, PeerEvent)>) -> PeerEvent {
+        let (id, event) = rx.recv().await.unwrap();
+        assert_eq!(id, 7);
+        event
+    }
+
+    // the next message of the remote end that isn't a keep-alive
+    async fn remote_recv(remote: &mut Framed<DuplexStream>) -> Message {
+        loop {
+            match remote.recv().await.unwrap().unwrap() {
+                Message::KeepAlive => continue,
+                message => return message,
+            }
+        }
+    }
+
+    const BLOCK: BlockRequest = BlockRequest {
+        index: 2,
+        begin: 0,
+        length: 4,
+    };
+
+    #[tokio::test]
+    async fn download_side() {
+        let (handle, mut rx, mut remote) = connect();
+
+        let bits = Bitfield::from_bytes(&[0b0110_0000, 0], PIECES).unwrap();
+        remote
+            .send(&Message::BitField(bits.as_bytes().to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(event(&mut rx).await, PeerEvent::Bitfield(bits));
+        remote.send(&Message::Have(9)).await.unwrap();
+        assert_eq!(event(&mut rx).await, PeerEvent::Have(9));
+
+        handle.send(PeerCommand::Interested);
+        assert_eq!(remote_recv(&mut remote).await, Message::Interested);
+
+        // requests while choked come back
+        handle.send(PeerCommand::Request(BLOCK));
+        assert_eq!(event(&mut rx).await, PeerEvent::Dropped(vec![BLOCK]));
+
+        remote.send(&Message::Unchoke).await.unwrap();
+        assert_eq!(event(&mut rx).await, PeerEvent::Unchoked);
+        handle.send(PeerCommand::Request(BLOCK));
+        assert_eq!(
+            remote_recv(&mut remote).await,
+            Message::Request {
+                index: 2,
+                begin: 0,
+                length: 4
+            }
+        );
+
+        // unrequested blocks are ignored, requested ones reported
+        let piece = |begin| Message::Piece {
+            index: 2,
+            begin,
+            block: vec![1, 2, 3, 4],
+        };
+        remote.send(&piece(4)).await.unwrap();
+        remote.send(&piece(0)).await.unwrap();
+        assert_eq!(
+            event(&mut rx).await,
+            PeerEvent::Block {
+                index: 2,
+                begin: 0,
+                data: vec![1, 2, 3, 4]
+            }
+        );
+
+        // a choke hands back what was outstanding
+        handle.send(PeerCommand::Request(BLOCK));
+        remote_recv(&mut remote).await;
+        remote.send(&Message::Choke).await.unwrap();
+        assert_eq!(event(&mut rx).await, PeerEvent::Choked(vec![BLOCK]));
+
+        drop(remote);
+        assert_eq!(event(&mut rx).await, PeerEvent::Closed(vec![]));
+        assert!(handle.join().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn upload_side() {
+        let (handle, mut rx, mut remote) = connect();
+        let request = |begin| Message::Request {
+            index: 2,
+            begin,
+            length: 4,
+        };
+
+        // we choke it: ignored
+        remote.send(&request(8)).await.unwrap();
+        remote.send(&Message::Interested).await.unwrap();
+        assert_eq!(event(&mut rx).await, PeerEvent::Interested);
+
+        handle.send(PeerCommand::Unchoke);
+        assert_eq!(remote_recv(&mut remote).await, Message::Unchoke);
+        remote.send(&request(0)).await.unwrap();
+        assert_eq!(event(&mut rx).await, PeerEvent::Request(BLOCK));
+        let block = |begin| PeerCommand::Block {
+            index: 2,
+            begin,
+            data: vec![9; 4],
+        };
+        // only what was asked for goes out
+        handle.send(block(8));
+        handle.send(block(0));
+        assert_eq!(
+            remote_recv(&mut remote).await,
+            Message::Piece {
+                index: 2,
+                begin: 0,
+                block: vec![9; 4]
+            }
+        );
+
+        // cancelled requests aren't answered
+        remote.send(&request(4)).await.unwrap();
+        event(&mut rx).await;
+        remote
+            .send(&Message::Cancel {
+                index: 2,
+                begin: 4,
+                length: 4,
+            })
+            .await
+            .unwrap();
+        let cancelled = BlockRequest { begin: 4, ..BLOCK };
+        assert_eq!(event(&mut rx).await, PeerEvent::Cancel(cancelled));
+        handle.send(block(4));
+        handle.send(PeerCommand::Have(3));
+        assert_eq!(remote_recv(&mut remote).await, Message::Have(3));
+
+        handle.send(PeerCommand::Close);
+        assert_eq!(event(&mut rx).await, PeerEvent::Closed(vec![]));
+        assert!(handle.join().await.is_ok());
+        assert_eq!(remote.recv().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn requests_are_capped() {
+        let (handle, mut rx, mut remote) = connect();
+        handle.send(PeerCommand::Unchoke);
+        assert_eq!(remote_recv(&mut remote).await, Message::Unchoke);
+        // asking for every block of piece 0, without ever reading one
+        for begin in 0..=MAX_PEER_REQUESTS as u32 {
+            let request = Message::Request {
+                index: 0,
+                begin: begin * 4,
+                length: 4,
+            };
+            remote.send(&request).await.unwrap();
+        }
+        for _ in 0..MAX_PEER_REQUESTS {
+            assert!(matches!(event(&mut rx).await, PeerEvent::Request(_)));
+        }
+        assert_eq!(event(&mut rx).await, PeerEvent::Closed(vec![]));
+        assert!(matches!(
+            handle.join().await,
+            Err(PeerError::Protocol("too many requests"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn keep_alive_and_timeout() {
+        let (handle, mut rx, mut remote) = connect();
+        // we speak up even with nothing to say
+        assert_eq!(remote.recv().await.unwrap(), Some(Message::KeepAlive));
+        assert_eq!(remote.recv().await.unwrap(), Some(Message::KeepAlive));
+
+        remote.send(&Message::Unchoke).await.unwrap();
+        event(&mut rx).await;
+        handle.send(PeerCommand::Request(BLOCK));
+        // the peer never answers, nor says anything else
+        assert_eq!(event(&mut rx).await, PeerEvent::Closed(vec![BLOCK]));
+        assert!(matches!(handle.join().await, Err(PeerError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn protocol_violations() {
+        let violations = [
+            vec![Message::Have(PIECES as u32)],
+            vec![Message::Unchoke, Message::BitField(vec![0, 0])],
+            vec![Message::BitField(vec![0])],
+            vec![Message::BitField(vec![0, 0xff])],
+            vec![Message::Request {
+                index: 0,
+                begin: 0,
+                length: 1 << 18,
+            }],
+        ];
+        for messages in violations {
+            let (handle, mut rx, mut remote) = connect();
+            for message in &messages {
+                remote.send(message).await.unwrap();
+            }
+            while !matches!(event(&mut rx).await, PeerEvent::Closed(_)) {}
+            assert!(
+                matches!(handle.join().await, Err(PeerError::Protocol(_))),
+                "{messages:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn deep_pipelines_dont_block_the_controller() {
+        let (ours, theirs) = pipe();
+        // room for a few events only, as the download gives every peer
+        let (tx, mut rx) = mpsc::channel(4);
+        let handle = PeerConnection::new(7, ours, PIECES).spawn(tx);
+        // a fast seed, answering every request at once
+        let seed = tokio::spawn(async move {
+            let mut remote = Framed::new(theirs);
+            remote.send(&Message::Unchoke).await.unwrap();
+            while let Ok(Some(message)) = remote.recv().await {
+                if let Message::Request {
+                    index,
+                    begin,
+                    length,
+                } = message
+                {
+                    let block = vec![0; length as usize];
+                    let piece = Message::Piece {
+                        index,
+                        begin,
+                        block,
+                    };
+                    remote.send(&piece).await.unwrap();
+                }
+            }
+        });
+        assert_eq!(event(&mut rx).await, PeerEvent::Unchoked);
+
+        // a pipeline as deep as the scheduler allows, sent without reading
+        // a single event in between
+        for i in 0..250 {
+            let request = BlockRequest {
+                index: i % PIECES as u32,
+                begin: i / PIECES as u32 * 4,
+                length: 4,
+            };
+            assert!(handle.send(PeerCommand::Request(request)));
+        }
+        for _ in 0..250 {
+            let event = timeout(Duration::from_secs(5), event(&mut rx)).await;
+            assert!(matches!(event, Ok(PeerEvent::Block { .. })), "{event:?}");
+        }
+        handle.send(PeerCommand::Close);
+        handle.join().await.unwrap();
+        seed.await.unwrap();
+    }
+}
@@ -1,222 +1,684 @@
-use std::{path::PathBuf, str::FromStr, sync::Arc};
-use tokio::sync::mpsc::{self, Sender};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::sync::Mutex;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
 
 use uttd::AsyncStream;
 
-use crate::torrent::Torrent;
+use crate::{
+    announcer::TransferStats,
+    cache::BlockCache,
+    connection::{PeerCommand, PeerConnection, PeerEvent, PeerHandle},
+    error::StorageError,
+    picker::{PiecePicker, BLOCK_SIZE},
+    resume::{recheck, resume_path, ResumeData, TrackerState},
+    scheduler::{CompletedPiece, Scheduler},
+    storage::{FileLayout, FileStorage, Storage},
+    torrent::Torrent,
+    verify::{PieceVerifier, Verdict},
+};
 
-#[derive(Debug, Eq, PartialEq)]
-pub enum Message {
-    KeepAlive,
-    Choke,
-    Unchoke,
-    Interested,
-    NotInterested,
-    Have(u32),
-    Unrecognized,
-    Request,
-    BitField(Bitfield),
-    // BitField(Vec<u8>),
-    Piece(Vec<u8>),
-    Finish,
-}
+// how often requests are scheduled when no peer says anything
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(1);
+// how often resume data is saved, so a crash loses little
+const RESUME_INTERVAL: Duration = Duration::from_secs(60);
+// how many peer addresses are kept in the resume data
+const SAVED_PEERS: usize = 200;
 
 #[derive(Debug)]
 pub enum DownloadError {
     Unrecognized,
 }
 
-impl Message {
-    fn try_from(value: &[u8], len: usize) -> Self {
-        
-        match value[0] {
-            // id
-            0 => Self::Choke,
-            1 => Self::Unchoke,
-            2 => Self::Interested,
-            3 => Self::NotInterested,
-            4 => Self::Have(u32::from_be_bytes(value[1..5].try_into().unwrap())),
-            5 => Self::BitField(Bitfield::from_bytes(&value[1..(len - 1)], value.len())),
-            7 => Self::Piece(value[1..(len - 9)].to_vec()),
-            13 => Self::Request,
-            _ => todo!(),
-        }
-    }
-}
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Bitfield {
-    bit: BitfieldInner,
+#[derive(Debug)]
+pub struct Participants<S = BlockCache<FileStorage>> {
+    pub file_size: usize,
+    pub block_size: usize,
+    pub piece_count: usize,
+    /// the SHA1 hashes of the pieces, as in `Info::pieces`
+    pub pieces: Vec<u8>,
+    /// the handshaken peers, with the address they were reached at
+    pub peers: Vec<(SocketAddr, Arc<Mutex<AsyncStream>>)>,
+    pub storage: S,
+    pub info_hash: [u8; 20],
+    /// where progress is saved and picked up again, `None` to always recheck
+    pub resume: Option<PathBuf>,
+    /// what's been transferred, shared with the `Announcer`
+    pub stats: Arc<TransferStats>,
+    /// the tracker state saved with the progress, from `Announcer::watch_tracker_state`
+    pub tracker: Option<watch::Receiver<TrackerState>>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum BitfieldInner {
-    Large(Vec<u8>),
-    Compact(u64),
+impl Participants<BlockCache<FileStorage>> {
+    /// Download into the current directory, through a cache,
+    /// resuming from the resume data found there
+    pub async fn new(
+        t: &Torrent,
+        peers: Vec<(SocketAddr, Arc<Mutex<AsyncStream>>)>,
+    ) -> Result<Self, StorageError> {
+        let layout = FileLayout::from_torrent(t)?;
+        let resume = resume_path(Path::new("."), &layout);
+        let storage = BlockCache::new(FileStorage::new(".", layout));
+        Ok(Participants::with_storage(t, peers, storage).with_resume(resume))
+    }
 }
 
-impl Bitfield {
-    fn new(cap: usize) -> Self {
+impl<S: Storage> Participants<S> {
+    /// Download into `storage`
+    pub fn with_storage(
+        t: &Torrent,
+        peers: Vec<(SocketAddr, Arc<Mutex<AsyncStream>>)>,
+        storage: S,
+    ) -> Self {
         Self {
-            bit: BitfieldInner::Large(vec![0; (cap / 8) + 1]),
+            file_size: t.calculate_left(),
+            block_size: t.info.piece_length,
+            piece_count: t.piece_count(),
+            pieces: t.info.pieces.clone(),
+            peers,
+            storage,
+            info_hash: t.hash,
+            resume: None,
+            stats: Arc::new(TransferStats::new(t.calculate_left() as u64)),
+            tracker: None,
         }
     }
 
-    pub fn new_compact() -> Self {
-        Self {
-            bit: BitfieldInner::Compact(0),
-        }
+    /// Save progress to `path`, and pick up from there
+    pub fn with_resume(mut self, path: impl Into<PathBuf>) -> Self {
+        self.resume = Some(path.into());
+        self
     }
 
-    pub fn len(&self) -> usize {
-        match &self.bit {
-            BitfieldInner::Large(x_compact) => x_compact.len(),
-            BitfieldInner::Compact(_) => 64,
-        }
+    /// Count transfers in `stats`, e.g. those the `Announcer` reports
+    pub fn with_stats(mut self, stats: Arc<TransferStats>) -> Self {
+        self.stats = stats;
+        self
     }
 
-    pub fn from_bytes(bits: &[u8], cap: usize) -> Self {
-        let mut bfield = Self::new(8 * (cap - 1));
-        if let BitfieldInner::Large(x_field) = &mut bfield.bit {
-            bits.iter().enumerate().for_each(|(i, x)| {
-                let x = u8::from_be(*x);
-                x_field[i] = x;
-            });
+    /// Save the tracker state along with the progress
+    pub fn with_tracker(mut self, tracker: watch::Receiver<TrackerState>) -> Self {
+        self.tracker = Some(tracker);
+        self
+    }
+
+    /// The progress saved by a previous run of this torrent, if any.
+    /// Its `tracker` goes to `Announcer::with_tracker_state`, its `peers`
+    /// are worth trying while the trackers haven't answered
+    pub fn resumed(&self) -> Option<ResumeData> {
+        let path = self.resume.as_deref()?;
+        ResumeData::load(path, self.storage.layout())
+            .ok()
+            .flatten()
+            .filter(|data| data.info_hash == self.info_hash)
+    }
+
+    /// Run a connection per peer until every piece is downloaded or every peer is gone,
+    /// writing out every piece that matches its hash, and handing it to `completed`.
+    /// Starts from what was done before a restart, and saves how far it got
+    /// every `RESUME_INTERVAL` and when done
+    pub async fn download(
+        mut self,
+        completed: mpsc::Sender<CompletedPiece>,
+    ) -> Result<(), StorageError> {
+        let piece_count = self.piece_count;
+        let (tx, mut rx) = mpsc::channel(self.peers.len().max(1) * 4);
+        let picker = PiecePicker::new(piece_count, self.block_size, self.file_size);
+        let mut scheduler = Scheduler::new(picker);
+        let mut verifier = PieceVerifier::new(std::mem::take(&mut self.pieces));
+        let mut resume = self.restore(&mut scheduler, &verifier);
+        let mut handles = HashMap::new();
+        let mut connected = vec![];
+        for (id, (addr, stream)) in std::mem::take(&mut self.peers).into_iter().enumerate() {
+            // the handshake is done, the connection owns the stream from now on
+            let Ok(stream) = Arc::try_unwrap(stream) else {
+                continue;
+            };
+            let connection = PeerConnection::new(id, stream.into_inner(), piece_count);
+            handles.insert(id, connection.spawn(tx.clone()));
+            scheduler.add_peer(id, Instant::now());
+            connected.push(addr);
         }
-        bfield
+        drop(tx);
+        // the peers of this run first, then those of earlier runs
+        resume.peers.retain(|addr| !connected.contains(addr));
+        connected.append(&mut resume.peers);
+        connected.truncate(SAVED_PEERS);
+        resume.peers = connected;
+
+        // timeouts and queue depths are looked at even when nothing happens
+        let mut tick = tokio::time::interval(SCHEDULE_INTERVAL);
+        let start = Instant::now() + RESUME_INTERVAL;
+        let mut save_tick = tokio::time::interval_at(start, RESUME_INTERVAL);
+        while !scheduler.picker().is_complete() {
+            // every connection holds a sender, so this ends once they all closed
+            let (id, event) = tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = tick.tick() => {
+                    Self::dispatch(&handles, scheduler.schedule(Instant::now()));
+                    continue;
+                }
+                _ = save_tick.tick() => {
+                    self.save(&scheduler, &mut resume)?;
+                    continue;
+                }
+            };
+            match event {
+                PeerEvent::Bitfield(bits) => scheduler.bitfield(id, bits),
+                PeerEvent::Have(index) => scheduler.have(id, index),
+                PeerEvent::Unchoked => scheduler.unchoked(id),
+                PeerEvent::Choked(dropped) => scheduler.choked(id, &dropped),
+                PeerEvent::Dropped(dropped) => scheduler.dropped(id, &dropped),
+                PeerEvent::Interested => {
+                    if let Some(handle) = handles.get(&id) {
+                        handle.send(PeerCommand::Unchoke);
+                    }
+                }
+                PeerEvent::Request(request) => {
+                    let Some(handle) = handles.get(&id) else {
+                        continue;
+                    };
+                    if !scheduler.picker().have().get(request.index as usize) {
+                        continue;
+                    }
+                    let (index, begin) = (request.index, request.begin);
+                    // a request beyond the piece is the peer's problem, not ours
+                    let Ok(data) = self.storage.read_block(index, begin, request.length) else {
+                        continue;
+                    };
+                    let len = data.len() as u64;
+                    if handle.send(PeerCommand::Block { index, begin, data }) {
+                        resume.uploaded += len;
+                        self.stats.add_uploaded(len);
+                    }
+                }
+                PeerEvent::Block { index, begin, data } => {
+                    let Some(piece) = scheduler.block(id, index, begin, data) else {
+                        continue;
+                    };
+                    match verifier.verify(&piece) {
+                        Verdict::Passed => {
+                            self.storage.write_block(piece.index, 0, &piece.data)?;
+                            self.storage.piece_verified(piece.index)?;
+                            scheduler.picker_mut().piece_verified(piece.index);
+                            resume.downloaded += piece.data.len() as u64;
+                            self.stats.add_downloaded(piece.data.len() as u64);
+                            self.stats.set_left(Self::left(&scheduler));
+                            for handle in handles.values() {
+                                handle.send(PeerCommand::Have(piece.index));
+                            }
+                            if completed.send(piece).await.is_err() {
+                                break;
+                            }
+                        }
+                        Verdict::Failed { banned } => {
+                            // every block of it is requested again
+                            self.storage.piece_failed(piece.index);
+                            scheduler.picker_mut().piece_failed(piece.index);
+                            for id in banned {
+                                if let Some(handle) = handles.get(&id) {
+                                    handle.send(PeerCommand::Close);
+                                }
+                            }
+                        }
+                    }
+                }
+                PeerEvent::Closed(_) => {
+                    scheduler.remove_peer(id);
+                    if let Some(handle) = handles.remove(&id) {
+                        let _ = handle.join().await;
+                    }
+                }
+                _ => {}
+            }
+            // interested in whoever has something we don't
+            if let (Some(handle), Some(bits)) = (handles.get(&id), scheduler.peer_bitfield(id)) {
+                if !bits.and_not(scheduler.picker().have()).is_clear() {
+                    handle.send(PeerCommand::Interested);
+                }
+            }
+            Self::dispatch(&handles, scheduler.schedule(Instant::now()));
+        }
+        self.save(&scheduler, &mut resume)
     }
 
-    pub fn set(&mut self, index: usize) {
-        match &mut self.bit {
-            BitfieldInner::Large(x_field) => {
-                let max_point = index / 8;
-                let min_point = index % 8;
-                x_field[max_point] ^= 1 << min_point;
+    // what was done before a restart: from the resume data if the files are
+    // as it left them, else from hashing whatever is in storage
+    fn restore(&mut self, scheduler: &mut Scheduler, verifier: &PieceVerifier) -> ResumeData {
+        let layout = self.storage.layout().clone();
+        let data = match self.resumed() {
+            Some(data) if data.matches(&self.storage) => {
+                for (index, blocks) in &data.partial {
+                    for block in blocks.ones() {
+                        let begin = block as u32 * BLOCK_SIZE;
+                        let len = BLOCK_SIZE.min(layout.piece_len(*index) - begin);
+                        if let Ok(bytes) = self.storage.read_block(*index, begin, len) {
+                            scheduler.restore_block(*index, begin, bytes);
+                        }
+                    }
+                }
+                scheduler.picker_mut().restore(&data.have);
+                data
             }
-            BitfieldInner::Compact(x_compac) => {
-                self.bit = BitfieldInner::Compact(*x_compac ^ (1 << index));
+            saved => {
+                let mut data = saved.unwrap_or_else(|| ResumeData::new(self.info_hash, &layout));
+                data.have = recheck(&mut self.storage, verifier);
+                data.partial.clear();
+                scheduler.picker_mut().restore(&data.have);
+                data
             }
+        };
+        self.stats.set_left(Self::left(scheduler));
+        data
+    }
+
+    // bytes of the pieces we don't have yet
+    fn left(scheduler: &Scheduler) -> u64 {
+        let picker = scheduler.picker();
+        let missing = picker.have().zeros();
+        missing
+            .map(|index| picker.piece_len(index as u32) as u64)
+            .sum()
+    }
+
+    // flush the storage with the blocks of unfinished pieces, then save how far we got
+    fn save(&mut self, scheduler: &Scheduler, resume: &mut ResumeData) -> Result<(), StorageError> {
+        let Some(path) = self.resume.clone() else {
+            return self.storage.flush();
+        };
+        for (index, begin, data) in scheduler.received_blocks() {
+            self.storage.write_block(index, begin, data)?;
+        }
+        self.storage.flush()?;
+        resume.have = scheduler.picker().have().clone();
+        resume.partial = scheduler.picker().partial();
+        let files = self.storage.layout().files().len();
+        resume.files = (0..files).map(|file| self.storage.stamp(file)).collect();
+        if let Some(tracker) = &self.tracker {
+            resume.tracker = tracker.borrow().clone();
         }
+        resume.save(&path)?;
+        Ok(())
     }
 
-    pub fn get(&mut self, index: u64) -> u64 {
-        match &mut self.bit {
-            BitfieldInner::Large(x_field) => {
-                let max_point = (index / 8) as usize;
-                let min_point = index % 8;
-                let res = x_field[max_point] >> min_point;
-                res as u64
+    fn dispatch(handles: &HashMap<usize, PeerHandle>, commands: Vec<(usize, PeerCommand)>) {
+        for (id, command) in commands {
+            if let Some(handle) = handles.get(&id) {
+                handle.send(command);
             }
-            BitfieldInner::Compact(x_compac) => *x_compac >> index,
         }
     }
 }
 
-#[test]
-fn bitfield_large() {
-    let mut bits = Bitfield::new(10);
-    bits.set(4);
-    assert_eq!(bits.get(4), 1);
-    assert!(bits.len() == 2);
-}
+#[cfg(test)]
+mod test {
+    use std::{fs, io::Write, net::SocketAddr, sync::Arc, time::Duration};
 
-#[test]
-fn bitfield_compact() {
-    let mut bits = Bitfield::new_compact();
-    bits.set(4);
-    assert_eq!(bits.get(4), 1);
-    assert!(bits.len() == 64);
-}
+    use tokio::io::DuplexStream;
+    use tokio::sync::{mpsc, watch, Mutex};
+    use uttd::{transport::pipe, AsyncStream};
 
-#[derive(Debug)]
-pub struct Participants {
-    pub file_size: usize,
-    pub block_size: usize,
-    pub peers: Vec<Arc<Mutex<AsyncStream>>>,
-    pub path: PathBuf,
-}
+    use super::Participants;
+    use crate::{
+        announcer::TransferStats,
+        bitfield::Bitfield,
+        cache::BlockCache,
+        mem_storage::MemoryStorage,
+        message::{Framed, Message},
+        picker::BLOCK_SIZE,
+        resume::{ResumeData, TrackerState},
+        storage::{test::scratch_dir, FileLayout, FileStamp, FileStorage},
+        torrent::{FileMode, Info},
+        verify::test::hashes,
+    };
 
-impl Participants {
-    pub async fn new(t: &Torrent, peers: Vec<Arc<Mutex<AsyncStream>>>) -> Self {
-        let file_size = t.calculate_left();
-        let block_size = t.info.piece_length;
-        let folder = &t.info.name;
-        let path = PathBuf::from_str(folder).unwrap();
+    const PIECE: usize = 1 << 15;
 
-        Self {
+    // a single file torrent
+    fn layout(length: usize) -> FileLayout {
+        let info = Info {
+            name: "file".to_owned(),
+            piece_length: PIECE,
+            pieces: vec![],
+            mode: FileMode::SingleMode { length },
+        };
+        FileLayout::new(&info).unwrap()
+    }
+
+    fn peer(stream: DuplexStream, port: u16) -> (SocketAddr, Arc<Mutex<AsyncStream>>) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        (
+            addr,
+            Arc::new(Mutex::new(AsyncStream::from_transport(stream))),
+        )
+    }
+
+    // a peer with all of `content`, answering every request once it's asked to
+    async fn seed(stream: DuplexStream, content: Vec<u8>, piece_count: usize) {
+        seed_of(stream, content, Bitfield::full(piece_count)).await
+    }
+
+    // a peer with the `have` pieces of `content`
+    async fn seed_of(stream: DuplexStream, content: Vec<u8>, have: Bitfield) {
+        let mut framed = Framed::new(stream);
+        let bits = have.as_bytes().to_vec();
+        framed.send(&Message::BitField(bits)).await.unwrap();
+        while let Ok(Some(message)) = framed.recv().await {
+            match message {
+                Message::Interested => framed.send(&Message::Unchoke).await.unwrap(),
+                Message::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let start = index as usize * PIECE + begin as usize;
+                    let block = content[start..start + length as usize].to_vec();
+                    let piece = Message::Piece {
+                        index,
+                        begin,
+                        block,
+                    };
+                    framed.send(&piece).await.unwrap();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn download_from_seeds() {
+        let dir = scratch_dir("download");
+        let file_size = 2 * PIECE + 100;
+        let content: Vec<u8> = (0..file_size).map(|i| (i % 251) as u8).collect();
+        let mut peers = vec![];
+        let mut seeds = vec![];
+        for port in 0..2 {
+            let (ours, theirs) = pipe();
+            peers.push(peer(ours, port));
+            seeds.push(tokio::spawn(seed(theirs, content.clone(), 3)));
+        }
+        let participants = Participants {
             file_size,
-            block_size,
+            block_size: PIECE,
+            piece_count: 3,
+            pieces: hashes(&content, PIECE),
             peers,
-            path,
+            storage: FileStorage::new(&dir, layout(file_size)),
+            info_hash: [0; 20],
+            resume: None,
+            stats: Arc::new(TransferStats::new(file_size as u64)),
+            tracker: None,
+        };
+        let (tx, mut rx) = mpsc::channel(3);
+        let download = tokio::spawn(participants.download(tx));
+
+        let mut pieces = vec![];
+        for _ in 0..3 {
+            pieces.push(rx.recv().await.unwrap());
         }
-    }
+        pieces.sort_by_key(|p| p.index);
+        let data: Vec<u8> = pieces.into_iter().flat_map(|p| p.data).collect();
+        assert_eq!(data, content);
 
-    // continous reading
-    async fn listen_peers(url: Arc<Mutex<AsyncStream>>, tx: Sender<(usize, Message)>, idx: usize) {
-        loop {
-            let message_len = url.lock().await.read_once().await.unwrap();
-            if message_len == 0 {
-                return;
-                // return Message::KeepAlive;
-            }
-            let mut message = vec![0u8; message_len as usize];
-            url.lock().await.read_multiple(&mut message).await.unwrap();
-            let which_message: Message =
-                Message::try_from(message.as_slice(), message_len as usize);
-            tx.send((idx, which_message)).await.unwrap();
+        // done once every piece is in, which hangs up on the seeds
+        download.await.unwrap().unwrap();
+        for seed in seeds {
+            seed.await.unwrap();
         }
+        assert_eq!(fs::read(dir.join("file")).unwrap(), content);
+        fs::remove_dir_all(dir).unwrap();
     }
-    pub async fn download(self) {
-        // TODO: Remove unwrap
 
-        // _ = tokio::fs::create_dir("./.torain_temp").await;
-        let peers_amt = self.peers.len();
-        assert!(peers_amt > 0);
+    #[tokio::test]
+    async fn bad_seeds_are_banned() {
+        let file_size = 3 * PIECE;
+        let content = vec![1; file_size];
+        let (ours, theirs) = pipe();
+        let participants = Participants {
+            file_size,
+            block_size: PIECE,
+            piece_count: 3,
+            pieces: hashes(&content, PIECE),
+            peers: vec![peer(ours, 1)],
+            storage: MemoryStorage::new(layout(file_size)),
+            info_hash: [0; 20],
+            resume: None,
+            stats: Arc::new(TransferStats::new(file_size as u64)),
+            tracker: None,
+        };
+        // sends zeros instead
+        let seed = tokio::spawn(seed(theirs, vec![0; file_size], 3));
+        let (tx, mut rx) = mpsc::channel(3);
+        participants.download(tx).await.unwrap();
+        assert!(rx.recv().await.is_none());
+        seed.await.unwrap();
+    }
 
-        // am i choking the remote peer?
-        let mut _am_chok: Bitfield = Bitfield::new_compact();
-        // am i interested in the remote peer?
-        let mut _am_interested = Bitfield::new_compact();
+    #[tokio::test]
+    async fn resume_after_restart() {
+        let dir = scratch_dir("resume-download");
+        let file_size = 2 * PIECE + 100;
+        let content: Vec<u8> = (0..file_size).map(|i| (i % 251) as u8).collect();
+        let path = dir.join("file.resume");
+        let participants = |peers| {
+            Participants {
+                file_size,
+                block_size: PIECE,
+                piece_count: 3,
+                pieces: hashes(&content, PIECE),
+                peers,
+                storage: BlockCache::new(FileStorage::new(&dir, layout(file_size))),
+                info_hash: [3; 20],
+                resume: None,
+                stats: Arc::new(TransferStats::new(file_size as u64)),
+                tracker: None,
+            }
+            .with_resume(&path)
+        };
 
-        // is the remote peer choking me?
-        let mut peer_chok = Bitfield::new_compact();
-        // is the remote peer interested in me?
-        let mut peer_interested = Bitfield::new_compact();
+        let (ours, theirs) = pipe();
+        let seeding = tokio::spawn(seed(theirs, content.clone(), 3));
+        let peers = vec![peer(ours, 1)];
+        let (tx, _rx) = mpsc::channel(3);
+        participants(peers).download(tx).await.unwrap();
+        seeding.await.unwrap();
+        let saved = ResumeData::load(&path, &layout(file_size))
+            .unwrap()
+            .unwrap();
+        assert!(saved.have.is_full());
+        assert_eq!(saved.downloaded, file_size as u64);
 
-        let mut piece_map: Vec<Bitfield> = Vec::new();
+        // changed behind our back, but with the same size and time: trusted
+        let mut file = fs::File::options()
+            .write(true)
+            .open(dir.join("file"))
+            .unwrap();
+        let modified = file.metadata().unwrap().modified().unwrap();
+        file.write_all(&[0xff]).unwrap();
+        file.set_modified(modified).unwrap();
+        let (tx, _rx) = mpsc::channel(3);
+        participants(vec![]).download(tx).await.unwrap();
+        let loaded = ResumeData::load(&path, &layout(file_size))
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded, saved);
 
-        let (tx, mut rx) = mpsc::channel::<(usize, Message)>(peers_amt);
-        let mut handles = vec![];
+        // touched: rechecked
+        file.set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        let (tx, _rx) = mpsc::channel(3);
+        participants(vec![]).download(tx).await.unwrap();
+        let rechecked = ResumeData::load(&path, &layout(file_size))
+            .unwrap()
+            .unwrap();
+        assert_eq!(rechecked.have.ones().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(rechecked.downloaded, file_size as u64);
 
-        for (idx, url) in self.peers.into_iter().enumerate() {
-            let txc = tx.clone();
-            let hnd = tokio::spawn(async move { Self::listen_peers(url, txc, idx).await });
-            handles.push(hnd);
-        }
+        // cut short in the middle of piece 1: its first block is kept, and
+        // never asked for again, as a seed would get it wrong
+        file.set_len((PIECE + BLOCK_SIZE as usize) as u64).unwrap();
+        let mut partial = rechecked.clone();
+        partial.have = Bitfield::new(3);
+        let mut blocks = Bitfield::new(2);
+        blocks.set(0);
+        partial.partial.insert(1, blocks);
+        partial.files = vec![FileStamp::of(&dir.join("file"))];
+        partial.save(&path).unwrap();
+        let mut wrong = content.clone();
+        wrong[PIECE..PIECE + BLOCK_SIZE as usize].fill(0);
+        let (ours, theirs) = pipe();
+        let seeding = tokio::spawn(seed(theirs, wrong, 3));
+        let (tx, _rx) = mpsc::channel(3);
+        participants(vec![peer(ours, 1)])
+            .download(tx)
+            .await
+            .unwrap();
+        seeding.await.unwrap();
+        assert_eq!(fs::read(dir.join("file")).unwrap(), content);
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-        // continous receiving from multiple sources
-        loop {
-            if let Some((idx, value)) = rx.recv().await {
-                // TODO:
-                match value {
-                    Message::BitField(x_field) => piece_map.push(x_field),
-                    Message::Choke => peer_chok.set(idx),
-                    Message::Unchoke => peer_chok.set(idx),
-                    Message::Interested => peer_interested.set(idx),
-                    _ => {
-                        println!("{:?}", value);
-                        unimplemented!()
-                    }
-                };
+    // a peer with only the last piece, handing it over once it got a block from us
+    async fn trader(stream: DuplexStream, content: Vec<u8>) -> Vec<u8> {
+        let mut framed = Framed::new(stream);
+        let mut have = Bitfield::new(3);
+        have.set(2);
+        framed
+            .send(&Message::BitField(have.as_bytes().to_vec()))
+            .await
+            .unwrap();
+        framed.send(&Message::Interested).await.unwrap();
+        let mut stashed = vec![];
+        let mut got = None;
+        let mut asked = false;
+        while let Ok(Some(message)) = framed.recv().await {
+            match message {
+                Message::Interested => framed.send(&Message::Unchoke).await.unwrap(),
+                Message::Have(index) if !asked => {
+                    asked = true;
+                    let length = BLOCK_SIZE;
+                    let begin = 0;
+                    let request = Message::Request {
+                        index,
+                        begin,
+                        length,
+                    };
+                    framed.send(&request).await.unwrap();
+                }
+                Message::Piece { block, .. } => got = Some(block),
+                Message::Request {
+                    index,
+                    begin,
+                    length,
+                } => stashed.push((index, begin, length)),
+                _ => {}
+            }
+            if got.is_some() {
+                for (index, begin, length) in stashed.drain(..) {
+                    let start = index as usize * PIECE + begin as usize;
+                    let block = content[start..start + length as usize].to_vec();
+                    let piece = Message::Piece {
+                        index,
+                        begin,
+                        block,
+                    };
+                    framed.send(&piece).await.unwrap();
+                }
             }
-            println!("{:?}", piece_map);
         }
+        got.unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn uploads_peers_and_tracker_are_saved() {
+        let dir = scratch_dir("resume-upload");
+        let file_size = 2 * PIECE + 100;
+        let content: Vec<u8> = (0..file_size).map(|i| (i % 251) as u8).collect();
+        let path = dir.join("file.resume");
+        let (ours, theirs) = pipe();
+        let mut first_two = Bitfield::new(3);
+        first_two.set(0);
+        first_two.set(1);
+        let seed = tokio::spawn(seed_of(theirs, content.clone(), first_two));
+        let (ours2, theirs2) = pipe();
+        let trader = tokio::spawn(trader(theirs2, content.clone()));
+        let state = TrackerState {
+            tracker_id: Some(b"xyz".to_vec()),
+            interval: Some(Duration::from_secs(900)),
+        };
+        let (_tracker, watched) = watch::channel(state.clone());
+        let stats = Arc::new(TransferStats::new(file_size as u64));
+        let participants = Participants {
+            file_size,
+            block_size: PIECE,
+            piece_count: 3,
+            pieces: hashes(&content, PIECE),
+            peers: vec![peer(ours, 1), peer(ours2, 2)],
+            storage: FileStorage::new(&dir, layout(file_size)),
+            info_hash: [4; 20],
+            resume: None,
+            stats: stats.clone(),
+            tracker: None,
+        }
+        .with_resume(&path)
+        .with_tracker(watched);
+        let (tx, _rx) = mpsc::channel(3);
+        participants.download(tx).await.unwrap();
+        seed.await.unwrap();
+        let block = trader.await.unwrap();
+        assert_eq!(block.len(), BLOCK_SIZE as usize);
+        assert!(content.windows(block.len()).any(|w| w == block));
+
+        let saved = ResumeData::load(&path, &layout(file_size))
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.uploaded, BLOCK_SIZE as u64);
+        assert_eq!(stats.uploaded(), BLOCK_SIZE as u64);
+        assert_eq!(stats.downloaded(), file_size as u64);
+        assert_eq!(stats.left(), 0);
+        assert_eq!(saved.tracker, state);
+        let addrs: Vec<SocketAddr> = ["127.0.0.1:1", "127.0.0.1:2"]
+            .map(|a| a.parse().unwrap())
+            .to_vec();
+        assert_eq!(saved.peers, addrs);
+
+        // and handed back to the next run, peers of earlier runs behind the new ones
+        let (ours, _theirs) = pipe();
+        let participants = Participants {
+            file_size,
+            block_size: PIECE,
+            piece_count: 3,
+            pieces: hashes(&content, PIECE),
+            peers: vec![peer(ours, 3)],
+            storage: FileStorage::new(&dir, layout(file_size)),
+            info_hash: [4; 20],
+            resume: None,
+            stats: Arc::new(TransferStats::new(file_size as u64)),
+            tracker: None,
+        }
+        .with_resume(&path);
+        assert_eq!(participants.resumed().unwrap().tracker, state);
+        let (tx, _rx) = mpsc::channel(3);
+        participants.download(tx).await.unwrap();
+        let saved = ResumeData::load(&path, &layout(file_size))
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.peers[0], "127.0.0.1:3".parse().unwrap());
+        assert_eq!(saved.peers[1..], addrs);
+        assert_eq!(saved.tracker, state);
+        assert_eq!(saved.uploaded, BLOCK_SIZE as u64);
+        fs::remove_dir_all(dir).unwrap();
     }
-}
 
-#[cfg(test)]
-mod test {
     // use crate::download::Participants;
     // use crate::{torrent::Torrent, tracker::TrackerParams};
 
@@ -227,7 +689,7 @@ mod test {
     //     // let fs = "nocountry.torrent";
     //     let torrent = Torrent::from_file(fs).unwrap();
     //     let tracker = TrackerParams::new(&torrent);
-    //     let announce = tracker.announce().unwrap();
+    //     let announce = tracker.announce().await.unwrap();
     //     let info_hash = torrent.hash;
     //     let peer_id = tracker.peer_id;
     //     let streams = announce.handshake(info_hash, peer_id).await;
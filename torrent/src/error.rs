@@ -1,6 +1,9 @@
 use core::fmt;
 use std::fmt::Display;
 
+use bencode::utils::BencodeErr;
+use uttd::UttdError;
+
 #[derive(Debug)]
 pub enum TorrentError {
     UnexpectedField,
@@ -11,3 +14,230 @@ impl Display for TorrentError {
         write!(f, "Unexpected field encountered. Aborting")
     }
 }
+
+/// Why an announce to a tracker failed
+#[derive(Debug)]
+pub enum TrackerError {
+    /// The tracker couldn't be reached, or didn't answer like a tracker
+    Network(UttdError),
+    /// The answer isn't the bencoded dictionary it should be
+    Bencode(BencodeErr),
+    /// The tracker refused the announce, for this reason
+    Failure(String),
+}
+
+impl Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackerError::Network(e) => write!(f, "{e}"),
+            TrackerError::Bencode(_) => write!(f, "malformed tracker response"),
+            TrackerError::Failure(reason) => write!(f, "tracker failure: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TrackerError::Network(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<UttdError> for TrackerError {
+    fn from(value: UttdError) -> Self {
+        match value {
+            // UDP trackers send their failure reason in an error packet
+            UttdError::TrackerFailure(reason) => TrackerError::Failure(reason),
+            e => TrackerError::Network(e),
+        }
+    }
+}
+
+impl From<BencodeErr> for TrackerError {
+    fn from(value: BencodeErr) -> Self {
+        TrackerError::Bencode(value)
+    }
+}
+
+/// A peer wire message that couldn't be read or sent
+#[derive(Debug)]
+pub enum MessageError {
+    /// The payload isn't the size a message of this id has
+    InvalidLength {
+        id: u8,
+        len: usize,
+    },
+    /// The length prefix is more than we accept
+    TooLarge(u32),
+    /// The stream ended in the middle of a message
+    Truncated,
+    Io(std::io::Error),
+}
+
+impl Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::InvalidLength { id, len } => {
+                write!(f, "message {id} can't have a {len} byte payload")
+            }
+            MessageError::TooLarge(len) => write!(f, "message of {len} bytes is too large"),
+            MessageError::Truncated => write!(f, "stream ended in the middle of a message"),
+            MessageError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MessageError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MessageError {
+    fn from(value: std::io::Error) -> Self {
+        MessageError::Io(value)
+    }
+}
+
+/// A bitfield that doesn't fit the torrent it's for
+#[derive(Debug, PartialEq, Eq)]
+pub enum BitfieldError {
+    /// Not the number of bytes needed for the torrent's pieces
+    WrongLength { expected: usize, got: usize },
+    /// Bits past the last piece are set
+    SpareBitsSet,
+}
+
+impl Display for BitfieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitfieldError::WrongLength { expected, got } => {
+                write!(f, "bitfield of {got} bytes, expected {expected}")
+            }
+            BitfieldError::SpareBitsSet => write!(f, "bitfield has bits set past the last piece"),
+        }
+    }
+}
+
+impl std::error::Error for BitfieldError {}
+
+/// Why a connection to a peer ended
+#[derive(Debug)]
+pub enum PeerError {
+    /// The peer sent something we couldn't read, or the stream failed
+    Message(MessageError),
+    /// The peer broke the rules of the protocol
+    Protocol(&'static str),
+    /// Nothing was heard from the peer for too long
+    Timeout,
+}
+
+impl Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerError::Message(e) => write!(f, "{e}"),
+            PeerError::Protocol(reason) => write!(f, "peer broke the protocol: {reason}"),
+            PeerError::Timeout => write!(f, "peer went quiet"),
+        }
+    }
+}
+
+impl std::error::Error for PeerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PeerError::Message(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<MessageError> for PeerError {
+    fn from(value: MessageError) -> Self {
+        PeerError::Message(value)
+    }
+}
+
+/// Torrent data that couldn't be stored or read back
+#[derive(Debug)]
+pub enum StorageError {
+    /// A file path of the torrent would lead out of the download directory
+    UnsafePath(String),
+    /// Bytes past the end of the torrent
+    OutOfRange {
+        offset: u64,
+        len: u64,
+    },
+    /// `pieces` of the torrent doesn't hold one hash for each of its pieces
+    PieceHashes {
+        len: usize,
+        pieces: u32,
+    },
+    Io(std::io::Error),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::UnsafePath(path) => write!(f, "unsafe path in torrent: {path:?}"),
+            StorageError::OutOfRange { offset, len } => {
+                write!(f, "{len} bytes at {offset} are past the end of the torrent")
+            }
+            StorageError::PieceHashes { len, pieces } => {
+                write!(f, "{len} bytes of piece hashes for {pieces} pieces")
+            }
+            StorageError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(value: std::io::Error) -> Self {
+        StorageError::Io(value)
+    }
+}
+
+/// Resume data that couldn't be read back
+#[derive(Debug)]
+pub enum ResumeError {
+    /// Not bencode, or not what we write
+    Invalid(&'static str),
+    Io(std::io::Error),
+}
+
+impl Display for ResumeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResumeError::Invalid(reason) => write!(f, "invalid resume data: {reason}"),
+            ResumeError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ResumeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ResumeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ResumeError {
+    fn from(value: std::io::Error) -> Self {
+        ResumeError::Io(value)
+    }
+}
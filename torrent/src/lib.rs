@@ -1,5 +1,20 @@
+pub mod announce_list;
+pub mod announcer;
+pub mod bitfield;
+pub mod cache;
+pub mod connection;
 pub mod download;
 pub mod error;
+pub mod mem_storage;
+pub mod message;
+#[cfg(unix)]
+pub mod mmap_storage;
 pub mod peers;
+pub mod picker;
+pub mod resume;
+pub mod scheduler;
+pub mod storage;
 pub mod torrent;
 pub mod tracker;
+pub mod verify;
+pub mod webseed;
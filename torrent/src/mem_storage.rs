@@ -0,0 +1,100 @@
+use std::path::Path;
+
+use crate::{
+    error::StorageError,
+    storage::{FileLayout, Storage},
+};
+
+/// Torrent data kept in memory, for tests and for torrents not meant to last.
+/// Files grow as they are written to, like on disk
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    layout: FileLayout,
+    files: Vec<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(layout: FileLayout) -> Self {
+        let files = layout.files().iter().map(|_| Vec::new()).collect();
+        Self { layout, files }
+    }
+
+    /// The bytes of file `file` of the layout
+    pub fn file(&self, file: usize) -> &[u8] {
+        &self.files[file]
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), StorageError> {
+        let mut written = 0;
+        for slice in self.layout.slices(offset, data.len() as u64)? {
+            let file = &mut self.files[slice.file];
+            let (start, end) = (slice.offset as usize, (slice.offset + slice.len) as usize);
+            if file.len() < end {
+                file.resize(end, 0);
+            }
+            file[start..end].copy_from_slice(&data[written..written + slice.len as usize]);
+            written += slice.len as usize;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        let mut data = Vec::with_capacity(len as usize);
+        for slice in self.layout.slices(offset, len)? {
+            let (start, end) = (slice.offset as usize, (slice.offset + slice.len) as usize);
+            let part = self.files[slice.file]
+                .get(start..end)
+                .ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+            data.extend_from_slice(part);
+        }
+        Ok(data)
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn has_piece(&mut self, index: u32) -> bool {
+        self.layout
+            .holds_piece(index, |file| self.files[file].len() as u64)
+    }
+
+    /// Nothing is on disk to move
+    fn move_to(&mut self, _root: &Path) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn delete(&mut self) -> Result<(), StorageError> {
+        self.files.iter_mut().for_each(Vec::clear);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MemoryStorage;
+    use crate::storage::{
+        test::{exercise, multi_info},
+        FileLayout, Storage,
+    };
+
+    #[test]
+    fn behaves_like_storage() {
+        let layout = FileLayout::new(&multi_info(
+            &[(&["a"], 7), (&["empty"], 0), (&["b"], 13)],
+            8,
+        ))
+        .unwrap();
+        let mut storage = MemoryStorage::new(layout);
+        exercise(&mut storage);
+        storage.delete().unwrap();
+        assert!(storage.file(0).is_empty());
+        assert!(!storage.has_piece(0));
+    }
+}
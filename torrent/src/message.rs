@@ -0,0 +1,441 @@
+// REFERENCE: https://www.bittorrent.org/beps/bep_0003.html#peer-messages
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use uttd::transport::Transport;
+
+use crate::error::MessageError;
+
+// the largest message accepted: a 1 MiB bitfield is 8 million pieces,
+// far more than any torrent has, and blocks are 16 KiB
+pub const MAX_MESSAGE_LEN: u32 = 1 << 20;
+
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+const PORT: u8 = 9;
+
+/// A message of the peer wire protocol, after the handshake
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    /// the sender has this piece
+    Have(u32),
+    /// the pieces the sender has, highest bit of the first byte is piece 0
+    BitField(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// the port the sender's DHT node listens on
+    Port(u16),
+    /// a message of an extension we don't speak, kept as is
+    Unrecognized(u8, Vec<u8>),
+}
+
+impl Message {
+    /// Decode a message from `frame`, everything after the length prefix.
+    /// An empty frame is a keep-alive
+    pub fn decode(frame: &[u8]) -> Result<Self, MessageError> {
+        let Some((&id, payload)) = frame.split_first() else {
+            return Ok(Self::KeepAlive);
+        };
+        let invalid = || MessageError::InvalidLength {
+            id,
+            len: payload.len(),
+        };
+        let u32_at = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+        let expect = |len: usize| match payload.len() == len {
+            true => Ok(()),
+            false => Err(invalid()),
+        };
+
+        let message = match id {
+            CHOKE => expect(0).map(|_| Self::Choke)?,
+            UNCHOKE => expect(0).map(|_| Self::Unchoke)?,
+            INTERESTED => expect(0).map(|_| Self::Interested)?,
+            NOT_INTERESTED => expect(0).map(|_| Self::NotInterested)?,
+            HAVE => expect(4).map(|_| Self::Have(u32_at(0)))?,
+            BITFIELD => Self::BitField(payload.to_vec()),
+            REQUEST | CANCEL => {
+                expect(12)?;
+                let (index, begin, length) = (u32_at(0), u32_at(4), u32_at(8));
+                match id {
+                    REQUEST => Self::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => Self::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            PIECE => {
+                if payload.len() < 8 {
+                    return Err(invalid());
+                }
+                Self::Piece {
+                    index: u32_at(0),
+                    begin: u32_at(4),
+                    block: payload[8..].to_vec(),
+                }
+            }
+            PORT => {
+                expect(2)?;
+                Self::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            _ => Self::Unrecognized(id, payload.to_vec()),
+        };
+        Ok(message)
+    }
+
+    /// Append the message, length prefix included, to `buf`
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&[0; 4]);
+        match self {
+            Self::KeepAlive => {}
+            Self::Choke => buf.push(CHOKE),
+            Self::Unchoke => buf.push(UNCHOKE),
+            Self::Interested => buf.push(INTERESTED),
+            Self::NotInterested => buf.push(NOT_INTERESTED),
+            Self::Have(index) => {
+                buf.push(HAVE);
+                buf.extend_from_slice(&index.to_be_bytes());
+            }
+            Self::BitField(bits) => {
+                buf.push(BITFIELD);
+                buf.extend_from_slice(bits);
+            }
+            Self::Request {
+                index,
+                begin,
+                length,
+            }
+            | Self::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                buf.push(match self {
+                    Self::Request { .. } => REQUEST,
+                    _ => CANCEL,
+                });
+                buf.extend_from_slice(&index.to_be_bytes());
+                buf.extend_from_slice(&begin.to_be_bytes());
+                buf.extend_from_slice(&length.to_be_bytes());
+            }
+            Self::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                buf.push(PIECE);
+                buf.extend_from_slice(&index.to_be_bytes());
+                buf.extend_from_slice(&begin.to_be_bytes());
+                buf.extend_from_slice(block);
+            }
+            Self::Port(port) => {
+                buf.push(PORT);
+                buf.extend_from_slice(&port.to_be_bytes());
+            }
+            Self::Unrecognized(id, payload) => {
+                buf.push(*id);
+                buf.extend_from_slice(payload);
+            }
+        }
+        let len = (buf.len() - start - 4) as u32;
+        buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+    }
+
+    /// The message, length prefix included
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+}
+
+/// Splits a byte stream into messages and turns messages into bytes,
+/// refusing messages longer than its max length
+#[derive(Debug, Clone, Copy)]
+pub struct MessageCodec {
+    max_len: u32,
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        Self {
+            max_len: MAX_MESSAGE_LEN,
+        }
+    }
+
+    pub fn with_max_len(max_len: u32) -> Self {
+        Self { max_len }
+    }
+
+    /// Take the first message off the front of `buf`.
+    /// `Ok(None)` if `buf` doesn't hold a whole message yet
+    pub fn decode(&mut self, buf: &mut Vec<u8>) -> Result<Option<Message>, MessageError> {
+        let Some(prefix) = buf.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(prefix.try_into().unwrap());
+        if len > self.max_len {
+            return Err(MessageError::TooLarge(len));
+        }
+        let end = 4 + len as usize;
+        if buf.len() < end {
+            return Ok(None);
+        }
+        let message = Message::decode(&buf[4..end]);
+        buf.drain(..end);
+        message.map(Some)
+    }
+
+    pub fn encode(&mut self, message: &Message, buf: &mut Vec<u8>) {
+        message.encode(buf)
+    }
+}
+
+// how much is read from the stream at once
+const READ_CHUNK: usize = 1 << 14;
+
+/// Messages over any transport: a stream of bytes on one side, `Message`s on the other
+#[derive(Debug)]
+pub struct Framed<T> {
+    io: T,
+    codec: MessageCodec,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+}
+
+impl<T: Transport> Framed<T> {
+    pub fn new(io: T) -> Self {
+        Self::with_codec(io, MessageCodec::new())
+    }
+
+    pub fn with_codec(io: T, codec: MessageCodec) -> Self {
+        Self {
+            io,
+            codec,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// The transport back. Bytes read past the last message are lost
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    /// Write `message` out and flush it
+    pub async fn send(&mut self, message: &Message) -> Result<(), MessageError> {
+        self.write_buf.clear();
+        self.codec.encode(message, &mut self.write_buf);
+        self.io.write_all(&self.write_buf).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    /// The next message. `Ok(None)` once the peer closed the stream between messages.
+    ///
+    /// Cancel safe: dropping the future loses no bytes, so it can be raced in `select!`
+    pub async fn recv(&mut self) -> Result<Option<Message>, MessageError> {
+        loop {
+            if let Some(message) = self.codec.decode(&mut self.read_buf)? {
+                return Ok(Some(message));
+            }
+            self.read_buf.reserve(READ_CHUNK);
+            if self.io.read_buf(&mut self.read_buf).await? == 0 {
+                return match self.read_buf.is_empty() {
+                    true => Ok(None),
+                    false => Err(MessageError::Truncated),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncWriteExt;
+    use uttd::transport::pipe;
+
+    use super::{Framed, Message, MessageCodec};
+    use crate::error::MessageError;
+
+    fn every_message() -> Vec<Message> {
+        vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(0x01020304),
+            Message::BitField(vec![0b1010_0000, 0xff]),
+            Message::BitField(vec![]),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                index: 2,
+                begin: 0,
+                block: vec![7; 100],
+            },
+            Message::Piece {
+                index: 3,
+                begin: 4,
+                block: vec![],
+            },
+            Message::Cancel {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Port(6881),
+            Message::Unrecognized(20, vec![0, b'd', b'e']),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for message in every_message() {
+            let bytes = message.to_bytes();
+            let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+            assert_eq!(len, bytes.len() - 4);
+            assert_eq!(Message::decode(&bytes[4..]).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn wire_format() {
+        let request = Message::Request {
+            index: 1,
+            begin: 2,
+            length: 3,
+        };
+        assert_eq!(
+            request.to_bytes(),
+            [0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]
+        );
+        assert_eq!(Message::KeepAlive.to_bytes(), [0, 0, 0, 0]);
+        assert_eq!(
+            Message::Port(0x1ae1).to_bytes(),
+            [0, 0, 0, 3, 9, 0x1a, 0xe1]
+        );
+        assert_eq!(Message::Have(5).to_bytes(), [0, 0, 0, 5, 4, 0, 0, 0, 5]);
+    }
+
+    #[test]
+    fn malformed_payloads() {
+        let bad: &[&[u8]] = &[
+            &[0, 1],
+            &[3, 0],
+            &[4, 0, 0, 1],
+            &[4, 0, 0, 0, 1, 2],
+            &[6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0],
+            &[7, 0, 0, 0, 1, 0, 0, 0],
+            &[8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 4],
+            &[9, 1],
+        ];
+        for frame in bad {
+            let err = Message::decode(frame).unwrap_err();
+            assert!(
+                matches!(err, MessageError::InvalidLength { id, len } if id == frame[0] && len == frame.len() - 1),
+                "{frame:?} gave {err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn codec_waits_for_whole_messages() {
+        let mut codec = MessageCodec::new();
+        let mut wire = Vec::new();
+        for message in every_message() {
+            codec.encode(&message, &mut wire);
+        }
+
+        // fed a byte at a time
+        let mut buf = Vec::new();
+        let mut decoded = Vec::new();
+        for byte in wire {
+            buf.push(byte);
+            while let Some(message) = codec.decode(&mut buf).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert!(buf.is_empty());
+        assert_eq!(decoded, every_message());
+
+        let mut huge = (super::MAX_MESSAGE_LEN + 1).to_be_bytes().to_vec();
+        assert!(matches!(
+            codec.decode(&mut huge),
+            Err(MessageError::TooLarge(_))
+        ));
+        let mut small = MessageCodec::with_max_len(4);
+        let mut piece = Message::Have(1).to_bytes();
+        assert!(small.decode(&mut piece).is_err());
+    }
+
+    #[tokio::test]
+    async fn framed_over_a_pipe() {
+        let (a, b) = pipe();
+        let mut ours = Framed::new(a);
+        let mut theirs = Framed::new(b);
+        let peer = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(message) = theirs.recv().await.unwrap() {
+                received.push(message);
+            }
+            received
+        });
+        for message in every_message() {
+            ours.send(&message).await.unwrap();
+        }
+        drop(ours);
+        assert_eq!(peer.await.unwrap(), every_message());
+
+        // the stream ends half way through a message
+        let (a, mut b) = pipe();
+        let mut ours = Framed::new(a);
+        b.write_all(&[0, 0, 0, 5, 4, 0]).await.unwrap();
+        drop(b);
+        assert!(matches!(ours.recv().await, Err(MessageError::Truncated)));
+    }
+}
@@ -0,0 +1,220 @@
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use crate::{
+    error::StorageError,
+    storage::{delete_files, move_files, open_file, FileLayout, FileStamp, Storage},
+};
+
+/// Torrent data in memory-mapped files, so uploads are served straight
+/// from the page cache without a read per block.
+/// A file is created at its full length and mapped when first written to.
+///
+/// Another process truncating a mapped file kills this one with SIGBUS,
+/// so the files are best left alone while mapped
+#[derive(Debug)]
+pub struct MmapStorage {
+    root: PathBuf,
+    layout: FileLayout,
+    maps: Vec<Option<Mapping>>,
+}
+
+impl MmapStorage {
+    pub fn new(root: impl Into<PathBuf>, layout: FileLayout) -> Self {
+        let maps = layout.files().iter().map(|_| None).collect();
+        Self {
+            root: root.into(),
+            layout,
+            maps,
+        }
+    }
+
+    /// Where file `file` of the layout is on disk
+    pub fn path(&self, file: usize) -> PathBuf {
+        self.root.join(&self.layout.files()[file].path)
+    }
+
+    fn map(&mut self, file: usize, create: bool) -> Result<&mut Mapping, StorageError> {
+        if self.maps[file].is_none() {
+            let length = self.layout.files()[file].length;
+            let handle = open_file(&self.path(file), create)?;
+            let on_disk = handle.metadata()?.len();
+            if on_disk < length {
+                // reading a file that's too short would fault
+                if !create {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                handle.set_len(length)?;
+            }
+            self.maps[file] = Some(Mapping::new(handle, length as usize)?);
+        }
+        Ok(self.maps[file].as_mut().unwrap())
+    }
+
+    fn unmap_all(&mut self) -> Result<(), StorageError> {
+        self.flush()?;
+        self.maps.iter_mut().for_each(|map| *map = None);
+        Ok(())
+    }
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), StorageError> {
+        let mut written = 0;
+        for slice in self.layout.slices(offset, data.len() as u64)? {
+            let (start, end) = (slice.offset as usize, (slice.offset + slice.len) as usize);
+            let map = self.map(slice.file, true)?;
+            map.as_mut_slice()[start..end]
+                .copy_from_slice(&data[written..written + slice.len as usize]);
+            written += slice.len as usize;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        let mut data = Vec::with_capacity(len as usize);
+        for slice in self.layout.slices(offset, len)? {
+            let (start, end) = (slice.offset as usize, (slice.offset + slice.len) as usize);
+            let map = self.map(slice.file, false)?;
+            data.extend_from_slice(&map.as_slice()[start..end]);
+        }
+        Ok(data)
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        for map in self.maps.iter().flatten() {
+            map.flush()?;
+        }
+        Ok(())
+    }
+
+    fn has_piece(&mut self, index: u32) -> bool {
+        self.layout.holds_piece(index, |file| {
+            fs::metadata(self.path(file)).map_or(0, |m| m.len())
+        })
+    }
+
+    fn move_to(&mut self, root: &Path) -> Result<(), StorageError> {
+        self.unmap_all()?;
+        move_files(&self.layout, &self.root, root)?;
+        self.root = root.to_owned();
+        Ok(())
+    }
+
+    fn delete(&mut self) -> Result<(), StorageError> {
+        self.maps.iter_mut().for_each(|map| *map = None);
+        delete_files(&self.layout, &self.root)
+    }
+
+    fn stamp(&self, file: usize) -> Option<FileStamp> {
+        FileStamp::of(&self.path(file))
+    }
+}
+
+// a whole file, mapped shared and writable
+#[derive(Debug)]
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    _file: File,
+}
+
+// the mapping is owned, like a Vec's buffer
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(file: File, len: usize) -> io::Result<Self> {
+        if len == 0 {
+            // nothing to map, and mmap refuses empty mappings
+            return Ok(Self {
+                ptr: ptr::NonNull::dangling().as_ptr(),
+                len,
+                _file: file,
+            });
+        }
+        // SAFETY: a new mapping of a file at least `len` bytes long, not aliased anywhere
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+            _file: file,
+        })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: `ptr` is valid for `len` bytes until dropped
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: as above, and `&mut self` makes it exclusive
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        // SAFETY: the range is the mapping itself
+        match unsafe { libc::msync(self.ptr as *mut libc::c_void, self.len, libc::MS_SYNC) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: unmapped once, nothing borrows it past `self`
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::MmapStorage;
+    use crate::storage::{
+        test::{exercise, multi_info, scratch_dir},
+        FileLayout, Storage,
+    };
+
+    #[test]
+    fn behaves_like_storage() {
+        let dir = scratch_dir("mmap");
+        let info = multi_info(&[(&["a"], 7), (&["empty"], 0), (&["b"], 13)], 8);
+        let mut storage = MmapStorage::new(&dir, FileLayout::new(&info).unwrap());
+        exercise(&mut storage);
+
+        // what's written is in the files
+        let content: Vec<u8> = (0..20).collect();
+        assert_eq!(fs::read(dir.join("album/b")).unwrap(), &content[7..]);
+        storage.move_to(&dir.join("moved")).unwrap();
+        assert_eq!(storage.read(0, 20).unwrap(), content);
+        storage.delete().unwrap();
+        assert!(!dir.join("moved/album").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,7 +1,10 @@
-pub(crate) use std::{sync::Arc, time::Duration};
+pub(crate) use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 
-use uttd::{url::Url, utp::UtpPacket, AsyncStream, AsyncStreamType, UttdError};
+use uttd::{
+    dns::Resolver, proxy::Proxy, transport::Transport, utp::UtpSocket, AsyncStream,
+    AsyncStreamType, UttdError,
+};
 
 #[repr(C)]
 #[repr(packed)]
@@ -40,23 +43,36 @@ pub struct Peers {
     pub interval: i32,
     pub seeders: i32,
     pub leechers: i32,
-    pub peer: Vec<Url>,
+    pub peer: Vec<SocketAddr>,
+    /// connect to peers through this proxy, over TCP only
+    pub proxy: Option<Proxy>,
+    /// don't announce again before this many seconds, unless the event calls for it
+    pub min_interval: Option<i32>,
+    /// to be sent back to the tracker in later announces
+    pub tracker_id: Option<Vec<u8>>,
+    /// the announce worked, but the tracker has something to say
+    pub warning: Option<String>,
 }
 
 impl Peers {
-    pub fn new(interval: i32, seeders: i32, leechers: i32, ip: Vec<Url>) -> Self {
+    pub fn new(interval: i32, seeders: i32, leechers: i32, ip: Vec<SocketAddr>) -> Self {
         Self {
             interval,
             seeders,
             leechers,
             peer: ip,
+            proxy: None,
+            min_interval: None,
+            tracker_id: None,
+            warning: None,
         }
     }
+    /// Connect to every peer, keeping those that answered the handshake, with their address
     pub async fn handshake(
         self,
         info_hash: [u8; 20],
         peer_id: [u8; 20],
-    ) -> Vec<Arc<Mutex<AsyncStream>>> {
+    ) -> Vec<(SocketAddr, Arc<Mutex<AsyncStream>>)> {
         // let peer: Vec<Arc<Url>> = self.peer.clone().into_iter().map(|x| Arc::new(x)).collect();
         let peer = self.peer;
         let mut handshake = Handshake::new(info_hash, peer_id);
@@ -66,32 +82,54 @@ impl Peers {
 
         let mut successful_streams = Vec::with_capacity(peer.len());
 
-        for url in peer {
+        // every uTP connection of an address family goes over the same port.
+        // uTP can't go through a proxy
+        let (utp4, utp6) = match self.proxy {
+            Some(_) => (None, None),
+            None => {
+                let bind = |addr| async move { UtpSocket::bind(addr).await.ok().map(Arc::new) };
+                let v4 = peer.iter().any(|p| p.is_ipv4());
+                let v6 = peer.iter().any(|p| p.is_ipv6());
+                (
+                    if v4 { bind("0.0.0.0:0").await } else { None },
+                    if v6 { bind("[::]:0").await } else { None },
+                )
+            }
+        };
+        let proxy = self.proxy.map(Arc::new);
+
+        for addr in peer {
             let bytes = handshake_bytes.clone();
-            let handle = tokio::spawn(Self::initiate_handshake(url, bytes));
-            handles.push(handle);
+            let utp = match addr {
+                SocketAddr::V4(_) => utp4.clone(),
+                SocketAddr::V6(_) => utp6.clone(),
+            };
+            let handle = tokio::spawn(Self::initiate_handshake(addr, bytes, utp, proxy.clone()));
+            handles.push((addr, handle));
         }
 
-        for handle in handles {
-            let res = handle.await.unwrap();
-            if let Ok(r) = res {
+        for (addr, handle) in handles {
+            // a handshake that failed or panicked leaves the peer out
+            if let Ok(Ok(r)) = handle.await {
                 let r = Arc::new(Mutex::new(r));
-                successful_streams.push(r);
+                successful_streams.push((addr, r));
             }
         }
         successful_streams
     }
 
     async fn initiate_handshake(
-        url: Url,
+        addr: SocketAddr,
         handshake_bytes: Arc<Vec<u8>>,
+        utp: Option<Arc<UtpSocket>>,
+        proxy: Option<Arc<Proxy>>,
     ) -> Result<AsyncStream, UttdError> {
         tokio::select! {
-            res = Self::initiate_handshake_tcp(&url, handshake_bytes.clone()) => {
+            res = Self::initiate_handshake_tcp(addr, handshake_bytes.clone(), proxy) => {
                 res
             }
 
-            res = Self::handshake_utp(&url) => {
+            res = Self::handshake_utp(addr, handshake_bytes.clone(), utp) => {
                 res
             }
 
@@ -100,111 +138,59 @@ impl Peers {
     }
 
     async fn initiate_handshake_tcp(
-        url: &Url,
+        addr: SocketAddr,
         handshake_bytes: Arc<Vec<u8>>,
+        proxy: Option<Arc<Proxy>>,
     ) -> Result<AsyncStream, UttdError> {
-        let mut stream = tokio::time::timeout(
-            Duration::from_secs(5),
-            tokio::net::TcpStream::connect(&url.host),
-        )
+        let mut stream = tokio::time::timeout(Duration::from_secs(5), async {
+            match proxy {
+                Some(proxy) => proxy.connect(&addr.to_string()).await,
+                None => Resolver::global().connect(&addr.to_string()).await,
+            }
+        })
         .await??;
 
-        let mut res = vec![0; 68];
-        // let result = stream?.write_all(&handshake_bytes, &mut res);
-        let br = AsyncStream::send_tcp(&mut stream, &handshake_bytes, &mut res).await?;
-
-        // Though the docs proclaim that "all current implmentation" of the bittorrent protocol
-        // set all the reserved bytes to 0, most peer support atleast a few extentions, most
-        // torrent clients also modify some reserved bytes. `reserved[5]` and `reserved[7]` is usually set.
-        // `reserved[5]` == 0x10 indicates that this peer actually supports the extended bittorrent protocol,
-        // while reserved[7] == 0x04 indicates the peer suppor the fast extention. `reserved[7]` == 0x01 indicates
-        // that the peer supports DHT nodes. Combining both, `reserved[7]` == 0x05 means that the peer supports
-        // both the fast extention and the DHT extention.
-        //
-        // As such, the response reserved bytes from a received packet generally looks like this:
-        //  0  1  2  3  4  5   6  7
-        // [0, 0, 0, 0, 0, 16, 0, 5]
-
-        // IMPORTANT: this checks if the peer supports the extended bittorret protocol
-        // https://www.bittorrent.org/beps/bep_0010.html
-        // Peers announce to each other whether or not they support the extended protocol by setting the
-        // reserved[5](0-indexed) byte to 0x10 (16 in decimal)
-
-        // if res[25] & 0x10 != 0 {
-        //     println!("Found Extended",);
-        // }
-        //
-
-        // [0, 0, 0, 197]
-        // [20, 0, 100, 49, 50, 58, 99, 111, 109, 112, 108, 101, 116, 101, 95, 97, 103, 111, 105, 50, 54, 49, 56, 101, 49, 58, 109, 100]
-        //  |
-        //  |
-        //  |
-        //  |
-        //  This is the extended byte message.
-        // TODO: figure out the message
-        // https://www.bittorrent.org/beps/bep_0010.html
-
-        // TODO: restruct these elsewhere
-        let mut dht_msg_len = vec![0; 4];
-
-        AsyncStream::read_multiple_tcp(&mut stream, &mut dht_msg_len).await?;
-        let dht_msg_len = u32::from_be_bytes(dht_msg_len[0..4].try_into().unwrap());
-
-        // TODO: make this beautiful
-        let mut temp = vec![0; 2];
-        AsyncStream::read_multiple_tcp(&mut stream, &mut temp).await?;
-
-        if temp[0] == 20 {
-            let mut dht_msg = vec![0_u8; dht_msg_len as usize - 2];
-            AsyncStream::read_multiple_tcp(&mut stream, &mut dht_msg).await?;
-            _ = bencode::bencode::decode(&mut dht_msg.into_iter()).expect("Can't decode bencode");
-        }
-
-        // `dht_msg` is u8-bytes of bencoded dictionary with various keys
-        // see more: https://www.bittorrent.org/beps/bep_0010.html
-
-        // [0, 0, 0, 197]
-        // [20, 0, 100, 49, 50, 58, 99, 111, 109, 112, 108, 101, 116, 101, 95, 97, 103, 111, 105, 50, 54, 49, 56, 101, 49, 58, 109, 100]
-        // println!(
-        //     "Initial Value len: {}",
-        //     u32::from_be_bytes([dht_msg[0], dht_msg[1], dht_msg[2], dht_msg[3]])
-        // );
-        //
-        //
-        // -----------------------------------------------------------------------------------------------------------------------------------
-        //
-        //
-
-        if br == 68 && res[0] == 19 {
-            Ok(AsyncStream {
-                async_stream_type: AsyncStreamType::TcpStream(stream),
-            })
-        } else {
-            Err(UttdError::FailedRequest)
-        }
+        // whatever the peer sends after its handshake is for `Framed` to read
+        Self::exchange_handshake(&mut stream, &handshake_bytes).await?;
+        Ok(AsyncStream {
+            async_stream_type: AsyncStreamType::TcpStream(stream),
+        })
     }
 
-    async fn handshake_utp(url: &Url) -> Result<AsyncStream, UttdError> {
-        let mut stream = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
-        stream.connect(&url.host).await?;
-
-        let bytes = UtpPacket::new().as_bytes();
-        let mut res = vec![0; 20];
-
-        let _ = AsyncStream::send_utp(&mut stream, &bytes, &mut res).await?;
-
-        // println!("Gets here with: br: {} & res: {}", br, res[0]);
-        // println!("{:?}", url);
+    async fn handshake_utp(
+        addr: SocketAddr,
+        handshake_bytes: Arc<Vec<u8>>,
+        utp: Option<Arc<UtpSocket>>,
+    ) -> Result<AsyncStream, UttdError> {
+        // without a socket it's up to TCP
+        let Some(utp) = utp else {
+            return std::future::pending().await;
+        };
+        let mut stream = tokio::time::timeout(Duration::from_secs(5), utp.connect(addr)).await??;
+        Self::exchange_handshake(&mut stream, &handshake_bytes).await?;
+        Ok(AsyncStream {
+            async_stream_type: AsyncStreamType::UtpStream(stream),
+        })
+    }
 
-        if res[0] == 33 {
-            return Ok(AsyncStream {
-                async_stream_type: AsyncStreamType::UtpStream(stream),
-            });
+    /// Send our handshake over `stream` and wait for the peer's,
+    /// which has to be for the same torrent
+    async fn exchange_handshake<T: Transport>(
+        stream: &mut T,
+        handshake_bytes: &[u8],
+    ) -> Result<(), UttdError> {
+        let mut res = vec![0; 68];
+        let br = AsyncStream::send_tcp(stream, handshake_bytes, &mut res).await?;
+        if br != 68 || res[..20] != handshake_bytes[..20] {
+            return Err(UttdError::ProtocolViolation("invalid handshake"));
         }
-
-        // TODO: remove this line
-        Err(UttdError::FailedRequest)
+        // the reserved bytes in between say which extensions the peer supports
+        if res[28..48] != handshake_bytes[28..48] {
+            return Err(UttdError::ProtocolViolation(
+                "handshake for another torrent",
+            ));
+        }
+        Ok(())
     }
 }
 
@@ -214,20 +200,90 @@ mod test {
     use std::{
         io::{Read, Write},
         net::TcpStream,
+        sync::Arc,
+    };
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use uttd::transport::pipe;
+
+    use crate::{
+        message::{Framed, Message},
+        peers::{Handshake, Peers},
+        torrent::Torrent,
+        tracker::TrackerParams,
     };
 
-    use crate::{peers::Handshake, torrent::Torrent, tracker::TrackerParams};
+    #[tokio::test]
+    async fn handshake_over_pipe() {
+        let (mut ours, mut theirs) = pipe();
+        let mut handshake = Handshake::new([1; 20], [2; 20]);
+        let bytes = handshake.as_bytes_mut().to_vec();
+        let peer = tokio::spawn(async move {
+            let mut received = [0; 68];
+            theirs.read_exact(&mut received).await.unwrap();
+            let mut reply = Handshake::new([1; 20], [3; 20]);
+            theirs.write_all(reply.as_bytes_mut()).await.unwrap();
+            received
+        });
+
+        Peers::exchange_handshake(&mut ours, &bytes).await.unwrap();
+        assert_eq!(peer.await.unwrap().to_vec(), bytes);
+
+        // a peer that doesn't speak the protocol
+        let (mut ours, mut theirs) = pipe();
+        tokio::spawn(async move { theirs.write_all(&[0; 68]).await });
+        assert!(Peers::exchange_handshake(&mut ours, &bytes).await.is_err());
+
+        // nor has the torrent
+        let (mut ours, mut theirs) = pipe();
+        let mut other = Handshake::new([9; 20], [3; 20]);
+        let other = other.as_bytes_mut().to_vec();
+        tokio::spawn(async move { theirs.write_all(&other).await });
+        assert!(Peers::exchange_handshake(&mut ours, &bytes).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tcp_streams_start_after_the_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut received = [0; 68];
+            sock.read_exact(&mut received).await.unwrap();
+            let mut reply = Handshake::new([1; 20], [3; 20]);
+            sock.write_all(reply.as_bytes_mut()).await.unwrap();
+            let mut framed = Framed::new(sock);
+            framed.send(&Message::BitField(vec![0xa0])).await.unwrap();
+            framed.send(&Message::KeepAlive).await.unwrap();
+            // stay until the other side is done
+            while let Ok(Some(_)) = framed.recv().await {}
+        });
+
+        let peers = Peers::new(0, 1, 0, vec![addr]);
+        let mut streams = peers.handshake([1; 20], [2; 20]).await;
+        assert_eq!(streams.len(), 1);
+        let (got, stream) = streams.pop().unwrap();
+        assert_eq!(got, addr);
+        let stream = Arc::try_unwrap(stream).unwrap().into_inner();
+        let mut framed = Framed::new(stream);
+        assert_eq!(
+            framed.recv().await.unwrap(),
+            Some(Message::BitField(vec![0xa0]))
+        );
+        assert_eq!(framed.recv().await.unwrap(), Some(Message::KeepAlive));
+    }
 
     // // WARNING: This may fail
-    #[test]
+    #[tokio::test]
     #[ignore = "This may fail because peers are notoriously unreliable. A peer may not be valid all the time."]
-    fn connect_test() {
+    async fn connect_test() {
         let peer = "112.156.141.234:4681";
 
         let fs = "pulpfiction.torrent";
         let torrent = Torrent::from_file(fs).unwrap();
         let tracker = TrackerParams::new(&torrent);
-        let _peers = tracker.announce().unwrap();
+        let _peers = tracker.announce().await.unwrap();
         let info_hash = torrent.hash;
         let peer_id = tracker.peer_id;
         let mut data = Vec::new();
@@ -253,7 +309,7 @@ mod test {
         let fs = "debian.torrent";
         let torrent = Torrent::from_file(fs).unwrap();
         let tracker = TrackerParams::new(&torrent);
-        let _announce = tracker.announce().unwrap();
+        let _announce = tracker.announce().await.unwrap();
         let info_hash = torrent.hash;
         let peer_id = tracker.peer_id;
         let mut handshake = Handshake::new(info_hash, peer_id);
@@ -266,7 +322,7 @@ mod test {
         let fs = "debian.torrent";
         let torrent = Torrent::from_file(fs).unwrap();
         let tracker = TrackerParams::new(&torrent);
-        let announce = tracker.announce().unwrap();
+        let announce = tracker.announce().await.unwrap();
         let info_hash = torrent.hash;
         let peer_id = tracker.peer_id;
         let streams = announce.handshake(info_hash, peer_id).await;
@@ -280,7 +336,7 @@ mod test {
         let fs = "pulpfiction.torrent";
         let torrent = Torrent::from_file(fs).unwrap();
         let tracker = TrackerParams::new(&torrent);
-        let announce = tracker.announce().unwrap();
+        let announce = tracker.announce().await.unwrap();
         let info_hash = torrent.hash;
         let peer_id = tracker.peer_id;
         let streams = announce.handshake(info_hash, peer_id).await;
@@ -0,0 +1,500 @@
+// REFERENCE: https://www.bittorrent.org/bittorrentecon.pdf (piece selection)
+
+use std::collections::BTreeMap;
+
+use crypto::tinymt::TinyMT;
+
+use crate::{bitfield::Bitfield, connection::BlockRequest, torrent::Torrent};
+
+// the size of the blocks requested, what every client expects
+pub const BLOCK_SIZE: u32 = 1 << 14;
+// rarest-first is pointless before we have pieces to trade,
+// complete pieces sooner by picking the first ones at random
+pub const RANDOM_FIRST: usize = 4;
+
+/// In which order whole new pieces are started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PickStrategy {
+    /// the pieces fewest peers have, at random among equals,
+    /// after the first few pieces picked at random
+    #[default]
+    RarestFirst,
+    /// lowest index first, to play a file while it downloads
+    Sequential,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Missing,
+    Requested,
+    Received,
+}
+
+/// Decides which blocks to request from whom.
+/// Knows how many peers have each piece, which pieces we have,
+/// and the state of every block of the pieces being downloaded
+#[derive(Debug)]
+pub struct PiecePicker {
+    piece_length: usize,
+    total_length: usize,
+    have: Bitfield,
+    availability: Vec<u32>,
+    // pieces started but not verified, by index
+    partial: BTreeMap<u32, Vec<Block>>,
+    strategy: PickStrategy,
+    random_first: usize,
+    rng: TinyMT,
+}
+
+impl PiecePicker {
+    /// `piece_count` pieces of `piece_length` bytes, the last one holding
+    /// whatever is left of `total_length`
+    pub fn new(piece_count: usize, piece_length: usize, total_length: usize) -> Self {
+        Self {
+            piece_length,
+            total_length,
+            have: Bitfield::new(piece_count),
+            availability: vec![0; piece_count],
+            partial: BTreeMap::new(),
+            strategy: PickStrategy::default(),
+            random_first: RANDOM_FIRST,
+            rng: TinyMT::from_clock(),
+        }
+    }
+
+    pub fn from_torrent(torrent: &Torrent) -> Self {
+        Self::new(
+            torrent.piece_count(),
+            torrent.info.piece_length,
+            torrent.calculate_left(),
+        )
+    }
+
+    pub fn with_strategy(mut self, strategy: PickStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Pick pieces at random until we have `pieces` of them
+    pub fn with_random_first(mut self, pieces: usize) -> Self {
+        self.random_first = pieces;
+        self
+    }
+
+    /// Break ties using `seed`, for reproducible picks
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        self.rng = TinyMT::rand(seed);
+        self
+    }
+
+    pub fn set_strategy(&mut self, strategy: PickStrategy) {
+        self.strategy = strategy;
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.have.len()
+    }
+
+    /// The pieces we have verified
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.is_full()
+    }
+
+    /// How many connected peers have piece `index`
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability[index as usize]
+    }
+
+    /// Length of piece `index`, the last one is usually shorter
+    pub fn piece_len(&self, index: u32) -> u32 {
+        let start = (index as usize * self.piece_length).min(self.total_length);
+        (self.total_length - start).min(self.piece_length) as u32
+    }
+
+    fn block_count(&self, index: u32) -> usize {
+        self.piece_len(index).div_ceil(BLOCK_SIZE) as usize
+    }
+
+    /// A peer told us what it has
+    pub fn peer_bitfield(&mut self, bits: &Bitfield) {
+        for index in bits.ones() {
+            self.availability[index] += 1;
+        }
+    }
+
+    /// A peer got piece `index`
+    pub fn peer_have(&mut self, index: u32) {
+        self.availability[index as usize] += 1;
+    }
+
+    /// A peer with these pieces left
+    pub fn peer_left(&mut self, bits: &Bitfield) {
+        for index in bits.ones() {
+            self.availability[index] = self.availability[index].saturating_sub(1);
+        }
+    }
+
+    /// Up to `max` blocks to request from a peer having `peer` pieces.
+    /// Pieces already started come first, so they finish and can be shared;
+    /// then new pieces, in the order of the strategy.
+    /// The blocks are requested until received or given back
+    pub fn pick(&mut self, peer: &Bitfield, max: usize) -> Vec<BlockRequest> {
+        let mut picked = Vec::new();
+        let wanted = peer.and_not(&self.have);
+        let order = match self.strategy {
+            PickStrategy::Sequential => wanted.ones().map(|i| i as u32).collect(),
+            PickStrategy::RarestFirst => {
+                let mut started: Vec<u32> = wanted
+                    .ones()
+                    .map(|i| i as u32)
+                    .filter(|i| self.partial.contains_key(i))
+                    .collect();
+                // the closest to done first
+                started.sort_by_key(|i| self.missing(*i));
+                started.extend(self.new_pieces(&wanted));
+                started
+            }
+        };
+        for index in order {
+            if picked.len() == max {
+                break;
+            }
+            self.take_blocks(index, max, &mut picked);
+        }
+        picked
+    }
+
+    // pieces not started yet, in the order to start them
+    fn new_pieces(&mut self, wanted: &Bitfield) -> Vec<u32> {
+        let mut pieces: Vec<u32> = wanted
+            .ones()
+            .map(|i| i as u32)
+            .filter(|i| !self.partial.contains_key(i))
+            .collect();
+        // so equally rare pieces come in a random order
+        self.rng.shuffle(&mut pieces);
+        if self.have.count() >= self.random_first {
+            pieces.sort_by_key(|i| self.availability[*i as usize]);
+        }
+        pieces
+    }
+
+    fn missing(&self, index: u32) -> usize {
+        self.partial[&index]
+            .iter()
+            .filter(|b| **b == Block::Missing)
+            .count()
+    }
+
+    fn take_blocks(&mut self, index: u32, max: usize, picked: &mut Vec<BlockRequest>) {
+        let piece_len = self.piece_len(index);
+        let count = self.block_count(index);
+        let blocks = self
+            .partial
+            .entry(index)
+            .or_insert_with(|| vec![Block::Missing; count]);
+        for (i, block) in blocks.iter_mut().enumerate() {
+            if picked.len() == max {
+                break;
+            }
+            if *block == Block::Missing {
+                *block = Block::Requested;
+                let begin = i as u32 * BLOCK_SIZE;
+                picked.push(BlockRequest {
+                    index,
+                    begin,
+                    length: BLOCK_SIZE.min(piece_len - begin),
+                });
+            }
+        }
+        // nothing was taken from a new piece
+        if blocks.iter().all(|b| *b == Block::Missing) {
+            self.partial.remove(&index);
+        }
+    }
+
+    fn block_mut(&mut self, request: &BlockRequest) -> Option<&mut Block> {
+        self.partial
+            .get_mut(&request.index)?
+            .get_mut((request.begin / BLOCK_SIZE) as usize)
+    }
+
+    /// A block arrived. `true` once every block of its piece did,
+    /// and the piece is ready to be verified
+    pub fn received(&mut self, request: &BlockRequest) -> bool {
+        match self.block_mut(request) {
+            Some(block) => *block = Block::Received,
+            None => return false,
+        }
+        self.partial[&request.index]
+            .iter()
+            .all(|b| *b == Block::Received)
+    }
+
+    /// Requests that won't be answered, e.g. the peer choked us or left;
+    /// their blocks can be picked again
+    pub fn abort(&mut self, requests: &[BlockRequest]) {
+        for request in requests {
+            if let Some(block @ Block::Requested) = self.block_mut(request) {
+                *block = Block::Missing;
+            }
+            let untouched = self
+                .partial
+                .get(&request.index)
+                .is_some_and(|blocks| blocks.iter().all(|b| *b == Block::Missing));
+            if untouched {
+                self.partial.remove(&request.index);
+            }
+        }
+    }
+
+    /// Piece `index` matched its hash
+    pub fn piece_verified(&mut self, index: u32) {
+        self.partial.remove(&index);
+        self.have.set(index as usize);
+    }
+
+    /// Piece `index` didn't match its hash, every block is needed again
+    pub fn piece_failed(&mut self, index: u32) {
+        self.partial.remove(&index);
+    }
+
+    /// The blocks received of every piece not verified yet, one bit per block
+    pub fn partial(&self) -> BTreeMap<u32, Bitfield> {
+        self.partial
+            .iter()
+            .filter_map(|(index, blocks)| {
+                let mut bits = Bitfield::new(blocks.len());
+                for (i, _) in blocks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, b)| **b == Block::Received)
+                {
+                    bits.set(i);
+                }
+                (!bits.is_clear()).then_some((*index, bits))
+            })
+            .collect()
+    }
+
+    /// Pieces verified before a restart
+    pub fn restore(&mut self, have: &Bitfield) {
+        let piece_count = self.have.len();
+        for index in have.ones().filter(|i| *i < piece_count) {
+            self.partial.remove(&(index as u32));
+            self.have.set(index);
+        }
+    }
+
+    /// A block received before a restart, of a piece not verified yet.
+    /// `false` if there's no such block, or it would complete its piece:
+    /// only new blocks do
+    pub fn restore_block(&mut self, request: &BlockRequest) -> bool {
+        let index = request.index;
+        let block = (request.begin / BLOCK_SIZE) as usize;
+        if index as usize >= self.have.len()
+            || self.have.get(index as usize)
+            || block >= self.block_count(index)
+        {
+            return false;
+        }
+        let block_count = self.block_count(index);
+        let blocks = self
+            .partial
+            .entry(index)
+            .or_insert_with(|| vec![Block::Missing; block_count]);
+        let missing = blocks.iter().filter(|b| **b != Block::Received).count();
+        if missing == 1 && blocks[block] != Block::Received {
+            return false;
+        }
+        blocks[block] = Block::Received;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PickStrategy, PiecePicker, BLOCK_SIZE};
+    use crate::{bitfield::Bitfield, connection::BlockRequest};
+
+    const PIECE: usize = 2 * BLOCK_SIZE as usize;
+
+    // 8 pieces of two blocks, the last one a single short block
+    fn picker() -> PiecePicker {
+        PiecePicker::new(8, PIECE, 7 * PIECE + 100)
+            .with_random_first(0)
+            .with_seed(1)
+    }
+
+    fn bits(pieces: &[usize]) -> Bitfield {
+        let mut bits = Bitfield::new(8);
+        for i in pieces {
+            bits.set(*i);
+        }
+        bits
+    }
+
+    fn pieces(requests: &[BlockRequest]) -> Vec<u32> {
+        let mut pieces: Vec<u32> = requests.iter().map(|r| r.index).collect();
+        pieces.dedup();
+        pieces
+    }
+
+    #[test]
+    fn blocks_of_pieces() {
+        let mut picker = picker().with_strategy(PickStrategy::Sequential);
+        assert_eq!(picker.piece_len(0), PIECE as u32);
+        assert_eq!(picker.piece_len(7), 100);
+        let picked = picker.pick(&bits(&[6, 7]), 10);
+        let block = |index, begin, length| BlockRequest {
+            index,
+            begin,
+            length,
+        };
+        assert_eq!(
+            picked,
+            [
+                block(6, 0, BLOCK_SIZE),
+                block(6, BLOCK_SIZE, BLOCK_SIZE),
+                block(7, 0, 100)
+            ]
+        );
+        // everything is requested already
+        assert!(picker.pick(&bits(&[6, 7]), 10).is_empty());
+    }
+
+    #[test]
+    fn more_hashes_than_bytes() {
+        let picker = PiecePicker::new(10, PIECE, 7 * PIECE + 100);
+        assert_eq!(picker.piece_len(7), 100);
+        assert_eq!(picker.piece_len(8), 0);
+        assert_eq!(picker.piece_len(9), 0);
+    }
+
+    #[test]
+    fn rarest_first() {
+        let mut picker = picker();
+        picker.peer_bitfield(&Bitfield::full(8));
+        picker.peer_bitfield(&bits(&[0, 1, 2, 3, 5]));
+        picker.peer_bitfield(&bits(&[0, 1, 2, 4, 5]));
+        picker.peer_have(3);
+        assert_eq!(picker.availability(3), 3);
+        let picked = picker.pick(&Bitfield::full(8), 5);
+        let mut started = pieces(&picked[..3]);
+        started.sort();
+        // 6 and 7 are the rarest, then 4 which one peer fewer has than the rest
+        assert_eq!(started, [6, 7]);
+        assert_eq!(pieces(&picked[3..]), [4]);
+
+        picker.peer_left(&Bitfield::full(8));
+        assert_eq!(picker.availability(3), 2);
+        assert_eq!(picker.availability(6), 0);
+    }
+
+    #[test]
+    fn random_first() {
+        // nobody has much, so rarest-first alone would always start piece 0
+        let first_piece = |seed| {
+            let mut picker = PiecePicker::new(8, PIECE, 8 * PIECE)
+                .with_random_first(4)
+                .with_seed(seed);
+            picker.peer_bitfield(&bits(&[0]));
+            picker.peer_bitfield(&Bitfield::full(8));
+            picker.pick(&Bitfield::full(8), 1)[0].index
+        };
+        let mut firsts: Vec<u32> = (0..16).map(first_piece).collect();
+        firsts.sort();
+        firsts.dedup();
+        assert!(firsts.len() > 1);
+    }
+
+    #[test]
+    fn partial_pieces_first() {
+        let mut picker = picker();
+        picker.peer_bitfield(&Bitfield::full(8));
+        picker.peer_bitfield(&bits(&[0, 1, 3, 4, 5, 6, 7]));
+        // the rarest piece is started, but only one of its blocks asked for
+        let first = picker.pick(&Bitfield::full(8), 1);
+        assert_eq!((first[0].index, first[0].begin), (2, 0));
+        // no longer the rarest, but it's finished before starting anything else
+        picker.peer_have(2);
+        let next = picker.pick(&Bitfield::full(8), 1);
+        assert_eq!((next[0].index, next[0].begin), (2, BLOCK_SIZE));
+    }
+
+    #[test]
+    fn sequential() {
+        let mut picker = picker().with_strategy(PickStrategy::Sequential);
+        picker.peer_bitfield(&bits(&[5]));
+        picker.piece_verified(0);
+        let picked = picker.pick(&bits(&[1, 3, 5]), 5);
+        assert_eq!(pieces(&picked), [1, 3, 5]);
+    }
+
+    #[test]
+    fn block_lifecycle() {
+        let mut picker = picker().with_strategy(PickStrategy::Sequential);
+        let peer = bits(&[1, 2]);
+        let picked = picker.pick(&peer, 2);
+        assert_eq!(pieces(&picked), [1]);
+
+        // choked: the blocks can be picked again
+        picker.abort(&picked[1..]);
+        assert_eq!(picker.pick(&peer, 1), picked[1..]);
+
+        assert!(!picker.received(&picked[0]));
+        assert!(picker.received(&picked[1]));
+        // the hash didn't match
+        picker.piece_failed(1);
+        assert_eq!(picker.pick(&peer, 2), picked);
+        picker.received(&picked[0]);
+        picker.received(&picked[1]);
+        picker.piece_verified(1);
+        assert!(picker.have().get(1));
+        assert_eq!(pieces(&picker.pick(&peer, 2)), [2]);
+        // blocks nobody asked for change nothing
+        assert!(!picker.received(&BlockRequest {
+            index: 4,
+            begin: 0,
+            length: BLOCK_SIZE
+        }));
+    }
+
+    #[test]
+    fn complete() {
+        let mut picker = PiecePicker::new(2, PIECE, 2 * PIECE);
+        picker.piece_verified(0);
+        assert!(!picker.is_complete());
+        picker.piece_verified(1);
+        assert!(picker.is_complete());
+        assert!(picker.pick(&Bitfield::full(2), 4).is_empty());
+    }
+
+    #[test]
+    fn restore_after_restart() {
+        let block = |index, begin| BlockRequest {
+            index,
+            begin,
+            length: BLOCK_SIZE,
+        };
+        let mut picker = picker().with_strategy(PickStrategy::Sequential);
+        picker.restore(&bits(&[0, 2]));
+        assert!(picker.restore_block(&block(1, BLOCK_SIZE)));
+        // verified, past the end, or the last block missing
+        assert!(!picker.restore_block(&block(2, 0)));
+        assert!(!picker.restore_block(&block(7, BLOCK_SIZE)));
+        assert!(!picker.restore_block(&block(1, 0)));
+
+        assert_eq!(picker.have(), &bits(&[0, 2]));
+        let partial = picker.partial();
+        assert_eq!(partial.keys().collect::<Vec<_>>(), [&1]);
+        assert_eq!(partial[&1].ones().collect::<Vec<_>>(), [1]);
+        // only what's missing is asked for
+        assert_eq!(picker.pick(&Bitfield::full(8), 1), [block(1, 0)]);
+        assert!(picker.received(&block(1, 0)));
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bencode::bencode::{decode, BTypes};
+use bencode::benencode::ser;
+
+use crate::{
+    bitfield::Bitfield,
+    error::ResumeError,
+    picker::BLOCK_SIZE,
+    storage::{FileLayout, FileStamp, Storage},
+    verify::PieceVerifier,
+};
+
+/// What a tracker told us that's worth remembering across restarts
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackerState {
+    pub tracker_id: Option<Vec<u8>>,
+    /// time between announces, as last asked by the tracker
+    pub interval: Option<Duration>,
+}
+
+/// How far a download got, kept next to it so a restart picks up
+/// where it left off instead of hashing every piece again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    /// the pieces verified
+    pub have: Bitfield,
+    /// the blocks on disk of the pieces not verified yet, one bit per block
+    pub partial: BTreeMap<u32, Bitfield>,
+    /// the files as they were when saved, by index into `FileLayout::files`
+    pub files: Vec<Option<FileStamp>>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// peers worth trying again
+    pub peers: Vec<SocketAddr>,
+    pub tracker: TrackerState,
+}
+
+impl ResumeData {
+    /// Nothing downloaded yet of the torrent laid out by `layout`
+    pub fn new(info_hash: [u8; 20], layout: &FileLayout) -> Self {
+        Self {
+            info_hash,
+            have: Bitfield::new(layout.piece_count() as usize),
+            partial: BTreeMap::new(),
+            files: vec![None; layout.files().len()],
+            uploaded: 0,
+            downloaded: 0,
+            peers: Vec::new(),
+            tracker: TrackerState::default(),
+        }
+    }
+
+    /// The files of `storage` are as they were when this was saved,
+    /// so `have` and `partial` can be trusted without a recheck
+    pub fn matches(&self, storage: &impl Storage) -> bool {
+        self.files.len() == storage.layout().files().len()
+            && self
+                .files
+                .iter()
+                .enumerate()
+                .all(|(file, stamp)| stamp.is_some() && *stamp == storage.stamp(file))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut d = BTreeMap::new();
+        d.insert("info-hash".to_owned(), bytes(&self.info_hash));
+        d.insert("pieces".to_owned(), bytes(self.have.as_bytes()));
+        let partial = self
+            .partial
+            .iter()
+            .map(|(index, blocks)| (index.to_string(), bytes(blocks.as_bytes())))
+            .collect();
+        d.insert("partial".to_owned(), BTypes::DICT(partial));
+        let files = self
+            .files
+            .iter()
+            .map(|stamp| {
+                let mut file = BTreeMap::new();
+                // a file that didn't exist is an empty dict
+                if let Some(stamp) = stamp {
+                    file.insert("length".to_owned(), int(stamp.length));
+                    file.insert("modified".to_owned(), int(stamp.modified));
+                }
+                BTypes::DICT(file)
+            })
+            .collect();
+        d.insert("files".to_owned(), BTypes::LIST(files));
+        d.insert("uploaded".to_owned(), int(self.uploaded));
+        d.insert("downloaded".to_owned(), int(self.downloaded));
+        let peers = self
+            .peers
+            .iter()
+            .map(|peer| bytes(peer.to_string().as_bytes()))
+            .collect();
+        d.insert("peers".to_owned(), BTypes::LIST(peers));
+        if let Some(id) = &self.tracker.tracker_id {
+            d.insert("tracker id".to_owned(), bytes(id));
+        }
+        if let Some(interval) = self.tracker.interval {
+            d.insert("interval".to_owned(), int(interval.as_secs()));
+        }
+        ser(&BTypes::DICT(d))
+    }
+
+    /// Read back what `encode` wrote for the torrent laid out by `layout`
+    pub fn decode(data: &[u8], layout: &FileLayout) -> Result<Self, ResumeError> {
+        let Ok(BTypes::DICT(d)) = decode(&mut data.iter().copied()) else {
+            return Err(ResumeError::Invalid("not a bencoded dict"));
+        };
+        let info_hash = as_bytes(get(&d, "info-hash")?)?
+            .try_into()
+            .map_err(|_| ResumeError::Invalid("info hash isn't 20 bytes"))?;
+        let have =
+            Bitfield::from_bytes(as_bytes(get(&d, "pieces")?)?, layout.piece_count() as usize)
+                .map_err(|_| ResumeError::Invalid("pieces don't fit the torrent"))?;
+
+        let BTypes::DICT(partial) = get(&d, "partial")? else {
+            return Err(ResumeError::Invalid("partial isn't a dict"));
+        };
+        let partial = partial
+            .iter()
+            .map(|(index, blocks)| {
+                let index: u32 = index
+                    .parse()
+                    .ok()
+                    .filter(|i| *i < layout.piece_count())
+                    .ok_or(ResumeError::Invalid("partial piece out of range"))?;
+                let block_count = layout.piece_len(index).div_ceil(BLOCK_SIZE) as usize;
+                let blocks = Bitfield::from_bytes(as_bytes(blocks)?, block_count)
+                    .map_err(|_| ResumeError::Invalid("blocks don't fit their piece"))?;
+                Ok((index, blocks))
+            })
+            .collect::<Result<_, ResumeError>>()?;
+
+        let BTypes::LIST(files) = get(&d, "files")? else {
+            return Err(ResumeError::Invalid("files isn't a list"));
+        };
+        let files = files
+            .iter()
+            .map(|file| match file {
+                BTypes::DICT(file) if file.is_empty() => Ok(None),
+                BTypes::DICT(file) => Ok(Some(FileStamp {
+                    length: as_int(get(file, "length")?)?,
+                    modified: as_int(get(file, "modified")?)?,
+                })),
+                _ => Err(ResumeError::Invalid("file isn't a dict")),
+            })
+            .collect::<Result<_, _>>()?;
+
+        let BTypes::LIST(peers) = get(&d, "peers")? else {
+            return Err(ResumeError::Invalid("peers isn't a list"));
+        };
+        // a peer we can't read is one less to try
+        let peers = peers
+            .iter()
+            .filter_map(|peer| std::str::from_utf8(as_bytes(peer).ok()?).ok()?.parse().ok())
+            .collect();
+
+        let tracker = TrackerState {
+            tracker_id: d
+                .get("tracker id")
+                .map(as_bytes)
+                .transpose()?
+                .map(<[u8]>::to_vec),
+            interval: d
+                .get("interval")
+                .map(as_int)
+                .transpose()?
+                .map(Duration::from_secs),
+        };
+        Ok(Self {
+            info_hash,
+            have,
+            partial,
+            files,
+            uploaded: as_int(get(&d, "uploaded")?)?,
+            downloaded: as_int(get(&d, "downloaded")?)?,
+            peers,
+            tracker,
+        })
+    }
+
+    /// Write to `path`, through a temporary file so a crash
+    /// never leaves half of it behind
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("resume.tmp");
+        fs::write(&tmp, self.encode())?;
+        fs::rename(tmp, path)
+    }
+
+    /// What was saved at `path`, `None` if nothing was
+    pub fn load(path: &Path, layout: &FileLayout) -> Result<Option<Self>, ResumeError> {
+        match fs::read(path) {
+            Ok(data) => Self::decode(&data, layout).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Where the resume data of the torrent downloaded under `root` is kept:
+/// beside its file or directory, named after it
+pub fn resume_path(root: &Path, layout: &FileLayout) -> PathBuf {
+    let mut name = layout.name().as_os_str().to_owned();
+    name.push(".resume");
+    root.join(name)
+}
+
+/// The pieces in `storage` that match their hash, reading every one there is
+pub fn recheck(storage: &mut impl Storage, verifier: &PieceVerifier) -> Bitfield {
+    let mut have = Bitfield::new(storage.layout().piece_count() as usize);
+    for index in 0..have.len() as u32 {
+        if !storage.has_piece(index) {
+            continue;
+        }
+        if let Ok(data) = storage.read_piece(index) {
+            if verifier.check(index, &data) {
+                have.set(index as usize);
+            }
+        }
+    }
+    have
+}
+
+fn int(i: u64) -> BTypes {
+    BTypes::INT(i as i64)
+}
+
+fn bytes(b: &[u8]) -> BTypes {
+    BTypes::BSTRING(b.to_vec())
+}
+
+fn get<'a>(d: &'a BTreeMap<String, BTypes>, key: &'static str) -> Result<&'a BTypes, ResumeError> {
+    d.get(key).ok_or(ResumeError::Invalid(key))
+}
+
+fn as_int(value: &BTypes) -> Result<u64, ResumeError> {
+    match value {
+        BTypes::INT(i) if *i >= 0 => Ok(*i as u64),
+        _ => Err(ResumeError::Invalid("expected a positive integer")),
+    }
+}
+
+fn as_bytes(value: &BTypes) -> Result<&[u8], ResumeError> {
+    match value {
+        BTypes::BSTRING(s) => Ok(s),
+        _ => Err(ResumeError::Invalid("expected a string")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, time::Duration};
+
+    use super::{recheck, resume_path, ResumeData, TrackerState};
+    use crate::{
+        bitfield::Bitfield,
+        error::ResumeError,
+        storage::{
+            test::{multi_info, scratch_dir},
+            FileLayout, FileStamp, FileStorage, Storage,
+        },
+        verify::{test::hashes, PieceVerifier},
+    };
+
+    // 3 pieces of 8 bytes, in two files
+    fn layout() -> FileLayout {
+        FileLayout::new(&multi_info(&[(&["a"], 10), (&["b"], 10)], 8)).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let layout = layout();
+        let mut data = ResumeData::new([7; 20], &layout);
+        data.have.set(2);
+        data.partial.insert(0, Bitfield::full(1));
+        data.files[1] = Some(FileStamp {
+            length: 10,
+            modified: 1_700_000_000_123_456_789,
+        });
+        data.uploaded = 5;
+        data.downloaded = 8;
+        data.peers = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[::1]:51413".parse().unwrap(),
+        ];
+        data.tracker = TrackerState {
+            tracker_id: Some(b"abc".to_vec()),
+            interval: Some(Duration::from_secs(1800)),
+        };
+        assert_eq!(ResumeData::decode(&data.encode(), &layout).unwrap(), data);
+
+        let empty = ResumeData::new([0; 20], &layout);
+        assert_eq!(ResumeData::decode(&empty.encode(), &layout).unwrap(), empty);
+    }
+
+    #[test]
+    fn refuses_what_doesnt_fit() {
+        let mut data = ResumeData::new([7; 20], &layout());
+        data.partial.insert(3, Bitfield::new(1));
+        let err = ResumeData::decode(&data.encode(), &layout()).unwrap_err();
+        assert!(matches!(err, ResumeError::Invalid(_)));
+        // saved for another torrent
+        let other = FileLayout::new(&multi_info(&[(&["a"], 100)], 8)).unwrap();
+        let data = ResumeData::new([7; 20], &other);
+        assert!(ResumeData::decode(&data.encode(), &layout()).is_err());
+        assert!(ResumeData::decode(b"i3e", &layout()).is_err());
+    }
+
+    #[test]
+    fn saved_next_to_the_download() {
+        let dir = scratch_dir("resume");
+        let layout = layout();
+        let path = resume_path(&dir, &layout);
+        assert_eq!(path, dir.join("album.resume"));
+        assert_eq!(ResumeData::load(&path, &layout).unwrap(), None);
+
+        let mut storage = FileStorage::new(&dir, layout.clone());
+        let content: Vec<u8> = (0..20).collect();
+        storage.write(0, &content).unwrap();
+        storage.flush().unwrap();
+        let mut data = ResumeData::new([1; 20], &layout);
+        data.files = (0..2).map(|file| storage.stamp(file)).collect();
+        data.save(&path).unwrap();
+        let loaded = ResumeData::load(&path, &layout).unwrap().unwrap();
+        assert!(loaded.matches(&storage));
+
+        // touched since
+        let file = fs::File::options()
+            .write(true)
+            .open(dir.join("album/b"))
+            .unwrap();
+        file.set_len(11).unwrap();
+        assert!(!loaded.matches(&storage));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recheck_hashes_what_is_there() {
+        let dir = scratch_dir("recheck");
+        let mut storage = FileStorage::new(&dir, layout());
+        let content: Vec<u8> = (0..20).collect();
+        let verifier = PieceVerifier::new(hashes(&content, 8));
+        storage.write(0, &content[..16]).unwrap();
+        storage.write(8, &[0]).unwrap();
+        let have = recheck(&mut storage, &verifier);
+        assert_eq!(have.ones().collect::<Vec<_>>(), [0]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,483 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::{
+    bitfield::Bitfield,
+    connection::{BlockRequest, PeerCommand},
+    picker::{PiecePicker, BLOCK_SIZE},
+    torrent::Torrent,
+};
+
+// a request unanswered for this long goes to someone else
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+// keep enough requests in flight to cover this much of a peer's transfer,
+// so the pipe never runs dry while the next request travels
+pub const QUEUE_TIME: Duration = Duration::from_secs(3);
+pub const MIN_QUEUE_DEPTH: usize = 2;
+pub const MAX_QUEUE_DEPTH: usize = 250;
+// how long a peer's rate is measured before the depth is adapted
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Every block of a piece, ready to be verified
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompletedPiece {
+    pub index: u32,
+    pub data: Vec<u8>,
+    /// the peers that sent blocks of it
+    pub peers: Vec<usize>,
+}
+
+#[derive(Debug)]
+struct PeerState {
+    bitfield: Bitfield,
+    choked: bool,
+    // our requests, with when they were sent
+    outstanding: HashMap<BlockRequest, Instant>,
+    depth: usize,
+    // bytes per second, averaged over the last windows
+    rate: f64,
+    window_start: Instant,
+    window_bytes: usize,
+}
+
+#[derive(Debug)]
+struct PieceBuffer {
+    data: Vec<u8>,
+    peers: Vec<usize>,
+}
+
+/// Keeps every unchoked peer's request pipeline full with blocks from the picker,
+/// adapts each pipeline to the peer's rate, hands timed-out requests to others,
+/// and assembles blocks into pieces.
+///
+/// Doesn't do any IO: the controller reports what the peers said,
+/// and sends the commands `schedule` returns
+#[derive(Debug)]
+pub struct Scheduler {
+    picker: PiecePicker,
+    peers: BTreeMap<usize, PeerState>,
+    pieces: HashMap<u32, PieceBuffer>,
+    request_timeout: Duration,
+}
+
+impl Scheduler {
+    pub fn new(picker: PiecePicker) -> Self {
+        Self {
+            picker,
+            peers: BTreeMap::new(),
+            pieces: HashMap::new(),
+            request_timeout: REQUEST_TIMEOUT,
+        }
+    }
+
+    pub fn from_torrent(torrent: &Torrent) -> Self {
+        Self::new(PiecePicker::from_torrent(torrent))
+    }
+
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    pub fn picker(&self) -> &PiecePicker {
+        &self.picker
+    }
+
+    pub fn picker_mut(&mut self) -> &mut PiecePicker {
+        &mut self.picker
+    }
+
+    /// How many requests peer `id` may have in flight
+    pub fn queue_depth(&self, id: usize) -> Option<usize> {
+        self.peers.get(&id).map(|p| p.depth)
+    }
+
+    /// Our requests peer `id` hasn't answered yet
+    pub fn outstanding(&self, id: usize) -> usize {
+        self.peers.get(&id).map_or(0, |p| p.outstanding.len())
+    }
+
+    /// A peer connected; it chokes us and has nothing until it says otherwise
+    pub fn add_peer(&mut self, id: usize, now: Instant) {
+        self.peers.insert(
+            id,
+            PeerState {
+                bitfield: Bitfield::new(self.picker.piece_count()),
+                choked: true,
+                outstanding: HashMap::new(),
+                depth: MIN_QUEUE_DEPTH,
+                rate: 0.0,
+                window_start: now,
+                window_bytes: 0,
+            },
+        );
+    }
+
+    /// A peer is gone, what it was asked for goes to others
+    pub fn remove_peer(&mut self, id: usize) {
+        if let Some(peer) = self.peers.remove(&id) {
+            let requests: Vec<_> = peer.outstanding.into_keys().collect();
+            self.picker.abort(&requests);
+            self.picker.peer_left(&peer.bitfield);
+        }
+    }
+
+    pub fn bitfield(&mut self, id: usize, bits: Bitfield) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            self.picker.peer_left(&peer.bitfield);
+            self.picker.peer_bitfield(&bits);
+            peer.bitfield = bits;
+        }
+    }
+
+    pub fn have(&mut self, id: usize, index: u32) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            if !peer.bitfield.get(index as usize) {
+                peer.bitfield.set(index as usize);
+                self.picker.peer_have(index);
+            }
+        }
+    }
+
+    /// The pieces of peer `id`
+    pub fn peer_bitfield(&self, id: usize) -> Option<&Bitfield> {
+        self.peers.get(&id).map(|p| &p.bitfield)
+    }
+
+    pub fn unchoked(&mut self, id: usize) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.choked = false;
+        }
+    }
+
+    /// Peer `id` choked us, `dropped` are the requests it won't answer
+    pub fn choked(&mut self, id: usize, dropped: &[BlockRequest]) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.choked = true;
+        }
+        self.dropped(id, dropped);
+    }
+
+    /// Requests of peer `id` that won't be answered
+    pub fn dropped(&mut self, id: usize, dropped: &[BlockRequest]) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            for request in dropped {
+                peer.outstanding.remove(request);
+            }
+        }
+        self.picker.abort(dropped);
+    }
+
+    /// Peer `id` sent a block. Once it's the last block of its piece,
+    /// the whole piece is returned, ready to be verified
+    pub fn block(
+        &mut self,
+        id: usize,
+        index: u32,
+        begin: u32,
+        data: Vec<u8>,
+    ) -> Option<CompletedPiece> {
+        let request = BlockRequest {
+            index,
+            begin,
+            length: data.len() as u32,
+        };
+        let peer = self.peers.get_mut(&id)?;
+        peer.outstanding.remove(&request)?;
+        peer.window_bytes += data.len();
+
+        let piece_len = self.picker.piece_len(index) as usize;
+        let piece = self.pieces.entry(index).or_insert_with(|| PieceBuffer {
+            data: vec![0; piece_len],
+            peers: Vec::new(),
+        });
+        let begin = begin as usize;
+        piece.data[begin..begin + data.len()].copy_from_slice(&data);
+        if !piece.peers.contains(&id) {
+            piece.peers.push(id);
+        }
+        if !self.picker.received(&request) {
+            return None;
+        }
+        let piece = self.pieces.remove(&index)?;
+        Some(CompletedPiece {
+            index,
+            data: piece.data,
+            peers: piece.peers,
+        })
+    }
+
+    /// A block received before a restart, read back from storage
+    pub fn restore_block(&mut self, index: u32, begin: u32, data: Vec<u8>) {
+        let request = BlockRequest {
+            index,
+            begin,
+            length: data.len() as u32,
+        };
+        let piece_len = self.picker.piece_len(index) as usize;
+        if begin as usize + data.len() > piece_len || !self.picker.restore_block(&request) {
+            return;
+        }
+        let piece = self.pieces.entry(index).or_insert_with(|| PieceBuffer {
+            data: vec![0; piece_len],
+            peers: Vec::new(),
+        });
+        let begin = begin as usize;
+        piece.data[begin..begin + data.len()].copy_from_slice(&data);
+    }
+
+    /// Every block received of the pieces not complete yet, as (index, begin, data)
+    pub fn received_blocks(&self) -> Vec<(u32, u32, &[u8])> {
+        let mut blocks = Vec::new();
+        for (index, bits) in self.picker.partial() {
+            let Some(piece) = self.pieces.get(&index) else {
+                continue;
+            };
+            for block in bits.ones() {
+                let begin = block * BLOCK_SIZE as usize;
+                let end = (begin + BLOCK_SIZE as usize).min(piece.data.len());
+                blocks.push((index, begin as u32, &piece.data[begin..end]));
+            }
+        }
+        blocks
+    }
+
+    /// What to send to whom: cancels for requests that timed out,
+    /// and requests to fill every unchoked peer's pipeline
+    pub fn schedule(&mut self, now: Instant) -> Vec<(usize, PeerCommand)> {
+        let mut commands = Vec::new();
+        for (id, peer) in &mut self.peers {
+            let timed_out: Vec<BlockRequest> = peer
+                .outstanding
+                .iter()
+                .filter(|(_, sent)| now.duration_since(**sent) >= self.request_timeout)
+                .map(|(request, _)| *request)
+                .collect();
+            if !timed_out.is_empty() {
+                // a slow peer gets fewer requests
+                peer.depth = (peer.depth / 2).max(MIN_QUEUE_DEPTH);
+            }
+            for request in timed_out {
+                peer.outstanding.remove(&request);
+                self.picker.abort(&[request]);
+                commands.push((*id, PeerCommand::Cancel(request)));
+            }
+            peer.adapt_depth(now);
+        }
+
+        for (id, peer) in &mut self.peers {
+            if peer.choked || peer.outstanding.len() >= peer.depth {
+                continue;
+            }
+            let free = peer.depth - peer.outstanding.len();
+            for request in self.picker.pick(&peer.bitfield, free) {
+                peer.outstanding.insert(request, now);
+                commands.push((*id, PeerCommand::Request(request)));
+            }
+        }
+        commands
+    }
+}
+
+impl PeerState {
+    fn adapt_depth(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+        self.rate = (self.rate + rate) / 2.0;
+        self.window_start = now;
+        self.window_bytes = 0;
+        let depth = self.rate * QUEUE_TIME.as_secs_f64() / BLOCK_SIZE as f64;
+        self.depth = (depth as usize).clamp(MIN_QUEUE_DEPTH, MAX_QUEUE_DEPTH);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{CompletedPiece, Scheduler, MIN_QUEUE_DEPTH};
+    use crate::{
+        bitfield::Bitfield,
+        connection::{BlockRequest, PeerCommand},
+        picker::{PickStrategy, PiecePicker, BLOCK_SIZE},
+    };
+
+    const PIECE: usize = 2 * BLOCK_SIZE as usize;
+    // three pieces of two blocks, the last block of the last piece short
+    const TOTAL: usize = 2 * PIECE + BLOCK_SIZE as usize + 10;
+
+    fn scheduler() -> Scheduler {
+        let picker = PiecePicker::new(3, PIECE, TOTAL).with_strategy(PickStrategy::Sequential);
+        Scheduler::new(picker).with_request_timeout(Duration::from_secs(5))
+    }
+
+    fn requests(commands: &[(usize, PeerCommand)]) -> Vec<(usize, BlockRequest)> {
+        commands
+            .iter()
+            .filter_map(|(id, c)| match c {
+                PeerCommand::Request(r) => Some((*id, *r)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // answer a request with bytes telling where they come from
+    fn answer(scheduler: &mut Scheduler, id: usize, r: BlockRequest) -> Option<CompletedPiece> {
+        let data = vec![(r.index * 2 + r.begin / BLOCK_SIZE) as u8; r.length as usize];
+        scheduler.block(id, r.index, r.begin, data)
+    }
+
+    #[test]
+    fn pipelines_and_assembles() {
+        let now = Instant::now();
+        let mut scheduler = scheduler();
+        scheduler.add_peer(1, now);
+        scheduler.bitfield(1, Bitfield::full(3));
+        // choked: nothing to send
+        assert!(scheduler.schedule(now).is_empty());
+
+        scheduler.unchoked(1);
+        let sent = requests(&scheduler.schedule(now));
+        assert_eq!(sent.len(), MIN_QUEUE_DEPTH);
+        assert_eq!(scheduler.outstanding(1), MIN_QUEUE_DEPTH);
+        // the pipe is full
+        assert!(scheduler.schedule(now).is_empty());
+
+        assert_eq!(answer(&mut scheduler, 1, sent[0].1), None);
+        let piece = answer(&mut scheduler, 1, sent[1].1).unwrap();
+        assert_eq!(piece.index, 0);
+        assert_eq!(piece.peers, [1]);
+        assert_eq!(piece.data.len(), PIECE);
+        assert_eq!(piece.data[0], 0);
+        assert_eq!(piece.data[PIECE - 1], 1);
+
+        // the last piece: a whole block and a short one
+        scheduler.picker_mut().piece_verified(0);
+        let mut last = None;
+        for _ in 0..3 {
+            for (_, r) in requests(&scheduler.schedule(now)) {
+                if let Some(piece) = answer(&mut scheduler, 1, r) {
+                    scheduler.picker_mut().piece_verified(piece.index);
+                    last = Some(piece);
+                }
+            }
+        }
+        let last = last.unwrap();
+        assert_eq!(last.index, 2);
+        assert_eq!(last.data.len(), BLOCK_SIZE as usize + 10);
+        assert_eq!(last.data[BLOCK_SIZE as usize], 5);
+        assert!(scheduler.picker().is_complete());
+    }
+
+    #[test]
+    fn unrequested_blocks_are_ignored() {
+        let now = Instant::now();
+        let mut scheduler = scheduler();
+        scheduler.add_peer(1, now);
+        scheduler.bitfield(1, Bitfield::full(3));
+        scheduler.unchoked(1);
+        let sent = requests(&scheduler.schedule(now));
+        // from someone else, or of another length
+        assert_eq!(answer(&mut scheduler, 2, sent[0].1), None);
+        assert_eq!(scheduler.block(1, 0, 0, vec![0; 10]), None);
+        assert_eq!(scheduler.outstanding(1), 2);
+    }
+
+    #[test]
+    fn restored_blocks_complete_pieces() {
+        let now = Instant::now();
+        let mut scheduler = scheduler();
+        scheduler.restore_block(0, BLOCK_SIZE, vec![1; BLOCK_SIZE as usize]);
+        // not a block
+        scheduler.restore_block(2, BLOCK_SIZE, vec![5; BLOCK_SIZE as usize]);
+        assert_eq!(
+            scheduler.received_blocks(),
+            [(0, BLOCK_SIZE, &[1; BLOCK_SIZE as usize][..])]
+        );
+
+        scheduler.add_peer(1, now);
+        scheduler.bitfield(1, Bitfield::full(3));
+        scheduler.unchoked(1);
+        let sent = requests(&scheduler.schedule(now));
+        assert_eq!((sent[0].1.index, sent[0].1.begin), (0, 0));
+        let piece = answer(&mut scheduler, 1, sent[0].1).unwrap();
+        assert_eq!(piece.data[..PIECE / 2], [0; PIECE / 2]);
+        assert_eq!(piece.data[PIECE / 2..], [1; PIECE / 2]);
+        assert!(scheduler.received_blocks().is_empty());
+    }
+
+    #[test]
+    fn choke_and_timeout_reassign() {
+        let now = Instant::now();
+        let mut scheduler = scheduler();
+        for id in [1, 2] {
+            scheduler.add_peer(id, now);
+            scheduler.bitfield(id, Bitfield::full(3));
+        }
+        scheduler.unchoked(1);
+        let first = requests(&scheduler.schedule(now));
+        assert!(first.iter().all(|(id, _)| *id == 1));
+
+        // peer 1 chokes us, peer 2 gets its requests
+        let dropped: Vec<_> = first.iter().map(|(_, r)| *r).collect();
+        scheduler.choked(1, &dropped);
+        scheduler.unchoked(2);
+        let second = requests(&scheduler.schedule(now));
+        assert_eq!(second.iter().map(|(_, r)| *r).collect::<Vec<_>>(), dropped);
+        assert!(second.iter().all(|(id, _)| *id == 2));
+
+        // peer 2 never answers: cancelled and given to peer 1
+        scheduler.unchoked(1);
+        let later = now + Duration::from_secs(5);
+        let commands = scheduler.schedule(later);
+        let cancels: Vec<_> = commands
+            .iter()
+            .filter(|(_, c)| matches!(c, PeerCommand::Cancel(_)))
+            .collect();
+        assert_eq!(cancels.len(), 2);
+        assert!(cancels.iter().all(|(id, _)| *id == 2));
+        let third = requests(&commands);
+        assert!(third.iter().any(|(id, r)| *id == 1 && *r == dropped[0]));
+
+        // peer 1 leaves, its blocks go to peer 2 once it has room
+        scheduler.remove_peer(1);
+        for (_, r) in third.iter().filter(|(id, _)| *id == 2) {
+            answer(&mut scheduler, 2, *r);
+        }
+        let fourth = requests(&scheduler.schedule(later));
+        assert!(fourth.iter().any(|(id, r)| *id == 2 && *r == dropped[0]));
+    }
+
+    #[test]
+    fn depth_follows_rate() {
+        let picker = PiecePicker::new(1000, PIECE, 1000 * PIECE);
+        let mut scheduler = Scheduler::new(picker);
+        let mut now = Instant::now();
+        scheduler.add_peer(1, now);
+        scheduler.bitfield(1, Bitfield::full(1000));
+        scheduler.unchoked(1);
+        // a fast peer answers everything, every 100ms
+        for _ in 0..50 {
+            for (_, r) in requests(&scheduler.schedule(now)) {
+                answer(&mut scheduler, 1, r);
+            }
+            now += Duration::from_millis(100);
+        }
+        let fast = scheduler.queue_depth(1).unwrap();
+        assert!(fast > 20, "{fast}");
+
+        // then stops answering
+        for _ in 0..5 {
+            scheduler.schedule(now);
+            now += Duration::from_secs(1);
+        }
+        assert!(scheduler.queue_depth(1).unwrap() < fast);
+    }
+}
@@ -0,0 +1,666 @@
+use std::ffi::OsStr;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::{
+    error::StorageError,
+    torrent::{FileMode, Info, Torrent},
+};
+
+/// A file of the torrent: where it lies among the torrent's bytes, and on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// relative to the download directory, starting with the torrent's name
+    pub path: PathBuf,
+    /// where the file starts in the torrent
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// The part of a file some bytes of the torrent fall on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    /// index into `FileLayout::files`
+    pub file: usize,
+    /// where the bytes start in the file
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Length and last modification of a file on disk,
+/// to tell whether it changed while we weren't looking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub length: u64,
+    /// nanoseconds since the Unix epoch
+    pub modified: u64,
+}
+
+impl FileStamp {
+    /// `None` if there's no such file
+    pub fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            length: metadata.len(),
+            modified: modified.as_nanos() as u64,
+        })
+    }
+}
+
+/// The torrent's files laid end to end, as pieces see them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileLayout {
+    name: PathBuf,
+    files: Vec<FileEntry>,
+    piece_length: u64,
+    total_length: u64,
+}
+
+impl FileLayout {
+    /// Refuses torrents with paths that would leave the download directory:
+    /// every component must be a plain name, no `..`, no root, no separators
+    pub fn new(info: &Info) -> Result<Self, StorageError> {
+        let name = PathBuf::from(safe_component(&info.name)?);
+        let files: Vec<(PathBuf, u64)> = match &info.mode {
+            FileMode::SingleMode { length } => vec![(name.clone(), *length as u64)],
+            FileMode::MultiMode { files } => files
+                .iter()
+                .map(|f| {
+                    if f.path.is_empty() {
+                        return Err(StorageError::UnsafePath(String::new()));
+                    }
+                    let mut path = name.clone();
+                    for component in &f.path {
+                        path.push(safe_component(component)?);
+                    }
+                    Ok((path, f.length as u64))
+                })
+                .collect::<Result<_, _>>()?,
+        };
+
+        let mut offset = 0;
+        let files = files
+            .into_iter()
+            .map(|(path, length)| {
+                let entry = FileEntry {
+                    path,
+                    offset,
+                    length,
+                };
+                offset += length;
+                entry
+            })
+            .collect();
+        Ok(Self {
+            name,
+            files,
+            piece_length: info.piece_length as u64,
+            total_length: offset,
+        })
+    }
+
+    /// Also refuses torrents whose hashes don't match the length of their files
+    pub fn from_torrent(torrent: &Torrent) -> Result<Self, StorageError> {
+        let layout = Self::new(&torrent.info)?;
+        let len = torrent.info.pieces.len();
+        if len != layout.piece_count() as usize * 20 {
+            return Err(StorageError::PieceHashes {
+                len,
+                pieces: layout.piece_count(),
+            });
+        }
+        Ok(layout)
+    }
+
+    /// The torrent's name: its file, or the directory of its files
+    pub fn name(&self) -> &Path {
+        &self.name
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        match self.piece_length {
+            0 => 0,
+            len => self.total_length.div_ceil(len) as u32,
+        }
+    }
+
+    /// Length of piece `index`, the last one is usually shorter
+    pub fn piece_len(&self, index: u32) -> u32 {
+        let start = self.offset(index, 0).min(self.total_length);
+        (self.total_length - start).min(self.piece_length) as u32
+    }
+
+    /// Where block `begin` of piece `index` starts in the torrent
+    pub fn offset(&self, index: u32, begin: u32) -> u64 {
+        index as u64 * self.piece_length + begin as u64
+    }
+
+    /// The parts of files `len` bytes at `offset` of the torrent fall on, in order.
+    /// Empty files hold no bytes and never show up
+    pub fn slices(&self, offset: u64, len: u64) -> Result<Vec<FileSlice>, StorageError> {
+        let end = offset
+            .checked_add(len)
+            .filter(|end| *end <= self.total_length)
+            .ok_or(StorageError::OutOfRange { offset, len })?;
+        let slices = self
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.length > 0 && f.offset < end && f.offset + f.length > offset)
+            .map(|(i, f)| {
+                let start = offset.max(f.offset);
+                FileSlice {
+                    file: i,
+                    offset: start - f.offset,
+                    len: end.min(f.offset + f.length) - start,
+                }
+            })
+            .collect();
+        Ok(slices)
+    }
+
+    /// Every file piece `index` spans is long enough to hold its part,
+    /// given the length each file has
+    pub fn holds_piece(&self, index: u32, file_len: impl Fn(usize) -> u64) -> bool {
+        let (offset, len) = (self.offset(index, 0), self.piece_len(index) as u64);
+        match self.slices(offset, len) {
+            Ok(slices) => slices.iter().all(|s| file_len(s.file) >= s.offset + s.len),
+            Err(_) => false,
+        }
+    }
+}
+
+// a single plain file or directory name
+fn safe_component(component: &str) -> Result<&str, StorageError> {
+    let mut components = Path::new(component).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None)
+            if name == OsStr::new(component) && !component.contains(['/', '\\']) =>
+        {
+            Ok(component)
+        }
+        _ => Err(StorageError::UnsafePath(component.to_owned())),
+    }
+}
+
+/// Where the torrent's data lives. Offsets are into the torrent's bytes,
+/// laid out by `layout`
+pub trait Storage: Send + Debug {
+    fn layout(&self) -> &FileLayout;
+
+    /// Write `data` at `offset` of the torrent, across files if it has to
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), StorageError>;
+
+    /// Read `len` bytes at `offset` of the torrent
+    fn read(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, StorageError>;
+
+    /// Make sure everything written is kept
+    fn flush(&mut self) -> Result<(), StorageError>;
+
+    /// Every byte of piece `index` can be read back.
+    /// Says nothing of whether they are the right ones, verifying does
+    fn has_piece(&mut self, index: u32) -> bool;
+
+    /// Move the files under the directory `root`
+    fn move_to(&mut self, root: &Path) -> Result<(), StorageError>;
+
+    /// Remove every file of the torrent
+    fn delete(&mut self) -> Result<(), StorageError>;
+
+    fn write_block(&mut self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+        let offset = self.layout().offset(index, begin);
+        self.write(offset, data)
+    }
+
+    fn read_block(&mut self, index: u32, begin: u32, len: u32) -> Result<Vec<u8>, StorageError> {
+        let offset = self.layout().offset(index, begin);
+        self.read(offset, len as u64)
+    }
+
+    fn read_piece(&mut self, index: u32) -> Result<Vec<u8>, StorageError> {
+        let len = self.layout().piece_len(index);
+        self.read_block(index, 0, len)
+    }
+
+    /// Piece `index` passed its hash check. Storages that hold data back
+    /// write it out now
+    fn piece_verified(&mut self, _index: u32) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Piece `index` failed its hash check. Storages that hold data back
+    /// drop what they have of it
+    fn piece_failed(&mut self, _index: u32) {}
+
+    /// Length and modification time of file `file` of the layout,
+    /// `None` if it doesn't exist or the storage has no files
+    fn stamp(&self, _file: usize) -> Option<FileStamp> {
+        None
+    }
+}
+
+/// Torrent data in files under a download directory.
+/// Files are created when first written to, and kept open
+#[derive(Debug)]
+pub struct FileStorage {
+    root: PathBuf,
+    layout: FileLayout,
+    handles: Vec<Option<File>>,
+}
+
+impl FileStorage {
+    pub fn new(root: impl Into<PathBuf>, layout: FileLayout) -> Self {
+        let handles = layout.files.iter().map(|_| None).collect();
+        Self {
+            root: root.into(),
+            layout,
+            handles,
+        }
+    }
+
+    /// Where file `file` of the layout is on disk
+    pub fn path(&self, file: usize) -> PathBuf {
+        self.root.join(&self.layout.files[file].path)
+    }
+
+    /// Create every directory and file, files at their full length
+    /// (sparse, where the filesystem allows)
+    pub fn allocate(&mut self) -> Result<(), StorageError> {
+        for file in 0..self.handles.len() {
+            let length = self.layout.files[file].length;
+            let handle = self.open(file, true)?;
+            if handle.metadata()?.len() < length {
+                handle.set_len(length)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, file: usize, create: bool) -> Result<&File, StorageError> {
+        if self.handles[file].is_none() {
+            self.handles[file] = Some(open_file(&self.path(file), create)?);
+        }
+        Ok(self.handles[file].as_ref().unwrap())
+    }
+}
+
+impl Storage for FileStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), StorageError> {
+        let mut written = 0;
+        for slice in self.layout.slices(offset, data.len() as u64)? {
+            let part = &data[written..written + slice.len as usize];
+            write_at(self.open(slice.file, true)?, part, slice.offset)?;
+            written += part.len();
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        let mut data = vec![0; len as usize];
+        let mut read = 0;
+        for slice in self.layout.slices(offset, len)? {
+            let part = &mut data[read..read + slice.len as usize];
+            read_at(self.open(slice.file, false)?, part, slice.offset)?;
+            read += part.len();
+        }
+        Ok(data)
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        for handle in self.handles.iter().flatten() {
+            handle.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn has_piece(&mut self, index: u32) -> bool {
+        self.layout.holds_piece(index, |file| {
+            fs::metadata(self.path(file)).map_or(0, |m| m.len())
+        })
+    }
+
+    fn move_to(&mut self, root: &Path) -> Result<(), StorageError> {
+        self.flush()?;
+        self.handles.iter_mut().for_each(|handle| *handle = None);
+        move_files(&self.layout, &self.root, root)?;
+        self.root = root.to_owned();
+        Ok(())
+    }
+
+    fn delete(&mut self) -> Result<(), StorageError> {
+        self.handles.iter_mut().for_each(|handle| *handle = None);
+        delete_files(&self.layout, &self.root)
+    }
+
+    fn stamp(&self, file: usize) -> Option<FileStamp> {
+        FileStamp::of(&self.path(file))
+    }
+}
+
+/// Open a file of the torrent for reading and writing,
+/// creating it and its directories if asked to
+pub(crate) fn open_file(path: &Path, create: bool) -> Result<File, StorageError> {
+    if create {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(create)
+        .truncate(false)
+        .open(path)?;
+    Ok(file)
+}
+
+/// Move the files of `layout` that exist from under `from` to under `to`
+pub(crate) fn move_files(layout: &FileLayout, from: &Path, to: &Path) -> Result<(), StorageError> {
+    for file in &layout.files {
+        let (old, new) = (from.join(&file.path), to.join(&file.path));
+        if !old.exists() {
+            continue;
+        }
+        if let Some(parent) = new.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // renaming fails across filesystems
+        if fs::rename(&old, &new).is_err() {
+            fs::copy(&old, &new)?;
+            fs::remove_file(&old)?;
+        }
+        remove_empty_dirs(&old, from);
+    }
+    Ok(())
+}
+
+/// Remove the files of `layout` under `root`, and the directories they leave empty
+pub(crate) fn delete_files(layout: &FileLayout, root: &Path) -> Result<(), StorageError> {
+    for file in &layout.files {
+        let path = root.join(&file.path);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => remove_empty_dirs(&path, root),
+        }
+    }
+    Ok(())
+}
+
+// the directories between `path` and `root`, as long as they are empty
+fn remove_empty_dirs(path: &Path, root: &Path) {
+    for dir in path.ancestors().skip(1) {
+        if dir == root || !dir.starts_with(root) || fs::remove_dir(dir).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let n = file.seek_write(buf, offset)?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let n = file.seek_read(buf, offset)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf = &mut buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{fs, path::PathBuf};
+
+    use super::{FileLayout, FileSlice, FileStorage, Storage};
+    use crate::{
+        error::StorageError,
+        torrent::{FileMode, Files, Info, Torrent},
+    };
+
+    /// An empty directory of its own for a test
+    pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("torain-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// What every `Storage` must do, on a layout of 20 bytes in pieces of 8
+    /// with the first piece spanning files
+    pub(crate) fn exercise(storage: &mut impl Storage) {
+        assert!(!storage.has_piece(1));
+        assert!(storage.read(0, 1).is_err());
+        let content: Vec<u8> = (0..20).collect();
+        storage.write_block(1, 0, &content[8..16]).unwrap();
+        assert!(storage.has_piece(1));
+        assert!(!storage.has_piece(0));
+        storage.write_block(0, 0, &content[..8]).unwrap();
+        storage.write_block(2, 0, &content[16..]).unwrap();
+        storage.flush().unwrap();
+        assert!(storage.has_piece(0));
+        assert_eq!(storage.read(0, 20).unwrap(), content);
+        assert_eq!(storage.read_piece(2).unwrap(), &content[16..]);
+        assert_eq!(storage.read_block(1, 1, 3).unwrap(), [9, 10, 11]);
+        assert!(matches!(
+            storage.write(19, &[0; 2]),
+            Err(StorageError::OutOfRange { .. })
+        ));
+    }
+
+    pub(crate) fn multi_info(files: &[(&[&str], usize)], piece_length: usize) -> Info {
+        let files = files
+            .iter()
+            .map(|(path, length)| Files {
+                length: *length,
+                path: path.iter().map(|c| c.to_string()).collect(),
+            })
+            .collect();
+        Info {
+            name: "album".to_owned(),
+            piece_length,
+            pieces: vec![],
+            mode: FileMode::MultiMode { files },
+        }
+    }
+
+    #[test]
+    fn single_file() {
+        let info = Info {
+            name: "song.flac".to_owned(),
+            piece_length: 10,
+            pieces: vec![],
+            mode: FileMode::SingleMode { length: 25 },
+        };
+        let layout = FileLayout::new(&info).unwrap();
+        assert_eq!(layout.files()[0].path, PathBuf::from("song.flac"));
+        assert_eq!(layout.total_length(), 25);
+        assert_eq!(
+            layout.slices(layout.offset(2, 0), 5).unwrap(),
+            [FileSlice {
+                file: 0,
+                offset: 20,
+                len: 5
+            }]
+        );
+    }
+
+    #[test]
+    fn pieces_span_files() {
+        let info = multi_info(
+            &[
+                (&["a"], 7),
+                (&["empty"], 0),
+                (&["sub", "b"], 2),
+                (&["c"], 11),
+            ],
+            8,
+        );
+        let layout = FileLayout::new(&info).unwrap();
+        assert_eq!(layout.files()[2].path, PathBuf::from("album/sub/b"));
+        assert_eq!(layout.files()[3].offset, 9);
+        // piece 0 ends in the second file, piece 1 runs from it into the last
+        let slice = |file, offset, len| FileSlice { file, offset, len };
+        assert_eq!(
+            layout.slices(layout.offset(0, 0), 8).unwrap(),
+            [slice(0, 0, 7), slice(2, 0, 1)]
+        );
+        assert_eq!(
+            layout.slices(layout.offset(1, 0), 8).unwrap(),
+            [slice(2, 1, 1), slice(3, 0, 7)]
+        );
+        assert_eq!(layout.slices(16, 4).unwrap(), [slice(3, 7, 4)]);
+        assert!(matches!(
+            layout.slices(16, 5),
+            Err(StorageError::OutOfRange { offset: 16, len: 5 })
+        ));
+    }
+
+    #[test]
+    fn unsafe_paths() {
+        for path in [
+            &["..", "etc", "passwd"][..],
+            &["/etc"],
+            &["a/../../b"],
+            &["a\\..\\b"],
+            &["."],
+            &[""],
+            &[],
+        ] {
+            let info = multi_info(&[(path, 1)], 1);
+            assert!(
+                matches!(FileLayout::new(&info), Err(StorageError::UnsafePath(_))),
+                "{path:?}"
+            );
+        }
+        let mut info = multi_info(&[(&["fine"], 1)], 1);
+        info.name = "..".to_owned();
+        assert!(FileLayout::new(&info).is_err());
+        info.name = "still fine..".to_owned();
+        assert!(FileLayout::new(&info).is_ok());
+    }
+
+    #[test]
+    fn piece_hashes_match_the_files() {
+        let torrent = |pieces: usize| Torrent {
+            info: Info {
+                pieces: vec![0; pieces * 20],
+                ..multi_info(&[(&["a"], 7), (&["b"], 3)], 4)
+            },
+            ..Default::default()
+        };
+        assert!(FileLayout::from_torrent(&torrent(3)).is_ok());
+        for pieces in [2, 4] {
+            assert!(matches!(
+                FileLayout::from_torrent(&torrent(pieces)),
+                Err(StorageError::PieceHashes { pieces: 3, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let dir = scratch_dir("storage");
+        let info = multi_info(
+            &[
+                (&["a"], 7),
+                (&["empty"], 0),
+                (&["sub", "b"], 2),
+                (&["c"], 11),
+            ],
+            8,
+        );
+        let mut storage = FileStorage::new(&dir, FileLayout::new(&info).unwrap());
+        exercise(&mut storage);
+
+        let content: Vec<u8> = (0..20).collect();
+        assert_eq!(fs::read(dir.join("album/a")).unwrap(), &content[..7]);
+        assert_eq!(fs::read(dir.join("album/sub/b")).unwrap(), &content[7..9]);
+        assert_eq!(fs::read(dir.join("album/c")).unwrap(), &content[9..]);
+        // only files written to exist, until allocated
+        assert!(!dir.join("album/empty").exists());
+        storage.allocate().unwrap();
+        assert!(dir.join("album/empty").exists());
+        assert_eq!(storage.read(0, 20).unwrap(), content);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn move_and_delete() {
+        let dir = scratch_dir("move");
+        let info = multi_info(&[(&["sub", "a"], 7), (&["b"], 3)], 4);
+        let mut storage = FileStorage::new(dir.join("from"), FileLayout::new(&info).unwrap());
+        storage.write(0, &[1; 10]).unwrap();
+        fs::write(dir.join("from/album/sub/keep"), b"not ours").unwrap();
+
+        storage.move_to(&dir.join("to")).unwrap();
+        assert_eq!(storage.path(1), dir.join("to/album/b"));
+        assert_eq!(fs::read(dir.join("to/album/sub/a")).unwrap(), [1; 7]);
+        assert!(!dir.join("from/album/b").exists());
+        // directories still holding other files stay
+        assert!(dir.join("from/album/sub/keep").exists());
+        assert_eq!(storage.read(6, 2).unwrap(), [1; 2]);
+
+        storage.delete().unwrap();
+        assert!(!dir.join("to/album").exists());
+        assert!(dir.join("to").exists());
+        assert!(!storage.has_piece(0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn allocate_full_length() {
+        let dir = scratch_dir("allocate");
+        let info = multi_info(&[(&["a"], 7), (&["b"], 3)], 4);
+        let mut storage = FileStorage::new(&dir, FileLayout::new(&info).unwrap());
+        storage.allocate().unwrap();
+        assert_eq!(fs::metadata(storage.path(0)).unwrap().len(), 7);
+        assert_eq!(storage.read(5, 5).unwrap(), [0; 5]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -14,12 +14,18 @@ use uttd::url::Url;
 #[derive(Default, Debug, Clone)]
 pub struct Torrent {
     /// url of the tracker. Announce requests are sent
-    /// to this server --- Can be HTTP or UDP
+    /// to this server --- Can be HTTP or UDP.
+    /// The first tracker of `announce_list` when the torrent leaves it out
     pub announce: Url,
 
     /// Multiple announce list as specified in BEP 00012
-    /// https://www.bittorrent.org/beps/bep_0012.html    
-    pub announce_list: Option<Vec<String>>,
+    /// https://www.bittorrent.org/beps/bep_0012.html
+    /// Tiers of trackers, empty if the torrent only has `announce`
+    pub announce_list: Vec<Vec<Url>>,
+
+    /// Web seeds as specified in BEP 00019
+    /// https://www.bittorrent.org/beps/bep_0019.html
+    pub url_list: Option<Vec<Url>>,
 
     /// Date of creation of the torrent
     pub creation_date: Option<usize>,
@@ -103,7 +109,9 @@ impl Torrent {
         let mut torrent = Self::default();
         if let Ok(b) = bencode::bencode::decode(u8s) {
             if let BTypes::DICT(d) = b {
-                torrent.decode_fields(d).expect("Failed to decrypt fields");
+                torrent
+                    .decode_fields(d)
+                    .map_err(|_| TorrentError::UnexpectedField)?;
             } else {
                 return Err(TorrentError::UnexpectedField);
             }
@@ -116,8 +124,17 @@ impl Torrent {
     /// Decode fields of the torrent
     /// @arg 1: BTreeMap of bencoded dictionary
     fn decode_fields(&mut self, d: BTreeMap<String, BTypes>) -> Result<(), DecodeError> {
-        self.announce = d.get("announce").unwrap().try_into()?;
-        self.announce_list = decode_option(d.get("announce-list"))?;
+        self.announce_list = Self::de_announce_list(d.get("announce-list"));
+        self.announce = match d.get("announce") {
+            Some(announce) => announce.try_into()?,
+            None => self
+                .announce_list
+                .first()
+                .and_then(|tier| tier.first())
+                .cloned()
+                .ok_or(DecodeError::EOF)?,
+        };
+        self.url_list = Self::de_url_list(d.get("url-list"))?;
         self.creation_date = decode_option(d.get("creation date"))?;
         self.comment = decode_option(d.get("comment"))?;
         self.created_by = decode_option(d.get("created by"))?;
@@ -130,14 +147,14 @@ impl Torrent {
     /// Decode info field specifically
     fn decode_info_fields(&mut self, d: Option<&BTypes>) -> Result<(), DecodeError> {
         if let Some(BTypes::DICT(d)) = d {
-            self.info.name = d.get("name").unwrap().try_into()?;
-            self.info.piece_length = d.get("piece length").unwrap().try_into()?;
-            self.info.pieces = d.get("pieces").unwrap().try_into()?;
+            self.info.name = d.get("name").ok_or(DecodeError::EOF)?.try_into()?;
+            self.info.piece_length = d.get("piece length").ok_or(DecodeError::EOF)?.try_into()?;
+            self.info.pieces = d.get("pieces").ok_or(DecodeError::EOF)?.try_into()?;
             if let Some(p) = d.get("files") {
                 self.info.mode = Self::de_multi_file_mode(p)?;
             } else {
                 self.info.mode = FileMode::SingleMode {
-                    length: d.get("length").unwrap().try_into()?,
+                    length: d.get("length").ok_or(DecodeError::EOF)?.try_into()?,
                 }
             }
         } else {
@@ -147,28 +164,61 @@ impl Torrent {
     }
 
     /// Decoded info field for multi-field mode
-    /// More keys need to be decoded for multi-mode than single-mode
+    /// More keys need to be decoded for multi-mode than single-mode.
+    /// A file without a length or a path of strings is an error
     fn de_multi_file_mode(d: &BTypes) -> Result<FileMode, DecodeError> {
         if let BTypes::LIST(l) = d {
-            let files: Vec<Files> = l
+            let files = l
                 .iter()
-                .filter_map(|d| {
-                    if let BTypes::DICT(dict) = d {
-                        Some(Files {
-                            length: dict.get("length").unwrap().try_into().unwrap(),
-                            path: dict.get("path").unwrap().try_into().unwrap(),
-                        })
-                    } else {
-                        None
-                    }
+                .filter_map(|d| match d {
+                    BTypes::DICT(dict) => Some(dict),
+                    _ => None,
+                })
+                .map(|dict| {
+                    let length = dict.get("length").ok_or(DecodeError::EOF)?;
+                    let path = dict.get("path").ok_or(DecodeError::EOF)?;
+                    Ok(Files {
+                        length: length.try_into()?,
+                        path: path.try_into()?,
+                    })
                 })
-                .collect();
+                .collect::<Result<Vec<Files>, DecodeError>>()?;
             Ok(FileMode::MultiMode { files })
         } else {
             Err(DecodeError::EOF)
         }
     }
 
+    /// Decode the tiers of the announce list
+    /// Trackers with urls that don't parse and tiers left empty are dropped.
+    /// A tracker outside of any tier is taken as a tier of its own
+    fn de_announce_list(d: Option<&BTypes>) -> Vec<Vec<Url>> {
+        let Some(BTypes::LIST(tiers)) = d else {
+            return Vec::new();
+        };
+        tiers
+            .iter()
+            .map(|tier| match tier {
+                BTypes::LIST(trackers) => {
+                    trackers.iter().filter_map(|t| t.try_into().ok()).collect()
+                }
+                tracker => tracker.try_into().into_iter().collect(),
+            })
+            .filter(|tier: &Vec<Url>| !tier.is_empty())
+            .collect()
+    }
+
+    /// Decode the web seed list
+    /// `url-list` may be a single url or a list of urls
+    fn de_url_list(d: Option<&BTypes>) -> Result<Option<Vec<Url>>, DecodeError> {
+        match d {
+            Some(BTypes::BSTRING(s)) if s.is_empty() => Ok(None),
+            Some(b @ BTypes::BSTRING(_)) => Ok(Some(vec![b.try_into()?])),
+            Some(BTypes::LIST(l)) => Ok(Some(l.iter().filter_map(|u| u.try_into().ok()).collect())),
+            _ => Ok(None),
+        }
+    }
+
     /// Calculate the SHA1 hash of the bencoded info dict
     pub fn info_hash(&mut self, info: Option<&BTypes>) {
         if let Some(bt) = info {
@@ -189,6 +239,10 @@ impl Torrent {
             FileMode::MultiMode { ref files } => files.iter().map(|f| f.length).sum::<usize>(),
         }
     }
+    /// Number of pieces, one SHA1 hash of 20 bytes each in `pieces`
+    pub fn piece_count(&self) -> usize {
+        self.info.pieces.len() / 20
+    }
 }
 
 #[cfg(test)]
@@ -197,7 +251,10 @@ mod test {
 
     use uttd::url::Url;
 
-    use crate::torrent::FileMode;
+    use crate::{
+        error::TorrentError,
+        torrent::{FileMode, Files},
+    };
 
     use super::Torrent;
 
@@ -249,6 +306,96 @@ mod test {
         )
     }
 
+    #[test]
+    fn web_seed_url_list() {
+        let single = "d8:announce30:http://tracker.example.com/ann8:url-list26:http://seed.example.com/a/4:infod6:lengthi10e4:name5:a.txt12:piece lengthi16384e6:pieces0:ee";
+        let torrent = Torrent::from_str(single).unwrap();
+        assert_eq!(
+            torrent.url_list,
+            Some(vec![Url::new("http://seed.example.com/a/").unwrap()])
+        );
+
+        let list = "d8:announce30:http://tracker.example.com/ann8:url-listl20:http://a.example.com20:http://b.example.come4:infod6:lengthi10e4:name5:a.txt12:piece lengthi16384e6:pieces0:ee";
+        let torrent = Torrent::from_str(list).unwrap();
+        assert_eq!(torrent.url_list.map(|u| u.len()), Some(2));
+    }
+
+    #[test]
+    fn announce_list_tiers() {
+        let tiered = "d8:announce30:http://tracker.example.com/ann13:announce-listll22:http://a.example.com/a22:http://b.example.com/ael21:udp://c.example.com:1elee4:infod6:lengthi10e4:name5:a.txt12:piece lengthi16384e6:pieces0:ee";
+        let torrent = Torrent::from_str(tiered).unwrap();
+        assert_eq!(
+            torrent.announce_list,
+            vec![
+                vec![
+                    Url::new("http://a.example.com/a").unwrap(),
+                    Url::new("http://b.example.com/a").unwrap()
+                ],
+                vec![Url::new("udp://c.example.com:1").unwrap()],
+            ]
+        );
+
+        // a flat list is a tier per tracker
+        let flat = "d8:announce30:http://tracker.example.com/ann13:announce-listl22:http://a.example.com/a22:http://b.example.com/ae4:infod6:lengthi10e4:name5:a.txt12:piece lengthi16384e6:pieces0:ee";
+        let torrent = Torrent::from_str(flat).unwrap();
+        assert_eq!(torrent.announce_list.len(), 2);
+
+        let torrent = Torrent::from_str("d8:announce30:http://tracker.example.com/ann4:infod6:lengthi10e4:name5:a.txt12:piece lengthi16384e6:pieces0:ee").unwrap();
+        assert!(torrent.announce_list.is_empty());
+    }
+
+    #[test]
+    fn missing_fields() {
+        // BEP 12 torrents may only have the announce-list
+        let torrent = Torrent::from_str("d13:announce-listll22:http://a.example.com/aee4:infod6:lengthi10e4:name5:a.txt12:piece lengthi16384e6:pieces0:ee").unwrap();
+        assert_eq!(
+            torrent.announce,
+            Url::new("http://a.example.com/a").unwrap()
+        );
+
+        for torrent in [
+            "d4:infod6:lengthi10e4:name5:a.txt12:piece lengthi16384e6:pieces0:ee",
+            "d8:announce30:http://tracker.example.com/ann4:infod6:lengthi10e12:piece lengthi16384e6:pieces0:ee",
+            "d8:announce30:http://tracker.example.com/ann4:infod6:lengthi10e4:name5:a.txt6:pieces0:ee",
+            "d8:announce30:http://tracker.example.com/ann4:infod6:lengthi10e4:name5:a.txt12:piece lengthi16384eee",
+            "d8:announce30:http://tracker.example.com/ann4:infod4:name5:a.txt12:piece lengthi16384e6:pieces0:ee",
+        ] {
+            assert!(
+                matches!(Torrent::from_str(torrent), Err(TorrentError::UnexpectedField)),
+                "{torrent}"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_file_list() {
+        let info = |files: &str| {
+            format!("d8:announce30:http://tracker.example.com/ann4:infod5:files{files}4:name1:a12:piece lengthi16384e6:pieces0:ee")
+        };
+        let torrent = Torrent::from_str(&info("ld6:lengthi10e4:pathl1:b5:c.txteee")).unwrap();
+        assert_eq!(
+            torrent.info.mode,
+            FileMode::MultiMode {
+                files: vec![Files {
+                    length: 10,
+                    path: vec!["b".to_owned(), "c.txt".to_owned()],
+                }]
+            }
+        );
+
+        // a nested list in the path, no path, no length
+        for files in [
+            "ld6:lengthi10e4:pathl1:bl1:ceeee",
+            "ld6:lengthi10eee",
+            "ld4:pathl1:beee",
+        ] {
+            assert!(matches!(
+                Torrent::from_str(&info(files)),
+                Err(TorrentError::UnexpectedField)
+            ));
+        }
+    }
+
     #[test]
     fn multi_info_hash() {
         let fs = "pulpfiction.torrent";
@@ -1,14 +1,21 @@
 use bencode::bencode::decode;
 use bencode::bencode::BTypes;
-use bencode::utils::BencodeErr;
-use uttd::urutil::{build_url, response};
-use uttd::StreamType;
-use uttd::{url::Url, Stream};
-
+use bencode::utils::{decode_option, BencodeErr};
+use crypto::tinymt::TinyMT;
+use uttd::error::UrlError;
+use uttd::http::HttpClient;
+use uttd::proxy::Proxy;
+use uttd::udp_tracker::{AnnounceRequest, UdpTracker};
+use uttd::url::{Scheme, Url};
+use uttd::urutil::{build_url, compact_v4, compact_v6, encode, ScrapeStats};
+use uttd::UttdError;
+
+use crate::error::TrackerError;
 use crate::peers::Peers;
 use crate::torrent::Torrent;
-use core::panic;
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
 
 pub struct TrackerParams<'a> {
     pub url: Url,
@@ -20,21 +27,72 @@ pub struct TrackerParams<'a> {
     pub left: u64,
     pub compact: &'a [u8],
     pub event: Event,
-    pub trackerid: Option<[u8; 20]>,
+    /// given by the tracker in an earlier answer
+    pub trackerid: Option<Vec<u8>>,
+    /// random key identifying this client to the tracker across ip changes
+    pub key: u32,
+    /// how many peers to ask for, the tracker decides if `None`
+    pub numwant: Option<u32>,
+    /// dial the tracker and the peers it returns through this proxy
+    pub proxy: Option<Proxy>,
+}
+
+/// UDP tracker clients, one per tracker, kept between announces and scrapes
+/// so a connection id is reused for as long as it's valid (BEP 15)
+#[derive(Debug, Default)]
+pub struct UdpTrackers {
+    trackers: Mutex<HashMap<String, UdpTracker>>,
+}
+
+impl UdpTrackers {
+    // the client kept for the tracker of `params`, or a new one.
+    // It's out of the pool until given back, so no lock is held while it waits
+    async fn take(&self, params: &TrackerParams<'_>) -> Result<UdpTracker, UttdError> {
+        let kept = self
+            .trackers
+            .lock()
+            .ok()
+            .and_then(|mut trackers| trackers.remove(&params.url.host));
+        match kept {
+            Some(tracker) => Ok(tracker),
+            None => params.udp_tracker().await,
+        }
+    }
+
+    fn give_back(&self, url: &Url, tracker: UdpTracker) {
+        if let Ok(mut trackers) = self.trackers.lock() {
+            trackers.insert(url.host.clone(), tracker);
+        }
+    }
 }
 
 // TODO: work on DHT protocol... type shit
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Event {
+    /// one of the announces made at regular intervals
+    Empty,
     Started,
     Stopped,
     Completed,
 }
 
+// event codes used by UDP trackers
+impl From<Event> for u32 {
+    fn from(value: Event) -> Self {
+        match value {
+            Event::Empty => 0,
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
+        }
+    }
+}
+
 impl From<Event> for &str {
     fn from(value: Event) -> Self {
         match value {
+            Event::Empty => "",
             Event::Started => "started",
             Event::Stopped => "stopped",
             Event::Completed => "completed",
@@ -58,6 +116,9 @@ impl<'a> TrackerParams<'a> {
             compact: b"1",
             event: Event::Started,
             trackerid: None,
+            key: TinyMT::from_clock().get_u32(),
+            numwant: None,
+            proxy: None,
         }
     }
 
@@ -71,125 +132,339 @@ impl<'a> TrackerParams<'a> {
 
         map.insert("info_hash", self.info_hash.to_vec());
         map.insert("peer_id", self.peer_id.to_vec());
-        map.insert("port", self.port.to_be_bytes().to_vec());
+        map.insert("port", self.port.to_string().into_bytes());
         map.insert("uploaded", uploaded);
         map.insert("downloaded", downloaded);
         map.insert("left", left);
         map.insert("compact", self.compact.to_vec());
+        map.insert("key", format!("{:08x}", self.key).into_bytes());
+        if let Some(numwant) = self.numwant {
+            map.insert("numwant", numwant.to_string().into_bytes());
+        }
+        if let Some(trackerid) = &self.trackerid {
+            map.insert("trackerid", trackerid.clone());
+        }
 
-        let event: &str = self.event.into();
-        map.insert("event", event.as_bytes().to_vec());
+        // regular announces go without an event
+        if self.event != Event::Empty {
+            let event: &str = self.event.into();
+            map.insert("event", event.as_bytes().to_vec());
+        }
         map
     }
-    pub fn announce(&self) -> Result<Peers, BencodeErr> {
+    /// Announce to the tracker using a fresh `HttpClient` and `UdpTrackers`
+    pub async fn announce(&self) -> Result<Peers, TrackerError> {
+        self.announce_with(&self.http_client(), &UdpTrackers::default())
+            .await
+    }
+
+    pub(crate) fn http_client(&self) -> HttpClient {
+        match &self.proxy {
+            Some(proxy) => HttpClient::new().with_proxy(proxy.clone()),
+            None => HttpClient::new(),
+        }
+    }
+
+    async fn udp_tracker(&self) -> Result<UdpTracker, UttdError> {
+        match &self.proxy {
+            Some(proxy) => UdpTracker::with_proxy(&self.url, proxy).await,
+            None => UdpTracker::new(&self.url).await,
+        }
+    }
+
+    /// Announce to the tracker. HTTP announces go through `client`,
+    /// reusing any connection it keeps alive to the tracker,
+    /// UDP announces through `udp`, reusing its connection id
+    pub async fn announce_with(
+        &self,
+        client: &HttpClient,
+        udp: &UdpTrackers,
+    ) -> Result<Peers, TrackerError> {
         match self.url.scheme {
-            uttd::url::Scheme::UDP => self.announce_udp(),
-            _ => self.announce_tcp(),
+            Scheme::UDP => self.announce_udp(udp).await,
+            _ => self.announce_tcp(client).await,
         }
     }
 
-    fn announce_tcp(&self) -> Result<Peers, BencodeErr> {
+    async fn announce_tcp(&self, client: &HttpClient) -> Result<Peers, TrackerError> {
         let params = &self.params();
         let url = &self.url;
         let path = build_url(&url.location, params);
-        let mut stream = Stream::new(url).unwrap();
-        let mut res = stream.get(&path).unwrap();
-        let response = response(uttd::url::Scheme::HTTP, &mut res).unwrap();
-        let body = response.1.to_vec();
-        let (interval, sock) = Self::bencoded_ip_mode(body);
-
-        let peers = Peers::new(interval as i32, 0, 0, sock);
+        let res = client.get(url, &path).await?;
+        if res.status != 200 {
+            return Err(UttdError::HttpStatus(res.status).into());
+        }
+        let mut peers = Self::bencoded_ip_mode(res.body)?;
+        peers.proxy = self.proxy.clone();
 
         Ok(peers)
-        // response.interval = interval;
     }
-    fn announce_udp(&self) -> Result<Peers, BencodeErr> {
-        let mut request_body: Vec<u8> = Vec::new();
-
-        let url = &self.url;
-        let mut stream = Stream::new(url).unwrap();
-        let connection_id;
-        if let StreamType::UDP(ref u) = stream.stream {
-            connection_id = u.connection_id;
-        } else {
-            return Err(BencodeErr::Berr);
+    async fn announce_udp(&self, udp: &UdpTrackers) -> Result<Peers, TrackerError> {
+        let mut tracker = udp.take(self).await?;
+        let request = AnnounceRequest {
+            info_hash: self
+                .info_hash
+                .try_into()
+                .map_err(|_| UttdError::ProtocolViolation("info hash isn't 20 bytes"))?,
+            peer_id: self.peer_id,
+            downloaded: self.downloaded,
+            left: self.left,
+            uploaded: self.uploaded,
+            event: self.event.into(),
+            key: self.key,
+            num_want: self.numwant.map_or(-1, |n| n.min(i32::MAX as u32) as i32),
+            port: self.port,
         };
+        let res = tracker.announce(&request).await;
+        udp.give_back(&self.url, tracker);
+        let (info, res) = res?;
 
-        request_body.extend_from_slice(&connection_id.to_be_bytes()); // connection_id
-        request_body.extend_from_slice(&1_u32.to_be_bytes()); // action
-        request_body.extend_from_slice(&1_u32.to_be_bytes()); // transaction_id
-        request_body.extend_from_slice(self.info_hash); // info_hash
-        request_body.extend_from_slice(&self.peer_id); // peer_id
-        request_body.extend_from_slice(&0_u64.to_be_bytes()); // downloaded
-        request_body.extend_from_slice(&self.left.to_be_bytes()); // left
-        request_body.extend_from_slice(&self.uploaded.to_be_bytes()); // uploaded
-        request_body.extend_from_slice(&2_u32.to_be_bytes()); // event
-        request_body.extend_from_slice(&0_u32.to_be_bytes()); // ip address
-        request_body.extend_from_slice(&1_u32.to_be_bytes()); // key
-        request_body.extend_from_slice(&(-1_i32).to_be_bytes()); // num_want
-        request_body.extend_from_slice(&self.port.to_be_bytes()); // port
+        let mut peers = Peers::new(info.interval, info.seeders, info.leechers, res);
+        peers.proxy = self.proxy.clone();
 
-        assert!(request_body.len() == 98);
+        Ok(peers)
+    }
 
-        let mut res = vec![0; 1024];
-        stream.send(&request_body, &mut res).unwrap();
+    /// Scrape the tracker for the stats of every torrent in `info_hashes` at once
+    /// using a fresh `HttpClient` and `UdpTrackers`
+    pub async fn scrape(
+        &self,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+        let udp = UdpTrackers::default();
+        self.scrape_with(&self.http_client(), &udp, info_hashes)
+            .await
+    }
 
-        let (info, body) = response(uttd::url::Scheme::UDP, &mut res).unwrap();
-        let body = body.to_vec();
-        let res = Self::compact_ip_mode(body.as_slice());
+    /// Scrape the tracker for the stats of every torrent in `info_hashes` at once.
+    /// Torrents the tracker doesn't know about are left out of the result.
+    /// HTTP trackers are scraped through `client`; those whose announce url
+    /// doesn't allow deriving a scrape url (BEP 48) can't be scraped.
+    /// UDP trackers are scraped through `udp`
+    pub async fn scrape_with(
+        &self,
+        client: &HttpClient,
+        udp: &UdpTrackers,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+        match self.url.scheme {
+            Scheme::UDP => self.scrape_udp(udp, info_hashes).await,
+            _ => self.scrape_tcp(client, info_hashes).await,
+        }
+    }
 
-        let peers = Peers::new(info.interval, info.seeders, info.leechers, res);
+    async fn scrape_tcp(
+        &self,
+        client: &HttpClient,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+        let url = Self::scrape_url(&self.url).ok_or(UttdError::InvalidUrl(UrlError::InvalidUrl))?;
+        let mut path = url.location.clone();
+        let mut separator = if path.contains('?') { '&' } else { '?' };
+        for hash in info_hashes {
+            path.push(separator);
+            path.push_str("info_hash=");
+            path.push_str(&encode(hash));
+            separator = '&';
+        }
+        let res = client.get(&url, &path).await?;
+        if res.status != 200 {
+            return Err(UttdError::HttpStatus(res.status).into());
+        }
+        Self::parse_scrape(res.body)
+    }
 
-        Ok(peers)
+    async fn scrape_udp(
+        &self,
+        udp: &UdpTrackers,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+        let mut tracker = udp.take(self).await?;
+        let stats = tracker.scrape(info_hashes).await;
+        udp.give_back(&self.url, tracker);
+        let stats = stats?;
+        Ok(info_hashes.iter().copied().zip(stats).collect())
     }
 
-    pub fn compact_ip_mode(bytes: &[u8]) -> Vec<Url> {
-        let ips: Vec<Url> = bytes
-            .chunks(6)
-            .map(|x| {
-                let ip: [u8; 4] = x[0..4].try_into().unwrap();
-                let port = u16::from_be_bytes(x[4..6].try_into().unwrap());
-                Url::from_ip_bytes(&ip, port, uttd::url::Scheme::HTTP)
-            })
-            .collect();
+    /// Derive the scrape url of an HTTP tracker from its announce url (BEP 48):
+    /// the last path component has to start with "announce", which is replaced by "scrape".
+    /// Returns `None` if the tracker doesn't follow the convention
+    pub fn scrape_url(announce: &Url) -> Option<Url> {
+        let (path, query) = match announce.location.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (announce.location.as_str(), None),
+        };
+        let (dir, last) = match path.rsplit_once('/') {
+            Some((dir, last)) => (Some(dir), last),
+            None => (None, path),
+        };
+        let rest = last.strip_prefix("announce")?;
+
+        let mut location = dir.map_or(String::new(), |dir| format!("{dir}/"));
+        location.push_str("scrape");
+        location.push_str(rest);
+        if let Some(query) = query {
+            location.push('?');
+            location.push_str(query);
+        }
+        Some(Url {
+            location,
+            ..announce.clone()
+        })
+    }
 
-        ips
+    // d5:filesd20:<info_hash>d8:completei..e10:downloadedi..e10:incompletei..eeee
+    fn parse_scrape(bytes: Vec<u8>) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+        let decoded = decode(&mut bytes.into_iter()).map_err(|_| BencodeErr::Berr)?;
+        let BTypes::DICT(d) = decoded else {
+            return Err(BencodeErr::Berr.into());
+        };
+        if let Some(reason) = d.get("failure reason") {
+            return Err(TrackerError::Failure(reason.try_into()?));
+        }
+        let Some(BTypes::DICT(files)) = d.get("files") else {
+            return Err(BencodeErr::Berr.into());
+        };
+
+        let mut stats = HashMap::new();
+        for (hash, file) in files {
+            // dictionary keys are decoded byte for byte into chars
+            let hash: Vec<u8> = hash.chars().map(|c| c as u8).collect();
+            let (Ok(hash), BTypes::DICT(file)) = (hash.try_into(), file) else {
+                continue;
+            };
+            let count = |key| {
+                file.get(key)
+                    .map_or(Ok(0), usize::try_from)
+                    .map(|n| n as i32)
+            };
+            stats.insert(
+                hash,
+                ScrapeStats {
+                    complete: count("complete")?,
+                    downloaded: count("downloaded")?,
+                    incomplete: count("incomplete")?,
+                },
+            );
+        }
+        Ok(stats)
     }
 
-    fn bencoded_ip_mode(bytes: Vec<u8>) -> (usize, Vec<Url>) {
-        let mut ips = Vec::new();
-        let mut interval = 0;
+    /// IPv4 peers in compact form, 6 bytes each (BEP 23)
+    pub fn compact_ip_mode(bytes: &[u8]) -> Vec<SocketAddr> {
+        compact_v4(bytes)
+    }
 
-        let decoded_body = decode(&mut bytes.into_iter()).unwrap();
-        if let BTypes::DICT(d) = decoded_body {
-            if d.get("failure").is_some() {
-                panic!("FAILED");
-            }
-            interval = d.get("interval").unwrap().try_into().unwrap();
-            let peers = d.get("peers").unwrap();
-            if let BTypes::LIST(l) = peers {
-                l.iter().for_each(|peers| {
-                    if let BTypes::DICT(peer_list) = peers {
-                        let ip: String = peer_list.get("ip").unwrap().try_into().unwrap();
-                        let port: usize = peer_list.get("port").unwrap().try_into().unwrap();
-                        ips.push(Url::from_ip(&ip, port as u16).unwrap());
+    // A tracker that answers with a failure reason, or not with a dictionary, failed
+    fn bencoded_ip_mode(bytes: Vec<u8>) -> Result<Peers, TrackerError> {
+        let decoded_body = decode(&mut bytes.into_iter()).map_err(|_| BencodeErr::Berr)?;
+        let BTypes::DICT(d) = decoded_body else {
+            return Err(BencodeErr::Berr.into());
+        };
+        if let Some(reason) = d.get("failure reason") {
+            return Err(TrackerError::Failure(reason.try_into()?));
+        }
+        let interval: usize = d.get("interval").ok_or(BencodeErr::Berr)?.try_into()?;
+        // complete and incomplete are optional
+        let count = |key| d.get(key).and_then(|c| usize::try_from(c).ok());
+        let seeders = count("complete").unwrap_or(0) as i32;
+        let leechers = count("incomplete").unwrap_or(0) as i32;
+
+        let mut ips = Vec::new();
+        match d.get("peers").ok_or(BencodeErr::Berr)? {
+            BTypes::LIST(l) => {
+                for peer in l {
+                    // peers missing their address are skipped
+                    let BTypes::DICT(peer) = peer else {
+                        continue;
                     };
-                });
-            } else if let BTypes::BSTRING(bpeers) = peers {
-                return (interval, Self::compact_ip_mode(bpeers));
+                    let ip: Option<String> = decode_option(peer.get("ip"))?;
+                    let port: Option<usize> = decode_option(peer.get("port"))?;
+                    // so are peers given by domain name rather than by address
+                    let ip = ip.and_then(|ip| ip.parse::<IpAddr>().ok());
+                    if let (Some(ip), Some(port)) = (ip, port) {
+                        ips.push(SocketAddr::new(ip, port as u16));
+                    }
+                }
             }
-        };
-        (interval, ips)
+            BTypes::BSTRING(bpeers) => ips = Self::compact_ip_mode(bpeers),
+            _ => {}
+        }
+        // IPv6 peers come separately (BEP 7)
+        if let Some(BTypes::BSTRING(bpeers)) = d.get("peers6") {
+            ips.extend(compact_v6(bpeers));
+        }
+
+        let mut peers = Peers::new(interval as i32, seeders, leechers, ips);
+        peers.min_interval = count("min interval").map(|i| i as i32);
+        if let Some(BTypes::BSTRING(id)) = d.get("tracker id") {
+            peers.tracker_id = Some(id.clone());
+        }
+        peers.warning = decode_option(d.get("warning message"))?;
+        Ok(peers)
     }
 }
 
 #[cfg(test)]
-mod test {
-
+pub(crate) mod test {
+
+    use std::net::SocketAddr;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+        task::JoinHandle,
+    };
+
+    use uttd::http::HttpClient;
     use uttd::url::Url;
+    use uttd::urutil::ScrapeStats;
+
+    use super::{Event, TrackerParams, UdpTrackers};
+    use crate::{error::TrackerError, torrent::Torrent};
+
+    // answers HTTP requests with the next of `bodies`, the last one over and over.
+    // Yields the request lines
+    pub(crate) async fn serve<B>(bodies: Vec<B>) -> (String, mpsc::UnboundedReceiver<String>)
+    where
+        B: AsRef<[u8]> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for i in 0.. {
+                let body = bodies[i.min(bodies.len() - 1)].as_ref();
+                let (mut sock, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let read = sock.read(&mut buf).await.unwrap();
+                    req.extend_from_slice(&buf[..read]);
+                }
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                sock.write_all(head.as_bytes()).await.unwrap();
+                sock.write_all(body).await.unwrap();
+                let req = String::from_utf8(req).unwrap();
+                let _ = tx.send(req.lines().next().unwrap().to_owned());
+            }
+        });
+        (addr, rx)
+    }
 
-    use super::TrackerParams;
-    use crate::torrent::Torrent;
+    // answers HTTP requests with `body`, yielding the first request line
+    pub(crate) async fn serve_once(body: Vec<u8>) -> (String, JoinHandle<String>) {
+        let (addr, mut requests) = serve(vec![body]).await;
+        let handle = tokio::spawn(async move { requests.recv().await.unwrap() });
+        (addr, handle)
+    }
 
     #[test]
     fn left_single_mode() {
@@ -215,52 +490,289 @@ mod test {
         }
     }
 
-    #[test]
-    fn announce_tcp() {
+    #[tokio::test]
+    async fn announce_tcp() {
         let fs = "debian.torrent";
         let torrent = Torrent::from_file(fs).unwrap();
         let tracker = TrackerParams::new(&torrent);
-        let announce = tracker.announce().unwrap();
+        let announce = tracker.announce().await.unwrap();
         assert!(!announce.peer.is_empty());
     }
 
-    #[test]
-    fn announce_udp() {
+    #[tokio::test]
+    async fn announce_udp() {
         let fs = "pulpfiction.torrent";
         let torrent = Torrent::from_file(fs).unwrap();
         let tracker = TrackerParams::new(&torrent);
-        let announce = tracker.announce().unwrap();
+        let announce = tracker.announce().await.unwrap();
         assert!(!announce.peer.is_empty());
     }
 
+    #[tokio::test]
+    async fn announce_udp_loopback() {
+        // minimal BEP 15 tracker that checks the announced fields
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (read, from) = socket.recv_from(&mut buf).await.unwrap();
+                let req = &buf[..read];
+                let mut res = req[8..12].to_vec();
+                res.extend_from_slice(&req[12..16]);
+                if req[8..12] == 0_u32.to_be_bytes() {
+                    res.extend_from_slice(&42_i64.to_be_bytes());
+                } else {
+                    assert_eq!(req[0..8], 42_i64.to_be_bytes());
+                    assert_eq!(req[56..64], 7_u64.to_be_bytes()); // downloaded
+                    assert_eq!(req[80..84], 3_u32.to_be_bytes()); // event: stopped
+                    res.extend_from_slice(&1800_i32.to_be_bytes());
+                    res.extend_from_slice(&4_i32.to_be_bytes());
+                    res.extend_from_slice(&9_i32.to_be_bytes());
+                    res.extend_from_slice(&[127, 0, 0, 1, 31, 144]);
+                }
+                socket.send_to(&res, from).await.unwrap();
+            }
+        });
+
+        let torrent = Torrent {
+            announce: Url::new(&format!("udp://{}", addr)).unwrap(),
+            ..Default::default()
+        };
+        let mut tracker = TrackerParams::new(&torrent);
+        tracker.downloaded = 7;
+        tracker.event = Event::Stopped;
+
+        let peers = tracker.announce().await.unwrap();
+        assert_eq!(
+            (peers.interval, peers.leechers, peers.seeders),
+            (1800, 4, 9)
+        );
+        assert_eq!(peers.peer, ["127.0.0.1:8080".parse().unwrap()]);
+    }
+
+    #[test]
+    fn scrape_url_convention() {
+        let scrape = |announce: &str| {
+            let url = Url::new(announce).unwrap();
+            TrackerParams::scrape_url(&url).map(|u| u.location)
+        };
+        // examples from BEP 48
+        assert_eq!(scrape("http://example.com/announce").unwrap(), "scrape");
+        assert_eq!(scrape("http://example.com/x/announce").unwrap(), "x/scrape");
+        assert_eq!(
+            scrape("http://example.com/announce.php").unwrap(),
+            "scrape.php"
+        );
+        assert_eq!(
+            scrape("http://example.com/announce?x2%0644").unwrap(),
+            "scrape?x2%0644"
+        );
+        assert_eq!(scrape("http://example.com/a"), None);
+        assert_eq!(scrape("http://example.com/x%064announce"), None);
+        assert_eq!(scrape("http://example.com/announce/x"), None);
+    }
+
+    #[tokio::test]
+    async fn scrape_http() {
+        let known = [0x0a; 20];
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&known);
+        body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let (addr, request) = serve_once(body).await;
+
+        let torrent = Torrent {
+            announce: Url::new(&format!("http://{addr}/announce?passkey=1")).unwrap(),
+            ..Default::default()
+        };
+        let tracker = TrackerParams::new(&torrent);
+        let stats = tracker.scrape(&[known, [0xff; 20]]).await.unwrap();
+
+        let expected = ScrapeStats {
+            complete: 5,
+            downloaded: 50,
+            incomplete: 10,
+        };
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[&known], expected);
+
+        let request = request.await.unwrap();
+        let query = format!(
+            "GET /scrape?passkey=1&info_hash={}&info_hash={} HTTP/1.1",
+            "%0a".repeat(20),
+            "%ff".repeat(20)
+        );
+        assert_eq!(request, query);
+    }
+
+    #[tokio::test]
+    async fn scrape_http_failure() {
+        let (addr, _) = serve_once(b"d14:failure reason6:deniede".to_vec()).await;
+        let torrent = Torrent {
+            announce: Url::new(&format!("http://{addr}/announce")).unwrap(),
+            ..Default::default()
+        };
+        let tracker = TrackerParams::new(&torrent);
+        assert!(matches!(
+            tracker.scrape(&[[0; 20]]).await,
+            Err(TrackerError::Failure(reason)) if reason == "denied"
+        ));
+    }
+
+    // a UDP tracker on loopback, counting the connects it gets
+    async fn udp_loopback() -> (Url, Arc<AtomicUsize>) {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (read, from) = socket.recv_from(&mut buf).await.unwrap();
+                let req = &buf[..read];
+                let mut res = req[8..12].to_vec();
+                res.extend_from_slice(&req[12..16]);
+                match u32::from_be_bytes(req[8..12].try_into().unwrap()) {
+                    0 => {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        res.extend_from_slice(&42_i64.to_be_bytes());
+                    }
+                    // an interval of 900, no leechers, one seeder, no peers
+                    1 => {
+                        res.extend_from_slice(&900_i32.to_be_bytes());
+                        res.extend_from_slice(&0_i32.to_be_bytes());
+                        res.extend_from_slice(&1_i32.to_be_bytes());
+                    }
+                    // seeders = first byte of the hash, no downloads, no leechers
+                    _ => {
+                        for hash in req[16..].chunks_exact(20) {
+                            res.extend_from_slice(&(hash[0] as i32).to_be_bytes());
+                            res.extend_from_slice(&[0; 8]);
+                        }
+                    }
+                }
+                socket.send_to(&res, from).await.unwrap();
+            }
+        });
+        (Url::new(&format!("udp://{}", addr)).unwrap(), connects)
+    }
+
+    #[tokio::test]
+    async fn scrape_udp_loopback() {
+        let (announce, _) = udp_loopback().await;
+        let torrent = Torrent {
+            announce,
+            ..Default::default()
+        };
+        let tracker = TrackerParams::new(&torrent);
+        let stats = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+        assert_eq!(stats[&[1; 20]].complete, 1);
+        assert_eq!(stats[&[2; 20]].complete, 2);
+    }
+
+    #[tokio::test]
+    async fn udp_connection_is_reused() {
+        let (announce, connects) = udp_loopback().await;
+        let torrent = Torrent {
+            announce,
+            hash: [7; 20],
+            ..Default::default()
+        };
+        let tracker = TrackerParams::new(&torrent);
+        let (client, udp) = (HttpClient::new(), UdpTrackers::default());
+        for _ in 0..2 {
+            let peers = tracker.announce_with(&client, &udp).await.unwrap();
+            assert_eq!((peers.interval, peers.seeders), (900, 1));
+        }
+        tracker
+            .scrape_with(&client, &udp, &[[7; 20]])
+            .await
+            .unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn announce_counts() {
+        let data = b"d8:completei3e10:incompletei7e8:intervali900e5:peers0:e".to_vec();
+        let peers = TrackerParams::bencoded_ip_mode(data).unwrap();
+        assert_eq!((peers.seeders, peers.leechers, peers.interval), (3, 7, 900));
+        assert!(peers.peer.is_empty());
+        assert_eq!(peers.min_interval, None);
+    }
+
+    #[test]
+    fn announce_extras() {
+        let data = b"d8:intervali900e12:min intervali60e5:peers0:10:tracker id3:a&b15:warning message4:slowe".to_vec();
+        let peers = TrackerParams::bencoded_ip_mode(data).unwrap();
+        assert_eq!(peers.min_interval, Some(60));
+        assert_eq!(peers.tracker_id.as_deref(), Some(&b"a&b"[..]));
+        assert_eq!(peers.warning.as_deref(), Some("slow"));
+
+        let data = b"d14:failure reason6:denied8:intervali900e5:peers0:e".to_vec();
+        let err = TrackerParams::bencoded_ip_mode(data).unwrap_err();
+        assert!(matches!(err, TrackerError::Failure(reason) if reason == "denied"));
+    }
+
+    #[tokio::test]
+    async fn announce_query() {
+        let (addr, request) = serve_once(b"d8:intervali900e5:peers0:e".to_vec()).await;
+        let torrent = Torrent {
+            announce: Url::new(&format!("http://{addr}/announce")).unwrap(),
+            ..Default::default()
+        };
+        let mut tracker = TrackerParams::new(&torrent);
+        tracker.event = Event::Empty;
+        tracker.key = 0xbeef;
+        tracker.numwant = Some(50);
+        tracker.trackerid = Some(b"a b".to_vec());
+        tracker.announce().await.unwrap();
+
+        let request = request.await.unwrap();
+        assert!(request.contains("key=0000beef"));
+        assert!(request.contains("numwant=50"));
+        assert!(request.contains("trackerid=a%20b"));
+        assert!(!request.contains("event"));
+    }
+
     #[test]
     fn parse_compact_ip() {
         let ip = &[127, 0, 0, 1, 31, 144, 0, 0, 0, 0, 0, 0];
-        let mut expected = vec![Url::from_ip_bytes(
-            &[127, 0, 0, 1],
-            8080,
-            uttd::url::Scheme::HTTP,
-        )];
-        expected.push(Url::from_ip_bytes(
-            &[0, 0, 0, 0],
-            0,
-            uttd::url::Scheme::HTTP,
-        ));
+        let expected: Vec<SocketAddr> = vec![
+            "127.0.0.1:8080".parse().unwrap(),
+            "0.0.0.0:0".parse().unwrap(),
+        ];
 
         let ips = TrackerParams::compact_ip_mode(ip);
         assert_eq!(ips, expected);
     }
 
+    #[test]
+    fn parse_peers6() {
+        let mut data = b"d8:intervali100e5:peers6:".to_vec();
+        data.extend_from_slice(&[127, 0, 0, 1, 31, 144]);
+        data.extend_from_slice(b"6:peers618:");
+        data.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        data.extend_from_slice(&[0; 11]);
+        data.extend_from_slice(&[1, 0x1a, 0xe1]);
+        data.push(b'e');
+        let res = TrackerParams::bencoded_ip_mode(data).unwrap();
+        let expected: Vec<SocketAddr> = vec![
+            "127.0.0.1:8080".parse().unwrap(),
+            "[2001:db8::1]:6881".parse().unwrap(),
+        ];
+        assert_eq!(res.peer, expected);
+    }
+
     #[test]
     fn parse_non_compact_ip() {
-        let data = "d8:intervali100e5:peersld2:ip13:192.168.1.1054:porti6881eed2:ip9:127.0.0.14:porti8080eeee"
+        let data = "d8:intervali100e5:peersld2:ip13:192.168.1.1054:porti6881eed2:ip3:::14:porti8080eed2:ip11:example.com4:porti1eeee"
             .as_bytes()
             .to_vec();
-        let res = TrackerParams::bencoded_ip_mode(data);
-        let expected = vec![
-            Url::from_ip("192.168.1.105", 6881).unwrap(),
-            Url::from_ip("127.0.0.1", 8080).unwrap(),
+        let res = TrackerParams::bencoded_ip_mode(data).unwrap();
+        let expected: Vec<SocketAddr> = vec![
+            "192.168.1.105:6881".parse().unwrap(),
+            "[::1]:8080".parse().unwrap(),
         ];
-        assert_eq!(res.1, expected);
+        assert_eq!(res.peer, expected);
     }
 }
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+
+use crypto::sha1::Sha1;
+
+use crate::{scheduler::CompletedPiece, torrent::Torrent};
+
+// how many bad pieces a peer may send blocks of before it's banned;
+// a single failure may be someone else's fault
+pub const BAN_THRESHOLD: u32 = 3;
+
+/// What became of a completed piece
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// the piece is good, to be kept and announced
+    Passed,
+    /// the data is bad and must be downloaded again;
+    /// `banned` are the peers that took part once too often
+    Failed { banned: Vec<usize> },
+}
+
+/// Checks completed pieces against the hashes of the torrent
+/// and keeps track of the peers that sent bad data
+#[derive(Debug)]
+pub struct PieceVerifier {
+    // `Info::pieces`: 20 bytes of SHA1 per piece
+    hashes: Vec<u8>,
+    failures: HashMap<usize, u32>,
+    banned: HashSet<usize>,
+    ban_threshold: u32,
+}
+
+impl PieceVerifier {
+    /// `hashes` is the concatenation of the piece hashes, as in `Info::pieces`
+    pub fn new(hashes: Vec<u8>) -> Self {
+        Self {
+            hashes,
+            failures: HashMap::new(),
+            banned: HashSet::new(),
+            ban_threshold: BAN_THRESHOLD,
+        }
+    }
+
+    pub fn from_torrent(torrent: &Torrent) -> Self {
+        Self::new(torrent.info.pieces.clone())
+    }
+
+    /// Ban peers after they sent blocks of `failures` bad pieces
+    pub fn with_ban_threshold(mut self, failures: u32) -> Self {
+        self.ban_threshold = failures;
+        self
+    }
+
+    /// `data` has the hash of piece `index`
+    pub fn check(&self, index: u32, data: &[u8]) -> bool {
+        let start = index as usize * 20;
+        let Some(expected) = self.hashes.get(start..start + 20) else {
+            return false;
+        };
+        let mut sha = Sha1::new();
+        sha.append_hash(data);
+        sha.get_hash() == expected
+    }
+
+    /// Check `piece`, blaming every peer that sent a block of it if it's bad
+    pub fn verify(&mut self, piece: &CompletedPiece) -> Verdict {
+        if self.check(piece.index, &piece.data) {
+            return Verdict::Passed;
+        }
+        let mut banned = Vec::new();
+        for peer in &piece.peers {
+            let failures = self.failures.entry(*peer).or_default();
+            *failures += 1;
+            if *failures >= self.ban_threshold && self.banned.insert(*peer) {
+                banned.push(*peer);
+            }
+        }
+        Verdict::Failed { banned }
+    }
+
+    /// How many bad pieces `peer` sent blocks of
+    pub fn failures(&self, peer: usize) -> u32 {
+        self.failures.get(&peer).copied().unwrap_or(0)
+    }
+
+    pub fn is_banned(&self, peer: usize) -> bool {
+        self.banned.contains(&peer)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crypto::sha1::Sha1;
+
+    use super::{PieceVerifier, Verdict};
+    use crate::scheduler::CompletedPiece;
+
+    /// `Info::pieces` for `content` cut in pieces of `piece_length`
+    pub(crate) fn hashes(content: &[u8], piece_length: usize) -> Vec<u8> {
+        content
+            .chunks(piece_length)
+            .flat_map(|piece| {
+                let mut sha = Sha1::new();
+                sha.append_hash(piece);
+                sha.get_hash()
+            })
+            .collect()
+    }
+
+    fn piece(index: u32, data: &[u8], peers: &[usize]) -> CompletedPiece {
+        CompletedPiece {
+            index,
+            data: data.to_vec(),
+            peers: peers.to_vec(),
+        }
+    }
+
+    #[test]
+    fn check_against_hashes() {
+        let content: Vec<u8> = (0..250).collect();
+        let verifier = PieceVerifier::new(hashes(&content, 100));
+        assert!(verifier.check(0, &content[..100]));
+        assert!(verifier.check(2, &content[200..]));
+        assert!(!verifier.check(1, &content[..100]));
+        assert!(!verifier.check(2, &content[199..]));
+        // no such piece
+        assert!(!verifier.check(3, &[]));
+    }
+
+    #[test]
+    fn repeat_offenders_are_banned() {
+        let content = [7u8; 64];
+        let mut verifier = PieceVerifier::new(hashes(&content, 32)).with_ban_threshold(2);
+        let bad = [0u8; 32];
+        assert_eq!(
+            verifier.verify(&piece(0, &content[..32], &[1, 2])),
+            Verdict::Passed
+        );
+        assert_eq!(
+            verifier.verify(&piece(0, &bad, &[1, 2])),
+            Verdict::Failed { banned: vec![] }
+        );
+        assert_eq!(verifier.failures(2), 1);
+        assert_eq!(
+            verifier.verify(&piece(1, &bad, &[1, 3])),
+            Verdict::Failed { banned: vec![1] }
+        );
+        assert!(verifier.is_banned(1));
+        assert!(!verifier.is_banned(2));
+        // banned once only
+        assert_eq!(
+            verifier.verify(&piece(1, &bad, &[1, 3])),
+            Verdict::Failed { banned: vec![3] }
+        );
+        assert_eq!(verifier.failures(1), 3);
+    }
+}
@@ -0,0 +1,232 @@
+// REFERENCE: https://www.bittorrent.org/beps/bep_0019.html
+
+use uttd::{http::HttpClient, url::Url, UttdError};
+
+use crate::torrent::{FileMode, Torrent};
+
+/// A HTTP server that hosts the torrent's files (GetRight-style web seed)
+/// Pieces are fetched with ranged GET requests on the files they span.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSeed {
+    pub url: Url,
+}
+
+impl WebSeed {
+    pub fn new(url: Url) -> Self {
+        Self { url }
+    }
+
+    /// Location of a file on the web seed's host
+    /// Single file torrents: the url is the file itself, unless it ends with a '/',
+    /// in which case the torrent's name is appended.
+    /// Multi file torrents: {url}/{name}/{path...}
+    pub fn location(&self, torrent: &Torrent, path: &[String]) -> String {
+        let mut location = self.url.location.trim_start_matches('/').to_owned();
+        let multi = matches!(torrent.info.mode, FileMode::MultiMode { .. });
+
+        if multi || location.ends_with('/') || location.is_empty() {
+            if !location.is_empty() && !location.ends_with('/') {
+                location.push('/');
+            }
+            location.push_str(&escape(&torrent.info.name));
+        }
+        for component in path {
+            location.push('/');
+            location.push_str(&escape(component));
+        }
+        location
+    }
+
+    /// Download piece `index` from the web seed
+    /// A piece may span multiple files in multi file mode; each part is fetched
+    /// with its own ranged request.
+    pub async fn fetch_piece(
+        &self,
+        client: &HttpClient,
+        torrent: &Torrent,
+        index: usize,
+    ) -> Result<Vec<u8>, UttdError> {
+        let piece_length = torrent.info.piece_length as u64;
+        let total = torrent.calculate_left() as u64;
+        let start = index as u64 * piece_length;
+        if piece_length == 0 || start >= total {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into());
+        }
+        let end = (start + piece_length).min(total);
+
+        let files: Vec<(Vec<String>, u64)> = match &torrent.info.mode {
+            FileMode::SingleMode { length } => vec![(vec![], *length as u64)],
+            FileMode::MultiMode { files } => files
+                .iter()
+                .map(|f| (f.path.clone(), f.length as u64))
+                .collect(),
+        };
+
+        let mut piece = Vec::with_capacity((end - start) as usize);
+        let mut offset = 0;
+        for (path, length) in files {
+            let (file_start, file_end) = (offset, offset + length);
+            offset = file_end;
+            if file_end <= start || file_start >= end || length == 0 {
+                continue;
+            }
+            let range = (start.max(file_start) - file_start)..(end.min(file_end) - file_start);
+            let expected = (range.end - range.start) as usize;
+
+            let location = self.location(torrent, &path);
+            let res = client.get_range(&self.url, &location, range).await?;
+            if res.status != 206 {
+                return Err(UttdError::HttpStatus(res.status));
+            }
+            if res.body.len() != expected {
+                return Err(UttdError::ProtocolViolation(
+                    "web seed sent a partial range",
+                ));
+            }
+            piece.extend_from_slice(&res.body);
+        }
+        Ok(piece)
+    }
+}
+
+// percent-encode everything but unreserved characters (RFC 3986)
+fn escape(component: &str) -> String {
+    let mut escaped = String::new();
+    for b in component.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                escaped.push(b as char)
+            }
+            _ => escaped.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use uttd::{http::HttpClient, url::Url};
+
+    use super::WebSeed;
+    use crate::torrent::{FileMode, Files, Torrent};
+
+    // a tiny web server that answers ranged GETs for `files`, keyed by request path
+    async fn serve(files: HashMap<&'static str, &'static [u8]>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut sock, _)) = listener.accept().await {
+                let files = files.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 4096];
+                    loop {
+                        let mut req = Vec::new();
+                        while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+                            match sock.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => req.extend_from_slice(&buf[..n]),
+                            }
+                        }
+                        let req = String::from_utf8(req).unwrap();
+                        let path = req.split(' ').nth(1).unwrap();
+                        let Some(content) = files.get(path) else {
+                            let res = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+                            sock.write_all(res.as_bytes()).await.unwrap();
+                            continue;
+                        };
+                        let range = req
+                            .lines()
+                            .find_map(|l| l.strip_prefix("Range: bytes="))
+                            .unwrap();
+                        let (a, b) = range.split_once('-').unwrap();
+                        let (a, b): (usize, usize) = (a.parse().unwrap(), b.parse().unwrap());
+                        let body = &content[a..=b];
+                        let head = format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\n",
+                            body.len()
+                        );
+                        sock.write_all(head.as_bytes()).await.unwrap();
+                        sock.write_all(body).await.unwrap();
+                    }
+                });
+            }
+        });
+        addr.to_string()
+    }
+
+    fn torrent(name: &str, piece_length: usize, mode: FileMode) -> Torrent {
+        let mut torrent = Torrent::default();
+        torrent.info.name = name.to_owned();
+        torrent.info.piece_length = piece_length;
+        torrent.info.mode = mode;
+        torrent
+    }
+
+    #[test]
+    fn single_file_location() {
+        let t = torrent("debian 12.iso", 4, FileMode::SingleMode { length: 8 });
+        let seed = WebSeed::new(Url::new("http://seed.example.com/files/").unwrap());
+        assert_eq!(seed.location(&t, &[]), "files/debian%2012.iso");
+
+        let seed = WebSeed::new(Url::new("http://seed.example.com/files/d.iso").unwrap());
+        assert_eq!(seed.location(&t, &[]), "files/d.iso");
+    }
+
+    #[test]
+    fn multi_file_location() {
+        let files = vec![Files {
+            length: 8,
+            path: vec!["sub".into(), "a.txt".into()],
+        }];
+        let t = torrent("movie", 4, FileMode::MultiMode { files });
+        let seed = WebSeed::new(Url::new("http://seed.example.com/files").unwrap());
+        assert_eq!(
+            seed.location(&t, &["sub".into(), "a.txt".into()]),
+            "files/movie/sub/a.txt"
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_single_file_piece() {
+        let addr = serve(HashMap::from([("/seed/a.bin", &b"0123456789"[..])])).await;
+        let t = torrent("a.bin", 4, FileMode::SingleMode { length: 10 });
+        let seed = WebSeed::new(Url::new(&format!("http://{addr}/seed/")).unwrap());
+        let client = HttpClient::new();
+
+        assert_eq!(seed.fetch_piece(&client, &t, 1).await.unwrap(), b"4567");
+        // last piece is short
+        assert_eq!(seed.fetch_piece(&client, &t, 2).await.unwrap(), b"89");
+        assert!(seed.fetch_piece(&client, &t, 3).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_piece_spanning_files() {
+        let addr = serve(HashMap::from([
+            ("/movie/a.txt", &b"abc"[..]),
+            ("/movie/sub/b.txt", &b"defgh"[..]),
+        ]))
+        .await;
+        let files = vec![
+            Files {
+                length: 3,
+                path: vec!["a.txt".into()],
+            },
+            Files {
+                length: 5,
+                path: vec!["sub".into(), "b.txt".into()],
+            },
+        ];
+        let t = torrent("movie", 4, FileMode::MultiMode { files });
+        let seed = WebSeed::new(Url::new(&format!("http://{addr}/")).unwrap());
+        let client = HttpClient::new();
+
+        assert_eq!(seed.fetch_piece(&client, &t, 0).await.unwrap(), b"abcd");
+        assert_eq!(seed.fetch_piece(&client, &t, 1).await.unwrap(), b"efgh");
+    }
+}
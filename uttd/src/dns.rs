@@ -0,0 +1,380 @@
+// REFERENCE: https://www.rfc-editor.org/rfc/rfc8305 (Happy Eyeballs v2)
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use tokio::{net::TcpStream, task::JoinSet};
+
+use crate::{error::UrlError, UttdError};
+
+// how long a lookup is reused, the system resolver doesn't tell us the records' TTL
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+// head start every connection attempt gets before the next address is tried
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolves host names and connects to them.
+/// Lookups are cached for a while. Static hosts, an `/etc/hosts` of its own,
+/// take precedence over the system resolver.
+/// A host with both IPv4 and IPv6 addresses is connected to with Happy Eyeballs:
+/// its addresses are tried alternating between the families, each attempt getting
+/// a short head start, and the first connection to succeed wins.
+///
+/// ```
+/// use uttd::dns::Resolver;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let resolver = Resolver::new();
+///     resolver.add_host("tracker.test", "127.0.0.1".parse().unwrap());
+///     let addrs = resolver.lookup("tracker.test:6969").await.unwrap();
+///     assert_eq!(addrs, ["127.0.0.1:6969".parse().unwrap()]);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Resolver {
+    ttl: Duration,
+    hosts: Mutex<HashMap<String, Vec<IpAddr>>>,
+    // domain -> its addresses and when they were looked up
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Self::with_ttl(DEFAULT_TTL)
+    }
+
+    /// Create a resolver that reuses a lookup for `ttl`
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            hosts: Mutex::new(HashMap::new()),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The resolver every connection made by this crate goes through
+    pub fn global() -> &'static Resolver {
+        static GLOBAL: OnceLock<Resolver> = OnceLock::new();
+        GLOBAL.get_or_init(Resolver::new)
+    }
+
+    /// Resolve `name` to `addr` without asking the system resolver.
+    /// Adding more addresses for the same name gives it all of them
+    pub fn add_host(&self, name: &str, addr: IpAddr) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let addrs = hosts.entry(name.to_ascii_lowercase()).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    /// Resolve `host` ("{domain}:{port}") to the addresses to connect to,
+    /// in the order they should be tried
+    pub async fn lookup(&self, host: &str) -> Result<Vec<SocketAddr>, UttdError> {
+        let (domain, port) = split_host(host)?;
+        let ips = match self.known(&domain) {
+            Some(ips) => ips,
+            None => {
+                let ips: Vec<_> = tokio::net::lookup_host((domain.as_str(), port))
+                    .await
+                    .map(|addrs| addrs.map(|a| a.ip()).collect())
+                    .unwrap_or_default();
+                self.remember(&domain, &ips);
+                ips
+            }
+        };
+        socket_addrs(host, ips, port)
+    }
+
+    /// Blocking version of `lookup`
+    pub fn resolve(&self, host: &str) -> Result<Vec<SocketAddr>, UttdError> {
+        let (domain, port) = split_host(host)?;
+        let ips = match self.known(&domain) {
+            Some(ips) => ips,
+            None => {
+                let ips: Vec<_> = (domain.as_str(), port)
+                    .to_socket_addrs()
+                    .map(|addrs| addrs.map(|a| a.ip()).collect())
+                    .unwrap_or_default();
+                self.remember(&domain, &ips);
+                ips
+            }
+        };
+        socket_addrs(host, ips, port)
+    }
+
+    /// Open a TCP connection to `host`, racing its addresses
+    pub async fn connect(&self, host: &str) -> Result<TcpStream, UttdError> {
+        let addrs = self.lookup(host).await?;
+        race(
+            addrs,
+            CONNECTION_ATTEMPT_DELAY,
+            TcpStream::connect::<SocketAddr>,
+        )
+        .await
+        .map_err(|e| UttdError::from_io(e, host))
+    }
+
+    // addresses of `domain` that don't need a lookup
+    fn known(&self, domain: &str) -> Option<Vec<IpAddr>> {
+        if let Ok(ip) = domain.parse() {
+            return Some(vec![ip]);
+        }
+        if let Some(ips) = self.hosts.lock().unwrap().get(domain) {
+            return Some(ips.clone());
+        }
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(domain)
+            .filter(|(_, at)| at.elapsed() < self.ttl)
+            .map(|(ips, _)| ips.clone())
+    }
+
+    // failed lookups aren't cached, the next connect asks again
+    fn remember(&self, domain: &str, ips: &[IpAddr]) {
+        if ips.is_empty() {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (_, at)| at.elapsed() < self.ttl);
+        cache.insert(domain.to_owned(), (ips.to_vec(), Instant::now()));
+    }
+}
+
+// any local address of `addr`'s family, for a UDP socket that is to reach `addr`
+pub(crate) fn unspecified_for(addr: &SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0; 4], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0; 16], 0)),
+    }
+}
+
+// "{domain}:{port}" or "[{ipv6}]:{port}"
+fn split_host(host: &str) -> Result<(String, u16), UttdError> {
+    let (domain, port) = host
+        .rsplit_once(':')
+        .ok_or(UttdError::InvalidUrl(UrlError::InvalidUrl))?;
+    let port = port.parse().map_err(UrlError::from)?;
+    let domain = domain.trim_start_matches('[').trim_end_matches(']');
+    Ok((domain.to_ascii_lowercase(), port))
+}
+
+fn socket_addrs(host: &str, ips: Vec<IpAddr>, port: u16) -> Result<Vec<SocketAddr>, UttdError> {
+    if ips.is_empty() {
+        return Err(UttdError::DnsFailure(host.to_owned()));
+    }
+    let addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, port));
+    Ok(interleave(addrs.collect()))
+}
+
+/// Alternate between the address families, starting with the family of the
+/// address the resolver put first
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let ipv6_first = first.is_ipv6();
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == ipv6_first);
+    let mut other = other.into_iter();
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    for addr in preferred {
+        ordered.push(addr);
+        ordered.extend(other.next());
+    }
+    ordered.extend(other);
+    ordered
+}
+
+/// Connect to `addrs` in order. The next attempt starts as soon as the previous
+/// one failed, or once it has had `delay` to succeed. The first connection made
+/// wins and the attempts still going are dropped
+async fn race<T, F, Fut>(addrs: Vec<SocketAddr>, delay: Duration, connect: F) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut addrs = addrs.into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last = io::Error::from(io::ErrorKind::AddrNotAvailable);
+    loop {
+        match addrs.next() {
+            Some(addr) => {
+                attempts.spawn(connect(addr));
+            }
+            None if attempts.is_empty() => return Err(last),
+            None => {}
+        }
+        let more = !addrs.as_slice().is_empty();
+        tokio::select! {
+            Some(done) = attempts.join_next() => match done {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last = e,
+                Err(e) => last = e.into(),
+            },
+            _ = tokio::time::sleep(delay), if more => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use tokio::net::TcpListener;
+
+    use super::{interleave, race, Resolver};
+    use crate::UttdError;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn static_hosts() {
+        let resolver = Resolver::new();
+        resolver.add_host("Tracker.Test", "127.0.0.1".parse().unwrap());
+        resolver.add_host("tracker.test", "::1".parse().unwrap());
+        assert_eq!(
+            resolver.lookup("TRACKER.test:80").await.unwrap(),
+            addrs(&["127.0.0.1:80", "[::1]:80"])
+        );
+        assert_eq!(
+            resolver.resolve("tracker.test:80").unwrap(),
+            addrs(&["127.0.0.1:80", "[::1]:80"])
+        );
+
+        // literals aren't looked up
+        assert_eq!(
+            resolver.lookup("[::1]:6881").await.unwrap(),
+            addrs(&["[::1]:6881"])
+        );
+        assert_eq!(
+            resolver.resolve("10.0.0.1:6881").unwrap(),
+            addrs(&["10.0.0.1:6881"])
+        );
+    }
+
+    #[tokio::test]
+    async fn lookup_failures() {
+        let resolver = Resolver::new();
+        let res = resolver.lookup("tracker.test").await;
+        assert!(matches!(res, Err(UttdError::InvalidUrl(_))), "{res:?}");
+        let res = resolver.lookup("tracker.test:http").await;
+        assert!(matches!(res, Err(UttdError::InvalidUrl(_))), "{res:?}");
+        let res = resolver.lookup("tracker.invalid:80").await;
+        assert!(matches!(res, Err(UttdError::DnsFailure(h)) if h == "tracker.invalid:80"));
+        assert!(resolver.cache.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lookups_expire() {
+        let resolver = Resolver::with_ttl(Duration::from_millis(100));
+        resolver.lookup("localhost:80").await.unwrap();
+        assert!(resolver.known("localhost").is_some());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(resolver.known("localhost").is_none());
+        resolver.lookup("localhost:80").await.unwrap();
+        assert!(resolver.known("localhost").is_some());
+    }
+
+    #[test]
+    fn families_alternate() {
+        let mixed = addrs(&["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]);
+        assert_eq!(
+            interleave(mixed),
+            addrs(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"])
+        );
+        let mixed = addrs(&["10.0.0.1:1", "10.0.0.2:1", "10.0.0.3:1", "[::1]:1"]);
+        assert_eq!(
+            interleave(mixed),
+            addrs(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1", "10.0.0.3:1"])
+        );
+        assert!(interleave(vec![]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_address_is_overtaken() {
+        let started = Instant::now();
+        let winner = race(
+            addrs(&["[::1]:1", "10.0.0.1:1"]),
+            Duration::from_millis(50),
+            |addr| async move {
+                if addr.is_ipv6() {
+                    // a black hole
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }
+                Ok(addr)
+            },
+        )
+        .await
+        .unwrap();
+        assert!(winner.is_ipv4());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn failures_fall_through() {
+        let started = Instant::now();
+        let res = race(
+            addrs(&["10.0.0.1:1", "10.0.0.2:1", "10.0.0.3:1"]),
+            Duration::from_secs(10),
+            |addr| async move {
+                match addr.ip().to_string().as_str() {
+                    "10.0.0.3" => Ok(addr),
+                    _ => Err(io::Error::from(io::ErrorKind::ConnectionRefused)),
+                }
+            },
+        )
+        .await;
+        assert_eq!(res.unwrap(), "10.0.0.3:1".parse().unwrap());
+        // a failed attempt doesn't wait for the delay
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let res = race(addrs(&["10.0.0.1:1"]), Duration::ZERO, |_| async {
+            io::Result::<()>::Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+        })
+        .await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn connect_through_static_host() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let resolver = Resolver::new();
+        // nothing listens on ::1, or there's no IPv6 at all
+        resolver.add_host("peer.test", "::1".parse().unwrap());
+        resolver.add_host("peer.test", "127.0.0.1".parse().unwrap());
+
+        let stream = resolver
+            .connect(&format!("peer.test:{port}"))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+
+        drop(listener);
+        let res = resolver.connect(&format!("peer.test:{port}")).await;
+        assert!(
+            matches!(res, Err(UttdError::ConnectionRefused(_))),
+            "{res:?}"
+        );
+    }
+}
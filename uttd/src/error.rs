@@ -17,6 +17,8 @@ impl Display for UrlError {
     }
 }
 
+impl std::error::Error for UrlError {}
+
 impl From<ParseIntError> for UrlError {
     fn from(value: ParseIntError) -> Self {
         Self::ParseIntError(value)
@@ -0,0 +1,631 @@
+use std::{collections::HashMap, ops::Range, sync::Mutex, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    dns::Resolver,
+    error::UrlError,
+    proxy::Proxy,
+    tls::{AsyncTlsTcpStream, TlsConfig},
+    url::{Scheme, Url},
+    UttdError,
+};
+
+// default time allowed for a whole request, from connect to the last byte of the body
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+// idle connections kept around per host
+const MAX_IDLE_PER_HOST: usize = 4;
+// refuse response heads larger than this
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+// refuse response bodies larger than this, a web seed piece fits many times over
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Async HTTP/1.1 client
+/// Used for tracker announces, scrapes and web seed (BEP 19) downloads.
+/// Idle connections are kept alive and reused for later requests to the same host.
+///
+/// ```rust,no_run
+/// use uttd::http::HttpClient;
+/// use uttd::url::Url;
+/// # async fn run() {
+/// let client = HttpClient::new();
+/// let url = Url::new("http://bttracker.debian.org:6969/announce").unwrap();
+/// let res = client.get(&url, "announce").await.unwrap();
+/// assert_eq!(res.status, 200);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct HttpClient {
+    timeout: Duration,
+    tls: TlsConfig,
+    proxy: Option<Proxy>,
+    // idle connections, keyed by "{scheme}://{host}:{port}"
+    pool: Mutex<HashMap<String, Vec<Connection>>>,
+}
+
+#[derive(Debug)]
+enum Connection {
+    Plain(TcpStream),
+    Tls(Box<AsyncTlsTcpStream>),
+}
+
+/// A fully read HTTP response
+#[derive(Debug, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Get the value of the header `name`. Header names are case-insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// 2xx responses
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpClient {
+    pub fn new() -> Self {
+        Self::with_timeout(DEFAULT_TIMEOUT)
+    }
+
+    /// Create a client whose requests fail with `UttdError::RequestTimeout`
+    /// if they take longer than `timeout`
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            tls: TlsConfig::new(),
+            proxy: None,
+            pool: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Use `tls` to verify HTTPS servers, e.g. to trust a private tracker's own CA
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Connect to every host through `proxy`
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Perform a GET request on `url`'s host
+    /// `path` refers to the location of the url + any params, same as `Stream::get`
+    pub async fn get(&self, url: &Url, path: &str) -> Result<HttpResponse, UttdError> {
+        self.request(url, path, None).await
+    }
+
+    /// Perform a ranged GET request for the bytes in `range` (end exclusive)
+    /// Servers that ignore the `Range` header and answer with the whole
+    /// resource are handled by slicing the body.
+    pub async fn get_range(
+        &self,
+        url: &Url,
+        path: &str,
+        range: Range<u64>,
+    ) -> Result<HttpResponse, UttdError> {
+        if range.is_empty() {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into());
+        }
+        let header = format!("Range: bytes={}-{}\r\n", range.start, range.end - 1);
+        let mut res = self.request(url, path, Some(&header)).await?;
+
+        if res.status == 200 {
+            let start = range.start as usize;
+            let end = (range.end as usize).min(res.body.len());
+            if start >= end {
+                return Err(UttdError::ProtocolViolation(
+                    "range beyond the end of the body",
+                ));
+            }
+            res.body = res.body[start..end].to_vec();
+            res.status = 206;
+        }
+        Ok(res)
+    }
+
+    async fn request(
+        &self,
+        url: &Url,
+        path: &str,
+        extra_headers: Option<&str>,
+    ) -> Result<HttpResponse, UttdError> {
+        if url.scheme == Scheme::UDP {
+            return Err(UttdError::InvalidUrl(UrlError::InvalidUrl));
+        }
+        let key = Self::pool_key(url);
+        let head = format!(
+            "GET /{} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\n{}\r\n",
+            path.trim_start_matches('/'),
+            url.host_header(),
+            extra_headers.unwrap_or("")
+        );
+
+        tokio::time::timeout(self.timeout, async {
+            // a pooled connection may have been closed by the server while idle.
+            // If it fails before any response arrives, retry once on a fresh connection
+            if let Some(conn) = self.take_idle(&key) {
+                if let Ok(res) = self.exchange(&key, conn, head.as_bytes()).await {
+                    return Ok(res);
+                }
+            }
+            let conn = self.connect(url).await?;
+            self.exchange(&key, conn, head.as_bytes()).await
+        })
+        .await?
+    }
+
+    async fn connect(&self, url: &Url) -> Result<Connection, UttdError> {
+        let tcp = match &self.proxy {
+            Some(proxy) => proxy.connect(&url.authority()).await?,
+            None => Resolver::global().connect(&url.authority()).await?,
+        };
+        match url.scheme {
+            Scheme::HTTPS => {
+                let tls = self.tls.connect_async(url.domain(), tcp).await?;
+                Ok(Connection::Tls(Box::new(tls)))
+            }
+            _ => Ok(Connection::Plain(tcp)),
+        }
+    }
+
+    /// Write the request and read back a whole response.
+    /// The connection is returned to the pool if the server allows it
+    async fn exchange(
+        &self,
+        key: &str,
+        mut conn: Connection,
+        request: &[u8],
+    ) -> Result<HttpResponse, UttdError> {
+        let (response, reusable) = match &mut conn {
+            Connection::Plain(s) => Self::exchange_on(s, request).await?,
+            Connection::Tls(s) => Self::exchange_on(s.as_mut(), request).await?,
+        };
+        if reusable {
+            self.put_idle(key, conn);
+        }
+        Ok(response)
+    }
+
+    async fn exchange_on<S>(
+        stream: &mut S,
+        request: &[u8],
+    ) -> Result<(HttpResponse, bool), UttdError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream.write_all(request).await?;
+        stream.flush().await?;
+
+        let mut buf = Vec::new();
+        let head_end = loop {
+            if let Some(pos) = find(&buf, b"\r\n\r\n") {
+                break pos;
+            }
+            if buf.len() > MAX_HEAD_SIZE {
+                return Err(UttdError::ProtocolViolation("response head too large"));
+            }
+            Self::fill(stream, &mut buf).await?;
+        };
+        let (status, headers) = parse_head(&buf[..head_end])?;
+        let mut rest = buf.split_off(head_end + 4);
+
+        let mut response = HttpResponse {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+
+        let mut reusable = !response
+            .header("connection")
+            .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+
+        let chunked = response
+            .header("transfer-encoding")
+            .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"));
+
+        if chunked {
+            response.body = Self::read_chunked(stream, rest).await?;
+        } else if let Some(len) = response.header("content-length") {
+            let len: usize = len
+                .trim()
+                .parse()
+                .map_err(|_| UttdError::ProtocolViolation("invalid content-length"))?;
+            if len > MAX_BODY_SIZE {
+                return Err(UttdError::ProtocolViolation("body too large"));
+            }
+            while rest.len() < len {
+                Self::fill(stream, &mut rest).await?;
+            }
+            rest.truncate(len);
+            response.body = rest;
+        } else if status == 204 || status == 304 {
+            response.body = rest;
+        } else {
+            // the body is delimited by the server closing the connection.
+            // TLS servers that skip close_notify end with an unexpected EOF
+            match stream.read_to_end(&mut rest).await {
+                Err(e) if e.kind() != std::io::ErrorKind::UnexpectedEof => return Err(e.into()),
+                _ => (),
+            }
+            response.body = rest;
+            reusable = false;
+        }
+
+        Ok((response, reusable))
+    }
+
+    async fn read_chunked<S>(stream: &mut S, mut buf: Vec<u8>) -> Result<Vec<u8>, UttdError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut body = Vec::new();
+        loop {
+            let line_end = loop {
+                if let Some(pos) = find(&buf, b"\r\n") {
+                    break pos;
+                }
+                Self::fill(stream, &mut buf).await?;
+            };
+            // chunk extensions come after a ';'
+            let size = std::str::from_utf8(&buf[..line_end])
+                .ok()
+                .and_then(|line| {
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    usize::from_str_radix(size, 16).ok()
+                })
+                .ok_or(UttdError::ProtocolViolation("invalid chunk size"))?;
+            buf.drain(..line_end + 2);
+
+            if size == 0 {
+                // skip the (empty) trailer section
+                while find(&buf, b"\r\n").is_none() {
+                    Self::fill(stream, &mut buf).await?;
+                }
+                return Ok(body);
+            }
+
+            let end = size
+                .checked_add(2)
+                .filter(|_| size <= MAX_BODY_SIZE - body.len())
+                .ok_or(UttdError::ProtocolViolation("chunk too large"))?;
+            while buf.len() < end {
+                Self::fill(stream, &mut buf).await?;
+            }
+            body.extend_from_slice(&buf[..size]);
+            buf.drain(..end);
+        }
+    }
+
+    /// Read some more bytes from the stream. EOF is an error here as the
+    /// caller always expects more data
+    async fn fill<S>(stream: &mut S, buf: &mut Vec<u8>) -> Result<(), UttdError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut chunk = [0u8; 4096];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+        Ok(())
+    }
+
+    fn take_idle(&self, key: &str) -> Option<Connection> {
+        let mut pool = self.pool.lock().ok()?;
+        pool.get_mut(key)?.pop()
+    }
+
+    fn put_idle(&self, key: &str, conn: Connection) {
+        if let Ok(mut pool) = self.pool.lock() {
+            let idle = pool.entry(key.to_owned()).or_default();
+            if idle.len() < MAX_IDLE_PER_HOST {
+                idle.push(conn);
+            }
+        }
+    }
+
+    // plain and TLS connections to the same host:port must not be mixed up
+    fn pool_key(url: &Url) -> String {
+        let scheme = match url.scheme {
+            Scheme::HTTPS => "https",
+            _ => "http",
+        };
+        format!("{}://{}", scheme, url.authority())
+    }
+
+    /// Number of idle connections kept for `url`'s host
+    pub fn idle_connections(&self, url: &Url) -> usize {
+        self.pool
+            .lock()
+            .map(|p| p.get(&Self::pool_key(url)).map_or(0, |v| v.len()))
+            .unwrap_or(0)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// Parse the status line and headers
+// https://developer.mozilla.org/en-US/docs/Web/HTTP/Messages
+fn parse_head(head: &[u8]) -> Result<(u16, Vec<(String, String)>), UttdError> {
+    let malformed = UttdError::ProtocolViolation("malformed status line");
+    let Ok(head) = std::str::from_utf8(head) else {
+        return Err(UttdError::ProtocolViolation("response head isn't utf-8"));
+    };
+    let mut lines = head.split("\r\n");
+
+    let status_line = lines.next().unwrap_or("");
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    if !version.starts_with("HTTP/") {
+        return Err(malformed);
+    }
+    let Some(status) = parts.next().and_then(|s| s.parse::<u16>().ok()) else {
+        return Err(malformed);
+    };
+
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_owned(), v.trim().to_owned()))
+        .collect();
+
+    Ok((status, headers))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::HttpClient;
+    use crate::{
+        dns::Resolver,
+        tls::{self, TlsConfig},
+        url::Url,
+        UttdError,
+    };
+
+    // Serve `responses` in order, one per request, on any accepted connection.
+    // Returns the server url and a counter of accepted connections
+    async fn serve(responses: Vec<&'static str>) -> (Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        let responses = Arc::new(tokio::sync::Mutex::new(responses.into_iter()));
+
+        tokio::spawn(async move {
+            while let Ok((mut sock, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let responses = responses.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 4096];
+                    loop {
+                        let mut req = Vec::new();
+                        while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+                            match sock.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => req.extend_from_slice(&buf[..n]),
+                            }
+                        }
+                        let Some(res) = responses.lock().await.next() else {
+                            return;
+                        };
+                        sock.write_all(res.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let url = Url::new(&format!("http://{}/announce", addr)).unwrap();
+        (url, accepted)
+    }
+
+    #[tokio::test]
+    async fn content_length_body() {
+        let (url, _) = serve(vec!["HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"]).await;
+        let client = HttpClient::new();
+        let res = client.get(&url, "announce").await.unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn host_names_go_through_the_resolver() {
+        let (url, _) = serve(vec!["HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"]).await;
+        Resolver::global().add_host("tracker.http.test", "127.0.0.1".parse().unwrap());
+        let named = Url::new(&format!(
+            "http://tracker.http.test:{}/",
+            url.port().unwrap()
+        ))
+        .unwrap();
+        let res = HttpClient::new().get(&named, "announce").await.unwrap();
+        assert_eq!(res.body, b"ok");
+    }
+
+    #[tokio::test]
+    async fn through_proxies() {
+        let (url, accepted) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nsocks",
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nhttp",
+        ])
+        .await;
+        let socks5 = crate::proxy::test::socks5_proxy(None).await;
+        let client = HttpClient::new().with_proxy(socks5);
+        assert_eq!(client.get(&url, "announce").await.unwrap().body, b"socks");
+
+        let http = crate::proxy::test::http_proxy(Some(("user", "pass"))).await;
+        let client = HttpClient::new().with_proxy(http);
+        assert_eq!(client.get(&url, "announce").await.unwrap().body, b"http");
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn chunked_body() {
+        let (url, _) = serve(vec![
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\n\r\n",
+        ])
+        .await;
+        let client = HttpClient::new();
+        let res = client.get(&url, "/").await.unwrap();
+        assert_eq!(res.body, b"Wikipedia");
+    }
+
+    #[tokio::test]
+    async fn oversized_chunk() {
+        let (url, _) = serve(vec![
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\nWiki\r\n0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4000001\r\nWiki\r\n0\r\n\r\n",
+        ])
+        .await;
+        let client = HttpClient::new();
+        for _ in 0..2 {
+            let err = client.get(&url, "/").await.unwrap_err();
+            assert!(matches!(
+                err,
+                UttdError::ProtocolViolation("chunk too large")
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_alive_reuses_connection() {
+        let (url, accepted) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na",
+            "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb",
+        ])
+        .await;
+        let client = HttpClient::new();
+        assert_eq!(client.get(&url, "/").await.unwrap().body, b"a");
+        assert_eq!(client.idle_connections(&url), 1);
+        assert_eq!(client.get(&url, "/").await.unwrap().body, b"b");
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn connection_close_is_not_pooled() {
+        let (url, _) = serve(vec![
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 1\r\n\r\na",
+        ])
+        .await;
+        let client = HttpClient::new();
+        client.get(&url, "/").await.unwrap();
+        assert_eq!(client.idle_connections(&url), 0);
+    }
+
+    #[tokio::test]
+    async fn ranged_get() {
+        let (url, _) = serve(vec![
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 2-4/10\r\nContent-Length: 3\r\n\r\ncde",
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabcdefghij",
+        ])
+        .await;
+        let client = HttpClient::new();
+        let res = client.get_range(&url, "file", 2..5).await.unwrap();
+        assert_eq!((res.status, res.body.as_slice()), (206, &b"cde"[..]));
+
+        // the server ignored the range this time
+        let res = client.get_range(&url, "file", 2..5).await.unwrap();
+        assert_eq!((res.status, res.body.as_slice()), (206, &b"cde"[..]));
+    }
+
+    #[tokio::test]
+    async fn https_get_with_custom_root() {
+        let cert = tls::test::self_signed();
+        let addr = tls::test::serve_tls(
+            &cert,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nsecured",
+        )
+        .await;
+        let url = Url::new(&format!("https://{}/announce", addr)).unwrap();
+
+        let mut roots = TlsConfig::empty();
+        roots.add_root_certificate(cert.cert.der()).unwrap();
+        let client = HttpClient::new().with_tls(roots);
+        assert_eq!(client.get(&url, "announce").await.unwrap().body, b"secured");
+        // the TLS session is kept alive too
+        assert_eq!(client.idle_connections(&url), 1);
+        assert_eq!(client.get(&url, "announce").await.unwrap().body, b"secured");
+
+        // not trusted by the default roots
+        assert!(HttpClient::new().get(&url, "announce").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        // accept but never answer
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_sock, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        let url = Url::new(&format!("http://{}/announce", addr)).unwrap();
+        let client = HttpClient::with_timeout(Duration::from_millis(100));
+        let res = client.get(&url, "/").await;
+        assert!(matches!(res, Err(UttdError::RequestTimeout)));
+    }
+
+    #[tokio::test]
+    async fn failures_are_told_apart() {
+        let client = HttpClient::with_timeout(Duration::from_secs(5));
+
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::new(&format!("http://{}/", closed.local_addr().unwrap())).unwrap();
+        drop(closed);
+        let res = client.get(&url, "/").await;
+        assert!(
+            matches!(res, Err(UttdError::ConnectionRefused(_))),
+            "{res:?}"
+        );
+
+        // the .invalid TLD never resolves
+        let url = Url::new("http://tracker.invalid:80/").unwrap();
+        let res = client.get(&url, "/").await;
+        assert!(matches!(res, Err(UttdError::DnsFailure(h)) if h == "tracker.invalid:80"));
+
+        let (url, _) = serve(vec!["SMTP ready\r\n\r\n"]).await;
+        let res = client.get(&url, "/").await;
+        assert!(
+            matches!(res, Err(UttdError::ProtocolViolation(_))),
+            "{res:?}"
+        );
+
+        let url = Url::new("udp://tracker.example.com:80").unwrap();
+        let res = client.get(&url, "/").await;
+        assert!(matches!(res, Err(UttdError::InvalidUrl(_))), "{res:?}");
+    }
+}
@@ -1,23 +1,93 @@
+pub mod dns;
 pub mod error;
+pub mod http;
+pub mod proxy;
+pub mod tls;
+pub mod transport;
+pub mod udp_tracker;
 pub mod url;
 pub mod urutil;
 pub mod utp;
 
 use std::{
-    io::{Read, Write},
-    net::{AddrParseError, TcpStream, UdpSocket},
+    fmt::Display,
+    io::{ErrorKind, Read, Write},
+    net::{AddrParseError, TcpStream},
+    pin::Pin,
+    task::{Context, Poll},
     time::Duration,
 };
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use dns::{unspecified_for, Resolver};
+use error::UrlError;
+use tls::{AsyncTlsTcpStream, TlsConfig, TlsTcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use transport::Transport;
 use url::{Scheme, Url};
+use utp::UtpStream;
 
+/// Everything that can go wrong talking to trackers and peers
 #[derive(Debug)]
 pub enum UttdError {
     IpParseFail(AddrParseError),
     IoError(std::io::Error),
-    FailedRequest,
+    /// The url is malformed or has a scheme that can't be used here
+    InvalidUrl(UrlError),
+    /// The host name didn't resolve to any address
+    DnsFailure(String),
+    /// Nothing accepts connections at the host
+    ConnectionRefused(String),
     RequestTimeout,
+    /// The server answered with an unexpected HTTP status
+    HttpStatus(u16),
+    /// The tracker answered with an error message
+    TrackerFailure(String),
+    /// The remote end sent something the protocol doesn't allow
+    ProtocolViolation(&'static str),
+    /// The proxy refused or couldn't open the connection
+    ProxyFailure(String),
+    /// Bad certificate or TLS configuration
+    TlsFailure(&'static str),
+}
+
+impl Display for UttdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UttdError::IpParseFail(e) => write!(f, "Invalid ip address: {e}"),
+            UttdError::IoError(e) => write!(f, "I/O error: {e}"),
+            UttdError::InvalidUrl(e) => write!(f, "{e}"),
+            UttdError::DnsFailure(host) => write!(f, "Failed to resolve {host}"),
+            UttdError::ConnectionRefused(host) => write!(f, "Connection to {host} refused"),
+            UttdError::RequestTimeout => write!(f, "Request timed out"),
+            UttdError::HttpStatus(status) => write!(f, "Unexpected HTTP status {status}"),
+            UttdError::TrackerFailure(reason) => write!(f, "Tracker failure: {reason}"),
+            UttdError::ProtocolViolation(what) => write!(f, "Protocol violation: {what}"),
+            UttdError::ProxyFailure(reason) => write!(f, "Proxy failure: {reason}"),
+            UttdError::TlsFailure(what) => write!(f, "TLS failure: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for UttdError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UttdError::IpParseFail(e) => Some(e),
+            UttdError::IoError(e) => Some(e),
+            UttdError::InvalidUrl(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl UttdError {
+    // an I/O error while connecting to or talking with `host`
+    pub(crate) fn from_io(e: std::io::Error, host: &str) -> Self {
+        match e.kind() {
+            ErrorKind::ConnectionRefused => Self::ConnectionRefused(host.to_owned()),
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => Self::RequestTimeout,
+            _ => Self::IoError(e),
+        }
+    }
 }
 
 impl From<AddrParseError> for UttdError {
@@ -32,6 +102,12 @@ impl From<std::io::Error> for UttdError {
     }
 }
 
+impl From<UrlError> for UttdError {
+    fn from(value: UrlError) -> Self {
+        Self::InvalidUrl(value)
+    }
+}
+
 impl From<tokio::time::error::Elapsed> for UttdError {
     fn from(_: tokio::time::error::Elapsed) -> Self {
         Self::RequestTimeout
@@ -46,17 +122,12 @@ pub struct Stream {
 
 pub enum StreamType {
     TCP(TcpStream),
-    UDP(Udp),
-}
-
-pub struct Udp {
-    socket: UdpSocket,
-    pub connection_id: i64,
+    TLS(Box<TlsTcpStream>),
 }
 
 impl Stream {
-    /// Create a new Tcp or Udp stream on a Url
-    /// The type of stream is based on the scheme in the url
+    /// Create a new Tcp stream on a Url, over TLS for https urls.
+    /// UDP trackers are talked to with `udp_tracker::UdpTracker` instead
 
     /// ```
     /// use uttd::url::Url;
@@ -67,20 +138,19 @@ impl Stream {
     /// assert!(!res.is_empty());
     /// ```
     pub fn new(url: &Url) -> Result<Self, UttdError> {
+        Self::new_with_tls(url, &TlsConfig::new())
+    }
+
+    /// Same as `new`, but HTTPS servers are verified with `tls`
+    pub fn new_with_tls(url: &Url, tls: &TlsConfig) -> Result<Self, UttdError> {
         let stream = match url.scheme {
-            Scheme::HTTP => StreamType::TCP(TcpStream::connect(&url.host).unwrap()),
-            Scheme::UDP => {
-                let mut sock = UdpSocket::bind("0.0.0.0:0").unwrap();
-                sock.set_read_timeout(Some(Duration::from_secs(5)))?;
-                sock.set_write_timeout(Some(Duration::from_secs(5)))?;
-                sock.connect(&url.host).unwrap();
-                let connection_id = Self::initiate_udp(&mut sock)?;
-                StreamType::UDP(Udp {
-                    socket: sock,
-                    connection_id,
-                })
+            Scheme::HTTP => StreamType::TCP(Self::connect(&url.authority())?),
+            Scheme::HTTPS => {
+                let tcp = Self::connect(&url.authority())?;
+                tcp.set_read_timeout(Some(Duration::from_secs(15)))?;
+                StreamType::TLS(Box::new(tls.connect(url.domain(), tcp)?))
             }
-            _ => unimplemented!(),
+            Scheme::UDP => return Err(UttdError::InvalidUrl(UrlError::InvalidUrl)),
         };
         Ok(Stream {
             stream,
@@ -88,84 +158,42 @@ impl Stream {
         })
     }
 
-    /// UDP trackers require a initial handshake type message passing
-    /// Defined in BEP 00015
-    /// https://www.bittorrent.org/beps/bep_0015.html
-
-    pub fn initiate_udp(stream: &mut UdpSocket) -> Result<i64, UttdError> {
-        // magic
-        let protocol_id: i64 = 0x41727101980; // Protocol ID
-                                              // magic
-        let action: i32 = 0; // Action: connect
-                             // TODO: use rng
-        let transaction_id: i32 = 1; // Random Transaction ID
-
-        let mut buf = Vec::new();
-        buf.extend_from_slice(&protocol_id.to_be_bytes());
-        buf.extend_from_slice(&action.to_be_bytes());
-        buf.extend_from_slice(&transaction_id.to_be_bytes());
-        let mut res = vec![0; 16];
-        Self::send_udp(stream, &buf, &mut res)?;
-        // a UDP initiate response is always 16 bytes
-        // https://www.bittorrent.org/beps/bep_0029.html
-        let connection_id = i64::from_be_bytes(res[8..16].try_into().unwrap());
-        Ok(connection_id)
-    }
-
-    /// Send `data` to `url` and return response in `res`
-    /// Note: for UDP trackers, `res` must be initialized, preferably with 0's
-    /// ```rust,no_run
-    /// // will not work
-    /// let mut res: Vec<u8> = Vec::new();
-    /// // will work
-    /// let mut res = vec![0; 10];
-    /// // or
-    /// let mut res: Vec<u8> = Vec::with_capacity(10);
-    /// ```
-    pub fn send(&mut self, data: &[u8], res: &mut Vec<u8>) -> Result<(), UttdError> {
-        // Using Vec::new() works for tcp streams but fails for UDP requests because the .recv()
-        // method for UDP expects an already allocated buffer. Vec::new() just creates a container with length 0.
-        // So, we iniliatize a vec with vec![] to initialize a vec with 1024 bytes of space. If any request is larger than that,
-        // the vec accommodates to fill the space.
+    // try each of `host`'s addresses in turn
+    fn connect(host: &str) -> Result<TcpStream, UttdError> {
+        let addrs = Resolver::global().resolve(host)?;
+        TcpStream::connect(&addrs[..]).map_err(|e| UttdError::from_io(e, host))
+    }
 
-        // let mut res = vec![0u8; 1024];
-        // let mut udp_res = [0; 1024];
+    /// Send `data` to `url` and return response in `res`.
+    /// An empty `res` is read until the server closes, otherwise it's filled exactly
+    pub fn send(&mut self, data: &[u8], res: &mut Vec<u8>) -> Result<(), UttdError> {
         match &mut self.stream {
             StreamType::TCP(t) => {
                 Self::send_tcp(t, data, res)?;
             }
-            StreamType::UDP(t) => {
-                Self::send_udp(&mut t.socket, data, res)?;
+            StreamType::TLS(t) => {
+                Self::send_tcp(t.as_mut(), data, res)?;
             }
         }
         Ok(())
     }
 
-    fn send_tcp(stream: &mut TcpStream, data: &[u8], res: &mut Vec<u8>) -> Result<(), UttdError> {
+    fn send_tcp<S: Read + Write>(
+        stream: &mut S,
+        data: &[u8],
+        res: &mut Vec<u8>,
+    ) -> Result<(), UttdError> {
         if res.is_empty() {
             stream.write_all(data)?;
-            stream.read_to_end(res)?;
-        } else {
-            stream.write_all(data).unwrap();
-            assert!(res.len() == 68);
-            stream.read_exact(res).unwrap();
-        }
-        Ok(())
-    }
-
-    fn send_udp(stream: &mut UdpSocket, data: &[u8], res: &mut Vec<u8>) -> Result<(), UttdError> {
-        // if timeout, retry for 5 times
-        for _ in 0..5 {
-            stream.send(data).unwrap();
-            if stream.recv(res).is_ok() {
-                break;
+            // TLS servers that close without a close_notify end with an unexpected EOF
+            match stream.read_to_end(res) {
+                Err(e) if e.kind() != std::io::ErrorKind::UnexpectedEof => return Err(e.into()),
+                _ => (),
             }
+        } else {
+            stream.write_all(data)?;
+            stream.read_exact(res)?;
         }
-        // if after 10 tires still no response, return error
-        if res.is_empty() {
-            return Err(UttdError::FailedRequest);
-        }
-
         Ok(())
     }
 
@@ -183,7 +211,11 @@ impl Stream {
     /// ```
 
     pub fn get(&mut self, path: &str) -> Result<Vec<u8>, UttdError> {
-        let (host, _) = self.host.split_once(":").unwrap();
+        // https urls usually leave out the port
+        let host = self
+            .host
+            .split_once(':')
+            .map_or(self.host.as_str(), |(host, _)| host);
 
         let get_header = format!(
             "GET /{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
@@ -196,7 +228,8 @@ impl Stream {
 }
 
 /// Async version of `Stream`
-/// Holds a TcpStream underneth
+/// Holds a TcpStream underneth, or any other `Transport`.
+/// It's a `Transport` itself; a UDP socket reads and writes whole datagrams
 #[derive(Debug)]
 pub struct AsyncStream {
     pub async_stream_type: AsyncStreamType,
@@ -205,16 +238,27 @@ pub struct AsyncStream {
 #[derive(Debug)]
 pub enum AsyncStreamType {
     TcpStream(tokio::net::TcpStream),
-    UtpStream(tokio::net::UdpSocket),
+    TlsStream(Box<AsyncTlsTcpStream>),
+    /// uTP connection to a peer
+    UtpStream(UtpStream),
+    /// Plain UDP socket, for datagram protocols like the DHT's KRPC
+    Udp(tokio::net::UdpSocket),
+    /// Any other byte stream, e.g. an in-memory pipe
+    Transport(Box<dyn Transport>),
 }
 
 impl<'a> AsyncStream {
     pub async fn new(url: Url) -> Result<Self, UttdError> {
+        Self::new_with_tls(url, &TlsConfig::new()).await
+    }
+
+    /// Same as `new`, but HTTPS servers are verified with `tls`
+    pub async fn new_with_tls(url: Url, tls: &TlsConfig) -> Result<Self, UttdError> {
         match url.scheme {
             Scheme::HTTP => {
                 let stream = tokio::time::timeout(
                     Duration::from_secs(5),
-                    tokio::net::TcpStream::connect(&url.host),
+                    Resolver::global().connect(&url.authority()),
                 )
                 .await??;
                 Ok(AsyncStream {
@@ -222,13 +266,41 @@ impl<'a> AsyncStream {
                 })
             }
             Scheme::UDP => {
-                let stream = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
-                stream.connect(&url.host).await?;
+                let addrs = Resolver::global().lookup(&url.host).await?;
+                let stream = tokio::net::UdpSocket::bind(unspecified_for(&addrs[0])).await?;
+                stream.connect(&addrs[..]).await?;
+                Ok(AsyncStream {
+                    async_stream_type: AsyncStreamType::Udp(stream),
+                })
+            }
+            Scheme::HTTPS => {
+                let stream = tokio::time::timeout(Duration::from_secs(5), async {
+                    let tcp = Resolver::global().connect(&url.authority()).await?;
+                    tls.connect_async(url.domain(), tcp).await
+                })
+                .await??;
                 Ok(AsyncStream {
-                    async_stream_type: AsyncStreamType::UtpStream(stream),
+                    async_stream_type: AsyncStreamType::TlsStream(Box::new(stream)),
                 })
             }
-            _ => unimplemented!(),
+        }
+    }
+
+    /// Wrap any other byte stream
+    pub fn from_transport<T: Transport + 'static>(transport: T) -> Self {
+        AsyncStream {
+            async_stream_type: AsyncStreamType::Transport(Box::new(transport)),
+        }
+    }
+
+    // the byte stream underneath, or the datagram socket
+    fn transport(&mut self) -> Result<&mut dyn Transport, &mut tokio::net::UdpSocket> {
+        match &mut self.async_stream_type {
+            AsyncStreamType::TcpStream(t) => Ok(t),
+            AsyncStreamType::TlsStream(t) => Ok(t.as_mut()),
+            AsyncStreamType::UtpStream(u) => Ok(u),
+            AsyncStreamType::Transport(t) => Ok(t.as_mut()),
+            AsyncStreamType::Udp(u) => Err(u),
         }
     }
 
@@ -236,87 +308,77 @@ impl<'a> AsyncStream {
     /// Note: Peers are continuous stream of data. You must
     /// have initialized `res` with sufficient bytes. It only the exact bytes as is the capacity of `res`
     pub async fn send(&mut self, data: &[u8], res: &mut Vec<u8>) -> Result<usize, UttdError> {
-        match self {
-            AsyncStream {
-                async_stream_type: AsyncStreamType::TcpStream(t),
-            } => Self::send_tcp(t, data, res).await,
-            AsyncStream {
-                async_stream_type: AsyncStreamType::UtpStream(u),
-            } => Self::send_utp(u, data, res).await,
+        match self.transport() {
+            Ok(t) => Self::send_tcp(t, data, res).await,
+            Err(u) => Self::send_udp(u, data, res).await,
         }
     }
 
-    pub async fn send_tcp(
-        tcp: &mut tokio::net::TcpStream,
+    pub async fn send_tcp<S: AsyncRead + AsyncWrite + Unpin + ?Sized>(
+        tcp: &mut S,
         data: &[u8],
         res: &mut Vec<u8>,
     ) -> Result<usize, UttdError> {
-        tcp.write_all(data).await.unwrap();
+        tcp.write_all(data).await?;
+        tcp.flush().await?;
         let response = tokio::time::timeout(Duration::from_secs(15), tcp.read_exact(res)).await?;
         Ok(response?)
     }
 
-    pub async fn send_utp(
+    pub async fn send_udp(
         utp: &mut tokio::net::UdpSocket,
         data: &[u8],
         res: &mut Vec<u8>,
     ) -> Result<usize, UttdError> {
-        let mut read = 0;
+        let peer = utp.peer_addr()?.to_string();
+        // a failed receive is retried, up to 5 times, but a silent peer isn't waited for twice
+        let mut last = UttdError::RequestTimeout;
         for _ in 0..5 {
-            read = utp.send(data).await.unwrap();
-            if let Ok(r) = tokio::time::timeout(Duration::from_secs(10), utp.recv(res)).await? {
-                read = r;
-                break;
+            utp.send(data)
+                .await
+                .map_err(|e| UttdError::from_io(e, &peer))?;
+            match tokio::time::timeout(Duration::from_secs(10), utp.recv(res)).await? {
+                Ok(read) => return Ok(read),
+                Err(e) => last = UttdError::from_io(e, &peer),
             }
         }
-        // if after 10 tires still no response, return error
-        if res.is_empty() {
-            return Err(UttdError::FailedRequest);
-        }
-
-        Ok(read)
+        Err(last)
     }
 
     /// Read 4 bytes of data once and return
     pub async fn read_once(&mut self) -> Result<u32, UttdError> {
-        match self {
-            AsyncStream {
-                async_stream_type: AsyncStreamType::TcpStream(t),
-            } => Self::read_once_tcp(t).await,
-            _ => unimplemented!(),
+        match self.transport() {
+            Ok(t) => Self::read_once_tcp(t).await,
+            // a length prefix only makes sense on a byte stream
+            Err(_) => Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into()),
         }
     }
 
-    async fn read_once_tcp(tcp: &mut tokio::net::TcpStream) -> Result<u32, UttdError> {
+    async fn read_once_tcp<S: AsyncRead + Unpin + ?Sized>(tcp: &mut S) -> Result<u32, UttdError> {
         // peers send keep_alive messages every 2 minutes. If we don't receive anything for 2 minutes, we close the connection
         let mut res = [0_u8; 4];
         _ = tokio::time::timeout(Duration::from_secs(121), tcp.read_exact(&mut res)).await??;
-        let length = u32::from_be_bytes(res.try_into().unwrap());
-        Ok(length)
+        Ok(u32::from_be_bytes(res))
     }
 
     pub async fn read_multiple(&mut self, res: &mut Vec<u8>) -> Result<(), UttdError> {
-        match self {
-            AsyncStream {
-                async_stream_type: AsyncStreamType::TcpStream(t),
-            } => Self::read_multiple_tcp(t, res).await,
-            AsyncStream {
-                async_stream_type: AsyncStreamType::UtpStream(u),
-            } => Self::read_multiple_utp(u, res).await,
+        match self.transport() {
+            Ok(t) => Self::read_multiple_tcp(t, res).await,
+            Err(u) => Self::read_multiple_udp(u, res).await,
         }
     }
 
     // TODO: return the amount of bytes read
     /// Read `res.len()` bytes of data and pass it through `res`
-    pub async fn read_multiple_tcp(
-        tcp: &mut tokio::net::TcpStream,
+    pub async fn read_multiple_tcp<S: AsyncRead + Unpin + ?Sized>(
+        tcp: &mut S,
         res: &mut Vec<u8>,
     ) -> Result<(), UttdError> {
         _ = tokio::time::timeout(Duration::from_secs(121), tcp.read_exact(res)).await??;
         Ok(())
     }
 
-    pub async fn read_multiple_utp(
+    pub async fn read_multiple_udp(
         utp: &mut tokio::net::UdpSocket,
         res: &mut Vec<u8>,
     ) -> Result<(), UttdError> {
@@ -325,10 +387,55 @@ impl<'a> AsyncStream {
     }
 }
 
+impl AsyncRead for AsyncStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut().transport() {
+            Ok(t) => Pin::new(t).poll_read(cx, buf),
+            Err(u) => u.poll_recv(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut().transport() {
+            Ok(t) => Pin::new(t).poll_write(cx, data),
+            Err(u) => u.poll_send(cx, data),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut().transport() {
+            Ok(t) => Pin::new(t).poll_flush(cx),
+            Err(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut().transport() {
+            Ok(t) => Pin::new(t).poll_shutdown(cx),
+            Err(_) => Poll::Ready(Ok(())),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
 
-    use crate::{url::Url, utp::UtpPacket, AsyncStream, Stream, StreamType};
+    use crate::{
+        tls::{self, TlsConfig},
+        url::Url,
+        utp::{Packet, PacketType},
+        AsyncStream, Stream, UttdError,
+    };
     use std::{
         io::{Read, Write},
         net::TcpStream,
@@ -346,15 +453,9 @@ mod test {
     }
 
     #[test]
-    fn udp_get_request() {
-        let url = Url::new("udp://tracker.opentrackr.org:1337").unwrap();
-        let stream = Stream::new(&url).unwrap();
-        let mut res = 0;
-        if let StreamType::UDP(u) = stream.stream {
-            res = u.connection_id;
-        }
-
-        assert!(res != 0);
+    fn no_blocking_udp() {
+        let url = Url::new("udp://127.0.0.1:1337").unwrap();
+        assert!(matches!(Stream::new(&url), Err(UttdError::InvalidUrl(_))));
     }
 
     #[test]
@@ -383,6 +484,76 @@ mod test {
     //     assert!(res[0] != 0);
     // }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn https_stream_get() {
+        let cert = tls::test::self_signed();
+        let addr =
+            tls::test::serve_tls(&cert, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        let url = Url::new(&format!("https://{}/announce", addr)).unwrap();
+        let mut roots = TlsConfig::empty();
+        roots.add_root_certificate(cert.cert.der()).unwrap();
+
+        let res = tokio::task::spawn_blocking(move || {
+            let mut stream = Stream::new_with_tls(&url, &roots).unwrap();
+            stream.get("announce").unwrap()
+        })
+        .await
+        .unwrap();
+        assert!(res.starts_with(b"HTTP/1.1 200 OK"));
+        assert!(res.ends_with(b"ok"));
+    }
+
+    #[tokio::test]
+    async fn https_async_stream() {
+        let cert = tls::test::self_signed();
+        let addr =
+            tls::test::serve_tls(&cert, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        let url = Url::new(&format!("https://{}/announce", addr)).unwrap();
+        let mut roots = TlsConfig::empty();
+        roots.add_root_certificate(cert.cert.der()).unwrap();
+
+        let mut stream = AsyncStream::new_with_tls(url.clone(), &roots)
+            .await
+            .unwrap();
+        let mut res = vec![0; 40];
+        stream
+            .send(b"GET / HTTP/1.1\r\n\r\n", &mut res)
+            .await
+            .unwrap();
+        assert!(res.starts_with(b"HTTP/1.1 200 OK"));
+
+        // the self-signed certificate isn't trusted by default
+        assert!(AsyncStream::new(url).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_failures() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let refused = Url::new(&format!("http://{}/", closed.local_addr().unwrap())).unwrap();
+        drop(closed);
+        let unknown = Url::new("http://tracker.invalid:6969/announce").unwrap();
+
+        let res = Stream::new(&refused).map(|_| ());
+        assert!(
+            matches!(res, Err(UttdError::ConnectionRefused(_))),
+            "{res:?}"
+        );
+        let res = AsyncStream::new(refused).await.map(|_| ());
+        assert!(
+            matches!(res, Err(UttdError::ConnectionRefused(_))),
+            "{res:?}"
+        );
+
+        let res = Stream::new(&unknown).map(|_| ());
+        assert!(matches!(res, Err(UttdError::DnsFailure(_))), "{res:?}");
+        let res = AsyncStream::new(unknown).await.map(|_| ());
+        assert!(matches!(res, Err(UttdError::DnsFailure(h)) if h == "tracker.invalid:6969"));
+        assert_eq!(
+            UttdError::DnsFailure("tracker.invalid:6969".into()).to_string(),
+            "Failed to resolve tracker.invalid:6969"
+        );
+    }
+
     // IMPORTANT: Can't depend on this specific peer being alive to may fail
     #[tokio::test]
     #[should_panic]
@@ -390,11 +561,11 @@ mod test {
         let mut stream = tokio::net::UdpSocket::bind("0.0.0.0:0").await.unwrap();
         stream.connect("37.228.205.62:15223").await.unwrap();
 
-        let bytes = UtpPacket::new().as_bytes();
+        let bytes = Packet::new(PacketType::Syn, 1).as_bytes();
         println!("{:?}", bytes);
         let mut res = vec![0; 20];
 
-        let _ = AsyncStream::send_utp(&mut stream, &bytes, &mut res)
+        let _ = AsyncStream::send_udp(&mut stream, &bytes, &mut res)
             .await
             .unwrap();
         assert_eq!(res[0], 33);
@@ -0,0 +1,243 @@
+use std::{net::TcpStream, sync::Arc};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, ServerName},
+    ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::UttdError;
+
+/// Blocking TLS stream over TCP
+pub type TlsTcpStream = StreamOwned<ClientConnection, TcpStream>;
+/// Async TLS stream over TCP
+pub type AsyncTlsTcpStream = TlsStream<tokio::net::TcpStream>;
+
+/// Certificate verification settings for HTTPS connections
+/// Starts out trusting the Mozilla root CAs (via `webpki-roots`).
+/// Custom root CAs, e.g. for private trackers with their own CA, can be added on top.
+///
+/// ```
+/// use uttd::tls::TlsConfig;
+/// let mut tls = TlsConfig::new();
+/// assert!(tls.add_root_certificate(b"not a certificate").is_err());
+/// ```
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    roots: RootCertStore,
+    config: Arc<ClientConfig>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TlsConfig {
+    /// Trust the bundled Mozilla root CAs
+    pub fn new() -> Self {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        Self::from_roots(roots)
+    }
+
+    /// Trust no CA at all. Only certificates added with
+    /// `add_root_certificate` or `add_root_pem` will be accepted
+    pub fn empty() -> Self {
+        Self::from_roots(RootCertStore::empty())
+    }
+
+    fn from_roots(roots: RootCertStore) -> Self {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+        Self {
+            roots,
+            config: Arc::new(config),
+        }
+    }
+
+    /// Trust an additional root CA given in DER form
+    pub fn add_root_certificate(&mut self, der: &[u8]) -> Result<(), UttdError> {
+        self.roots
+            .add(CertificateDer::from(der.to_vec()))
+            .map_err(|_| UttdError::TlsFailure("invalid certificate"))?;
+        *self = Self::from_roots(self.roots.clone());
+        Ok(())
+    }
+
+    /// Trust every certificate in a PEM bundle
+    pub fn add_root_pem(&mut self, pem: &[u8]) -> Result<(), UttdError> {
+        let mut added = 0;
+        for cert in CertificateDer::pem_slice_iter(pem) {
+            let cert = cert.map_err(|_| UttdError::TlsFailure("invalid PEM"))?;
+            self.roots
+                .add(cert)
+                .map_err(|_| UttdError::TlsFailure("invalid certificate"))?;
+            added += 1;
+        }
+        if added == 0 {
+            return Err(UttdError::TlsFailure("no certificate in PEM"));
+        }
+        *self = Self::from_roots(self.roots.clone());
+        Ok(())
+    }
+
+    /// Wrap a connected blocking `TcpStream` in TLS. `host` is the server's
+    /// domain (or ip), without the port, and is verified against its certificate
+    pub fn connect(&self, host: &str, tcp: TcpStream) -> Result<TlsTcpStream, UttdError> {
+        let name = Self::server_name(host)?;
+        let conn = ClientConnection::new(self.config.clone(), name)
+            .map_err(|_| UttdError::TlsFailure("unusable client configuration"))?;
+        let mut stream = StreamOwned::new(conn, tcp);
+        // drive the handshake now so certificate errors surface here and not on the first read
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+        Ok(stream)
+    }
+
+    /// Async version of `connect`
+    pub async fn connect_async(
+        &self,
+        host: &str,
+        tcp: tokio::net::TcpStream,
+    ) -> Result<AsyncTlsTcpStream, UttdError> {
+        let name = Self::server_name(host)?;
+        let connector = TlsConnector::from(self.config.clone());
+        Ok(connector.connect(name, tcp).await?)
+    }
+
+    fn server_name(host: &str) -> Result<ServerName<'static>, UttdError> {
+        // strip the brackets from ipv6 literals
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        ServerName::try_from(host.to_owned())
+            .map_err(|_| UttdError::TlsFailure("invalid server name"))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::sync::Arc;
+
+    use rcgen::CertifiedKey;
+    use rustls::{
+        pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+        ServerConfig,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::TlsAcceptor;
+
+    use super::TlsConfig;
+
+    /// Self-signed certificate for 127.0.0.1 and localhost
+    pub(crate) fn self_signed() -> CertifiedKey<rcgen::KeyPair> {
+        rcgen::generate_simple_self_signed(vec!["127.0.0.1".into(), "localhost".into()]).unwrap()
+    }
+
+    pub(crate) fn server_config(cert: &CertifiedKey<rcgen::KeyPair>) -> Arc<ServerConfig> {
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der()));
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.der().clone()], key)
+            .unwrap();
+        Arc::new(config)
+    }
+
+    /// Local TLS server answering every request on a connection with `response`.
+    /// Connections are closed after requests that ask for it.
+    /// Returns the `{ip}:{port}` it listens on
+    pub(crate) async fn serve_tls(
+        cert: &CertifiedKey<rcgen::KeyPair>,
+        response: &'static [u8],
+    ) -> String {
+        let acceptor = TlsAcceptor::from(server_config(cert));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((sock, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut tls) = acceptor.accept(sock).await else {
+                        return;
+                    };
+                    let mut buf = vec![0u8; 4096];
+                    loop {
+                        let mut req = Vec::new();
+                        while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+                            match tls.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => req.extend_from_slice(&buf[..n]),
+                            }
+                        }
+                        if tls.write_all(response).await.is_err() {
+                            return;
+                        }
+                        if req.windows(17).any(|w| w == b"Connection: close") {
+                            _ = tls.shutdown().await;
+                            return;
+                        }
+                        _ = tls.flush().await;
+                    }
+                });
+            }
+        });
+        addr.to_string()
+    }
+
+    #[test]
+    fn pem_roots() {
+        let cert = self_signed();
+        let mut tls = TlsConfig::empty();
+        tls.add_root_pem(cert.cert.pem().as_bytes()).unwrap();
+        assert!(tls.add_root_pem(b"").is_err());
+    }
+
+    #[tokio::test]
+    async fn async_handshake_with_custom_root() {
+        let cert = self_signed();
+        let addr = serve_tls(&cert, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+
+        let mut tls = TlsConfig::new();
+        tls.add_root_certificate(cert.cert.der()).unwrap();
+
+        let tcp = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let mut stream = tls.connect_async("127.0.0.1", tcp).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut res = vec![0; 40];
+        stream.read_exact(&mut res).await.unwrap();
+        assert!(res.starts_with(b"HTTP/1.1 200 OK"));
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate_is_rejected() {
+        let cert = self_signed();
+        let addr = serve_tls(&cert, b"").await;
+
+        let tcp = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let res = TlsConfig::new().connect_async("127.0.0.1", tcp).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn hostname_mismatch_is_rejected() {
+        let cert = self_signed();
+        let addr = serve_tls(&cert, b"").await;
+
+        let mut tls = TlsConfig::empty();
+        tls.add_root_certificate(cert.cert.der()).unwrap();
+        let tcp = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let res = tls.connect_async("tracker.example.com", tcp).await;
+        assert!(res.is_err());
+    }
+}
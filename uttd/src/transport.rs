@@ -0,0 +1,84 @@
+use std::fmt::Debug;
+
+use tokio::io::{duplex, AsyncRead, AsyncWrite, DuplexStream};
+
+// bytes either end of a pipe buffers before writes wait for the other end to read
+const PIPE_BUFFER: usize = 1 << 16;
+
+/// A reliable, ordered byte stream to a peer: TCP, TLS, uTP or an in-memory pipe.
+/// Implemented for everything that is `AsyncRead + AsyncWrite`, so the peer wire
+/// code can be written once and run over any of them.
+///
+/// ```
+/// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+/// use uttd::transport::{pipe, Transport};
+///
+/// async fn ping(stream: &mut impl Transport) -> std::io::Result<()> {
+///     stream.write_all(b"ping").await
+/// }
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let (mut a, mut b) = pipe();
+///     ping(&mut a).await.unwrap();
+///     let mut buf = [0; 4];
+///     b.read_exact(&mut buf).await.unwrap();
+///     assert_eq!(&buf, b"ping");
+/// }
+/// ```
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Debug> Transport for T {}
+
+/// Two transports connected to each other in memory, what's written to one is read from the other.
+/// Stands in for the network in tests
+pub fn pipe() -> (DuplexStream, DuplexStream) {
+    duplex(PIPE_BUFFER)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::pipe;
+    use crate::{AsyncStream, AsyncStreamType};
+
+    #[tokio::test]
+    async fn async_stream_over_pipe() {
+        let (a, mut b) = pipe();
+        let mut stream = AsyncStream::from_transport(a);
+        let peer = tokio::spawn(async move {
+            let mut request = [0; 5];
+            b.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"hello");
+            b.write_all(&[0, 0, 0, 3, 7, 8, 9]).await.unwrap();
+            b.write_all(b"and more").await.unwrap();
+        });
+
+        let mut res = vec![0; 4];
+        assert_eq!(stream.send(b"hello", &mut res).await.unwrap(), 4);
+        assert_eq!(u32::from_be_bytes(res.try_into().unwrap()), 3);
+        let mut message = vec![0; 3];
+        stream.read_multiple(&mut message).await.unwrap();
+        assert_eq!(message, [7, 8, 9]);
+
+        // and as a transport of its own
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"and more");
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn datagrams_are_no_byte_stream() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(socket.local_addr().unwrap()).await.unwrap();
+        let mut stream = AsyncStream {
+            async_stream_type: AsyncStreamType::Udp(socket),
+        };
+        let err = stream.read_once().await.unwrap_err();
+        assert!(matches!(err, crate::UttdError::IoError(e) if e.kind() == ErrorKind::Unsupported));
+    }
+}
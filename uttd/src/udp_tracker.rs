@@ -0,0 +1,584 @@
+// REFERENCE: https://www.bittorrent.org/beps/bep_0015.html
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crypto::tinymt::TinyMT;
+use tokio::net::UdpSocket;
+
+use crate::{
+    dns::{unspecified_for, Resolver},
+    proxy::{Proxy, Socks5Udp},
+    url::Url,
+    urutil::{compact_v4, compact_v6, MetaInfo, ScrapeStats},
+    UttdError,
+};
+
+// magic
+const PROTOCOL_ID: i64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// a connection id may be used for one minute after it was received
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+// retransmit after 15 * 2 ^ n seconds, n going up to 8
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RETRANSMISSIONS: u32 = 8;
+// trackers only accept about 74 info hashes per scrape packet
+const MAX_SCRAPE_HASHES: usize = 74;
+const MAX_PACKET_SIZE: usize = 2048;
+
+/// Parameters of an announce request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    /// 0: none; 1: completed; 2: started; 3: stopped
+    pub event: u32,
+    pub key: u32,
+    /// -1 lets the tracker decide
+    pub num_want: i32,
+    pub port: u16,
+}
+
+/// Async client for a single UDP tracker
+/// The connection id is cached and reused until it expires.
+/// Unanswered requests are retransmitted as the spec describes.
+#[derive(Debug)]
+pub struct UdpTracker {
+    socket: Socket,
+    connection: Option<(i64, Instant)>,
+    rng: TinyMT,
+    base_timeout: Duration,
+    max_retransmissions: u32,
+    connection_ttl: Duration,
+}
+
+#[derive(Debug)]
+enum Socket {
+    Direct(UdpSocket),
+    /// through a SOCKS5 relay, to the tracker's "host:port"
+    Proxied(Socks5Udp, String),
+}
+
+impl Socket {
+    async fn send(&self, buf: &[u8]) -> Result<usize, UttdError> {
+        match self {
+            Self::Direct(socket) => Ok(socket.send(buf).await?),
+            Self::Proxied(relay, tracker) => relay.send_to(buf, tracker).await,
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize, UttdError> {
+        match self {
+            Self::Direct(socket) => Ok(socket.recv(buf).await?),
+            // the transaction id tells stray datagrams apart
+            Self::Proxied(relay, _) => Ok(relay.recv_from(buf).await?.0),
+        }
+    }
+
+    // trackers reached over IPv6 answer with IPv6 peers.
+    // A tracker named by domain behind a proxy is taken to be reached over IPv4
+    fn is_ipv6(&self) -> bool {
+        match self {
+            Self::Direct(socket) => socket.peer_addr().is_ok_and(|a| a.is_ipv6()),
+            Self::Proxied(_, tracker) => tracker.parse::<SocketAddr>().is_ok_and(|a| a.is_ipv6()),
+        }
+    }
+}
+
+impl UdpTracker {
+    /// Bind a local socket and point it at `url`'s host.
+    /// No packets are sent until the first request
+    pub async fn new(url: &Url) -> Result<Self, UttdError> {
+        let addrs = Resolver::global().lookup(&url.authority()).await?;
+        let socket = UdpSocket::bind(unspecified_for(&addrs[0])).await?;
+        socket.connect(&addrs[..]).await?;
+        Ok(Self::from_socket(socket))
+    }
+
+    /// Reach the tracker at `url` through a SOCKS5 `proxy`'s UDP relay
+    pub async fn with_proxy(url: &Url, proxy: &Proxy) -> Result<Self, UttdError> {
+        let relay = proxy.associate_udp().await?;
+        Ok(Self::with_socket(Socket::Proxied(relay, url.authority())))
+    }
+
+    /// Use an already connected socket
+    pub fn from_socket(socket: UdpSocket) -> Self {
+        Self::with_socket(Socket::Direct(socket))
+    }
+
+    fn with_socket(socket: Socket) -> Self {
+        Self {
+            socket,
+            connection: None,
+            rng: TinyMT::from_clock(),
+            base_timeout: BASE_TIMEOUT,
+            max_retransmissions: MAX_RETRANSMISSIONS,
+            connection_ttl: CONNECTION_ID_TTL,
+        }
+    }
+
+    /// Wait `base * 2 ^ n` before the n-th retransmission and give up after `max` of them.
+    /// The defaults are 15 seconds and 8
+    pub fn with_timeouts(mut self, base: Duration, max: u32) -> Self {
+        self.base_timeout = base;
+        self.max_retransmissions = max;
+        self
+    }
+
+    /// Announce and return the tracker's stats and the peers it gave.
+    /// Peers are IPv6 if the tracker was reached over IPv6, IPv4 otherwise
+    pub async fn announce(
+        &mut self,
+        req: &AnnounceRequest,
+    ) -> Result<(MetaInfo, Vec<SocketAddr>), UttdError> {
+        let res = self
+            .request(ACTION_ANNOUNCE, 20, |connection_id, transaction_id| {
+                let mut buf = Vec::with_capacity(98);
+                buf.extend_from_slice(&connection_id.to_be_bytes());
+                buf.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                buf.extend_from_slice(&transaction_id.to_be_bytes());
+                buf.extend_from_slice(&req.info_hash);
+                buf.extend_from_slice(&req.peer_id);
+                buf.extend_from_slice(&req.downloaded.to_be_bytes());
+                buf.extend_from_slice(&req.left.to_be_bytes());
+                buf.extend_from_slice(&req.uploaded.to_be_bytes());
+                buf.extend_from_slice(&req.event.to_be_bytes());
+                buf.extend_from_slice(&0_u32.to_be_bytes()); // ip address: default
+                buf.extend_from_slice(&req.key.to_be_bytes());
+                buf.extend_from_slice(&req.num_want.to_be_bytes());
+                buf.extend_from_slice(&req.port.to_be_bytes());
+                buf
+            })
+            .await?;
+
+        let info = MetaInfo {
+            interval: i32::from_be_bytes(res[8..12].try_into().unwrap()),
+            leechers: i32::from_be_bytes(res[12..16].try_into().unwrap()),
+            seeders: i32::from_be_bytes(res[16..20].try_into().unwrap()),
+        };
+        let peers = match self.socket.is_ipv6() {
+            true => compact_v6(&res[20..]),
+            false => compact_v4(&res[20..]),
+        };
+        Ok((info, peers))
+    }
+
+    /// Scrape stats for each of `info_hashes`, in the same order
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<Vec<ScrapeStats>, UttdError> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for hashes in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let res = self
+                .request(
+                    ACTION_SCRAPE,
+                    8 + 12 * hashes.len(),
+                    |connection_id, transaction_id| {
+                        let mut buf = Vec::with_capacity(16 + 20 * hashes.len());
+                        buf.extend_from_slice(&connection_id.to_be_bytes());
+                        buf.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                        buf.extend_from_slice(&transaction_id.to_be_bytes());
+                        hashes.iter().for_each(|h| buf.extend_from_slice(h));
+                        buf
+                    },
+                )
+                .await?;
+
+            stats.extend(
+                res[8..]
+                    .chunks_exact(12)
+                    .take(hashes.len())
+                    .map(|x| ScrapeStats {
+                        complete: i32::from_be_bytes(x[0..4].try_into().unwrap()),
+                        downloaded: i32::from_be_bytes(x[4..8].try_into().unwrap()),
+                        incomplete: i32::from_be_bytes(x[8..12].try_into().unwrap()),
+                    }),
+            );
+        }
+        Ok(stats)
+    }
+
+    /// Send a request built by `build(connection_id, transaction_id)` until a valid
+    /// response of at least `min_len` bytes arrives, reconnecting whenever the
+    /// connection id expires in between.
+    async fn request<F>(
+        &mut self,
+        action: u32,
+        min_len: usize,
+        build: F,
+    ) -> Result<Vec<u8>, UttdError>
+    where
+        F: Fn(i64, u32) -> Vec<u8>,
+    {
+        let mut n = 0;
+        loop {
+            let connection_id = match self.connection {
+                Some((id, at)) if at.elapsed() < self.connection_ttl => id,
+                _ => {
+                    let transaction_id = self.transaction_id();
+                    let mut buf = Vec::with_capacity(16);
+                    buf.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+                    buf.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                    buf.extend_from_slice(&transaction_id.to_be_bytes());
+
+                    match self
+                        .exchange(&buf, ACTION_CONNECT, transaction_id, 16, n)
+                        .await?
+                    {
+                        Some(res) => {
+                            let id = i64::from_be_bytes(res[8..16].try_into().unwrap());
+                            self.connection = Some((id, Instant::now()));
+                            id
+                        }
+                        None => {
+                            n = self.backoff(n)?;
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let transaction_id = self.transaction_id();
+            let buf = build(connection_id, transaction_id);
+            match self
+                .exchange(&buf, action, transaction_id, min_len, n)
+                .await?
+            {
+                Some(res) => return Ok(res),
+                None => n = self.backoff(n)?,
+            }
+        }
+    }
+
+    fn backoff(&self, n: u32) -> Result<u32, UttdError> {
+        if n >= self.max_retransmissions {
+            return Err(UttdError::RequestTimeout);
+        }
+        Ok(n + 1)
+    }
+
+    /// Send `buf` once and wait `base * 2 ^ n` for the matching response.
+    /// Returns `None` on timeout. Stale responses to earlier transactions are skipped
+    async fn exchange(
+        &self,
+        buf: &[u8],
+        action: u32,
+        transaction_id: u32,
+        min_len: usize,
+        n: u32,
+    ) -> Result<Option<Vec<u8>>, UttdError> {
+        self.socket.send(buf).await?;
+        let deadline = tokio::time::Instant::now() + self.base_timeout * 2_u32.pow(n);
+
+        let mut res = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            let read = match tokio::time::timeout_at(deadline, self.socket.recv(&mut res)).await {
+                Ok(read) => read?,
+                Err(_) => return Ok(None),
+            };
+            if read < 8 {
+                continue;
+            }
+            let res_action = u32::from_be_bytes(res[0..4].try_into().unwrap());
+            let res_transaction = u32::from_be_bytes(res[4..8].try_into().unwrap());
+            if res_transaction != transaction_id {
+                continue;
+            }
+            if res_action == ACTION_ERROR {
+                let message = String::from_utf8_lossy(&res[8..read]).into_owned();
+                return Err(UttdError::TrackerFailure(message));
+            }
+            if res_action != action {
+                return Err(UttdError::ProtocolViolation(
+                    "unexpected action in response",
+                ));
+            }
+            if read < min_len {
+                return Err(UttdError::ProtocolViolation("response too short"));
+            }
+            res.truncate(read);
+            return Ok(Some(res));
+        }
+    }
+
+    fn transaction_id(&mut self) -> u32 {
+        self.rng.rng();
+        self.rng.get_u32()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::net::UdpSocket;
+
+    use super::{AnnounceRequest, UdpTracker, ACTION_ANNOUNCE, ACTION_CONNECT, PROTOCOL_ID};
+    use crate::{url::Url, urutil::ScrapeStats, UttdError};
+
+    const CONNECTION_ID: i64 = 0x1122334455667788;
+
+    #[derive(Default)]
+    struct Counters {
+        connects: AtomicUsize,
+        announces: AtomicUsize,
+    }
+
+    /// A loopback tracker. The first `drop` packets are ignored, `reply` builds the
+    /// response to every other request
+    async fn tracker<F>(drop: usize, reply: F) -> (Url, Arc<Counters>)
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + 'static,
+    {
+        tracker_at("127.0.0.1:0", drop, reply).await
+    }
+
+    async fn tracker_at<F>(bind: &str, drop: usize, reply: F) -> (Url, Arc<Counters>)
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + 'static,
+    {
+        let socket = UdpSocket::bind(bind).await.unwrap();
+        let url = Url::new(&format!("udp://{}", socket.local_addr().unwrap())).unwrap();
+        let counters = Arc::new(Counters::default());
+        let c = counters.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            let mut seen = 0;
+            while let Ok((read, from)) = socket.recv_from(&mut buf).await {
+                seen += 1;
+                if seen <= drop {
+                    continue;
+                }
+                let req = &buf[..read];
+                if i64::from_be_bytes(req[0..8].try_into().unwrap()) == PROTOCOL_ID {
+                    c.connects.fetch_add(1, Ordering::SeqCst);
+                } else if u32::from_be_bytes(req[8..12].try_into().unwrap()) == ACTION_ANNOUNCE {
+                    c.announces.fetch_add(1, Ordering::SeqCst);
+                }
+                socket.send_to(&reply(req), from).await.unwrap();
+            }
+        });
+        (url, counters)
+    }
+
+    // answers connects with CONNECTION_ID and announces with one peer
+    fn well_behaved(req: &[u8]) -> Vec<u8> {
+        let mut res = Vec::new();
+        if i64::from_be_bytes(req[0..8].try_into().unwrap()) == PROTOCOL_ID {
+            res.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+            res.extend_from_slice(&req[12..16]);
+            res.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+            return res;
+        }
+        assert_eq!(
+            i64::from_be_bytes(req[0..8].try_into().unwrap()),
+            CONNECTION_ID
+        );
+        let action = u32::from_be_bytes(req[8..12].try_into().unwrap());
+        res.extend_from_slice(&action.to_be_bytes());
+        res.extend_from_slice(&req[12..16]);
+        if action == ACTION_ANNOUNCE {
+            assert_eq!(req.len(), 98);
+            // echo downloaded / event / key back through interval, leechers and seeders
+            let downloaded = u64::from_be_bytes(req[56..64].try_into().unwrap()) as i32;
+            res.extend_from_slice(&downloaded.to_be_bytes());
+            res.extend_from_slice(&req[80..84]);
+            res.extend_from_slice(&req[88..92]);
+            res.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        } else {
+            // scrape: stats are [i, i, i] for the i-th info hash
+            let hashes = (req.len() - 16) / 20;
+            for i in 0..hashes as i32 {
+                for _ in 0..3 {
+                    res.extend_from_slice(&i.to_be_bytes());
+                }
+            }
+        }
+        res
+    }
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            downloaded: 300,
+            left: 1000,
+            uploaded: 0,
+            event: 2,
+            key: 0xdead,
+            num_want: -1,
+            port: 6881,
+        }
+    }
+
+    fn fast(tracker: UdpTracker) -> UdpTracker {
+        tracker.with_timeouts(Duration::from_millis(50), 3)
+    }
+
+    #[tokio::test]
+    async fn announce_through_socks5() {
+        let (url, counters) = tracker(0, well_behaved).await;
+        let proxy = crate::proxy::test::socks5_proxy(Some(("user", "pass"))).await;
+        let mut client = fast(UdpTracker::with_proxy(&url, &proxy).await.unwrap());
+
+        let (info, peers) = client.announce(&request()).await.unwrap();
+        assert_eq!(info.interval, 300);
+        assert_eq!(peers, ["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(counters.announces.load(Ordering::SeqCst), 1);
+
+        // HTTP proxies can't carry UDP
+        let proxy = crate::proxy::test::http_proxy(None).await;
+        assert!(UdpTracker::with_proxy(&url, &proxy).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn announce_reuses_connection_id() {
+        let (url, counters) = tracker(0, well_behaved).await;
+        let mut client = fast(UdpTracker::new(&url).await.unwrap());
+
+        let (info, peers) = client.announce(&request()).await.unwrap();
+        assert_eq!(
+            (info.interval, info.leechers, info.seeders),
+            (300, 2, 0xdead)
+        );
+        assert_eq!(peers, ["127.0.0.1:6881".parse().unwrap()]);
+
+        client.announce(&request()).await.unwrap();
+        assert_eq!(counters.connects.load(Ordering::SeqCst), 1);
+        assert_eq!(counters.announces.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn ipv6_tracker_gives_ipv6_peers() {
+        let (url, _) = tracker_at("[::1]:0", 0, |req| {
+            let mut res = well_behaved(req);
+            if res.len() > 16 {
+                // swap the IPv4 peer for [::1]:6881
+                res.truncate(20);
+                res.extend_from_slice(&[0; 15]);
+                res.extend_from_slice(&[1, 0x1a, 0xe1]);
+            }
+            res
+        })
+        .await;
+        assert!(url.host.starts_with("[::1]:"));
+        let mut client = fast(UdpTracker::new(&url).await.unwrap());
+        let (_, peers) = client.announce(&request()).await.unwrap();
+        assert_eq!(peers, ["[::1]:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn expired_connection_id_reconnects() {
+        let (url, counters) = tracker(0, well_behaved).await;
+        let mut client = fast(UdpTracker::new(&url).await.unwrap());
+        client.connection_ttl = Duration::ZERO;
+
+        client.announce(&request()).await.unwrap();
+        client.announce(&request()).await.unwrap();
+        assert_eq!(counters.connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn retransmits_lost_packets() {
+        // loses the first connect and the first announce
+        let (url, counters) = tracker(1, well_behaved).await;
+        let mut client = fast(UdpTracker::new(&url).await.unwrap());
+        client.announce(&request()).await.unwrap();
+        assert_eq!(counters.connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retransmissions() {
+        let (url, _) = tracker(usize::MAX, well_behaved).await;
+        let mut client = UdpTracker::new(&url)
+            .await
+            .unwrap()
+            .with_timeouts(Duration::from_millis(10), 2);
+        let res = client.announce(&request()).await;
+        assert!(matches!(res, Err(UttdError::RequestTimeout)));
+    }
+
+    #[tokio::test]
+    async fn error_packet() {
+        let (url, _) = tracker(0, |req| {
+            let mut res = 3_u32.to_be_bytes().to_vec();
+            res.extend_from_slice(&req[12..16]);
+            res.extend_from_slice(b"torrent not registered");
+            res
+        })
+        .await;
+        let mut client = fast(UdpTracker::new(&url).await.unwrap());
+        match client.announce(&request()).await {
+            Err(UttdError::TrackerFailure(m)) => assert_eq!(m, "torrent not registered"),
+            res => panic!("expected a tracker failure, got {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn mismatched_transaction_id_is_ignored() {
+        let (url, _) = tracker(0, |req| {
+            let mut res = well_behaved(req);
+            res[4] ^= 0xff;
+            res
+        })
+        .await;
+        let mut client = UdpTracker::new(&url)
+            .await
+            .unwrap()
+            .with_timeouts(Duration::from_millis(10), 1);
+        assert!(matches!(
+            client.announce(&request()).await,
+            Err(UttdError::RequestTimeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn wrong_action_is_a_protocol_violation() {
+        let (url, _) = tracker(0, |req| {
+            let mut res = well_behaved(req);
+            res[3] = 7;
+            res
+        })
+        .await;
+        let mut client = fast(UdpTracker::new(&url).await.unwrap());
+        assert!(matches!(
+            client.announce(&request()).await,
+            Err(UttdError::ProtocolViolation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn scrape_many() {
+        let (url, _) = tracker(0, well_behaved).await;
+        let mut client = fast(UdpTracker::new(&url).await.unwrap());
+        let hashes = vec![[0u8; 20]; 80];
+        let stats = client.scrape(&hashes).await.unwrap();
+        assert_eq!(stats.len(), 80);
+        // split in two packets: 74 + 6
+        assert_eq!(
+            stats[75],
+            ScrapeStats {
+                complete: 1,
+                downloaded: 1,
+                incomplete: 1
+            }
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::net::{IpAddr, SocketAddr};
+
 use crate::error::UrlError;
 
 /// Url
@@ -54,7 +56,8 @@ impl<'a> Url {
         // let (base, port) = base.rsplit_once(':').ok_or(UrlError::InvalidUrl)?;
         let mut loc = "/";
 
-        if let Some((b, location)) = base.rsplit_once('/') {
+        // everything after the first '/' is the location, which may itself contain '/'
+        if let Some((b, location)) = base.split_once('/') {
             base = b;
             loc = location;
         }
@@ -65,23 +68,37 @@ impl<'a> Url {
             location: loc.to_owned(),
         })
     }
-    /// Create a `Url` from bytes in form of [x, x, x, x]
-    /// IPv4
+    /// Create a `Url` from the bytes of an IPv4 ([x, x, x, x]) or IPv6 (16 bytes) address.
+    /// Any other length is taken as the unspecified IPv4 address
     pub fn from_ip_bytes(ip: &'a [u8], port: u16, scheme: Scheme) -> Self {
-        let mut ip_addr = String::new();
-        ip.iter().for_each(|x| ip_addr.push_str(&format!("{}.", x)));
-        ip_addr.pop();
-        let ip = format!("{}:{}", ip_addr, port);
+        let ip = match ip.len() {
+            16 => IpAddr::from(<[u8; 16]>::try_from(ip).unwrap()),
+            4 => IpAddr::from(<[u8; 4]>::try_from(ip).unwrap()),
+            _ => IpAddr::from([0; 4]),
+        };
+        Self::from_socket_addr(SocketAddr::new(ip, port), scheme)
+    }
+
+    /// Create a `Url` pointing at `addr`. IPv6 hosts are put in brackets
+    /// ```
+    /// use uttd::url::{Scheme, Url};
+    /// let url = Url::from_socket_addr("[::1]:6881".parse().unwrap(), Scheme::UDP);
+    /// assert_eq!(url.host, "[::1]:6881");
+    /// ```
+    pub fn from_socket_addr(addr: SocketAddr, scheme: Scheme) -> Self {
         Self {
             scheme,
-            host: ip,
+            host: addr.to_string(),
             location: "/".to_owned(),
         }
     }
 
-    /// Create `Url` from a string of ip address
+    /// Create `Url` from a string of ip address, or a domain name
     pub fn from_ip(ip: &'a str, port: u16) -> Result<Self, UrlError> {
-        let ip = format!("{}:{}", ip, port);
+        let ip = match ip.parse::<IpAddr>() {
+            Ok(IpAddr::V6(v6)) => format!("[{}]:{}", v6, port),
+            _ => format!("{}:{}", ip, port),
+        };
         Ok(Self {
             scheme: Scheme::HTTP,
             host: ip,
@@ -89,16 +106,82 @@ impl<'a> Url {
         })
     }
 
-    /// Get the port associated with the remote address
+    // the host without its port, and the port if there's one.
+    // IPv6 hosts are in brackets, which is how their colons are told from the port's
+    fn split_port(&self) -> (&str, Option<&str>) {
+        if let Some(rest) = self.host.strip_prefix('[') {
+            return match rest.split_once(']') {
+                Some((ip, port)) => (ip, port.strip_prefix(':')),
+                None => (rest, None),
+            };
+        }
+        match self.host.split_once(':') {
+            // a bare IPv6 address has no port
+            Some((_, port)) if port.contains(':') => (&self.host, None),
+            Some((domain, port)) => (domain, Some(port)),
+            None => (&self.host, None),
+        }
+    }
+
+    /// The `{host}:{port}` to connect to
+    /// Falls back to the scheme's default port if the url doesn't name one
+    /// ```
+    /// use uttd::url::Url;
+    /// let url = Url::new("https://tracker.example.com/announce").unwrap();
+    /// assert_eq!(url.authority(), "tracker.example.com:443");
+    /// ```
+    pub fn authority(&self) -> String {
+        let (domain, port) = self.split_port();
+        if let Some(port) = port {
+            return format!("{}:{}", self.host_header(), port);
+        }
+        let port = match self.scheme {
+            Scheme::HTTPS => 443,
+            _ => 80,
+        };
+        // `domain` is unbracketed when IPv6
+        match domain.contains(':') {
+            true => format!("[{}]:{}", domain, port),
+            false => format!("{}:{}", domain, port),
+        }
+    }
+
+    /// Host of the remote address, without the port. IPv6 addresses lose their brackets
+    /// ```
+    /// use uttd::url::Url;
+    /// assert_eq!(Url::new("http://[::1]:8080/a").unwrap().domain(), "::1");
+    /// ```
+    pub fn domain(&self) -> &str {
+        self.split_port().0
+    }
+
+    /// Host of the remote address as it goes in a `Host` header: without the port,
+    /// IPv6 addresses in brackets
+    pub fn host_header(&self) -> String {
+        let domain = self.domain();
+        match domain.contains(':') {
+            true => format!("[{}]", domain),
+            false => domain.to_owned(),
+        }
+    }
+
+    /// The address of the host, if it's an ip address and not a domain name
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.authority().parse().ok()
+    }
+
+    /// Get the port associated with the remote address, or the scheme's default port
     /// ```
     /// use uttd::url::Url;
     /// let url = Url::new("http://google.com:80/some_page").unwrap();
-    /// assert_eq!(80, url.port());
+    /// assert_eq!(80, url.port().unwrap());
+    /// assert!(Url::new("udp://tracker.example.com:x").unwrap().port().is_err());
     /// ```
 
-    pub fn port(&self) -> u16 {
-        let (_, port) = self.host.split_once(':').unwrap();
-        port.parse().unwrap()
+    pub fn port(&self) -> Result<u16, UrlError> {
+        let authority = self.authority();
+        let (_, port) = authority.rsplit_once(':').ok_or(UrlError::InvalidUrl)?;
+        Ok(port.parse()?)
     }
 }
 
@@ -112,7 +195,7 @@ mod test {
     #[test]
     fn http_announce() {
         let url = Url::new("http://bttracker.debian.org:6969/announce").unwrap();
-        let port = url.port();
+        let port = url.port().unwrap();
         let scheme = url.scheme;
         let host = url.host;
 
@@ -126,7 +209,7 @@ mod test {
     fn udp_announce() {
         let url = Url::new("udp://open.demonii.com:1337").unwrap();
 
-        let port = url.port();
+        let port = url.port().unwrap();
         let scheme = url.scheme;
         let host = url.host;
 
@@ -135,4 +218,39 @@ mod test {
             (1337, Scheme::UDP, "open.demonii.com:1337".to_owned())
         );
     }
+
+    #[test]
+    fn ipv6_hosts() {
+        let url = Url::new("udp://[2001:db8::1]:6969/announce").unwrap();
+        assert_eq!(url.domain(), "2001:db8::1");
+        assert_eq!(url.port().unwrap(), 6969);
+        assert_eq!(url.authority(), "[2001:db8::1]:6969");
+        assert_eq!(url.host_header(), "[2001:db8::1]");
+        assert_eq!(
+            url.socket_addr(),
+            Some("[2001:db8::1]:6969".parse().unwrap())
+        );
+
+        let url = Url::new("https://[::1]/").unwrap();
+        assert_eq!(url.authority(), "[::1]:443");
+        let url = Url::from_ip("::1", 6881).unwrap();
+        assert_eq!(url.host, "[::1]:6881");
+
+        let mut ip = [0; 16];
+        ip[15] = 1;
+        let url = Url::from_ip_bytes(&ip, 6881, Scheme::UDP);
+        assert_eq!(url.host, "[::1]:6881");
+        assert_eq!(
+            Url::from_ip_bytes(&[10, 0, 0, 1], 80, Scheme::HTTP).host,
+            "10.0.0.1:80"
+        );
+        assert_eq!(Url::new("http://example.com/").unwrap().socket_addr(), None);
+    }
+
+    #[test]
+    fn nested_location() {
+        let url = Url::new("http://seed.example.com:8080/files/debian/").unwrap();
+        assert_eq!(url.host, "seed.example.com:8080");
+        assert_eq!(url.location, "files/debian/");
+    }
 }
@@ -1,32 +1,81 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
 
 use crate::{url::Scheme, UttdError};
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub struct MetaInfo {
     pub seeders: i32,
     pub leechers: i32,
     pub interval: i32,
 }
 
-fn encode(value: &[u8]) -> String {
+/// Tracker stats for one torrent, as returned by a scrape
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct ScrapeStats {
+    /// number of seeders
+    pub complete: i32,
+    /// number of times the torrent has been downloaded
+    pub downloaded: i32,
+    /// number of leechers
+    pub incomplete: i32,
+}
+
+/// A single address in compact form: 4 bytes of IPv4 (BEP 23) or 16 of IPv6 (BEP 7)
+/// address followed by 2 bytes of port. `None` for any other length
+pub fn compact_addr(bytes: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = bytes.split_at_checked(bytes.len().checked_sub(2)?)?;
+    let ip = match ip.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+}
+
+/// A list of IPv4 addresses in compact form, 6 bytes each.
+/// A trailing partial entry is ignored
+pub fn compact_v4(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes.chunks_exact(6).filter_map(compact_addr).collect()
+}
+
+/// A list of IPv6 addresses in compact form, 18 bytes each.
+/// A trailing partial entry is ignored
+pub fn compact_v6(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes.chunks_exact(18).filter_map(compact_addr).collect()
+}
+
+/// Percent-encode every byte of `value`, e.g. for an `info_hash` query parameter
+pub fn encode(value: &[u8]) -> String {
     let mut formatted = String::new();
 
     value.iter().for_each(|x| {
-        formatted.push_str(&format!("%{:x}", x));
+        formatted.push_str(&format!("%{:02x}", x));
     });
 
     formatted
 }
 
+// unreserved bytes go as they are, everything else is percent-encoded
 fn u8_string(value: &[u8]) -> String {
-    value.iter().map(|x| *x as char).collect::<String>()
+    value
+        .iter()
+        .map(|x| match x {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (*x as char).to_string()
+            }
+            _ => format!("%{:02x}", x),
+        })
+        .collect::<String>()
 }
 
+/// `base` with `params` appended to its query, if it already has one
 pub fn build_url(base: &str, params: &HashMap<&str, Vec<u8>>) -> String {
-    let mut url: String = String::from_str(base).expect("FAILED to create String");
+    let mut url = base.to_owned();
 
-    url.push('?');
+    url.push(if base.contains('?') { '&' } else { '?' });
 
     for (k, v) in params {
         if *k == "info_hash" {
@@ -54,12 +103,22 @@ pub fn response(scheme: Scheme, res: &mut Vec<u8>) -> Result<(MetaInfo, &mut [u8
 }
 
 pub fn response_body_udp(res: &mut Vec<u8>) -> Result<(MetaInfo, &mut [u8]), UttdError> {
+    // action 3: the rest of the packet is the error message
+    if res.len() >= 8 && res[0..4] == 3_u32.to_be_bytes() {
+        let message = String::from_utf8_lossy(&res[8..]);
+        return Err(UttdError::TrackerFailure(
+            message.trim_end_matches('\0').to_owned(),
+        ));
+    }
+    if res.len() < 20 {
+        return Err(UttdError::ProtocolViolation("announce response too short"));
+    }
     let (head, response) = res.split_at_mut(20);
     let interval = i32::from_be_bytes(head[8..12].try_into().unwrap());
     let leechers = i32::from_be_bytes(head[12..16].try_into().unwrap());
     let seeders = i32::from_be_bytes(head[16..20].try_into().unwrap());
 
-    let response_body = &mut response[20..];
+    let response_body = response;
 
     let info = MetaInfo {
         seeders,
@@ -68,15 +127,19 @@ pub fn response_body_udp(res: &mut Vec<u8>) -> Result<(MetaInfo, &mut [u8]), Utt
     };
 
     Ok((info, response_body))
-    // Err(UttdError::FailedRequest)
 }
 
 pub fn response_body_tcp(res: &mut Vec<u8>) -> Result<(MetaInfo, &mut [u8]), UttdError> {
-    let response_code =
-        (res[9] as u32 - 48) * 100 + (res[10] as u32 - 48) * 10 + (res[11] as u32 - 48);
+    // "HTTP/1.1 200 OK"
+    let response_code = res
+        .get(9..12)
+        .filter(|_| res.starts_with(b"HTTP/"))
+        .and_then(|code| std::str::from_utf8(code).ok())
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or(UttdError::ProtocolViolation("malformed status line"))?;
 
     if response_code != 200 {
-        return Err(UttdError::FailedRequest);
+        return Err(UttdError::HttpStatus(response_code));
     };
     let mut count = 0;
 
@@ -108,7 +171,47 @@ pub fn response_body_tcp(res: &mut Vec<u8>) -> Result<(MetaInfo, &mut [u8]), Utt
 
 mod test {
 
-    use super::response;
+    use std::collections::HashMap;
+
+    use super::{build_url, compact_addr, compact_v4, compact_v6, encode, response};
+    use crate::{url::Scheme, UttdError};
+
+    #[test]
+    fn compact_addresses() {
+        let v4 = [127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 1, 0, 80, 1];
+        assert_eq!(
+            compact_v4(&v4),
+            [
+                "127.0.0.1:6881".parse().unwrap(),
+                "10.0.0.1:80".parse().unwrap()
+            ]
+        );
+        let mut v6 = vec![0; 15];
+        v6.extend_from_slice(&[1, 0x1a, 0xe1]);
+        assert_eq!(compact_v6(&v6), ["[::1]:6881".parse().unwrap()]);
+        assert_eq!(compact_addr(&v6), Some("[::1]:6881".parse().unwrap()));
+        assert_eq!(compact_addr(&v4[..5]), None);
+        assert!(compact_v6(&v4).is_empty());
+    }
+
+    #[test]
+    fn build_url_escapes_reserved() {
+        let mut params = HashMap::new();
+        params.insert("trackerid", b"a b&c".to_vec());
+        assert_eq!(
+            build_url("announce", &params),
+            "announce?trackerid=a%20b%26c"
+        );
+        assert_eq!(
+            build_url("announce?passkey=abc", &params),
+            "announce?passkey=abc&trackerid=a%20b%26c"
+        );
+    }
+
+    #[test]
+    fn encode_pads_bytes() {
+        assert_eq!(encode(&[0x0a, 0xff, 0x00]), "%0a%ff%00");
+    }
 
     #[test]
     fn parse_ok() {
@@ -126,6 +229,26 @@ mod test {
     fn parse_err() {
         let mut value = "HTTP/1.1 301 Not Found".as_bytes().to_vec();
         let res = response(crate::url::Scheme::HTTP, &mut value);
-        assert!(res.is_err());
+        assert!(matches!(res, Err(UttdError::HttpStatus(301))));
+
+        for garbage in ["", "HTTP/1.1", "SSH-2.0-OpenSSH_9.6"] {
+            let mut value = garbage.as_bytes().to_vec();
+            let res = response(Scheme::HTTP, &mut value);
+            assert!(matches!(res, Err(UttdError::ProtocolViolation(_))));
+        }
+    }
+
+    #[test]
+    fn parse_udp_errors() {
+        let mut failure = vec![0, 0, 0, 3, 1, 2, 3, 4];
+        failure.extend_from_slice(b"unregistered torrent\0\0\0");
+        match response(Scheme::UDP, &mut failure) {
+            Err(UttdError::TrackerFailure(m)) => assert_eq!(m, "unregistered torrent"),
+            res => panic!("expected a tracker failure, got {:?}", res),
+        }
+
+        let mut short = vec![0, 0, 0, 1, 1, 2, 3, 4, 0, 0];
+        let res = response(Scheme::UDP, &mut short);
+        assert!(matches!(res, Err(UttdError::ProtocolViolation(_))));
     }
 }
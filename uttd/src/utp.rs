@@ -1,121 +1,702 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+// REFERENCE: https://www.bittorrent.org/beps/bep_0029.html
+
+mod connection;
+mod ledbat;
+mod mtu;
+pub mod packet;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Instant,
+};
 
 use crypto::tinymt::TinyMT;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{lookup_host, ToSocketAddrs, UdpSocket},
+    sync::{mpsc, Mutex as AsyncMutex, Notify},
+};
+
+use crate::UttdError;
+use connection::{Connection, State};
+pub use packet::{Packet, PacketType};
+
+const MAX_DATAGRAM: usize = 4096;
+// inbound connections waiting for `accept`, further SYNs are dropped
+const BACKLOG: usize = 32;
+// non-uTP datagrams waiting for `recv_from`, further datagrams are dropped
+const DATAGRAM_QUEUE: usize = 256;
+
+/// A UDP socket shared by any number of uTP connections.
+/// Incoming packets are handed to their connection by sender address and
+/// connection id, SYNs for unknown connections are queued up for `accept`.
+/// Anything that isn't uTP, e.g. DHT traffic, is passed on to `recv_from`.
+///
+/// ```no_run
+/// use uttd::utp::UtpSocket;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let socket = UtpSocket::bind("0.0.0.0:6881").await.unwrap();
+///     let outbound = socket.connect("127.0.0.1:6882").await.unwrap();
+///     let inbound = socket.accept().await.unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct UtpSocket {
+    mux: Arc<Mux>,
+    incoming: AsyncMutex<mpsc::Receiver<UtpStream>>,
+    datagrams: AsyncMutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    local_addr: SocketAddr,
+}
+
+#[derive(Debug)]
+struct Mux {
+    socket: UdpSocket,
+    /// packets for each connection, by remote address and the connection id the remote sends
+    connections: Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>,
+    // wakes the demultiplexer when a connection or the `UtpSocket` went away
+    changed: Notify,
+}
+
+impl UtpSocket {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, UttdError> {
+        Self::new(UdpSocket::bind(addr).await?)
+    }
+
+    /// Run uTP over an already bound `socket`
+    pub fn new(socket: UdpSocket) -> Result<Self, UttdError> {
+        let local_addr = socket.local_addr()?;
+        let mux = Arc::new(Mux {
+            socket,
+            connections: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+        });
+        let (incoming_tx, incoming) = mpsc::channel(BACKLOG);
+        let (datagrams_tx, datagrams) = mpsc::channel(DATAGRAM_QUEUE);
+        tokio::spawn(demultiplex(mux.clone(), incoming_tx, datagrams_tx));
+        Ok(Self {
+            mux,
+            incoming: AsyncMutex::new(incoming),
+            datagrams: AsyncMutex::new(datagrams),
+            local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Connect to the uTP peer at `addr`
+    pub async fn connect<A: ToSocketAddrs>(&self, addr: A) -> Result<UtpStream, UttdError> {
+        let addr = lookup_host(addr)
+            .await?
+            .find(|a| a.is_ipv4() == self.local_addr.is_ipv4())
+            .ok_or(io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+
+        let stream = {
+            let mut connections = self.mux.connections.lock().unwrap();
+            // the remote answers with our id, and sends with our id + 1 if it connects back
+            let mut id = random_id();
+            while connections.contains_key(&(addr, id)) {
+                id = id.wrapping_add(2);
+            }
+            let conn = Connection::connect(id, Instant::now());
+            UtpStream::open(&self.mux, &mut connections, conn, addr)
+        };
+        loop {
+            let notified = stream.shared.state_changed.notified();
+            {
+                let inner = stream.shared.inner.lock().unwrap();
+                match inner.conn.state() {
+                    State::Connected => break,
+                    State::Closed => {
+                        let kind = inner.conn.error().unwrap_or(io::ErrorKind::NotConnected);
+                        return Err(io::Error::from(kind).into());
+                    }
+                    State::SynSent => {}
+                }
+            }
+            notified.await;
+        }
+        Ok(stream)
+    }
+
+    /// Wait for a peer to connect
+    pub async fn accept(&self) -> Result<UtpStream, UttdError> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            // the demultiplexer only stops when the socket failed
+            .ok_or(io::Error::from(io::ErrorKind::BrokenPipe).into())
+    }
+
+    /// Receive a datagram that isn't uTP
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), UttdError> {
+        let (datagram, from) = self
+            .datagrams
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(io::Error::from(io::ErrorKind::BrokenPipe))?;
+        let n = buf.len().min(datagram.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok((n, from))
+    }
+
+    /// Send a datagram from the shared socket, bypassing uTP
+    pub async fn send_to<A: ToSocketAddrs>(
+        &self,
+        data: &[u8],
+        addr: A,
+    ) -> Result<usize, UttdError> {
+        Ok(self.mux.socket.send_to(data, addr).await?)
+    }
+}
+
+impl Drop for UtpSocket {
+    // the connections keep going, the demultiplexer stops after the last one closed
+    fn drop(&mut self) {
+        self.mux.changed.notify_one();
+    }
+}
+
+// Reads every datagram that arrives on the socket and hands it to its connection,
+// to `accept` or to `recv_from`
+async fn demultiplex(
+    mux: Arc<Mux>,
+    incoming: mpsc::Sender<UtpStream>,
+    datagrams: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        // the `UtpSocket` dropped its end of the channel
+        if incoming.is_closed() && mux.connections.lock().unwrap().is_empty() {
+            break;
+        }
+        let (read, from) = tokio::select! {
+            res = mux.socket.recv_from(&mut buf) => match res {
+                Ok(res) => res,
+                Err(_) => continue,
+            },
+            _ = mux.changed.notified() => continue,
+        };
+        let Ok(packet) = Packet::parse(&buf[..read]) else {
+            _ = datagrams.try_send((buf[..read].to_vec(), from));
+            continue;
+        };
 
-// use tokio::net::UdpSocket;
-
-// use crate::url::Url;
-
-const ST_DATA: u8 = 0;
-const ST_FIN: u8 = 1;
-const ST_STATE: u8 = 2;
-const ST_RESET: u8 = 3;
-const ST_SYN: u8 = 4;
-
-/// Async uTorrent Transport Protocol
-
-// 0       4       8               16              24              32
-// +-------+-------+---------------+---------------+---------------+
-// | type  | ver   | extension     | connection_id                 |
-// +-------+-------+---------------+---------------+---------------+
-// | timestamp_microseconds                                        |
-// +---------------+---------------+---------------+---------------+
-// | timestamp_difference_microseconds                             |
-// +---------------+---------------+---------------+---------------+
-// | wnd_size                                                      |
-// +---------------+---------------+---------------+---------------+
-// | seq_nr                        | ack_nr                        |
-// +---------------+---------------+---------------+---------------+
-#[derive(Eq, PartialEq, PartialOrd, Ord)]
-// #[repr(C, packed)]
-pub struct UtpPacket {
-    packet_version: u8,
-    extension: u8,
-    connection_id: u16,
-    timestamp: u32,
-    timestamp_difference: u32,
-    window_size: u32,
-    /// sequence number
-    seq_number: u16,
-    /// acknowledged number
-    ack_number: u16,
-}
-
-impl Default for UtpPacket {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-impl UtpPacket {
-    pub fn new() -> Self {
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-
-        let timestamp = (timestamp.as_secs().wrapping_add(1_000_000) as u32)
-            .wrapping_add(timestamp.subsec_micros());
+        let mut connections = mux.connections.lock().unwrap();
+        let id = packet.connection_id;
+        if let Some(conn) = connections.get(&(from, id)) {
+            _ = conn.send(packet);
+        } else if packet.packet_type == PacketType::Syn {
+            // a SYN is sent with the id the remote sends everything else with, minus one
+            if let Some(conn) = connections.get(&(from, id.wrapping_add(1))) {
+                _ = conn.send(packet);
+            } else if let Ok(permit) = incoming.try_reserve() {
+                let conn = Connection::accept(&packet, random_id(), Instant::now());
+                permit.send(UtpStream::open(&mux, &mut connections, conn, from));
+            }
+        }
+    }
+}
+
+/// Async uTorrent Transport Protocol stream
+/// A reliable, ordered byte stream over UDP, used like a `TcpStream`.
+/// Streams are created by a `UtpSocket`, which may be shared with other
+/// streams; a background task per stream does the actual sending, acking
+/// and retransmitting.
+///
+/// ```no_run
+/// use tokio::io::AsyncWriteExt;
+/// use uttd::utp::UtpStream;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let mut stream = UtpStream::connect("127.0.0.1:6881").await.unwrap();
+///     stream.write_all(b"hello").await.unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct UtpStream {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+#[derive(Debug)]
+struct Shared {
+    inner: Mutex<Inner>,
+    // wakes the driver when the application wrote, read or closed
+    driver: Notify,
+    // wakes `connect` on every state change
+    state_changed: Notify,
+}
+
+#[derive(Debug)]
+struct Inner {
+    conn: Connection,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Inner {
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl UtpStream {
+    /// Connect to the uTP peer at `addr` from a socket of its own
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, UttdError> {
+        let addr = lookup_host(addr)
+            .await?
+            .next()
+            .ok_or(io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+        let bind = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        UtpSocket::bind(bind).await?.connect(addr).await
+    }
+
+    /// Wait for a peer to connect to `socket` and take the socket over
+    pub async fn accept(socket: UdpSocket) -> Result<Self, UttdError> {
+        UtpSocket::new(socket)?.accept().await
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
 
+    // register `conn` with the demultiplexer and start driving it
+    fn open(
+        mux: &Arc<Mux>,
+        connections: &mut HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>,
+        conn: Connection,
+        peer_addr: SocketAddr,
+    ) -> Self {
+        let (packets_tx, packets) = mpsc::unbounded_channel();
+        connections.insert((peer_addr, conn.recv_id()), packets_tx);
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(Inner {
+                conn,
+                read_waker: None,
+                write_waker: None,
+            }),
+            driver: Notify::new(),
+            state_changed: Notify::new(),
+        });
+        tokio::spawn(drive(shared.clone(), mux.clone(), peer_addr, packets));
         Self {
-            // first message is the ST_SYN message
-            // the first four bits from the left is the version number (always one)
-            // and the last 4 are packet id
-            packet_version: (ST_SYN << 4) | 1,
-            extension: 0,
-            // "random" connection id
-            connection_id: TinyMT::rand(1).get_u32() as u16,
-            timestamp,
-            timestamp_difference: 0,
-            window_size: 0xf000,
-            // initialized to 1
-            seq_number: 1,
-            ack_number: 0,
-        }
-    }
-
-    pub fn refetch_timestamp(&mut self) {
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        let timestamp = (timestamp.as_secs().wrapping_add(1_000_000) as u32)
-            .wrapping_add(timestamp.subsec_micros());
-
-        self.timestamp = timestamp;
-    }
-
-    pub fn as_bytes(self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(&self.packet_version.to_be_bytes());
-        bytes.extend_from_slice(&self.extension.to_be_bytes());
-        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
-        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
-        bytes.extend_from_slice(&self.timestamp_difference.to_be_bytes());
-        bytes.extend_from_slice(&self.window_size.to_be_bytes());
-        bytes.extend_from_slice(&self.seq_number.to_be_bytes());
-        bytes.extend_from_slice(&self.ack_number.to_be_bytes());
-        // println!("{}", bytes.len());
-        bytes
-    }
-}
-
-// pub struct UtpSocket {
-//     pub socket: UdpSocket,
-// }
-
-// impl UtpSocket {
-//     pub async fn new(url: &Url) -> Self {
-//         let sock = UdpSocket::bind("0.0.0.0:0").await.unwrap();
-//         sock.connect(&url.host).await.unwrap();
-
-//         Self { socket: sock }
-//     }
-// }
+            shared,
+            // the socket is bound, it has an address
+            local_addr: mux.socket.local_addr().unwrap(),
+            peer_addr,
+        }
+    }
+}
+
+// Runs until the connection is closed: sends whatever the connection wants
+// to send, then waits for a packet, the application or the next timeout
+async fn drive(
+    shared: Arc<Shared>,
+    mux: Arc<Mux>,
+    peer_addr: SocketAddr,
+    mut packets: mpsc::UnboundedReceiver<Packet>,
+) {
+    let recv_id = shared.inner.lock().unwrap().conn.recv_id();
+    loop {
+        let (outgoing, deadline, closed) = {
+            let mut inner = shared.inner.lock().unwrap();
+            let now = Instant::now();
+            let outgoing: Vec<Vec<u8>> = std::iter::from_fn(|| inner.conn.poll_transmit(now))
+                .map(|p| p.as_bytes())
+                .collect();
+            inner.wake();
+            (outgoing, inner.conn.poll_timeout(), inner.conn.is_closed())
+        };
+        shared.state_changed.notify_one();
+        for packet in outgoing {
+            // a failed send is no different from a lost packet
+            _ = mux.socket.send_to(&packet, peer_addr).await;
+        }
+        if closed {
+            break;
+        }
+
+        let timeout = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            Some(packet) = packets.recv() => {
+                shared.inner.lock().unwrap().conn.handle(packet, Instant::now());
+            }
+            _ = shared.driver.notified() => {}
+            _ = timeout => {
+                shared.inner.lock().unwrap().conn.on_timeout(Instant::now());
+            }
+        }
+    }
+    mux.connections
+        .lock()
+        .unwrap()
+        .remove(&(peer_addr, recv_id));
+    mux.changed.notify_one();
+}
+
+fn random_id() -> u16 {
+    TinyMT::from_clock().get_u32() as u16
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        let read = inner.conn.read(buf.initialize_unfilled());
+        if read > 0 {
+            buf.advance(read);
+            self.shared.driver.notify_one();
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = inner.conn.error() {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if inner.conn.is_eof() || inner.conn.is_closed() {
+            return Poll::Ready(Ok(()));
+        }
+        inner.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if let Some(kind) = inner.conn.error() {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if inner.conn.is_closing() || inner.conn.is_closed() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let written = inner.conn.write(data);
+        if written == 0 && !data.is_empty() {
+            inner.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        self.shared.driver.notify_one();
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Sends a FIN once all written data is out. Reading is still possible
+    /// until the remote closes its side
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.inner.lock().unwrap().conn.close();
+        self.shared.driver.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    // close gracefully, the driver keeps going until the FIN is acked
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.shared.inner.lock() {
+            inner.conn.close();
+        }
+        self.shared.driver.notify_one();
+    }
+}
 
 #[cfg(test)]
 mod test {
-    // use crate::utp::ST_SYN;
+    use std::{
+        io::ErrorKind,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UdpSocket,
+    };
+
+    use super::{Packet, PacketType, UtpSocket, UtpStream};
+
+    async fn listener() -> (UdpSocket, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    // what the relay does to the datagrams passing through, by index
+    struct Path {
+        drop: fn(usize) -> bool,
+        // held back and sent after the next datagram
+        hold: fn(usize) -> bool,
+        // larger datagrams are dropped
+        mtu: usize,
+    }
+
+    // forwards datagrams between a client and `server` along `path`
+    async fn relay(server: SocketAddr, path: Path) -> SocketAddr {
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = relay.local_addr().unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            let mut client = None;
+            let mut held = None;
+            let mut buf = vec![0; 4096];
+            loop {
+                let (read, from) = relay.recv_from(&mut buf).await.unwrap();
+                let i = count.fetch_add(1, Ordering::Relaxed);
+                if (path.drop)(i) || read > path.mtu {
+                    continue;
+                }
+                let to = if from == server {
+                    match client {
+                        Some(client) => client,
+                        None => continue,
+                    }
+                } else {
+                    client = Some(from);
+                    server
+                };
+                if held.is_none() && (path.hold)(i) {
+                    held = Some((buf[..read].to_vec(), to));
+                    continue;
+                }
+                relay.send_to(&buf[..read], to).await.unwrap();
+                if let Some((datagram, to)) = held.take() {
+                    relay.send_to(&datagram, to).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    // forwards datagrams, dropping the ones whose index `drop` returns true for
+    async fn lossy_relay(server: SocketAddr, drop: fn(usize) -> bool) -> SocketAddr {
+        let path = Path {
+            drop,
+            hold: |_| false,
+            mtu: usize::MAX,
+        };
+        relay(server, path).await
+    }
+
+    #[tokio::test]
+    async fn echo() {
+        let (socket, addr) = listener().await;
+        let server = tokio::spawn(async move {
+            let mut stream = UtpStream::accept(socket).await.unwrap();
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).await.unwrap();
+            stream.write_all(&data).await.unwrap();
+        });
+
+        let mut client = UtpStream::connect(addr).await.unwrap();
+        assert_eq!(client.peer_addr(), addr);
+        client.write_all(b"hello uTP").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut echoed = Vec::new();
+        client.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"hello uTP");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn bulk_transfer() {
+        let (socket, addr) = listener().await;
+        let data: Vec<u8> = (0..1 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let expected = data.clone();
+        let server = tokio::spawn(async move {
+            let mut stream = UtpStream::accept(socket).await.unwrap();
+            stream.write_all(&data).await.unwrap();
+        });
+
+        let mut client = UtpStream::connect(addr).await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert!(received == expected);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn lost_packets_are_retransmitted() {
+        let (socket, addr) = listener().await;
+        // lose the SYN, the first data packet and an ack
+        let relay = lossy_relay(addr, |i| [0, 3, 6].contains(&i)).await;
+        let server = tokio::spawn(async move {
+            let mut stream = UtpStream::accept(socket).await.unwrap();
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).await.unwrap();
+            data
+        });
+
+        let mut client = UtpStream::connect(relay).await.unwrap();
+        for chunk in 0..4u8 {
+            client.write_all(&[chunk; 3000]).await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+
+        let data = server.await.unwrap();
+        assert_eq!(data.len(), 12000);
+        assert!(data
+            .chunks(3000)
+            .enumerate()
+            .all(|(i, c)| c == [i as u8; 3000]));
+    }
+
+    #[tokio::test]
+    async fn impaired_path() {
+        let (socket, addr) = listener().await;
+        // small MTU, some loss and plenty of reordering
+        let path = Path {
+            drop: |i| i % 17 == 5,
+            hold: |i| i % 7 == 2,
+            mtu: 1200,
+        };
+        let relay = relay(addr, path).await;
+        let data: Vec<u8> = (0..1 << 18).map(|i: u32| (i % 251) as u8).collect();
+        let expected = data.clone();
+        let server = tokio::spawn(async move {
+            let mut stream = UtpStream::accept(socket).await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let mut client = UtpStream::connect(relay).await.unwrap();
+        client.write_all(&data).await.unwrap();
+        client.shutdown().await.unwrap();
+        let received = server.await.unwrap();
+        assert!(received == expected);
+    }
+
+    #[tokio::test]
+    async fn connections_share_a_socket() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        let echo = tokio::spawn(async move {
+            let mut handles = Vec::new();
+            for _ in 0..3 {
+                let mut stream = server.accept().await.unwrap();
+                handles.push(tokio::spawn(async move {
+                    let mut data = Vec::new();
+                    stream.read_to_end(&mut data).await.unwrap();
+                    stream.write_all(&data).await.unwrap();
+                }));
+            }
+            for handle in handles {
+                handle.await.unwrap();
+            }
+        });
+
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut streams = Vec::new();
+        for i in 0..3u8 {
+            let mut stream = client.connect(addr).await.unwrap();
+            assert_eq!(stream.local_addr(), client.local_addr());
+            stream.write_all(&[i; 5000]).await.unwrap();
+            stream.shutdown().await.unwrap();
+            streams.push(stream);
+        }
+        for (i, stream) in streams.iter_mut().enumerate() {
+            let mut echoed = Vec::new();
+            stream.read_to_end(&mut echoed).await.unwrap();
+            assert_eq!(echoed, [i as u8; 5000]);
+        }
+        echo.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_datagrams_are_passed_on() {
+        let socket = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr();
+        let (dht, dht_addr) = listener().await;
+
+        // a KRPC ping in the middle of a uTP connection
+        let accepted = tokio::spawn(async move {
+            let mut stream = socket.accept().await.unwrap();
+            let mut buf = [0; 64];
+            let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(from, dht_addr);
+            socket.send_to(&buf[..n], from).await.unwrap();
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).await.unwrap();
+            data
+        });
+        let mut stream = UtpStream::connect(addr).await.unwrap();
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        dht.send_to(ping, addr).await.unwrap();
+        stream.write_all(b"still uTP").await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut buf = [0; 64];
+        let (n, _) = dht.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], ping);
+        assert_eq!(accepted.await.unwrap(), b"still uTP");
+    }
+
+    #[tokio::test]
+    async fn reset_is_reported() {
+        let (socket, addr) = listener().await;
+        let connecting = tokio::spawn(UtpStream::connect(addr));
+
+        // answer the SYN by hand, then reset the connection
+        let mut buf = vec![0; 4096];
+        let (read, from) = socket.recv_from(&mut buf).await.unwrap();
+        let syn = Packet::parse(&buf[..read]).unwrap();
+        let mut state = Packet::new(PacketType::State, syn.connection_id);
+        state.seq_nr = 1;
+        state.ack_nr = syn.seq_nr;
+        state.window_size = 1 << 16;
+        socket.send_to(&state.as_bytes(), from).await.unwrap();
 
-    use super::UtpPacket;
+        let mut client = connecting.await.unwrap().unwrap();
+        let reset = Packet::new(PacketType::Reset, syn.connection_id);
+        socket.send_to(&reset.as_bytes(), from).await.unwrap();
 
-    #[test]
-    fn new_packet() {
-        let upacket = UtpPacket::new();
-        assert_eq!(upacket.packet_version, 65);
+        let mut buf = [0; 16];
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+        assert!(client.write(b"anyone?").await.is_err());
     }
 }
@@ -0,0 +1,1061 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::ErrorKind,
+    time::{Duration, Instant},
+};
+
+use super::{
+    ledbat::Ledbat,
+    mtu::{MtuSearch, MAX_DATAGRAM},
+    packet::{Extension, Packet, PacketType, HEADER_SIZE, SELECTIVE_ACK},
+};
+
+/// Largest payload put in a single packet, once path MTU discovery found the path takes it
+pub const MAX_PAYLOAD: usize = MAX_DATAGRAM - HEADER_SIZE;
+// bytes the application may queue up before writes start blocking
+const SEND_BUFFER: usize = 1 << 20;
+// bytes received but not yet read by the application
+const RECV_BUFFER: usize = 1 << 20;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_RETRANSMISSIONS: u32 = 6;
+const MAX_SYN_RETRANSMISSIONS: u32 = 3;
+// how long to wait for the remote's FIN once ours has been acked
+const LINGER: Duration = Duration::from_secs(10);
+// out of order packets further ahead than this are dropped
+const MAX_REORDER: u16 = 1024;
+// the oldest packet in flight counts as lost after this many acks that don't ack it,
+// any packet counts as lost once this many packets sent after it have been selectively acked
+const DUPLICATE_ACKS_BEFORE_RESEND: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+#[derive(Debug)]
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    resend: bool,
+    /// declared lost before the timeout, fast retransmitted at most once
+    lost: bool,
+}
+
+/// State machine of a single uTP connection.
+/// Doesn't do any IO itself: packets are fed in with `handle`, packets to send
+/// are taken out with `poll_transmit` and time is passed in explicitly.
+#[derive(Debug)]
+pub struct Connection {
+    state: State,
+    error: Option<ErrorKind>,
+    epoch: Instant,
+    recv_id: u16,
+    send_id: u16,
+    /// sequence number of the next packet we send
+    seq_nr: u16,
+    /// last sequence number received in order
+    ack_nr: u16,
+    /// first sequence number we used, the remote learns it from our reply to its SYN
+    initial_seq_nr: u16,
+    reply_syn: bool,
+
+    send_buf: VecDeque<u8>,
+    in_flight: VecDeque<Sent>,
+    recv_buf: VecDeque<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+
+    fin_received: Option<u16>,
+    eof: bool,
+    closing: bool,
+    fin_sent: Option<u16>,
+    fin_acked_at: Option<Instant>,
+
+    ack_pending: bool,
+    duplicate_acks: u32,
+    peer_window: u32,
+    reply_micro: u32,
+    ledbat: Ledbat,
+    mtu: MtuSearch,
+
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    timeout_at: Option<Instant>,
+}
+
+// `a` comes before or is `b`, taking wrapping into account
+fn seq_le(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+fn seq_lt(a: u16, b: u16) -> bool {
+    a != b && seq_le(a, b)
+}
+
+impl Connection {
+    fn new(recv_id: u16, send_id: u16, seq_nr: u16, state: State, now: Instant) -> Self {
+        Self {
+            state,
+            error: None,
+            epoch: now,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr: 0,
+            initial_seq_nr: seq_nr,
+            reply_syn: false,
+            send_buf: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            fin_received: None,
+            eof: false,
+            closing: false,
+            fin_sent: None,
+            fin_acked_at: None,
+            ack_pending: false,
+            duplicate_acks: 0,
+            // until the remote tells us otherwise
+            peer_window: RECV_BUFFER as u32,
+            reply_micro: 0,
+            ledbat: Ledbat::new(now),
+            mtu: MtuSearch::default(),
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: INITIAL_TIMEOUT,
+            timeout_at: None,
+        }
+    }
+
+    /// Start a connection. The SYN is the first packet `poll_transmit` returns
+    pub fn connect(recv_id: u16, now: Instant) -> Self {
+        let mut conn = Self::new(recv_id, recv_id.wrapping_add(1), 1, State::SynSent, now);
+        let mut syn = Packet::new(PacketType::Syn, recv_id);
+        syn.seq_nr = conn.seq_nr;
+        conn.seq_nr = conn.seq_nr.wrapping_add(1);
+        conn.in_flight.push_back(Sent {
+            packet: syn,
+            sent_at: now,
+            transmissions: 0,
+            resend: true,
+            lost: false,
+        });
+        conn
+    }
+
+    /// Accept the connection initiated by `syn`. Our first packet will have `seq_nr`
+    pub fn accept(syn: &Packet, seq_nr: u16, now: Instant) -> Self {
+        let mut conn = Self::new(
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            seq_nr,
+            State::Connected,
+            now,
+        );
+        conn.ack_nr = syn.seq_nr;
+        conn.peer_window = syn.window_size;
+        conn.reply_micro = conn.timestamp(now).wrapping_sub(syn.timestamp);
+        conn.reply_syn = true;
+        conn
+    }
+
+    /// Connection id of the packets the remote sends
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn error(&self) -> Option<ErrorKind> {
+        self.error
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    /// The remote finished sending and everything it sent has been read
+    pub fn is_eof(&self) -> bool {
+        self.eof && self.recv_buf.is_empty()
+    }
+
+    /// `close` has been called, nothing more can be written
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// Take received data, in order
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let window_was_closed = self.recv_window() < MAX_PAYLOAD as u32;
+        let n = buf.len().min(self.recv_buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.recv_buf.drain(..n)) {
+            *dst = src;
+        }
+        // let the remote know it can send again
+        if n > 0 && window_was_closed && !self.is_closed() {
+            self.ack_pending = true;
+        }
+        n
+    }
+
+    /// Queue data to be sent. Returns how many bytes fit in the send buffer
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        if self.closing || self.is_closed() {
+            return 0;
+        }
+        let n = data.len().min(SEND_BUFFER - self.send_buf.len());
+        self.send_buf.extend(&data[..n]);
+        n
+    }
+
+    /// Send a FIN once all queued data is out
+    pub fn close(&mut self) {
+        self.closing = true;
+    }
+
+    /// Process a packet received from the remote
+    pub fn handle(&mut self, packet: Packet, now: Instant) {
+        if self.is_closed() {
+            return;
+        }
+        if packet.packet_type == PacketType::Syn {
+            // the remote didn't get our reply to its SYN
+            if packet.connection_id.wrapping_add(1) == self.recv_id {
+                self.reply_syn = true;
+            }
+            return;
+        }
+        if packet.connection_id != self.recv_id {
+            return;
+        }
+        if packet.packet_type == PacketType::Reset {
+            let kind = match self.state {
+                State::SynSent => ErrorKind::ConnectionRefused,
+                _ => ErrorKind::ConnectionReset,
+            };
+            self.fail(kind);
+            return;
+        }
+        if self.state == State::SynSent {
+            if packet.packet_type != PacketType::State
+                || packet.ack_nr != self.seq_nr.wrapping_sub(1)
+            {
+                return;
+            }
+            self.state = State::Connected;
+            // the reply to a SYN carries the sequence number of the remote's first packet
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+        }
+
+        self.peer_window = packet.window_size;
+        self.reply_micro = self.timestamp(now).wrapping_sub(packet.timestamp);
+        self.acked(&packet, now);
+        if matches!(packet.packet_type, PacketType::Data | PacketType::Fin) {
+            self.received(packet);
+        }
+        self.check_closed();
+    }
+
+    /// Next packet to put on the wire, if any
+    pub fn poll_transmit(&mut self, now: Instant) -> Option<Packet> {
+        if self.is_closed() {
+            // the ack for the remote's FIN may still have to go out
+            if self.ack_pending && self.error.is_none() {
+                self.ack_pending = false;
+                return Some(self.state_packet(now));
+            }
+            return None;
+        }
+
+        // always points at our first packet, even when data has been sent already
+        if self.reply_syn {
+            self.reply_syn = false;
+            let mut packet = self.state_packet(now);
+            packet.seq_nr = self.initial_seq_nr;
+            return Some(packet);
+        }
+        if let Some(i) = self.in_flight.iter().position(|s| s.resend) {
+            let mut packet = self.in_flight[i].packet.clone();
+            self.stamp(&mut packet, now);
+            let sent = &mut self.in_flight[i];
+            sent.packet = packet.clone();
+            sent.sent_at = now;
+            sent.transmissions += 1;
+            sent.resend = false;
+            self.timeout_at.get_or_insert(now + self.rto);
+            self.ack_sent();
+            return Some(packet);
+        }
+        if self.state == State::SynSent {
+            return None;
+        }
+
+        if !self.send_buf.is_empty() {
+            let flight: usize = self.in_flight.iter().map(|s| s.packet.payload.len()).sum();
+            let window = self.ledbat.window().min(self.peer_window as usize);
+            let size = self.send_buf.len().min(self.max_payload());
+            // a single packet is always allowed out, which also probes a closed window
+            if self.in_flight.is_empty() || flight + size <= window {
+                let payload = self.send_buf.drain(..size).collect();
+                return Some(self.send_new(PacketType::Data, payload, now));
+            }
+        }
+        if self.closing && self.fin_sent.is_none() && self.send_buf.is_empty() {
+            self.fin_sent = Some(self.seq_nr);
+            return Some(self.send_new(PacketType::Fin, Vec::new(), now));
+        }
+        if self.ack_pending {
+            self.ack_pending = false;
+            return Some(self.state_packet(now));
+        }
+        None
+    }
+
+    /// When `on_timeout` should be called next
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if self.is_closed() {
+            return None;
+        }
+        let linger = self.fin_acked_at.map(|at| at + LINGER);
+        match (self.timeout_at, linger) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    pub fn on_timeout(&mut self, now: Instant) {
+        if self.is_closed() {
+            return;
+        }
+        if self.fin_acked_at.is_some_and(|at| now >= at + LINGER) {
+            self.state = State::Closed;
+            return;
+        }
+        if self.timeout_at.is_none_or(|at| now < at) {
+            return;
+        }
+        let limit = match self.state {
+            State::SynSent => MAX_SYN_RETRANSMISSIONS,
+            _ => MAX_RETRANSMISSIONS,
+        };
+        if self
+            .in_flight
+            .front()
+            .is_some_and(|s| s.transmissions > limit)
+        {
+            self.fail(ErrorKind::TimedOut);
+            return;
+        }
+        // a probe too large for the path is dropped silently, that says nothing about congestion
+        if self
+            .in_flight
+            .front()
+            .is_some_and(|s| self.mtu.is_probe(s.packet.seq_nr))
+        {
+            self.declare_lost(0, now);
+            self.timeout_at = None;
+            return;
+        }
+        self.rto = (self.rto * 2).min(MAX_TIMEOUT);
+        self.ledbat.on_timeout(now);
+        self.in_flight.iter_mut().for_each(|s| s.resend = true);
+        self.timeout_at = None;
+    }
+
+    fn send_new(&mut self, packet_type: PacketType, payload: Vec<u8>, now: Instant) -> Packet {
+        let mut packet = Packet::new(packet_type, self.send_id);
+        packet.seq_nr = self.seq_nr;
+        packet.payload = payload;
+        self.stamp(&mut packet, now);
+        // pad the packet up to the probe size, the padding is stripped again if it's lost
+        if let Some(probe) = self
+            .mtu
+            .next_probe()
+            .filter(|_| packet_type == PacketType::Data)
+        {
+            packet.extensions = Extension::padding(probe - HEADER_SIZE - packet.payload.len());
+            self.mtu.start_probe(packet.seq_nr, packet.as_bytes().len());
+        }
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.in_flight.push_back(Sent {
+            packet: packet.clone(),
+            sent_at: now,
+            transmissions: 1,
+            resend: false,
+            lost: false,
+        });
+        self.timeout_at.get_or_insert(now + self.rto);
+        self.ack_sent();
+        packet
+    }
+
+    // packets carry our ack, but only a STATE packet tells the remote what arrived out of order
+    fn ack_sent(&mut self) {
+        if self.out_of_order.is_empty() {
+            self.ack_pending = false;
+        }
+    }
+
+    fn state_packet(&self, now: Instant) -> Packet {
+        let mut packet = Packet::new(PacketType::State, self.send_id);
+        packet.seq_nr = self.seq_nr;
+        self.stamp(&mut packet, now);
+        if !self.out_of_order.is_empty() {
+            let received = self.out_of_order.keys().copied();
+            packet.extensions = vec![Extension::selective_ack(self.ack_nr, received)];
+        }
+        packet
+    }
+
+    /// Largest payload that fits the path
+    pub fn max_payload(&self) -> usize {
+        self.mtu.floor() - HEADER_SIZE
+    }
+
+    fn stamp(&self, packet: &mut Packet, now: Instant) {
+        packet.timestamp = self.timestamp(now);
+        packet.timestamp_difference = self.reply_micro;
+        packet.window_size = self.recv_window();
+        packet.ack_nr = self.ack_nr;
+    }
+
+    fn timestamp(&self, now: Instant) -> u32 {
+        now.duration_since(self.epoch).as_micros() as u32
+    }
+
+    fn recv_window(&self) -> u32 {
+        RECV_BUFFER.saturating_sub(self.recv_buf.len()) as u32
+    }
+
+    // drop every packet up to and including the packet's `ack_nr` from the in flight queue,
+    // as well as the ones it acks selectively
+    fn acked(&mut self, packet: &Packet, now: Instant) {
+        let ack_nr = packet.ack_nr;
+        // acks for packets we never sent
+        if !seq_lt(ack_nr, self.seq_nr) {
+            return;
+        }
+        let mut bytes_acked = 0;
+        let mut progressed = false;
+        while self
+            .in_flight
+            .front()
+            .is_some_and(|s| seq_le(s.packet.seq_nr, ack_nr))
+        {
+            let sent = self.in_flight.pop_front().unwrap();
+            bytes_acked += self.delivered(sent, now);
+            progressed = true;
+        }
+
+        let selectively_acked = packet
+            .extensions
+            .iter()
+            .find(|e| e.kind == SELECTIVE_ACK)
+            .map(|e| e.selectively_acked(ack_nr))
+            .unwrap_or_default();
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            if selectively_acked.contains(&self.in_flight[i].packet.seq_nr) {
+                let sent = self.in_flight.remove(i).unwrap();
+                bytes_acked += self.delivered(sent, now);
+            } else {
+                i += 1;
+            }
+        }
+
+        if progressed {
+            self.duplicate_acks = 0;
+        }
+        if progressed || bytes_acked > 0 {
+            self.timeout_at = (!self.in_flight.is_empty()).then(|| now + self.rto);
+        }
+        if bytes_acked > 0 {
+            let delay = (packet.timestamp_difference != 0).then_some(packet.timestamp_difference);
+            self.ledbat.on_ack(bytes_acked, delay, now);
+        }
+
+        // whatever was sent well before the packets that did arrive is gone
+        for i in 0..self.in_flight.len() {
+            let seq_nr = self.in_flight[i].packet.seq_nr;
+            let acked_after = selectively_acked
+                .iter()
+                .filter(|s| seq_lt(seq_nr, **s))
+                .count();
+            if acked_after >= DUPLICATE_ACKS_BEFORE_RESEND as usize && !self.in_flight[i].lost {
+                self.declare_lost(i, now);
+            }
+        }
+
+        if !progressed && packet.packet_type == PacketType::State && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACKS_BEFORE_RESEND && !self.in_flight[0].lost {
+                self.declare_lost(0, now);
+            }
+        }
+    }
+
+    // bookkeeping for a packet that made it to the remote, returns its payload size
+    fn delivered(&mut self, sent: Sent, now: Instant) -> usize {
+        let seq_nr = sent.packet.seq_nr;
+        // retransmitted packets give ambiguous samples
+        if sent.transmissions == 1 {
+            self.update_rtt(now.duration_since(sent.sent_at));
+        }
+        if self.fin_sent == Some(seq_nr) {
+            self.fin_acked_at = Some(now);
+        }
+        self.mtu.on_acked(seq_nr);
+        sent.packet.payload.len()
+    }
+
+    // resend `in_flight[i]` without waiting for the timeout
+    fn declare_lost(&mut self, i: usize, now: Instant) {
+        let sent = &mut self.in_flight[i];
+        sent.resend = true;
+        sent.lost = true;
+        let seq_nr = sent.packet.seq_nr;
+        if self.mtu.is_probe(seq_nr) {
+            // too large for the path, goes out again as a regular packet
+            sent.packet.extensions.clear();
+            self.mtu.on_lost(seq_nr);
+        } else {
+            let rtt = self.rtt.unwrap_or(INITIAL_TIMEOUT);
+            self.ledbat.on_loss(rtt, now);
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                // rtt_var += (|delta| - rtt_var) / 4; rtt += (sample - rtt) / 8
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+        let rto = self.rtt.unwrap_or(INITIAL_TIMEOUT) + self.rtt_var * 4;
+        self.rto = rto.clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    fn received(&mut self, packet: Packet) {
+        let seq = packet.seq_nr;
+        self.ack_pending = true;
+        if self.eof
+            || !seq_lt(self.ack_nr, seq)
+            || seq.wrapping_sub(self.ack_nr) > MAX_REORDER
+            || self.fin_received.is_some_and(|fin| seq_lt(fin, seq))
+            || self.recv_buf.len() + packet.payload.len() > RECV_BUFFER
+        {
+            return;
+        }
+        if packet.packet_type == PacketType::Fin {
+            self.fin_received = Some(seq);
+        }
+        self.out_of_order.insert(seq, packet.payload);
+
+        while let Some(payload) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+            self.recv_buf.extend(payload);
+            if self.fin_received == Some(self.ack_nr) {
+                self.eof = true;
+                self.out_of_order.clear();
+                break;
+            }
+        }
+    }
+
+    fn check_closed(&mut self) {
+        if self.closing && self.fin_acked_at.is_some() && self.eof {
+            self.state = State::Closed;
+        }
+    }
+
+    fn fail(&mut self, kind: ErrorKind) {
+        self.state = State::Closed;
+        self.error = Some(kind);
+        self.in_flight.clear();
+        self.send_buf.clear();
+        self.ack_pending = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::ErrorKind,
+        time::{Duration, Instant},
+    };
+
+    use super::{Connection, State, MAX_PAYLOAD};
+    use crate::utp::{
+        mtu::MIN_DATAGRAM,
+        packet::{Packet, PacketType, HEADER_SIZE, SELECTIVE_ACK},
+    };
+
+    // everything `from` wants to send right now
+    fn drain(from: &mut Connection, now: Instant) -> Vec<Packet> {
+        std::iter::from_fn(|| from.poll_transmit(now)).collect()
+    }
+
+    // deliver packets back and forth until both sides are quiet
+    fn exchange(a: &mut Connection, b: &mut Connection, now: Instant) {
+        loop {
+            let (to_b, to_a) = (drain(a, now), drain(b, now));
+            if to_a.is_empty() && to_b.is_empty() {
+                break;
+            }
+            to_b.into_iter().for_each(|p| b.handle(p, now));
+            to_a.into_iter().for_each(|p| a.handle(p, now));
+        }
+    }
+
+    fn connected_pair(seq_nr: u16, now: Instant) -> (Connection, Connection) {
+        let mut client = Connection::connect(100, now);
+        let syn = client.poll_transmit(now).unwrap();
+        assert_eq!(syn.packet_type, PacketType::Syn);
+        assert_eq!(syn.connection_id, 100);
+
+        let mut server = Connection::accept(&syn, seq_nr, now);
+        exchange(&mut client, &mut server, now);
+        assert_eq!(client.state(), State::Connected);
+        (client, server)
+    }
+
+    fn read_all(conn: &mut Connection) -> Vec<u8> {
+        let mut buf = vec![0; 1 << 20];
+        let n = conn.read(&mut buf);
+        buf.truncate(n);
+        buf
+    }
+
+    #[test]
+    fn handshake() {
+        let now = Instant::now();
+        let mut client = Connection::connect(100, now);
+        let syn = client.poll_transmit(now).unwrap();
+        let mut server = Connection::accept(&syn, 5000, now);
+
+        let state = server.poll_transmit(now).unwrap();
+        assert_eq!(state.packet_type, PacketType::State);
+        // sent with the initiator's receive id
+        assert_eq!(state.connection_id, 100);
+        assert_eq!((state.seq_nr, state.ack_nr), (5000, syn.seq_nr));
+
+        client.handle(state, now);
+        assert_eq!(client.state(), State::Connected);
+    }
+
+    #[test]
+    fn data_both_ways() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected_pair(5000, now);
+
+        let big: Vec<u8> = (0..10 * MAX_PAYLOAD).map(|i| i as u8).collect();
+        assert_eq!(client.write(&big), big.len());
+        assert_eq!(server.write(b"pong"), 4);
+        exchange(&mut client, &mut server, now);
+
+        assert_eq!(read_all(&mut server), big);
+        assert_eq!(read_all(&mut client), b"pong");
+        assert!(client.in_flight.is_empty() && server.in_flight.is_empty());
+    }
+
+    #[test]
+    fn out_of_order_delivery() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected_pair(5000, now);
+
+        let payload = client.max_payload();
+        client.write(&vec![1; payload]);
+        client.write(&[2; 10]);
+        let mut packets = drain(&mut client, now);
+        assert_eq!(packets.len(), 2);
+        packets.reverse();
+        for p in packets {
+            server.handle(p, now);
+        }
+
+        let data = read_all(&mut server);
+        assert_eq!(data.len(), payload + 10);
+        assert_eq!((data[0], data[payload]), (1, 2));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected_pair(0xfffe, now);
+
+        for i in 0..5 {
+            server.write(&[i; 3]);
+            exchange(&mut client, &mut server, now);
+        }
+        assert_eq!(
+            read_all(&mut client),
+            [0, 0, 0, 1, 1, 1, 2, 2, 2, 3, 3, 3, 4, 4, 4]
+        );
+        assert!(server.in_flight.is_empty());
+    }
+
+    #[test]
+    fn congestion_window_limits_flight() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected_pair(5000, now);
+
+        let bytes = |packets: &[Packet]| packets.iter().map(|p| p.payload.len()).sum::<usize>();
+        client.write(&vec![0; 10 * MAX_PAYLOAD]);
+        let first = drain(&mut client, now);
+        assert!(bytes(&first) <= client.ledbat.window());
+
+        // acks without any queuing delay open the window further
+        let sent = bytes(&first);
+        first.into_iter().for_each(|p| server.handle(p, now));
+        drain(&mut server, now)
+            .into_iter()
+            .for_each(|p| client.handle(p, now));
+        assert!(bytes(&drain(&mut client, now)) > sent);
+    }
+
+    #[test]
+    fn fast_retransmit_on_duplicate_acks() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected_pair(5000, now);
+        // grow the window
+        for _ in 0..20 {
+            client.write(&vec![0; MAX_PAYLOAD]);
+            exchange(&mut client, &mut server, now);
+        }
+        read_all(&mut server);
+        let window = client.ledbat.window();
+        let payload = client.max_payload();
+
+        client.write(&vec![1; 4 * payload]);
+        let packets = drain(&mut client, now);
+        assert_eq!(packets.len(), 4);
+        // the first one is lost, each of the others triggers the same ack
+        for p in packets.into_iter().skip(1) {
+            server.handle(p, now);
+            let ack = server.poll_transmit(now).unwrap();
+            assert_eq!(ack.packet_type, PacketType::State);
+            client.handle(ack, now);
+        }
+
+        // resent right away, without waiting for the timeout
+        let resent = drain(&mut client, now);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].payload, vec![1; payload]);
+        assert!(client.ledbat.window() <= window / 2 + MAX_PAYLOAD);
+
+        resent.into_iter().for_each(|p| server.handle(p, now));
+        assert_eq!(read_all(&mut server), vec![1; 4 * payload]);
+    }
+
+    #[test]
+    fn selective_ack() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected_pair(5000, now);
+        let payload = client.max_payload();
+
+        client.write(&vec![1; 3 * payload]);
+        let mut packets = drain(&mut client, now);
+        assert_eq!(packets.len(), 3);
+        // the first one is lost, the other two arrive in reverse order
+        let first = packets.remove(0);
+        packets.reverse();
+        packets.into_iter().for_each(|p| server.handle(p, now));
+
+        let ack = server.poll_transmit(now).unwrap();
+        assert_eq!(ack.ack_nr, first.seq_nr.wrapping_sub(1));
+        let sack = &ack.extensions[0];
+        assert_eq!(sack.kind, SELECTIVE_ACK);
+        assert_eq!(sack.data, [0b11, 0, 0, 0]);
+        assert_eq!(
+            sack.selectively_acked(ack.ack_nr),
+            [first.seq_nr.wrapping_add(1), first.seq_nr.wrapping_add(2)]
+        );
+
+        // only the missing packet is still in flight
+        client.handle(ack, now);
+        assert_eq!(client.in_flight.len(), 1);
+        assert_eq!(client.in_flight[0].packet.seq_nr, first.seq_nr);
+        assert!(drain(&mut client, now).is_empty());
+
+        server.handle(first, now);
+        exchange(&mut client, &mut server, now);
+        assert_eq!(read_all(&mut server), vec![1; 3 * payload]);
+        assert!(client.in_flight.is_empty());
+    }
+
+    #[test]
+    fn selective_ack_recovers_holes_without_timeout() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected_pair(5000, now);
+        for _ in 0..20 {
+            client.write(&vec![0; MAX_PAYLOAD]);
+            exchange(&mut client, &mut server, now);
+        }
+        read_all(&mut server);
+        let payload = client.max_payload();
+        let rto = client.rto;
+
+        client.write(&vec![1; 8 * payload]);
+        let packets = drain(&mut client, now);
+        assert_eq!(packets.len(), 8);
+        // two holes, the second one further along than the duplicate acks reach
+        let lost = [packets[1].seq_nr, packets[3].seq_nr];
+        for p in packets {
+            if !lost.contains(&p.seq_nr) {
+                server.handle(p, now);
+            }
+        }
+        drain(&mut server, now)
+            .into_iter()
+            .for_each(|p| client.handle(p, now));
+
+        let resent = drain(&mut client, now);
+        assert_eq!(resent.iter().map(|p| p.seq_nr).collect::<Vec<_>>(), lost);
+        assert_eq!(client.rto, rto);
+        resent.into_iter().for_each(|p| server.handle(p, now));
+        exchange(&mut client, &mut server, now);
+        assert_eq!(read_all(&mut server), vec![1; 8 * payload]);
+        assert!(client.in_flight.is_empty());
+    }
+
+    #[test]
+    fn mtu_discovery() {
+        // the path drops datagrams above 1000 bytes
+        const PATH_MTU: usize = 1000;
+        let mut now = Instant::now();
+        let (mut client, mut server) = connected_pair(5000, now);
+        assert_eq!(client.max_payload(), MIN_DATAGRAM - HEADER_SIZE);
+
+        let data: Vec<u8> = (0..1 << 18).map(|i: u32| (i % 251) as u8).collect();
+        client.write(&data);
+        client.close();
+        let mut received = Vec::new();
+        while !server.is_eof() {
+            let mut quiet = true;
+            while let Some(p) = client.poll_transmit(now) {
+                quiet = false;
+                let size = p.as_bytes().len();
+                if size <= PATH_MTU {
+                    server.handle(p, now);
+                }
+            }
+            while let Some(p) = server.poll_transmit(now) {
+                quiet = false;
+                client.handle(p, now);
+            }
+            received.extend(read_all(&mut server));
+            if quiet {
+                now = client.poll_timeout().unwrap();
+                client.on_timeout(now);
+            }
+        }
+        assert!(received == data);
+        let datagram = client.max_payload() + HEADER_SIZE;
+        assert!(
+            datagram <= PATH_MTU && datagram > PATH_MTU - 16,
+            "{datagram}"
+        );
+        // lost probes aren't congestion
+        assert!(client.ledbat.window() > 4 * MAX_PAYLOAD);
+    }
+
+    #[test]
+    fn retransmit_lost_packet() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected_pair(5000, now);
+
+        client.write(b"lost");
+        let lost = drain(&mut client, now);
+        assert_eq!(lost.len(), 1);
+        // nothing happens before the timeout
+        let timeout = client.poll_timeout().unwrap();
+        client.on_timeout(timeout - Duration::from_millis(1));
+        assert!(drain(&mut client, now).is_empty());
+
+        client.on_timeout(timeout);
+        let resent = drain(&mut client, timeout);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].seq_nr, lost[0].seq_nr);
+        assert_eq!(resent[0].payload, b"lost");
+
+        resent.into_iter().for_each(|p| server.handle(p, timeout));
+        exchange(&mut client, &mut server, timeout);
+        assert_eq!(read_all(&mut server), b"lost");
+        assert_eq!(client.poll_timeout(), None);
+    }
+
+    #[test]
+    fn duplicate_packets_are_ignored() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected_pair(5000, now);
+
+        client.write(b"once");
+        let packets = drain(&mut client, now);
+        server.handle(packets[0].clone(), now);
+        server.handle(packets[0].clone(), now);
+        assert_eq!(read_all(&mut server), b"once");
+    }
+
+    #[test]
+    fn give_up_after_retransmissions() {
+        let mut now = Instant::now();
+        let (mut client, _server) = connected_pair(5000, now);
+
+        client.write(b"into the void");
+        while !client.is_closed() {
+            drain(&mut client, now);
+            now = client.poll_timeout().unwrap();
+            client.on_timeout(now);
+        }
+        assert_eq!(client.error(), Some(ErrorKind::TimedOut));
+        assert_eq!(client.write(b"more"), 0);
+    }
+
+    #[test]
+    fn syn_timeout() {
+        let mut now = Instant::now();
+        let mut client = Connection::connect(1, now);
+        let mut syns = 0;
+        while !client.is_closed() {
+            syns += drain(&mut client, now).len();
+            now = client.poll_timeout().unwrap();
+            client.on_timeout(now);
+        }
+        assert_eq!(syns, 4);
+        assert_eq!(client.error(), Some(ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn graceful_close() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected_pair(5000, now);
+
+        client.write(b"bye");
+        client.close();
+        exchange(&mut client, &mut server, now);
+        assert_eq!(read_all(&mut server), b"bye");
+        assert!(server.is_eof());
+        // the client waits for the server's FIN
+        assert!(!client.is_closed());
+
+        server.write(b"see you");
+        server.close();
+        exchange(&mut client, &mut server, now);
+        assert_eq!(read_all(&mut client), b"see you");
+        assert!(client.is_eof());
+        assert!(client.is_closed() && server.is_closed());
+        assert_eq!((client.error(), server.error()), (None, None));
+    }
+
+    #[test]
+    fn linger_after_fin() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected_pair(5000, now);
+
+        client.close();
+        exchange(&mut client, &mut server, now);
+        // the server never closes its side
+        let linger = client.poll_timeout().unwrap();
+        client.on_timeout(linger);
+        assert!(client.is_closed());
+        assert_eq!(client.error(), None);
+    }
+
+    #[test]
+    fn reset() {
+        let now = Instant::now();
+        let (mut client, server) = connected_pair(5000, now);
+
+        let mut reset = Packet::new(PacketType::Reset, server.send_id);
+        client.handle(reset.clone(), now);
+        assert_eq!(client.error(), Some(ErrorKind::ConnectionReset));
+
+        // refused while connecting
+        let mut client = Connection::connect(7, now);
+        drain(&mut client, now);
+        reset.connection_id = 7;
+        client.handle(reset, now);
+        assert_eq!(client.error(), Some(ErrorKind::ConnectionRefused));
+    }
+
+    #[test]
+    fn foreign_connection_id() {
+        let now = Instant::now();
+        let (mut client, _server) = connected_pair(5000, now);
+        client.handle(Packet::new(PacketType::Reset, 12345), now);
+        assert_eq!(client.state(), State::Connected);
+    }
+
+    #[test]
+    fn lost_syn_reply() {
+        let now = Instant::now();
+        let mut client = Connection::connect(100, now);
+        let syn = client.poll_transmit(now).unwrap();
+        let mut server = Connection::accept(&syn, 5000, now);
+
+        // the server's reply and first data packet never arrive
+        server.write(b"early");
+        assert_eq!(drain(&mut server, now).len(), 2);
+
+        let timeout = client.poll_timeout().unwrap();
+        client.on_timeout(timeout);
+        let syn = drain(&mut client, timeout);
+        syn.into_iter().for_each(|p| server.handle(p, timeout));
+        // the reply still points at the first data packet
+        let reply = server.poll_transmit(timeout).unwrap();
+        assert_eq!(reply.seq_nr, 5000);
+        client.handle(reply, timeout);
+
+        let timeout = server.poll_timeout().unwrap();
+        server.on_timeout(timeout);
+        exchange(&mut client, &mut server, timeout);
+        assert_eq!(read_all(&mut client), b"early");
+    }
+
+    #[test]
+    fn lossy_bulk_transfer() {
+        let mut now = Instant::now();
+        let (mut client, mut server) = connected_pair(40000, now);
+        let data: Vec<u8> = (0..1 << 20).map(|i: u32| (i % 251) as u8).collect();
+        assert_eq!(server.write(&data), data.len());
+        server.close();
+
+        let mut received = Vec::new();
+        let mut sent = 0;
+        while !client.is_eof() {
+            let mut quiet = true;
+            while let Some(p) = server.poll_transmit(now) {
+                quiet = false;
+                sent += 1;
+                // lose every 13th packet
+                if sent % 13 != 0 {
+                    client.handle(p, now);
+                }
+            }
+            while let Some(p) = client.poll_transmit(now) {
+                quiet = false;
+                server.handle(p, now);
+            }
+            received.extend(read_all(&mut client));
+            if quiet {
+                now = server.poll_timeout().unwrap();
+                server.on_timeout(now);
+            }
+        }
+        assert!(received == data);
+    }
+}
@@ -0,0 +1,262 @@
+// REFERENCE: https://www.bittorrent.org/beps/bep_0029.html#congestion-control
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use super::connection::MAX_PAYLOAD;
+
+/// Queuing delay LEDBAT aims for, in microseconds
+pub const TARGET: u32 = 100_000;
+/// The window grows by at most this many bytes per round trip
+const MAX_CWND_INCREASE_BYTES_PER_RTT: f64 = 3000.0;
+const MIN_WINDOW: usize = MAX_PAYLOAD;
+const MAX_WINDOW: usize = 1 << 20;
+const INITIAL_WINDOW: usize = 2 * MAX_PAYLOAD;
+/// base delay is the lowest delay seen over this many minutes
+const BASE_HISTORY: usize = 2;
+
+/// Low Extra Delay Background Transport congestion control.
+/// Keeps the one-way queuing delay at `TARGET` by growing the window while
+/// the delay is below it and shrinking it while above, so that uTP yields
+/// to other traffic sharing the link.
+///
+/// Delay samples are the `timestamp_difference` the remote echoes back: the
+/// time our packets took to reach it, plus the (unknown, constant) offset
+/// between both clocks. The offset cancels out against the base delay.
+#[derive(Debug)]
+pub struct Ledbat {
+    window: usize,
+    epoch: Instant,
+    /// lowest delay sample per minute, oldest first
+    history: VecDeque<(u64, u32)>,
+    last_decrease: Option<Instant>,
+}
+
+// `a` is smaller than `b`, the samples may wrap around
+fn delay_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+impl Ledbat {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            epoch: now,
+            history: VecDeque::with_capacity(BASE_HISTORY),
+            last_decrease: None,
+        }
+    }
+
+    /// Bytes allowed in flight
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Lowest delay sample of the last `BASE_HISTORY` minutes
+    pub fn base_delay(&self) -> Option<u32> {
+        self.history
+            .iter()
+            .map(|(_, delay)| *delay)
+            .reduce(|min, d| if delay_lt(d, min) { d } else { min })
+    }
+
+    /// `bytes_acked` bytes were acked by a packet carrying `delay` as its
+    /// timestamp difference; `None` if the remote didn't measure one
+    pub fn on_ack(&mut self, bytes_acked: usize, delay: Option<u32>, now: Instant) {
+        let our_delay = match delay {
+            Some(delay) => {
+                self.add_sample(delay, now);
+                let base = self.base_delay().unwrap_or(delay);
+                delay.wrapping_sub(base).min(i32::MAX as u32)
+            }
+            None => 0,
+        };
+
+        // off_target is 1 without any queuing delay and negative above the target
+        let off_target = ((TARGET as f64 - our_delay as f64) / TARGET as f64).max(-1.0);
+        let window_factor = bytes_acked as f64 / self.window as f64;
+        let gain = MAX_CWND_INCREASE_BYTES_PER_RTT * off_target * window_factor;
+        self.window =
+            (self.window as f64 + gain).clamp(MIN_WINDOW as f64, MAX_WINDOW as f64) as usize;
+    }
+
+    /// A packet was lost: halve the window, at most once per round trip
+    pub fn on_loss(&mut self, rtt: Duration, now: Instant) {
+        if self
+            .last_decrease
+            .is_some_and(|at| now.duration_since(at) < rtt)
+        {
+            return;
+        }
+        self.last_decrease = Some(now);
+        self.window = (self.window / 2).max(MIN_WINDOW);
+    }
+
+    /// The retransmission timer fired: start over from a single packet
+    pub fn on_timeout(&mut self, now: Instant) {
+        self.last_decrease = Some(now);
+        self.window = MIN_WINDOW;
+    }
+
+    fn add_sample(&mut self, delay: u32, now: Instant) {
+        let minute = now.duration_since(self.epoch).as_secs() / 60;
+        match self.history.back_mut() {
+            Some((m, min)) if *m == minute => {
+                if delay_lt(delay, *min) {
+                    *min = delay;
+                }
+            }
+            _ => {
+                self.history.push_back((minute, delay));
+            }
+        }
+        // forget minutes that fell out of the history
+        while self
+            .history
+            .front()
+            .is_some_and(|(m, _)| minute - m >= BASE_HISTORY as u64)
+        {
+            self.history.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Ledbat, INITIAL_WINDOW, MAX_PAYLOAD, MIN_WINDOW, TARGET};
+
+    /// A bottleneck link: `bandwidth` bytes per millisecond behind a fixed
+    /// one-way delay. Everything above the bandwidth delay product waits in the queue.
+    struct Link {
+        one_way: Duration,
+        bandwidth: usize,
+        // difference between the sender's and receiver's clocks
+        clock_offset: u32,
+    }
+
+    impl Link {
+        fn queuing_delay(&self, window: usize) -> Duration {
+            let rtt = self.one_way * 2;
+            let bdp = self.bandwidth * rtt.as_millis() as usize;
+            let queued = window.saturating_sub(bdp);
+            Duration::from_micros((queued * 1000 / self.bandwidth) as u64)
+        }
+
+        // send a full window, then process an ack for every packet.
+        // Returns the queuing delay of that round
+        fn round(&self, ledbat: &mut Ledbat, now: &mut Instant) -> Duration {
+            let window = ledbat.window();
+            let queuing = self.queuing_delay(window);
+            let delay = (self.one_way + queuing).as_micros() as u32;
+            for _ in 0..window.div_ceil(MAX_PAYLOAD) {
+                ledbat.on_ack(
+                    MAX_PAYLOAD,
+                    Some(delay.wrapping_add(self.clock_offset)),
+                    *now,
+                );
+            }
+            *now += self.one_way * 2 + queuing;
+            queuing
+        }
+    }
+
+    #[test]
+    fn converges_to_target_delay() {
+        let link = Link {
+            one_way: Duration::from_millis(20),
+            bandwidth: 1000,
+            // the clocks are so far apart the samples wrap around
+            clock_offset: u32::MAX - 50_000,
+        };
+        let mut now = Instant::now();
+        let mut ledbat = Ledbat::new(now);
+
+        let mut queuing = Duration::ZERO;
+        for _ in 0..300 {
+            queuing = link.round(&mut ledbat, &mut now);
+        }
+        let target = Duration::from_micros(TARGET as u64);
+        assert!(
+            queuing.abs_diff(target) < Duration::from_millis(5),
+            "{queuing:?}"
+        );
+        // and stays there
+        for _ in 0..100 {
+            let queuing = link.round(&mut ledbat, &mut now);
+            assert!(
+                queuing.abs_diff(target) < Duration::from_millis(5),
+                "{queuing:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn grows_without_queuing_delay() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(now);
+        // a full window acked without any delay grows the window by up to the maximum gain
+        for _ in 0..2 {
+            ledbat.on_ack(MAX_PAYLOAD, Some(20_000), now);
+        }
+        assert!(ledbat.window() > INITIAL_WINDOW);
+        assert!(ledbat.window() <= INITIAL_WINDOW + 3000);
+    }
+
+    #[test]
+    fn backs_off_above_target() {
+        let mut now = Instant::now();
+        let mut ledbat = Ledbat::new(now);
+        for _ in 0..100 {
+            ledbat.on_ack(MAX_PAYLOAD, Some(20_000), now);
+        }
+        let window = ledbat.window();
+
+        // another flow fills the queue: 150 ms of queuing delay
+        now += Duration::from_secs(1);
+        ledbat.on_ack(MAX_PAYLOAD, Some(170_000), now);
+        assert!(ledbat.window() < window);
+        assert_eq!(ledbat.base_delay(), Some(20_000));
+    }
+
+    #[test]
+    fn loss_halves_once_per_rtt() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(now);
+        for _ in 0..100 {
+            ledbat.on_ack(MAX_PAYLOAD, None, now);
+        }
+        let window = ledbat.window();
+        let rtt = Duration::from_millis(50);
+
+        ledbat.on_loss(rtt, now);
+        assert_eq!(ledbat.window(), window / 2);
+        // more losses from the same window
+        ledbat.on_loss(rtt, now + Duration::from_millis(10));
+        assert_eq!(ledbat.window(), window / 2);
+
+        ledbat.on_loss(rtt, now + rtt);
+        assert_eq!(ledbat.window(), window / 4);
+
+        ledbat.on_timeout(now + rtt);
+        assert_eq!(ledbat.window(), MIN_WINDOW);
+    }
+
+    #[test]
+    fn base_delay_history_expires() {
+        let start = Instant::now();
+        let mut ledbat = Ledbat::new(start);
+        ledbat.on_ack(MAX_PAYLOAD, Some(20_000), start);
+
+        // the route changed, every sample is 30 ms higher now
+        let minute = Duration::from_secs(60);
+        ledbat.on_ack(MAX_PAYLOAD, Some(50_000), start + minute);
+        assert_eq!(ledbat.base_delay(), Some(20_000));
+
+        ledbat.on_ack(MAX_PAYLOAD, Some(50_000), start + minute * 2);
+        assert_eq!(ledbat.base_delay(), Some(50_000));
+    }
+}
@@ -0,0 +1,119 @@
+// REFERENCE: https://www.rfc-editor.org/rfc/rfc4821
+
+/// Datagrams this small get through every IPv4 path (576 bytes minus IP and UDP headers)
+pub const MIN_DATAGRAM: usize = 548;
+/// Largest datagram that fits an Ethernet frame (1500 bytes minus IP and UDP headers)
+pub const MAX_DATAGRAM: usize = 1472;
+// stop searching once the path MTU is known this precisely
+const PRECISION: usize = 16;
+
+/// Packetization layer path MTU discovery.
+/// Regular packets are never larger than `floor`, which is known to get through.
+/// Every now and then one packet is padded to a size between `floor` and `ceiling`:
+/// if it's acked the floor goes up, if it's lost the ceiling comes down.
+#[derive(Debug)]
+pub struct MtuSearch {
+    floor: usize,
+    ceiling: usize,
+    /// sequence number and datagram size of the probe in flight
+    probe: Option<(u16, usize)>,
+}
+
+impl Default for MtuSearch {
+    fn default() -> Self {
+        Self {
+            floor: MIN_DATAGRAM,
+            ceiling: MAX_DATAGRAM,
+            probe: None,
+        }
+    }
+}
+
+impl MtuSearch {
+    /// Largest datagram known to get through
+    pub fn floor(&self) -> usize {
+        self.floor
+    }
+
+    /// Datagram size to probe next, `None` while a probe is in flight or the search is done
+    pub fn next_probe(&self) -> Option<usize> {
+        if self.probe.is_some() || self.ceiling - self.floor < PRECISION {
+            return None;
+        }
+        Some((self.floor + self.ceiling).div_ceil(2))
+    }
+
+    /// The packet `seq_nr` went out as a probe of `size` bytes
+    pub fn start_probe(&mut self, seq_nr: u16, size: usize) {
+        self.probe = Some((seq_nr, size));
+    }
+
+    pub fn is_probe(&self, seq_nr: u16) -> bool {
+        self.probe.is_some_and(|(seq, _)| seq == seq_nr)
+    }
+
+    /// `seq_nr` was acked, if it was the probe its size fits the path
+    pub fn on_acked(&mut self, seq_nr: u16) {
+        if let Some((_, size)) = self.probe.filter(|(seq, _)| *seq == seq_nr) {
+            self.floor = self.floor.max(size);
+            self.probe = None;
+        }
+    }
+
+    /// `seq_nr` was lost, if it was the probe its size is taken as too large
+    pub fn on_lost(&mut self, seq_nr: u16) {
+        if let Some((_, size)) = self.probe.filter(|(seq, _)| *seq == seq_nr) {
+            self.ceiling = (size - 1).max(self.floor);
+            self.probe = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MtuSearch, MAX_DATAGRAM, MIN_DATAGRAM, PRECISION};
+
+    // run the search against a path that lets through datagrams up to `path_mtu`
+    fn search(path_mtu: usize) -> MtuSearch {
+        let mut mtu = MtuSearch::default();
+        let mut seq = 0;
+        while let Some(size) = mtu.next_probe() {
+            assert!(size > mtu.floor());
+            mtu.start_probe(seq, size);
+            assert!(mtu.next_probe().is_none());
+            if size <= path_mtu {
+                mtu.on_acked(seq);
+            } else {
+                mtu.on_lost(seq);
+            }
+            seq += 1;
+        }
+        mtu
+    }
+
+    #[test]
+    fn converges() {
+        for path_mtu in [MIN_DATAGRAM, 576, 1000, 1280, 1400, MAX_DATAGRAM] {
+            let mtu = search(path_mtu);
+            assert!(mtu.floor() <= path_mtu);
+            assert!(path_mtu - mtu.floor() < PRECISION, "{path_mtu}");
+        }
+        // never above what Ethernet carries
+        assert_eq!(search(9000).floor(), search(MAX_DATAGRAM).floor());
+    }
+
+    #[test]
+    fn other_packets_are_ignored() {
+        let mut mtu = MtuSearch::default();
+        let size = mtu.next_probe().unwrap();
+        mtu.start_probe(10, size);
+        mtu.on_acked(9);
+        mtu.on_lost(11);
+        assert!(mtu.is_probe(10));
+        assert_eq!(mtu.floor(), MIN_DATAGRAM);
+
+        mtu.on_acked(10);
+        assert_eq!(mtu.floor(), size);
+        assert!(!mtu.is_probe(10));
+    }
+}
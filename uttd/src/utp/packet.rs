@@ -0,0 +1,303 @@
+use crate::UttdError;
+
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 20;
+
+/// Extension listing the packets received past `ack_nr + 1`
+pub const SELECTIVE_ACK: u8 = 1;
+/// Filler to make MTU probes bigger, receivers skip extensions they don't know
+pub const PADDING: u8 = 0xfe;
+// a selective ack covers at most this many bytes of bitmask
+const MAX_SELECTIVE_ACK: usize = 128;
+
+/// Type of a uTP packet, stored in the high 4 bits of the first byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketType {
+    /// regular data packet, always has a payload
+    Data = 0,
+    /// last packet of a connection
+    Fin = 1,
+    /// ack without payload, doesn't consume a sequence number
+    State = 2,
+    /// terminate the connection forcefully
+    Reset = 3,
+    /// initiate a connection
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = UttdError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Data),
+            1 => Ok(Self::Fin),
+            2 => Ok(Self::State),
+            3 => Ok(Self::Reset),
+            4 => Ok(Self::Syn),
+            _ => Err(UttdError::ProtocolViolation("unknown uTP packet type")),
+        }
+    }
+}
+
+/// Header extension, e.g. selective acks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Extension {
+    pub kind: u8,
+    pub data: Vec<u8>,
+}
+
+impl Extension {
+    /// Selective ack for the `received` sequence numbers, which all lie past `ack_nr + 1`.
+    /// Bit 0 of the first byte stands for `ack_nr + 2`, bit 7 for `ack_nr + 9` and so on.
+    /// The bitmask is a multiple of 32 bits long
+    pub fn selective_ack(ack_nr: u16, received: impl Iterator<Item = u16>) -> Self {
+        let base = ack_nr.wrapping_add(2);
+        let mut data = vec![0u8; 4];
+        for seq in received {
+            let offset = seq.wrapping_sub(base) as usize;
+            if offset >= MAX_SELECTIVE_ACK * 8 {
+                continue;
+            }
+            if offset / 8 >= data.len() {
+                data.resize((offset / 32 + 1) * 4, 0);
+            }
+            data[offset / 8] |= 1 << (offset % 8);
+        }
+        Self {
+            kind: SELECTIVE_ACK,
+            data,
+        }
+    }
+
+    /// Sequence numbers set in a selective ack sent along with `ack_nr`
+    pub fn selectively_acked(&self, ack_nr: u16) -> Vec<u16> {
+        let base = ack_nr.wrapping_add(2);
+        let mut acked = Vec::new();
+        for (i, byte) in self.data.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (1 << bit) != 0 {
+                    acked.push(base.wrapping_add((i * 8 + bit) as u16));
+                }
+            }
+        }
+        acked
+    }
+
+    /// Padding extensions adding up to (at most) `size` bytes
+    pub fn padding(mut size: usize) -> Vec<Self> {
+        let mut padding = Vec::new();
+        while size >= 2 {
+            let len = (size - 2).min(u8::MAX as usize);
+            padding.push(Self {
+                kind: PADDING,
+                data: vec![0; len],
+            });
+            size -= len + 2;
+        }
+        padding
+    }
+}
+
+// 0       4       8               16              24              32
+// +-------+-------+---------------+---------------+---------------+
+// | type  | ver   | extension     | connection_id                 |
+// +-------+-------+---------------+---------------+---------------+
+// | timestamp_microseconds                                        |
+// +---------------+---------------+---------------+---------------+
+// | timestamp_difference_microseconds                             |
+// +---------------+---------------+---------------+---------------+
+// | wnd_size                                                      |
+// +---------------+---------------+---------------+---------------+
+// | seq_nr                        | ack_nr                        |
+// +---------------+---------------+---------------+---------------+
+/// A uTP packet: header, extensions and payload
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_difference: u32,
+    /// bytes the sender is still willing to receive
+    pub window_size: u32,
+    /// sequence number
+    pub seq_nr: u16,
+    /// last sequence number received in order
+    pub ack_nr: u16,
+    pub extensions: Vec<Extension>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16) -> Self {
+        Self {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window_size: 0,
+            seq_nr: 0,
+            ack_nr: 0,
+            extensions: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let ext_size: usize = self.extensions.iter().map(|e| 2 + e.data.len()).sum();
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + ext_size + self.payload.len());
+        bytes.push(((self.packet_type as u8) << 4) | VERSION);
+        bytes.push(self.extensions.first().map_or(0, |e| e.kind));
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        bytes.extend_from_slice(&self.window_size.to_be_bytes());
+        bytes.extend_from_slice(&self.seq_nr.to_be_bytes());
+        bytes.extend_from_slice(&self.ack_nr.to_be_bytes());
+        // every extension starts with the type of the next one, 0 ends the chain
+        for (i, ext) in self.extensions.iter().enumerate() {
+            bytes.push(self.extensions.get(i + 1).map_or(0, |e| e.kind));
+            bytes.push(ext.data.len() as u8);
+            bytes.extend_from_slice(&ext.data);
+        }
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, UttdError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(UttdError::ProtocolViolation("uTP packet too short"));
+        }
+        if bytes[0] & 0x0f != VERSION {
+            return Err(UttdError::ProtocolViolation("unsupported uTP version"));
+        }
+        let packet_type = PacketType::try_from(bytes[0] >> 4)?;
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+
+        let mut extensions = Vec::new();
+        let mut kind = bytes[1];
+        let mut offset = HEADER_SIZE;
+        while kind != 0 {
+            if bytes.len() < offset + 2 {
+                return Err(UttdError::ProtocolViolation("truncated uTP extension"));
+            }
+            let (next, len) = (bytes[offset], bytes[offset + 1] as usize);
+            let data = bytes
+                .get(offset + 2..offset + 2 + len)
+                .ok_or(UttdError::ProtocolViolation("truncated uTP extension"))?;
+            extensions.push(Extension {
+                kind,
+                data: data.to_vec(),
+            });
+            kind = next;
+            offset += 2 + len;
+        }
+
+        Ok(Self {
+            packet_type,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            extensions,
+            payload: bytes[offset..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Extension, Packet, PacketType, SELECTIVE_ACK};
+
+    #[test]
+    fn new_packet() {
+        let packet = Packet::new(PacketType::Syn, 1).as_bytes();
+        assert_eq!(packet[0], 65);
+        assert_eq!(packet.len(), 20);
+    }
+
+    #[test]
+    fn round_trip() {
+        for packet_type in [
+            PacketType::Data,
+            PacketType::Fin,
+            PacketType::State,
+            PacketType::Reset,
+            PacketType::Syn,
+        ] {
+            let mut packet = Packet::new(packet_type, 0xbeef);
+            packet.timestamp = 1;
+            packet.timestamp_difference = 2;
+            packet.window_size = 3;
+            packet.seq_nr = 0xfffe;
+            packet.ack_nr = 5;
+            packet.payload = b"payload".to_vec();
+            assert_eq!(Packet::parse(&packet.as_bytes()).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn extensions() {
+        let mut packet = Packet::new(PacketType::State, 7);
+        packet.extensions = vec![
+            Extension {
+                kind: 1,
+                data: vec![0xff; 4],
+            },
+            Extension {
+                kind: 2,
+                data: vec![0; 8],
+            },
+        ];
+        let bytes = packet.as_bytes();
+        assert_eq!(bytes[1], 1);
+        assert_eq!(bytes[20..22], [2, 4]);
+        assert_eq!(Packet::parse(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn selective_ack_bitmask() {
+        // ack_nr + 1 = 11 is missing, 12, 13 and 20 arrived
+        let sack = Extension::selective_ack(10, [12, 13, 20].into_iter());
+        assert_eq!(sack.kind, SELECTIVE_ACK);
+        assert_eq!(sack.data, [0b0000_0011, 0b0000_0001, 0, 0]);
+        assert_eq!(sack.selectively_acked(10), [12, 13, 20]);
+
+        // grows in steps of 32 bits and wraps with the sequence numbers
+        let sack = Extension::selective_ack(0xfffe, [0, 40].into_iter());
+        assert_eq!(sack.data.len(), 8);
+        assert_eq!(sack.selectively_acked(0xfffe), [0, 40]);
+    }
+
+    #[test]
+    fn padding() {
+        let mut packet = Packet::new(PacketType::Data, 1);
+        packet.payload = vec![1; 10];
+        packet.extensions = Extension::padding(600);
+        let bytes = packet.as_bytes();
+        assert_eq!(bytes.len(), 20 + 600 + 10);
+        assert_eq!(Packet::parse(&bytes).unwrap().payload, vec![1; 10]);
+    }
+
+    #[test]
+    fn malformed() {
+        let packet = Packet::new(PacketType::Data, 1).as_bytes();
+        assert!(Packet::parse(&packet[..19]).is_err());
+
+        let mut bad_version = packet.clone();
+        bad_version[0] = 0x02;
+        assert!(Packet::parse(&bad_version).is_err());
+
+        let mut bad_type = packet.clone();
+        bad_type[0] = (5 << 4) | 1;
+        assert!(Packet::parse(&bad_type).is_err());
+
+        // extension claims more bytes than there are
+        let mut truncated = packet;
+        truncated[1] = 1;
+        truncated.extend_from_slice(&[0, 8, 0, 0]);
+        assert!(Packet::parse(&truncated).is_err());
+    }
+}